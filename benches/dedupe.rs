@@ -0,0 +1,117 @@
+// Rotating Bloom filter against the linear window it replaced.
+//
+//   cargo bench --no-default-features --features std --target <host triple>
+//
+// Traffic is a stream of message IDs where a share of lookups repeat an ID
+// seen a while back (a relayed copy arriving over a longer path). Reported per
+// structure: time per check, duplicates missed and fresh IDs wrongly dropped.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use bitchat_metal::protocol::dedupe::{DedupeConfig, DuplicateFilter};
+use bitchat_metal::protocol::message_id::MessageId;
+use bitchat_metal::protocol::rng::Rng;
+use heapless::Vec;
+
+const LOOKUPS: usize = 200_000;
+// One ID every 10 ms of virtual time
+const STEP_MS: u64 = 10;
+
+// The pre-Bloom scheme: the last N IDs, oldest evicted first
+struct LinearWindow<const N: usize> {
+    seen: Vec<MessageId, N>,
+}
+
+impl<const N: usize> LinearWindow<N> {
+    fn new() -> Self {
+        Self { seen: Vec::new() }
+    }
+
+    fn check_and_insert(&mut self, id: &MessageId) -> bool {
+        if self.seen.contains(id) {
+            return true;
+        }
+        if self.seen.is_full() {
+            self.seen.remove(0);
+        }
+        let _ = self.seen.push(*id);
+        false
+    }
+}
+
+struct Lookup {
+    id: MessageId,
+    duplicate: bool,
+}
+
+// `repeat_per_mille` of lookups repeat an ID from up to `max_age` IDs ago
+fn traffic(repeat_per_mille: u16, max_age: usize) -> std::vec::Vec<Lookup> {
+    let mut rng = Rng::new(0x5eed);
+    let mut fresh: std::vec::Vec<MessageId> = std::vec::Vec::new();
+    let mut lookups = std::vec::Vec::with_capacity(LOOKUPS);
+
+    for i in 0..LOOKUPS {
+        if !fresh.is_empty() && rng.chance(repeat_per_mille) {
+            let age = rng.range(1, max_age.min(fresh.len()) as u32) as usize;
+            lookups.push(Lookup { id: fresh[fresh.len() - age], duplicate: true });
+        } else {
+            let id = MessageId::compute(&[7; 8], i as u64, 1, &rng.next_u32().to_be_bytes());
+            fresh.push(id);
+            lookups.push(Lookup { id, duplicate: false });
+        }
+    }
+    lookups
+}
+
+struct Outcome {
+    ns_per_lookup: f64,
+    missed: usize,
+    false_drops: usize,
+}
+
+fn run(lookups: &[Lookup], mut check: impl FnMut(&MessageId, u64) -> bool) -> Outcome {
+    let mut missed = 0;
+    let mut false_drops = 0;
+    let start = Instant::now();
+    for (i, lookup) in lookups.iter().enumerate() {
+        let seen = black_box(check(&lookup.id, i as u64 * STEP_MS));
+        match (lookup.duplicate, seen) {
+            (true, false) => missed += 1,
+            (false, true) => false_drops += 1,
+            _ => {}
+        }
+    }
+    Outcome {
+        ns_per_lookup: start.elapsed().as_nanos() as f64 / lookups.len() as f64,
+        missed,
+        false_drops,
+    }
+}
+
+fn report(name: &str, outcome: Outcome) {
+    println!(
+        "  {:<22} {:>8.1} ns/lookup  {:>7} missed  {:>5} false drops",
+        name, outcome.ns_per_lookup, outcome.missed, outcome.false_drops
+    );
+}
+
+fn main() {
+    for (repeat_per_mille, max_age) in [(300, 16), (300, 200), (500, 1000)] {
+        println!("{} per mille repeats, up to {} IDs old:", repeat_per_mille, max_age);
+        let lookups = traffic(repeat_per_mille, max_age);
+
+        let mut window16 = LinearWindow::<16>::new();
+        report("linear, 16 (router)", run(&lookups, |id, _| window16.check_and_insert(id)));
+
+        let mut window32 = LinearWindow::<32>::new();
+        report("linear, 32 (handler)", run(&lookups, |id, _| window32.check_and_insert(id)));
+
+        let mut filter = DuplicateFilter::new(DedupeConfig::default());
+        report("bloom, default", run(&lookups, |id, now| filter.check_and_insert(id, now)));
+
+        let strict = DedupeConfig { false_positives_per_million: 10, ..DedupeConfig::default() };
+        let mut filter = DuplicateFilter::new(strict);
+        report("bloom, 10 ppm", run(&lookups, |id, now| filter.check_and_insert(id, now)));
+    }
+}
@@ -2,7 +2,9 @@ MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
   /* Softdevice S140 6.1.1 uses 0x26000 (152KB) of flash */
-  FLASH : ORIGIN = 0x00026000, LENGTH = 872K
-  /* Softdevice uses 0x2aa8 (~11KB) of RAM */
-  RAM : ORIGIN = 0x20002aa8, LENGTH = 245K
-}
\ No newline at end of file
+  /* Last 12K (0xFD000..0x100000) is reserved for persistent storage and the */
+  /* 256K below it (0xBD000..0xFD000) for incoming streams, see src/storage.rs */
+  FLASH : ORIGIN = 0x00026000, LENGTH = 604K
+  /* Softdevice RAM grows with MAX_CONNECTIONS (3 links at 247 MTU); 32K leaves headroom */
+  RAM : ORIGIN = 0x20008000, LENGTH = 224K
+}
@@ -0,0 +1,352 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use bitchat_metal::bitchat::{BitchatPacket, PacketType};
+use bitchat_metal::node::{AppEvent, LinkEvent, NodeCore, MAX_LINKS};
+use bitchat_metal::protocol::message_id::MessageId;
+use bitchat_metal::protocol::relay::LinkId;
+use bitchat_metal::protocol::rng::Rng;
+use bitchat_metal::replay::{Input, NodeIdentity, Recorded};
+
+use crate::scenario::{LinkSpec, Scenario};
+
+// One direction of a scenario link as seen from the sending node
+struct Channel {
+    peer: usize,
+    peer_link: LinkId,
+    spec: LinkSpec,
+    // The radio sends one frame at a time; later frames queue behind it
+    busy_until_us: u64,
+}
+
+enum Event {
+    Frame { node: usize, link: LinkId, data: Vec<u8> },
+    Timer { node: usize },
+    Send { node: usize, message: usize },
+}
+
+struct Scheduled {
+    at_us: u64,
+    seq: u64,
+    event: Event,
+}
+
+// Earliest first, ties in scheduling order, so runs are reproducible
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at_us, other.seq).cmp(&(self.at_us, self.seq))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at_us, self.seq) == (other.at_us, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub messages: usize,
+    // Texts handed to an application, first copy per node
+    pub delivered: usize,
+    // (message, node) pairs that should have received a text
+    pub expected: usize,
+    // Copies of an already received text arriving over the air
+    pub duplicates: usize,
+    // Texts the application saw twice; should always be zero
+    pub app_duplicates: usize,
+    pub frames_sent: usize,
+    pub frames_lost: usize,
+    pub hop_histogram: Vec<usize>,
+    pub airtime_us: u64,
+    pub busiest_node: Option<(String, u64)>,
+    // Replayable inputs of the node given to record(), one line each
+    pub recording: Vec<String>,
+}
+
+impl Report {
+    pub fn delivery_ratio(&self) -> f64 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+        self.delivered as f64 / self.expected as f64
+    }
+
+    pub fn max_hops(&self) -> usize {
+        self.hop_histogram.iter().rposition(|&n| n > 0).unwrap_or(0)
+    }
+
+    pub fn mean_hops(&self) -> f64 {
+        let total: usize = self.hop_histogram.iter().sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let sum: usize = self.hop_histogram.iter().enumerate().map(|(hops, n)| hops * n).sum();
+        sum as f64 / total as f64
+    }
+}
+
+pub struct Simulation<'a> {
+    scenario: &'a Scenario,
+    nodes: Vec<NodeCore>,
+    channels: Vec<[Option<Channel>; MAX_LINKS]>,
+    queue: BinaryHeap<Scheduled>,
+    seq: u64,
+    // The one pending Timer event per node; others in the queue are stale
+    timer_at: Vec<Option<u64>>,
+    rng: Rng,
+    // Per node: text IDs received over the air so far
+    heard: Vec<HashSet<MessageId>>,
+    // Per text: TTL it left its origin with
+    origin_ttl: HashMap<MessageId, u8>,
+    // Per node and message index: whether the application got it
+    received: Vec<Vec<bool>>,
+    airtime_by_node: Vec<u64>,
+    identities: Vec<NodeIdentity>,
+    recorded_node: Option<usize>,
+    report: Report,
+}
+
+impl<'a> Simulation<'a> {
+    pub fn new(scenario: &'a Scenario) -> Result<Self, String> {
+        let identities: Vec<NodeIdentity> = (0..scenario.nodes.len())
+            .map(|i| {
+                let index = (i as u32 + 1).to_be_bytes();
+                NodeIdentity {
+                    address: [0x5e, 0x11, index[0], index[1], index[2], index[3]],
+                    boot_epoch: 1,
+                    seed: scenario.seed.wrapping_mul(0x9e37_79b9_7f4a_7c15).wrapping_add(i as u64),
+                    nickname: heapless::String::try_from(scenario.nodes[i].as_str()).ok(),
+                }
+            })
+            .collect();
+        let nodes: Vec<NodeCore> = identities.iter().map(NodeIdentity::build).collect();
+
+        let mut channels: Vec<[Option<Channel>; MAX_LINKS]> =
+            (0..nodes.len()).map(|_| Default::default()).collect();
+        let mut next_link = vec![0usize; nodes.len()];
+        for spec in &scenario.links {
+            let (la, lb) = (next_link[spec.a], next_link[spec.b]);
+            if la >= MAX_LINKS || lb >= MAX_LINKS {
+                return Err(format!("more than {} links on one node", MAX_LINKS));
+            }
+            next_link[spec.a] += 1;
+            next_link[spec.b] += 1;
+            let (la, lb) = (LinkId(la as u8), LinkId(lb as u8));
+            channels[spec.a][la.0 as usize] = Some(Channel { peer: spec.b, peer_link: lb, spec: *spec, busy_until_us: 0 });
+            channels[spec.b][lb.0 as usize] = Some(Channel { peer: spec.a, peer_link: la, spec: *spec, busy_until_us: 0 });
+        }
+
+        let count = nodes.len();
+        Ok(Self {
+            scenario,
+            nodes,
+            channels,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            timer_at: vec![None; count],
+            rng: Rng::new(scenario.seed),
+            heard: vec![HashSet::new(); count],
+            origin_ttl: HashMap::new(),
+            received: vec![Vec::new(); count],
+            airtime_by_node: vec![0; count],
+            identities,
+            recorded_node: None,
+            report: Report::default(),
+        })
+    }
+
+    // Keeps everything fed to `node` in the report, for the replay tool
+    pub fn record(&mut self, node: usize) {
+        self.recorded_node = Some(node);
+        self.report.recording.push(self.identities[node].to_string());
+    }
+
+    fn note_input(&mut self, node: usize, at_ms: u64, input: Input) {
+        if self.recorded_node == Some(node) {
+            self.report.recording.push(Recorded { at_ms, input }.to_string());
+        }
+    }
+
+    pub fn run(mut self) -> Report {
+        let end_us = self.scenario.duration_ms * 1000;
+
+        for node in 0..self.nodes.len() {
+            for link in 0..MAX_LINKS {
+                if self.channels[node][link].is_some() {
+                    self.note_input(node, 0, Input::Connected(LinkId(link as u8)));
+                    self.nodes[node].handle(LinkEvent::Connected(LinkId(link as u8)), 0);
+                }
+            }
+            self.after_event(node, 0, false);
+        }
+
+        let scenario = self.scenario;
+        for send in &scenario.sends {
+            for i in 0..send.count as u64 {
+                let message = self.report.messages;
+                self.report.messages += 1;
+                let at_us = (send.at_ms + i * send.every_ms) * 1000;
+                self.schedule(at_us, Event::Send { node: send.node, message });
+            }
+        }
+        for received in &mut self.received {
+            received.resize(self.report.messages, false);
+        }
+
+        while let Some(next) = self.queue.pop() {
+            if next.at_us > end_us {
+                break;
+            }
+            let now_us = next.at_us;
+            let now_ms = now_us / 1000;
+            match next.event {
+                Event::Frame { node, link, data } => {
+                    if let Some(recorded) = Recorded::frame(now_ms, link, &data) {
+                        self.note_input(node, now_ms, recorded.input);
+                    }
+                    self.observe_frame(node, &data);
+                    self.nodes[node].handle(LinkEvent::BytesReceived(link, &data), now_ms);
+                    self.after_event(node, now_us, false);
+                }
+                Event::Timer { node } => {
+                    if self.timer_at[node] != Some(now_us) {
+                        continue;
+                    }
+                    self.timer_at[node] = None;
+                    self.nodes[node].handle(LinkEvent::TimerFired, now_ms);
+                    self.after_event(node, now_us, true);
+                }
+                Event::Send { node, message } => {
+                    let text = format!("sim:{}", message);
+                    if let Ok(text) = heapless::String::try_from(text.as_str()) {
+                        self.note_input(node, now_ms, Input::Send(text));
+                    }
+                    if self.nodes[node].send_text(&text, now_ms).is_ok() {
+                        self.received[node][message] = true;
+                        self.report.expected += self.nodes.len() - 1;
+                    }
+                    self.after_event(node, now_us, false);
+                }
+            }
+        }
+
+        self.finish()
+    }
+
+    fn schedule(&mut self, at_us: u64, event: Event) {
+        self.seq += 1;
+        self.queue.push(Scheduled { at_us, seq: self.seq, event });
+    }
+
+    // Transmits whatever the node queued, collects its app events and
+    // re-arms its timer
+    fn after_event(&mut self, node: usize, now_us: u64, timer_fired: bool) {
+        self.transmit(node, now_us);
+
+        while let Some(event) = self.nodes[node].poll_event() {
+            if let AppEvent::Text { payload, .. } = event {
+                let Some(message) = message_index(&payload) else { continue };
+                match self.received[node].get_mut(message) {
+                    Some(seen) if *seen => self.report.app_duplicates += 1,
+                    Some(seen) => {
+                        *seen = true;
+                        self.report.delivered += 1;
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        let deadline_us = self.nodes[node].poll_timeout().saturating_mul(1000);
+        // A deadline still due right after firing would spin; try again a tick later
+        let at_us = match deadline_us <= now_us {
+            true if timer_fired => now_us + 1000,
+            true => now_us,
+            false => deadline_us,
+        };
+        if self.timer_at[node].is_none_or(|t| at_us < t) {
+            self.timer_at[node] = Some(at_us);
+            self.schedule(at_us, Event::Timer { node });
+        }
+    }
+
+    fn transmit(&mut self, node: usize, now_us: u64) {
+        for link in 0..MAX_LINKS {
+            let link_id = LinkId(link as u8);
+            while let Some(frame) = self.nodes[node].next_transmit(link_id) {
+                let data = frame.to_vec();
+                self.nodes[node].transmitted(link_id, true);
+                let Some(channel) = self.channels[node][link].as_mut() else { continue };
+
+                let airtime_us = (data.len() as u64 * 8 * 1_000_000).div_ceil(channel.spec.bandwidth_bps);
+                let start_us = channel.busy_until_us.max(now_us);
+                channel.busy_until_us = start_us + airtime_us;
+                let arrival_us = channel.busy_until_us + channel.spec.latency_us;
+                let (peer, peer_link, loss) = (channel.peer, channel.peer_link, channel.spec.loss_per_mille);
+
+                self.airtime_by_node[node] += airtime_us;
+                self.report.airtime_us += airtime_us;
+                self.report.frames_sent += 1;
+                self.note_origin(node, &data);
+
+                if self.rng.chance(loss) {
+                    self.report.frames_lost += 1;
+                    continue;
+                }
+                self.schedule(arrival_us, Event::Frame { node: peer, link: peer_link, data });
+            }
+        }
+    }
+
+    fn note_origin(&mut self, node: usize, data: &[u8]) {
+        let Some(packet) = decode_text(data) else { return };
+        if packet.sender_id == self.nodes[node].device_id() {
+            self.origin_ttl.entry(packet.message_id()).or_insert(packet.ttl);
+        }
+    }
+
+    // Counts over-the-air duplicates and the hop count of first copies
+    fn observe_frame(&mut self, node: usize, data: &[u8]) {
+        let Some(packet) = decode_text(data) else { return };
+        if packet.sender_id == self.nodes[node].device_id() {
+            return;
+        }
+        let id = packet.message_id();
+        if !self.heard[node].insert(id) {
+            self.report.duplicates += 1;
+            return;
+        }
+        if let Some(ttl) = self.origin_ttl.get(&id) {
+            let hops = ttl.saturating_sub(packet.ttl) as usize + 1;
+            if self.report.hop_histogram.len() <= hops {
+                self.report.hop_histogram.resize(hops + 1, 0);
+            }
+            self.report.hop_histogram[hops] += 1;
+        }
+    }
+
+    fn finish(mut self) -> Report {
+        self.report.busiest_node = self.airtime_by_node.iter()
+            .enumerate()
+            .max_by_key(|(_, airtime)| **airtime)
+            .map(|(i, airtime)| (self.scenario.nodes[i].clone(), *airtime));
+        self.report
+    }
+}
+
+fn decode_text(data: &[u8]) -> Option<BitchatPacket> {
+    BitchatPacket::decode(data).ok().filter(|p| p.packet_type == PacketType::Text)
+}
+
+fn message_index(payload: &[u8]) -> Option<usize> {
+    std::str::from_utf8(payload).ok()?.strip_prefix("sim:")?.parse().ok()
+}
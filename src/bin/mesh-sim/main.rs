@@ -0,0 +1,141 @@
+// Discrete-event mesh simulator. Runs one NodeCore per scenario node on a
+// virtual clock and reports how broadcast texts spread.
+//
+//   mesh-sim [--seed N] [--record NODE FILE] <scenario>...
+//
+// --record writes what NODE received, in the format the replay tool reads.
+//
+// Exits non-zero if a scenario fails to parse or misses an `expect` line.
+
+mod engine;
+mod scenario;
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use engine::{Report, Simulation};
+use scenario::{Metric, Scenario};
+
+fn main() -> ExitCode {
+    let mut seed = None;
+    let mut record = None;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => match args.next().and_then(|s| s.parse().ok()) {
+                Some(value) => seed = Some(value),
+                None => return usage(),
+            },
+            "--record" => match (args.next(), args.next()) {
+                (Some(node), Some(file)) => record = Some((node, file)),
+                _ => return usage(),
+            },
+            "-h" | "--help" => return usage(),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        return usage();
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        let name = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or(path);
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        let mut scenario = match Scenario::parse(name, &text) {
+            Ok(scenario) => scenario,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        if let Some(seed) = seed {
+            scenario.seed = seed;
+        }
+
+        let report = match Simulation::new(&scenario) {
+            Ok(mut simulation) => {
+                if let Some((node, _)) = &record {
+                    match scenario.nodes.iter().position(|n| n == node) {
+                        Some(index) => simulation.record(index),
+                        None => {
+                            eprintln!("{}: no node {}", path, node);
+                            failed = true;
+                            continue;
+                        }
+                    }
+                }
+                simulation.run()
+            }
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        print_report(&scenario, &report);
+        if let Some((_, file)) = &record {
+            let mut text = report.recording.join("\n");
+            text.push('\n');
+            if let Err(e) = std::fs::write(file, text) {
+                eprintln!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+        failed |= !check_expectations(&scenario, &report);
+    }
+
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: mesh-sim [--seed N] [--record NODE FILE] <scenario>...");
+    ExitCode::from(2)
+}
+
+fn print_report(scenario: &Scenario, report: &Report) {
+    println!("== {} ({} nodes, {} links, {} s, seed {})",
+        scenario.name, scenario.nodes.len(), scenario.links.len(),
+        scenario.duration_ms / 1000, scenario.seed);
+    println!("  messages     {}", report.messages);
+    println!("  delivery     {:.3} ({}/{})", report.delivery_ratio(), report.delivered, report.expected);
+    println!("  duplicates   {} over the air, {} to the app", report.duplicates, report.app_duplicates);
+    println!("  frames       {} sent, {} lost", report.frames_sent, report.frames_lost);
+    let histogram: Vec<String> = report.hop_histogram.iter()
+        .enumerate()
+        .skip(1)
+        .map(|(hops, n)| format!("{}:{}", hops, n))
+        .collect();
+    println!("  hops         mean {:.2}, max {} [{}]", report.mean_hops(), report.max_hops(), histogram.join(" "));
+    let duration_us = (scenario.duration_ms * 1000).max(1);
+    print!("  airtime      {} ms", report.airtime_us / 1000);
+    if let Some((node, airtime_us)) = &report.busiest_node {
+        print!(", busiest {} at {:.1}%", node, *airtime_us as f64 * 100.0 / duration_us as f64);
+    }
+    println!();
+}
+
+fn check_expectations(scenario: &Scenario, report: &Report) -> bool {
+    let mut ok = true;
+    for expectation in &scenario.expectations {
+        let actual = match expectation.metric {
+            Metric::Delivery => report.delivery_ratio(),
+            Metric::Duplicates => report.duplicates as f64,
+            Metric::MaxHops => report.max_hops() as f64,
+            Metric::AirtimeMs => (report.airtime_us / 1000) as f64,
+        };
+        let holds = expectation.holds(actual);
+        println!("  expect       {} ... {} (got {})", expectation, if holds { "ok" } else { "FAILED" }, actual);
+        ok &= holds;
+    }
+    ok
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+
+// A scenario file, one directive per line; `#` starts a comment.
+//
+//   duration 60s
+//   seed 7
+//   node alice
+//   node bob
+//   link alice bob loss=5% latency=20ms bandwidth=250k
+//   send alice at=2s every=500ms count=20
+//   expect delivery >= 0.95
+//
+// Link parameters apply in both directions. Bandwidth is in bits per second
+// and accepts k/m suffixes. `send` broadcasts `count` texts from a node.
+// `expect` lines turn the report into a pass/fail check.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub name: String,
+    pub duration_ms: u64,
+    pub seed: u64,
+    pub nodes: Vec<String>,
+    pub links: Vec<LinkSpec>,
+    pub sends: Vec<SendSpec>,
+    pub expectations: Vec<Expectation>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkSpec {
+    pub a: usize,
+    pub b: usize,
+    pub loss_per_mille: u16,
+    pub latency_us: u64,
+    pub bandwidth_bps: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendSpec {
+    pub node: usize,
+    pub at_ms: u64,
+    pub every_ms: u64,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Delivery,
+    Duplicates,
+    MaxHops,
+    AirtimeMs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    AtLeast,
+    AtMost,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Expectation {
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub value: f64,
+}
+
+impl Expectation {
+    pub fn holds(&self, actual: f64) -> bool {
+        match self.comparison {
+            Comparison::AtLeast => actual >= self.value,
+            Comparison::AtMost => actual <= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metric = match self.metric {
+            Metric::Delivery => "delivery",
+            Metric::Duplicates => "duplicates",
+            Metric::MaxHops => "max_hops",
+            Metric::AirtimeMs => "airtime_ms",
+        };
+        let op = match self.comparison {
+            Comparison::AtLeast => ">=",
+            Comparison::AtMost => "<=",
+        };
+        write!(f, "{} {} {}", metric, op, self.value)
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Default link: a clean BLE connection at 2M PHY throughput after overhead
+const DEFAULT_LATENCY_US: u64 = 7_500;
+const DEFAULT_BANDWIDTH_BPS: u64 = 1_000_000;
+
+impl Scenario {
+    pub fn parse(name: &str, text: &str) -> Result<Self, ParseError> {
+        let mut scenario = Scenario {
+            name: name.to_string(),
+            duration_ms: 60_000,
+            seed: 1,
+            nodes: Vec::new(),
+            links: Vec::new(),
+            sends: Vec::new(),
+            expectations: Vec::new(),
+        };
+
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let err = |message: String| ParseError { line, message };
+            let content = raw.split('#').next().unwrap_or("").trim();
+            let mut words = content.split_whitespace();
+            let Some(directive) = words.next() else { continue };
+            let args: Vec<&str> = words.collect();
+
+            match directive {
+                "duration" => {
+                    let value = args.first().ok_or_else(|| err("duration needs a value".into()))?;
+                    scenario.duration_ms = parse_duration_us(value).map_err(err)? / 1000;
+                }
+                "seed" => {
+                    let value = args.first().ok_or_else(|| err("seed needs a value".into()))?;
+                    scenario.seed = value.parse().map_err(|_| err(format!("bad seed '{}'", value)))?;
+                }
+                "node" => {
+                    let node = args.first().ok_or_else(|| err("node needs a name".into()))?;
+                    if scenario.nodes.iter().any(|n| n == node) {
+                        return Err(err(format!("node '{}' defined twice", node)));
+                    }
+                    scenario.nodes.push(node.to_string());
+                }
+                "link" => {
+                    let [a, b, options @ ..] = args.as_slice() else {
+                        return Err(err("link needs two nodes".into()));
+                    };
+                    let a = scenario.node_index(a).map_err(err)?;
+                    let b = scenario.node_index(b).map_err(err)?;
+                    if a == b {
+                        return Err(err("link to itself".into()));
+                    }
+                    let mut link = LinkSpec {
+                        a,
+                        b,
+                        loss_per_mille: 0,
+                        latency_us: DEFAULT_LATENCY_US,
+                        bandwidth_bps: DEFAULT_BANDWIDTH_BPS,
+                    };
+                    for (key, value) in options.iter().map(|o| split_option(o)) {
+                        match key {
+                            "loss" => link.loss_per_mille = parse_ratio_per_mille(value).map_err(err)?,
+                            "latency" => link.latency_us = parse_duration_us(value).map_err(err)?,
+                            "bandwidth" => link.bandwidth_bps = parse_bandwidth(value).map_err(err)?,
+                            _ => return Err(err(format!("unknown link option '{}'", key))),
+                        }
+                    }
+                    scenario.links.push(link);
+                }
+                "send" => {
+                    let [node, options @ ..] = args.as_slice() else {
+                        return Err(err("send needs a node".into()));
+                    };
+                    let mut send = SendSpec {
+                        node: scenario.node_index(node).map_err(err)?,
+                        at_ms: 1_000,
+                        every_ms: 1_000,
+                        count: 1,
+                    };
+                    for (key, value) in options.iter().map(|o| split_option(o)) {
+                        match key {
+                            "at" => send.at_ms = parse_duration_us(value).map_err(err)? / 1000,
+                            "every" => send.every_ms = parse_duration_us(value).map_err(err)? / 1000,
+                            "count" => send.count = value.parse().map_err(|_| err(format!("bad count '{}'", value)))?,
+                            _ => return Err(err(format!("unknown send option '{}'", key))),
+                        }
+                    }
+                    scenario.sends.push(send);
+                }
+                "expect" => {
+                    let [metric, op, value] = args.as_slice() else {
+                        return Err(err("expect needs <metric> <op> <value>".into()));
+                    };
+                    let metric = match *metric {
+                        "delivery" => Metric::Delivery,
+                        "duplicates" => Metric::Duplicates,
+                        "max_hops" => Metric::MaxHops,
+                        "airtime_ms" => Metric::AirtimeMs,
+                        _ => return Err(err(format!("unknown metric '{}'", metric))),
+                    };
+                    let comparison = match *op {
+                        ">=" => Comparison::AtLeast,
+                        "<=" => Comparison::AtMost,
+                        _ => return Err(err(format!("unknown comparison '{}'", op))),
+                    };
+                    let value = value.parse().map_err(|_| err(format!("bad value '{}'", value)))?;
+                    scenario.expectations.push(Expectation { metric, comparison, value });
+                }
+                _ => return Err(err(format!("unknown directive '{}'", directive))),
+            }
+        }
+
+        if scenario.nodes.is_empty() {
+            return Err(ParseError { line: 0, message: "no nodes".into() });
+        }
+        Ok(scenario)
+    }
+
+    fn node_index(&self, name: &str) -> Result<usize, String> {
+        self.nodes.iter()
+            .position(|n| n == name)
+            .ok_or_else(|| format!("unknown node '{}'", name))
+    }
+}
+
+fn split_option(option: &str) -> (&str, &str) {
+    option.split_once('=').unwrap_or((option, ""))
+}
+
+// "250ms", "2s", "1.5s", "800us"; bare numbers are milliseconds
+fn parse_duration_us(value: &str) -> Result<u64, String> {
+    let (number, scale) = if let Some(n) = value.strip_suffix("us") {
+        (n, 1.0)
+    } else if let Some(n) = value.strip_suffix("ms") {
+        (n, 1_000.0)
+    } else if let Some(n) = value.strip_suffix('s') {
+        (n, 1_000_000.0)
+    } else {
+        (value, 1_000.0)
+    };
+    let number: f64 = number.parse().map_err(|_| format!("bad duration '{}'", value))?;
+    if number < 0.0 {
+        return Err(format!("negative duration '{}'", value));
+    }
+    Ok((number * scale) as u64)
+}
+
+// "5%" or "0.05"
+fn parse_ratio_per_mille(value: &str) -> Result<u16, String> {
+    let ratio = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => value.parse::<f64>(),
+    }
+    .map_err(|_| format!("bad ratio '{}'", value))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("ratio '{}' out of range", value));
+    }
+    Ok((ratio * 1000.0).round() as u16)
+}
+
+// "250k", "1m", "64000"
+fn parse_bandwidth(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let (number, scale) = if let Some(n) = lower.strip_suffix('k') {
+        (n, 1_000.0)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 1_000_000.0)
+    } else {
+        (lower.as_str(), 1.0)
+    };
+    let bps = number.parse::<f64>().map_err(|_| format!("bad bandwidth '{}'", value))? * scale;
+    if bps < 1.0 {
+        return Err(format!("bandwidth '{}' too low", value));
+    }
+    Ok(bps as u64)
+}
@@ -0,0 +1,314 @@
+use std::fmt::Write;
+
+use bitchat_metal::bitchat::announce::{TLV_NEIGHBORS, TLV_NICKNAME};
+use bitchat_metal::bitchat::{BitchatPacket, Flags, PacketType, PingPayload, BROADCAST_ID};
+use bitchat_metal::protocol::integrity::{self, AuthStatus, IntegrityError, MeshKey, TAG_SIZE};
+use bitchat_metal::protocol::message::{
+    MessageHeader, MessageType, FLAG_ACK_REQUESTED, FLAG_AUTHENTICATED, FLAG_DIRECTED, FLAG_RELAYED, FLAG_STREAM,
+    HEADER_SIZE, PROTOCOL_VERSION, RECIPIENT_SIZE,
+};
+use bitchat_metal::protocol::message_id::MessageId;
+use bitchat_metal::protocol::stream::{DIGEST_SIZE, MANIFEST_SIZE, STREAM_HEADER_SIZE};
+use bitchat_metal::stats::StatsSnapshot;
+
+use crate::input::to_hex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Auto,
+    Bitchat,
+    Message,
+}
+
+// Payload bytes shown in hex dumps before eliding the rest
+const DUMP_LIMIT: usize = 64;
+
+pub fn inspect(frame: &[u8], format: Format, key: Option<&MeshKey>) -> Result<String, String> {
+    let format = match format {
+        Format::Auto => detect(frame)?,
+        format => format,
+    };
+    let mut out = String::new();
+    match format {
+        Format::Message => inspect_message(&mut out, frame, key)?,
+        _ => inspect_bitchat(&mut out, frame)?,
+    }
+    Ok(out)
+}
+
+// Our own format has a version byte of 2 and a CRC, so a frame that checks
+// out as one is one. iOS packets are version 1.
+fn detect(frame: &[u8]) -> Result<Format, String> {
+    let header = MessageHeader::deserialize(frame);
+    if header.is_ok() && integrity::verify(frame, None).is_ok() {
+        return Ok(Format::Message);
+    }
+    match BitchatPacket::decode(frame) {
+        Ok(_) => Ok(Format::Bitchat),
+        // Show a damaged Message rather than nothing
+        Err(_) if header.is_ok() => Ok(Format::Message),
+        Err(e) => Err(format!(
+            "not a Message (version {:#04x}, expected {:#04x}) nor a BitchatPacket ({})",
+            frame.first().copied().unwrap_or(0), PROTOCOL_VERSION, e
+        )),
+    }
+}
+
+fn inspect_message(out: &mut String, frame: &[u8], key: Option<&MeshKey>) -> Result<(), String> {
+    let header = MessageHeader::deserialize(frame).map_err(|_| "invalid Message header".to_string())?;
+    let _ = writeln!(out, "protocol::Message, {} bytes", frame.len());
+    field(out, "version", header.version);
+    field(out, "type", format!("{:?} ({:#04x})", header.msg_type, header.msg_type as u8));
+    field(out, "sender", colon_hex(&header.sender_id));
+    field(out, "sequence", header.sequence);
+    field(out, "epoch", header.epoch);
+    field(out, "fragment", format!("{}/{}", header.fragment_index as u16 + 1, header.total_fragments));
+    field(out, "ttl", header.ttl);
+    field(out, "flags", flag_names(header.flags, &[
+        (FLAG_RELAYED, "RELAYED"),
+        (FLAG_STREAM, "STREAM"),
+        (FLAG_AUTHENTICATED, "AUTHENTICATED"),
+        (FLAG_ACK_REQUESTED, "ACK_REQUESTED"),
+        (FLAG_DIRECTED, "DIRECTED"),
+    ]));
+
+    let tagged = header.flags & FLAG_AUTHENTICATED != 0 && frame.len() >= HEADER_SIZE + TAG_SIZE;
+    let body_len = if tagged { frame.len() - TAG_SIZE } else { frame.len() };
+    let computed = integrity::crc16(&frame[..14], &frame[16..]);
+    let checksum = if computed == header.checksum {
+        format!("{:#06x} ok", header.checksum)
+    } else {
+        format!("{:#06x} BAD (computed {:#06x})", header.checksum, computed)
+    };
+    field(out, "checksum", checksum);
+
+    let tag = match (tagged, key, integrity::verify(frame, key)) {
+        (false, _, _) => "none".to_string(),
+        (true, None, _) => format!("{} (not checked, no --key)", to_hex(&frame[body_len..])),
+        (true, Some(_), Ok((AuthStatus::Authenticated, _))) => format!("{} ok", to_hex(&frame[body_len..])),
+        (true, Some(_), Err(IntegrityError::BadTag)) => format!("{} BAD", to_hex(&frame[body_len..])),
+        (true, Some(_), _) => format!("{} (not checked, checksum failed)", to_hex(&frame[body_len..])),
+    };
+    field(out, "tag", tag);
+
+    let payload = &frame[HEADER_SIZE..body_len];
+    field(out, "payload", format!("{} bytes", payload.len()));
+
+    if header.is_stream() {
+        return inspect_stream(out, payload);
+    }
+    if header.total_fragments > 1 {
+        // The payload is a slice of the whole message; only the reassembled
+        // message can be interpreted
+        dump(out, payload);
+        return Ok(());
+    }
+
+    let body = match payload.get(..RECIPIENT_SIZE) {
+        Some(recipient) if header.flags & FLAG_DIRECTED != 0 => {
+            field(out, "  to", colon_hex(recipient));
+            &payload[RECIPIENT_SIZE..]
+        }
+        _ => payload,
+    };
+    match header.msg_type {
+        MessageType::Text | MessageType::Relay => text(out, "text", body),
+        MessageType::Announce => text(out, "nickname", body),
+        MessageType::Ack => match MessageId::from_bytes(body) {
+            Some(id) => field(out, "  acks", to_hex(&id.0)),
+            None => dump(out, body),
+        },
+    }
+    let id = MessageId::compute(
+        &header.sender_id,
+        ((header.epoch as u64) << 16) | header.sequence as u64,
+        header.msg_type as u8,
+        payload,
+    );
+    field(out, "message id", to_hex(&id.0));
+    Ok(())
+}
+
+fn inspect_stream(out: &mut String, payload: &[u8]) -> Result<(), String> {
+    if payload.len() < STREAM_HEADER_SIZE {
+        field(out, "  stream", "truncated stream header");
+        return Ok(());
+    }
+    let index = u16::from_be_bytes([payload[0], payload[1]]);
+    let total = u16::from_be_bytes([payload[2], payload[3]]);
+    let offset = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+    let data = &payload[STREAM_HEADER_SIZE..];
+    field(out, "  stream", format!("fragment {} of {}", index, total));
+
+    if index == 0 {
+        if data.len() < MANIFEST_SIZE {
+            field(out, "  manifest", "truncated");
+            return Ok(());
+        }
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        field(out, "  length", format!("{} bytes", length));
+        field(out, "  sha-256", to_hex(&data[4..4 + DIGEST_SIZE]));
+    } else {
+        field(out, "  offset", offset);
+        dump(out, data);
+    }
+    Ok(())
+}
+
+fn inspect_bitchat(out: &mut String, frame: &[u8]) -> Result<(), String> {
+    let packet = BitchatPacket::decode(frame).map_err(|e| format!("invalid BitchatPacket: {}", e))?;
+    let _ = writeln!(out, "BitchatPacket, {} bytes", frame.len());
+    field(out, "version", packet.version);
+    let raw_type = frame[1];
+    let type_name = if packet.packet_type as u8 == raw_type {
+        format!("{:?}", packet.packet_type)
+    } else {
+        "unknown".to_string()
+    };
+    field(out, "type", format!("{} ({:#04x})", type_name, raw_type));
+    field(out, "ttl", packet.ttl);
+    field(out, "timestamp", format!(
+        "{} (epoch {}, uptime {} ms)",
+        packet.timestamp, packet.epoch(), packet.timestamp & 0xFFFF_FFFF
+    ));
+    field(out, "flags", flag_names(packet.flags, &[
+        (Flags::HAS_RECIPIENT, "HAS_RECIPIENT"),
+        (Flags::HAS_SIGNATURE, "HAS_SIGNATURE"),
+        (Flags::IS_COMPRESSED, "IS_COMPRESSED"),
+        (Flags::HAS_ROUTE, "HAS_ROUTE"),
+    ]));
+    field(out, "sender", colon_hex(&packet.sender_id));
+    match packet.recipient_id {
+        Some(BROADCAST_ID) => field(out, "recipient", "broadcast"),
+        Some(recipient) => field(out, "recipient", colon_hex(&recipient)),
+        None => {}
+    }
+    for (i, hop) in packet.route.iter().enumerate() {
+        field(out, &format!("route[{}]", i), colon_hex(hop));
+    }
+
+    field(out, "payload", format!("{} bytes", packet.payload.len()));
+    if packet.flags & Flags::IS_COMPRESSED != 0 {
+        field(out, "  note", "compressed, shown as is");
+        dump(out, &packet.payload);
+    } else {
+        match packet.packet_type {
+            PacketType::Announce => announce_tlvs(out, &packet.payload),
+            PacketType::Ping | PacketType::PingReply => ping(out, &packet.payload),
+            PacketType::StatsReply => stats(out, &packet.payload),
+            PacketType::Text => text(out, "text", &packet.payload),
+            _ => dump(out, &packet.payload),
+        }
+    }
+
+    match packet.signature {
+        // Ed25519 keys come from the Noise handshake, which we don't implement
+        Some(signature) => field(out, "signature", format!("{}... (64 bytes, not verified)", to_hex(&signature[..8]))),
+        None => field(out, "signature", "none"),
+    }
+    let consumed = packet.encode().map(|data| data.len()).unwrap_or(frame.len());
+    if consumed < frame.len() {
+        field(out, "trailing", format!("{} bytes after the packet (padding?)", frame.len() - consumed));
+    }
+    field(out, "message id", to_hex(&packet.message_id().0));
+    Ok(())
+}
+
+fn announce_tlvs(out: &mut String, payload: &[u8]) {
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    while offset < payload.len() {
+        let Some(&len) = payload.get(offset + 1) else { break };
+        let Some(value) = payload.get(offset + 2..offset + 2 + len as usize) else { break };
+        entries.push((payload[offset], value));
+        offset += 2 + len as usize;
+    }
+    if offset != payload.len() || !entries.iter().any(|(t, _)| *t == TLV_NICKNAME) {
+        // Older peers send the nickname on its own
+        text(out, "nickname", payload);
+        return;
+    }
+
+    for (tlv_type, value) in entries {
+        match tlv_type {
+            TLV_NICKNAME => text(out, "tlv 0x01", value),
+            TLV_NEIGHBORS => {
+                field(out, "  tlv 0x04", format!("neighbors, {}", value.len() / 8));
+                for neighbor in value.chunks(8) {
+                    field(out, "", colon_hex(neighbor));
+                }
+            }
+            _ => field(out, &format!("  tlv {:#04x}", tlv_type), format!("{} bytes: {}", value.len(), to_hex(value))),
+        }
+    }
+}
+
+fn ping(out: &mut String, payload: &[u8]) {
+    let ping = match PingPayload::decode(payload) {
+        Ok(ping) => ping,
+        Err(e) => {
+            field(out, "  ping", format!("undecodable: {}", e));
+            dump(out, payload);
+            return;
+        }
+    };
+    field(out, "  kind", if ping.traceroute { "traceroute" } else { "echo" });
+    field(out, "  nonce", ping.nonce);
+    field(out, "  initial ttl", ping.initial_ttl);
+    field(out, "  target ttl", ping.target_ttl);
+    for (i, hop) in ping.hops.iter().enumerate() {
+        field(out, &format!("  hop {}", i + 1), format!("{} at {} ms", colon_hex(&hop.peer), hop.time_ms));
+    }
+}
+
+fn stats(out: &mut String, payload: &[u8]) {
+    match StatsSnapshot::decode(payload) {
+        Ok(snapshot) => {
+            for line in snapshot.to_string().lines() {
+                let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+                field(out, &format!("  {}", name), value);
+            }
+        }
+        Err(e) => {
+            field(out, "  stats", format!("undecodable: {}", e));
+            dump(out, payload);
+        }
+    }
+}
+
+fn text(out: &mut String, name: &str, payload: &[u8]) {
+    match std::str::from_utf8(payload) {
+        Ok(text) => field(out, &format!("  {}", name), format!("{:?}", text)),
+        Err(_) => dump(out, payload),
+    }
+}
+
+fn dump(out: &mut String, bytes: &[u8]) {
+    let shown = &bytes[..bytes.len().min(DUMP_LIMIT)];
+    for (i, line) in shown.chunks(16).enumerate() {
+        field(out, &format!("  {:04x}", i * 16), to_hex(line));
+    }
+    if bytes.len() > DUMP_LIMIT {
+        field(out, "  ...", format!("{} more bytes", bytes.len() - DUMP_LIMIT));
+    }
+}
+
+fn field(out: &mut String, name: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "  {:<14} {}", name, value);
+}
+
+fn flag_names(flags: u8, names: &[(u8, &str)]) -> String {
+    let mut set: Vec<String> = names.iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unknown = names.iter().fold(flags, |rest, (bit, _)| rest & !bit);
+    if unknown != 0 {
+        set.push(format!("unknown {:#04x}", unknown));
+    }
+    format!("{:#04x} [{}]", flags, set.join(" "))
+}
+
+fn colon_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
@@ -0,0 +1,260 @@
+// Test frames from a one-line description, e.g.
+//
+//   bitchat type=text ttl=5 sender=0102030405060708 payload="hello"
+//   bitchat type=announce sender=aa nickname=alice neighbors=0102030405060708
+//   message type=text sender=010203040506 seq=7 epoch=2 payload=hex:00ff mtu=64
+//
+// Fields not given keep the library defaults. IDs shorter than their field are
+// zero padded on the right.
+
+use bitchat_metal::bitchat::{AnnouncePayload, BitchatPacket, Flags, PacketType, PingPayload, BROADCAST_ID};
+use bitchat_metal::protocol::integrity::MeshKey;
+use bitchat_metal::protocol::message::{Message, MessageType, MAX_FRAGMENT_SIZE};
+
+use crate::input::parse_hex;
+
+pub fn encode(description: &str, key: Option<&MeshKey>) -> Result<Vec<Vec<u8>>, String> {
+    let tokens = tokenize(description)?;
+    let (kind, fields) = tokens.split_first().ok_or("empty description")?;
+    let fields = fields
+        .iter()
+        .map(|t| t.split_once('=').ok_or_else(|| format!("expected key=value, got `{}`", t)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match kind.as_str() {
+        "bitchat" => encode_bitchat(&fields).map(|frame| vec![frame]),
+        "message" => encode_message(&fields, key),
+        other => Err(format!("unknown format `{}`, expected bitchat or message", other)),
+    }
+}
+
+fn encode_bitchat(fields: &[(&str, &str)]) -> Result<Vec<u8>, String> {
+    let mut packet_type = PacketType::Text;
+    let mut sender = [0u8; 8];
+    let mut payload: Option<Vec<u8>> = None;
+    let mut announce = AnnouncePayload::new("");
+    let mut announce_given = false;
+    let mut ping: Option<PingPayload> = None;
+    let mut rest = Vec::new();
+
+    for &(name, value) in fields {
+        match name {
+            "type" => packet_type = packet_type_named(value)?,
+            "sender" => sender = id(value)?,
+            "payload" => payload = Some(bytes(value)?),
+            "nickname" => {
+                announce.nickname = AnnouncePayload::new(value).nickname;
+                announce_given = true;
+            }
+            "neighbors" => {
+                for neighbor in value.split(',') {
+                    announce.neighbors.push(id(neighbor)?).map_err(|_| "too many neighbors")?;
+                }
+                announce_given = true;
+            }
+            "nonce" | "traceroute" => {
+                let ping = ping.get_or_insert(PingPayload {
+                    traceroute: false,
+                    nonce: 0,
+                    initial_ttl: 0,
+                    target_ttl: 0,
+                    hops: heapless::Vec::new(),
+                });
+                match name {
+                    "nonce" => ping.nonce = number(name, value)?,
+                    _ => ping.traceroute = flag(name, value)?,
+                }
+            }
+            _ => rest.push((name, value)),
+        }
+    }
+
+    let mut packet = BitchatPacket::new(packet_type, sender, &[])?;
+    let mut epoch = None;
+    for (name, value) in rest {
+        match name {
+            "ttl" => packet.ttl = number(name, value)?,
+            "timestamp" => packet.timestamp = number(name, value)?,
+            "epoch" => epoch = Some(number(name, value)?),
+            "recipient" => {
+                packet.recipient_id = Some(if value == "broadcast" { BROADCAST_ID } else { id(value)? });
+                packet.flags |= Flags::HAS_RECIPIENT;
+            }
+            "route" => {
+                for hop in value.split(',') {
+                    packet.route.push(id(hop)?).map_err(|_| "route too long")?;
+                }
+                packet.flags |= Flags::HAS_ROUTE;
+            }
+            "signature" => {
+                let mut signature = [0u8; 64];
+                fill(&mut signature, &bytes(value)?, "signature")?;
+                packet.signature = Some(signature);
+                packet.flags |= Flags::HAS_SIGNATURE;
+            }
+            "compressed" => {
+                if flag(name, value)? {
+                    packet.flags |= Flags::IS_COMPRESSED;
+                }
+            }
+            _ => return Err(format!("unknown bitchat field `{}`", name)),
+        }
+    }
+    if let Some(epoch) = epoch {
+        packet.timestamp = BitchatPacket::epoch_timestamp(epoch, packet.timestamp);
+    }
+
+    let payload = match (payload, ping, announce_given) {
+        (Some(payload), _, _) => payload,
+        (None, Some(mut ping), _) => {
+            ping.initial_ttl = packet.ttl;
+            ping.encode()?.to_vec()
+        }
+        (None, None, true) => announce.encode()?.to_vec(),
+        (None, None, false) => Vec::new(),
+    };
+    packet.payload = heapless::Vec::from_slice(&payload).map_err(|_| "payload too large")?;
+    packet.encode().map(|data| data.to_vec()).map_err(|e| e.to_string())
+}
+
+fn encode_message(fields: &[(&str, &str)], key: Option<&MeshKey>) -> Result<Vec<Vec<u8>>, String> {
+    let mut msg_type = MessageType::Text;
+    let mut sender = [0u8; 6];
+    let mut sequence = 0;
+    let mut payload = Vec::new();
+    let mut mtu = MAX_FRAGMENT_SIZE as u16 + 3;
+    let mut rest = Vec::new();
+
+    for &(name, value) in fields {
+        match name {
+            "type" => msg_type = message_type_named(value)?,
+            "sender" => fill(&mut sender, &hex(value)?, "sender")?,
+            "seq" => sequence = number(name, value)?,
+            "payload" => payload = bytes(value)?,
+            "mtu" => mtu = number(name, value)?,
+            _ => rest.push((name, value)),
+        }
+    }
+
+    let mut message = Message::new(msg_type, sender, sequence, &payload).map_err(|_| "payload too large")?;
+    for (name, value) in rest {
+        match name {
+            "ttl" => message.header.ttl = number(name, value)?,
+            "epoch" => message.header.epoch = number(name, value)?,
+            "flags" => message.header.flags = number(name, value)?,
+            _ => return Err(format!("unknown message field `{}`", name)),
+        }
+    }
+
+    let fragments = message
+        .fragments_with_key(mtu, key)
+        .map_err(|_| format!("payload needs more than 255 fragments at MTU {}", mtu))?;
+    Ok(fragments.map(|f| f.to_vec()).collect())
+}
+
+fn packet_type_named(name: &str) -> Result<PacketType, String> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "text" => PacketType::Text,
+        "announce" => PacketType::Announce,
+        "file" => PacketType::File,
+        "ack" => PacketType::Ack,
+        "discovery" => PacketType::Discovery,
+        "keepalive" => PacketType::KeepAlive,
+        "error" => PacketType::Error,
+        "ping" => PacketType::Ping,
+        "pingreply" => PacketType::PingReply,
+        "stats" => PacketType::Stats,
+        "statsreply" => PacketType::StatsReply,
+        _ => return Err(format!("unknown packet type `{}`", name)),
+    })
+}
+
+fn message_type_named(name: &str) -> Result<MessageType, String> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "text" => MessageType::Text,
+        "ack" => MessageType::Ack,
+        "announce" => MessageType::Announce,
+        "relay" => MessageType::Relay,
+        _ => return Err(format!("unknown message type `{}`", name)),
+    })
+}
+
+// Quoted or bare text, or `hex:` followed by bytes
+fn bytes(value: &str) -> Result<Vec<u8>, String> {
+    match value.strip_prefix("hex:") {
+        Some(digits) => hex(digits),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+fn hex(value: &str) -> Result<Vec<u8>, String> {
+    parse_hex(value).ok_or_else(|| format!("`{}` is not hex", value))
+}
+
+fn id(value: &str) -> Result<[u8; 8], String> {
+    let mut id = [0u8; 8];
+    fill(&mut id, &hex(value)?, "peer ID")?;
+    Ok(id)
+}
+
+fn fill(field: &mut [u8], value: &[u8], name: &str) -> Result<(), String> {
+    if value.len() > field.len() {
+        return Err(format!("{} is {} bytes, at most {} fit", name, value.len(), field.len()));
+    }
+    field[..value.len()].copy_from_slice(value);
+    Ok(())
+}
+
+fn number<T: TryFrom<u64>>(name: &str, value: &str) -> Result<T, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => value.parse(),
+    };
+    parsed
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("{} `{}` is not a number in range", name, value))
+}
+
+fn flag(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("{} `{}` is not a boolean", name, value)),
+    }
+}
+
+// Whitespace separated, with double quotes around values that contain spaces
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            '\\' if quoted => current.push(chars.next().ok_or("dangling backslash")?),
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    tokens.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            c => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("unterminated quote".to_string());
+    }
+    if started {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,86 @@
+// Turning what people paste into bytes. RTT logs print frames as defmt
+// `[01, 02, ..]` lists; other tools give plain hex, `0x` prefixed bytes or
+// base64.
+
+const BASE64_STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// Hex if the text reads as hex, base64 otherwise
+pub fn parse_text(text: &str, force_base64: bool) -> Result<Vec<u8>, String> {
+    if !force_base64 {
+        if let Some(bytes) = parse_hex(text) {
+            return Ok(bytes);
+        }
+    }
+    parse_base64(text).ok_or_else(|| {
+        if force_base64 { "not valid base64".to_string() } else { "neither hex nor base64".to_string() }
+    })
+}
+
+// Raw binary, unless the file holds a hex or base64 dump
+pub fn parse_file(contents: &[u8], force_base64: bool) -> Vec<u8> {
+    match std::str::from_utf8(contents) {
+        Ok(text) if !text.trim().is_empty() => {
+            parse_text(text, force_base64).unwrap_or_else(|_| contents.to_vec())
+        }
+        _ => contents.to_vec(),
+    }
+}
+
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let tokens: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | ':' | '[' | ']'))
+        .filter(|t| !t.is_empty())
+        .map(|t| t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")).unwrap_or(t))
+        .collect();
+    if tokens.is_empty() || !tokens.iter().all(|t| t.chars().all(|c| c.is_ascii_hexdigit())) {
+        return None;
+    }
+
+    // A list of single bytes may drop leading zeros ("[1, 2, ff]")
+    let digits: String = if tokens.len() > 1 && tokens.iter().all(|t| t.len() <= 2) {
+        tokens.iter().map(|t| format!("{:0>2}", t)).collect()
+    } else {
+        tokens.concat()
+    };
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Standard or URL-safe alphabet, padding optional
+pub fn parse_base64(text: &str) -> Option<Vec<u8>> {
+    let symbols: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let symbols = match symbols.iter().position(|&b| b == b'=') {
+        Some(pad) if symbols[pad..].iter().all(|&b| b == b'=') => &symbols[..pad],
+        Some(_) => return None,
+        None => &symbols[..],
+    };
+    if symbols.is_empty() || symbols.len() % 4 == 1 {
+        return None;
+    }
+
+    let value = |b: u8| {
+        BASE64_STANDARD.iter().position(|&s| s == b)
+            .or_else(|| BASE64_URL.iter().position(|&s| s == b))
+            .map(|v| v as u32)
+    };
+    let mut out = Vec::with_capacity(symbols.len() * 3 / 4);
+    for chunk in symbols.chunks(4) {
+        let mut word = 0u32;
+        for (i, &b) in chunk.iter().enumerate() {
+            word |= value(b)? << (18 - 6 * i);
+        }
+        let bytes = word.to_be_bytes();
+        out.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,157 @@
+// Decodes frames copied out of logs or sniffers, and builds test frames.
+//
+//   packet-inspect [--key HEX] [--as bitchat|message] [--base64] [FRAME | @FILE]...
+//   packet-inspect [--key HEX] encode DESCRIPTION...
+//
+// Frames are hex (any separators, defmt `[..]` lists included) or base64.
+// `@FILE` reads a raw capture or a text dump; with no frames given, stdin is
+// read one frame per line. A pcapng capture dump given as `@FILE` is listed
+// frame by frame. Whether a frame is a BitchatPacket or one of our
+// own Messages is detected unless `--as` says otherwise. `--key` is the mesh
+// key, used to check and produce authentication tags.
+
+mod decode;
+mod encode;
+mod input;
+
+use std::io::BufRead;
+use std::process::ExitCode;
+
+use bitchat_metal::capture::{CapturedFrame, Direction, PcapngReader};
+use bitchat_metal::protocol::integrity::MeshKey;
+use decode::Format;
+
+fn main() -> ExitCode {
+    let mut format = Format::Auto;
+    let mut force_base64 = false;
+    let mut key = None;
+    let mut inputs = Vec::new();
+    let mut encoding = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--as" => match args.next().as_deref() {
+                Some("bitchat") => format = Format::Bitchat,
+                Some("message") => format = Format::Message,
+                _ => return usage(),
+            },
+            "--base64" => force_base64 = true,
+            "--key" => match args.next().as_deref().and_then(parse_key) {
+                Some(value) => key = Some(value),
+                None => {
+                    eprintln!("--key takes 32 bytes of hex");
+                    return ExitCode::from(2);
+                }
+            },
+            "encode" if inputs.is_empty() && !encoding => encoding = true,
+            "-h" | "--help" => return usage(),
+            _ => inputs.push(arg),
+        }
+    }
+
+    if encoding {
+        if inputs.is_empty() {
+            return usage();
+        }
+        return match encode::encode(&inputs.join(" "), key.as_ref()) {
+            Ok(frames) => {
+                for frame in frames {
+                    println!("{}", input::to_hex(&frame));
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("encode: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut frames = Vec::new();
+    if inputs.is_empty() {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => frames.push((line.clone(), None, input::parse_text(&line, force_base64))),
+                Err(e) => {
+                    eprintln!("stdin: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+    for arg in inputs {
+        let Some(path) = arg.strip_prefix('@') else {
+            let frame = input::parse_text(&arg, force_base64);
+            frames.push((arg, None, frame));
+            continue;
+        };
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                frames.push((arg, None, Err(e.to_string())));
+                continue;
+            }
+        };
+        if !PcapngReader::is_pcapng(&contents) {
+            frames.push((arg, None, Ok(input::parse_file(&contents, force_base64))));
+            continue;
+        }
+        for frame in PcapngReader::new(&contents) {
+            match frame {
+                Ok(frame) => frames.push((arg.clone(), Some(label(&frame)), Ok(frame.data.to_vec()))),
+                Err(e) => frames.push((arg.clone(), None, Err(e.to_string()))),
+            }
+        }
+    }
+
+    let mut failed = false;
+    for (i, (source, label, frame)) in frames.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        if let Some(label) = label {
+            println!("{}", label);
+        }
+        match frame.and_then(|frame| decode::inspect(&frame, format, key.as_ref())) {
+            Ok(text) => print!("{}", text),
+            Err(e) => {
+                eprintln!("{}: {}", abbreviate(&source), e);
+                failed = true;
+            }
+        }
+    }
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: packet-inspect [--key HEX] [--as bitchat|message] [--base64] [FRAME | @FILE]...");
+    eprintln!("       packet-inspect [--key HEX] encode DESCRIPTION...");
+    ExitCode::from(2)
+}
+
+fn parse_key(text: &str) -> Option<MeshKey> {
+    let bytes: [u8; 32] = input::parse_hex(text)?.try_into().ok()?;
+    Some(MeshKey::new(bytes))
+}
+
+fn label(frame: &CapturedFrame) -> String {
+    format!(
+        "#{} at {} ms, link {} {}{}",
+        frame.seq + 1,
+        frame.timestamp_ms,
+        frame.link.0,
+        match frame.direction { Direction::Rx => "rx", Direction::Tx => "tx" },
+        if frame.original_len as usize > frame.data.len() { ", truncated" } else { "" }
+    )
+}
+
+fn abbreviate(source: &str) -> String {
+    if source.starts_with('@') {
+        return source.to_string();
+    }
+    match source.char_indices().nth(24) {
+        Some((end, _)) => format!("{}...", &source[..end]),
+        None => source.to_string(),
+    }
+}
@@ -0,0 +1,248 @@
+// Replays a recording into a fresh node and prints what it did.
+//
+//   replay [--handler] [--stats] [--address HEX] [--epoch N] [--seed N] <recording>...
+//
+// A recording is a text file (format in bitchat_metal::replay) or a pcapng
+// capture dump, whose received frames are replayed on the links they came in
+// on. Dumps don't say who the node was, so --address, --epoch and --seed set
+// that; for text recordings they override the `node` line.
+//
+// By default the frames go through NodeCore and the output lists frames sent
+// and application events, then with --stats the node's counters. With
+// --handler each frame goes straight into MessageHandler and the output lists
+// the actions it returned, with relay actions carried out as NodeCore does:
+// their frames are listed as `send` lines once the delay is up.
+//
+// `expect TEXT` and `reject TEXT` lines in a text recording check the output:
+// some output line must contain TEXT, or none may. Exits non-zero if one
+// fails, so a recording with checks works as a regression test.
+
+mod output;
+
+use std::process::ExitCode;
+
+use bitchat_metal::capture::{Direction, PcapngReader};
+use bitchat_metal::protocol::{Action, MessageHandler};
+use bitchat_metal::replay::{parse_line, Input, Line, NodeIdentity, Recorded, Replayer};
+
+struct Recording {
+    identity: Option<NodeIdentity>,
+    inputs: Vec<Recorded>,
+    expect: Vec<String>,
+    reject: Vec<String>,
+}
+
+#[derive(Default)]
+struct Overrides {
+    address: Option<[u8; 6]>,
+    boot_epoch: Option<u32>,
+    seed: Option<u64>,
+}
+
+fn main() -> ExitCode {
+    let mut handler_mode = false;
+    let mut show_stats = false;
+    let mut overrides = Overrides::default();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let ok = match arg.as_str() {
+            "--handler" => {
+                handler_mode = true;
+                true
+            }
+            "--stats" => {
+                show_stats = true;
+                true
+            }
+            "--address" => args.next().and_then(|a| parse_address(&a)).map(|a| overrides.address = Some(a)).is_some(),
+            "--epoch" => args.next().and_then(|a| a.parse().ok()).map(|e| overrides.boot_epoch = Some(e)).is_some(),
+            "--seed" => args.next().and_then(|a| a.parse().ok()).map(|s| overrides.seed = Some(s)).is_some(),
+            "-h" | "--help" => false,
+            _ => {
+                paths.push(arg);
+                true
+            }
+        };
+        if !ok {
+            return usage();
+        }
+    }
+    if paths.is_empty() {
+        return usage();
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        let recording = match load(path) {
+            Ok(recording) => recording,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        let identity = identity(recording.identity.as_ref(), &overrides);
+        println!("== {} ({}, {} inputs)", path, identity, recording.inputs.len());
+
+        let lines = if handler_mode {
+            replay_handler(&identity, &recording.inputs)
+        } else {
+            replay_node(&identity, &recording.inputs, show_stats)
+        };
+        for line in &lines {
+            println!("  {}", line);
+        }
+        failed |= !check(&recording, &lines);
+    }
+    if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: replay [--handler] [--stats] [--address HEX] [--epoch N] [--seed N] <recording>...");
+    ExitCode::from(2)
+}
+
+fn load(path: &str) -> Result<Recording, String> {
+    let contents = std::fs::read(path).map_err(|e| e.to_string())?;
+    let mut recording = Recording { identity: None, inputs: Vec::new(), expect: Vec::new(), reject: Vec::new() };
+
+    if PcapngReader::is_pcapng(&contents) {
+        let mut connected = [false; 256];
+        for frame in PcapngReader::new(&contents) {
+            let frame = frame.map_err(|e| e.to_string())?;
+            if frame.direction != Direction::Rx {
+                continue;
+            }
+            // The dump doesn't hold link setup, so links come up with their first frame
+            if !std::mem::replace(&mut connected[frame.link.0 as usize], true) {
+                recording.inputs.push(Recorded { at_ms: frame.timestamp_ms, input: Input::Connected(frame.link) });
+            }
+            let at_ms = frame.timestamp_ms;
+            recording.inputs.extend(Recorded::frame(at_ms, frame.link, &frame.data));
+        }
+        return Ok(recording);
+    }
+
+    let text = String::from_utf8(contents).map_err(|_| "neither text nor pcapng".to_string())?;
+    for (index, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if let Some(pattern) = line.strip_prefix("expect ") {
+            recording.expect.push(pattern.trim().to_string());
+            continue;
+        }
+        if let Some(pattern) = line.strip_prefix("reject ") {
+            recording.reject.push(pattern.trim().to_string());
+            continue;
+        }
+        match parse_line(line).map_err(|e| format!("line {}: {}", index + 1, e))? {
+            Line::Node(identity) => recording.identity = Some(identity),
+            Line::Input(recorded) => {
+                if recording.inputs.last().is_some_and(|last| recorded.at_ms < last.at_ms) {
+                    return Err(format!("line {}: time goes backwards", index + 1));
+                }
+                recording.inputs.push(recorded);
+            }
+            Line::Empty => {}
+        }
+    }
+    Ok(recording)
+}
+
+fn identity(recorded: Option<&NodeIdentity>, overrides: &Overrides) -> NodeIdentity {
+    let mut identity = recorded.cloned().unwrap_or(NodeIdentity {
+        address: [0x5e, 0x11, 0, 0, 0, 1],
+        boot_epoch: 1,
+        seed: 1,
+        nickname: None,
+    });
+    if let Some(address) = overrides.address {
+        identity.address = address;
+    }
+    if let Some(boot_epoch) = overrides.boot_epoch {
+        identity.boot_epoch = boot_epoch;
+    }
+    if let Some(seed) = overrides.seed {
+        identity.seed = seed;
+    }
+    identity
+}
+
+fn replay_node(identity: &NodeIdentity, inputs: &[Recorded], show_stats: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut replayer = Replayer::new(identity);
+    let mut out = |at_ms: u64, output| lines.push(format!("{:>7} {}", at_ms, output::describe(&output)));
+    for recorded in inputs {
+        replayer.feed(recorded, &mut out);
+    }
+    if show_stats {
+        let stats = replayer.node().stats(replayer.now_ms());
+        lines.extend(stats.to_string().lines().map(|line| format!("{:>7} stats {}", replayer.now_ms(), line)));
+    }
+    lines
+}
+
+fn replay_handler(identity: &NodeIdentity, inputs: &[Recorded]) -> Vec<String> {
+    let mut handler = MessageHandler::new(identity.address, identity.boot_epoch as u16);
+    handler.seed_rng(identity.seed);
+
+    let mut lines = Vec::new();
+    for recorded in inputs {
+        let Input::Frame(link, frame) = &recorded.input else { continue };
+        let at = recorded.at_ms;
+        drain_relays(&mut handler, at, at, &mut lines);
+        match handler.process_incoming(frame, at) {
+            Ok(Some(message)) => match handler.handle_message(&message, *link, at) {
+                Ok(actions) => {
+                    for action in &actions {
+                        lines.push(format!("{:>7} action {}", at, output::describe_action(action)));
+                        if let Action::Relay { target, delay_ms, layout } = action {
+                            let due_ms = at + *delay_ms as u64;
+                            handler.router_mut().queue_relay(&message, layout, *link, *target, due_ms);
+                        }
+                    }
+                }
+                Err(e) => lines.push(format!("{:>7} error {:?}", at, e)),
+            },
+            Ok(None) => lines.push(format!("{:>7} held", at)),
+            Err(e) => lines.push(format!("{:>7} error {:?}", at, e)),
+        }
+    }
+    // Whatever is still waiting out its delay
+    let end = inputs.last().map_or(0, |recorded| recorded.at_ms);
+    drain_relays(&mut handler, u64::MAX, end, &mut lines);
+    lines
+}
+
+// Queued relays that are due by `due_ms`, listed at `at`
+fn drain_relays(handler: &mut MessageHandler, due_ms: u64, at: u64, lines: &mut Vec<String>) {
+    while let Some(relay) = handler.router_mut().next_relay(due_ms) {
+        lines.push(format!("{:>7} action {}", at, output::describe_relay(&relay)));
+    }
+}
+
+fn check(recording: &Recording, lines: &[String]) -> bool {
+    let mut ok = true;
+    for pattern in &recording.expect {
+        let holds = lines.iter().any(|line| line.contains(pattern.as_str()));
+        println!("  expect {} ... {}", pattern, if holds { "ok" } else { "FAILED" });
+        ok &= holds;
+    }
+    for pattern in &recording.reject {
+        let holds = !lines.iter().any(|line| line.contains(pattern.as_str()));
+        println!("  reject {} ... {}", pattern, if holds { "ok" } else { "FAILED" });
+        ok &= holds;
+    }
+    ok
+}
+
+fn parse_address(text: &str) -> Option<[u8; 6]> {
+    if text.len() != 12 {
+        return None;
+    }
+    let mut address = [0u8; 6];
+    for (i, byte) in address.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(address)
+}
@@ -0,0 +1,70 @@
+// One line per output, stable across runs and easy to match with `expect`
+
+use bitchat_metal::bitchat::BitchatPacket;
+use bitchat_metal::node::AppEvent;
+use bitchat_metal::protocol::integrity;
+use bitchat_metal::protocol::message::MessageHeader;
+use bitchat_metal::protocol::relay::{RelayFrame, RelayTarget};
+use bitchat_metal::protocol::{Action, DeliveryEvent};
+use bitchat_metal::replay::Output;
+
+pub fn describe(output: &Output) -> String {
+    match output {
+        Output::Transmit(link, frame) => format!("tx {} {} {}", link.0, summary(frame), hex(frame)),
+        Output::Event(event) => format!("event {}", describe_event(event)),
+    }
+}
+
+pub fn describe_action(action: &Action) -> String {
+    match action {
+        Action::Deliver(id) => format!("deliver {}", hex(&id.0)),
+        Action::Reply(frame) => format!("reply {} {}", summary(frame), hex(frame)),
+        Action::Relay { target, delay_ms, layout } => {
+            format!("relay {} after {}ms, {} frames", describe_target(target), delay_ms, layout.len())
+        }
+        Action::AckTo { peer, frame } => format!("ack-to {} {}", hex(peer), hex(frame)),
+        Action::UpdatePeer { peer, update } => format!("update-peer {} {:?}", hex(peer), update),
+        Action::Drop(reason) => format!("drop {:?}", reason),
+    }
+}
+
+pub fn describe_relay(relay: &RelayFrame) -> String {
+    format!("send {} {} {}", describe_target(&relay.target), summary(&relay.frame), hex(&relay.frame))
+}
+
+fn describe_target(target: &RelayTarget) -> String {
+    match target {
+        RelayTarget::AllExcept(link) => format!("all-except {}", link.0),
+        RelayTarget::Link(link) => format!("link {}", link.0),
+    }
+}
+
+fn describe_event(event: &AppEvent) -> String {
+    match event {
+        AppEvent::Text { from, payload } => format!("text from {} {:?}", hex(from), String::from_utf8_lossy(payload)),
+        AppEvent::PeerAnnounced { peer, nickname } => format!("announced {} {:?}", hex(peer), nickname.as_str()),
+        AppEvent::Delivery(DeliveryEvent::Delivered(id)) => format!("delivered {}", hex(&id.0)),
+        AppEvent::Delivery(DeliveryEvent::Failed(id)) => format!("delivery-failed {}", hex(&id.0)),
+        AppEvent::Ping(result) => format!("ping {} rtt={}ms hops={}", hex(&result.target), result.rtt_ms, result.hops),
+        AppEvent::PingTimeout(peer) => format!("ping-timeout {}", hex(peer)),
+        AppEvent::Stats { peer, stats } => format!("stats {} {}", hex(peer), stats.to_string().replace('\n', "; ")),
+        AppEvent::Stream { from, info } => format!("stream from {} {} bytes", hex(from), info.total_len),
+    }
+}
+
+// Format and type of a frame, the part worth matching on
+fn summary(frame: &[u8]) -> String {
+    if let Ok(header) = MessageHeader::deserialize(frame) {
+        if integrity::verify(frame, None).is_ok() {
+            return format!("message {:?} ttl={}", header.msg_type, header.ttl);
+        }
+    }
+    match BitchatPacket::decode(frame) {
+        Ok(packet) => format!("bitchat {:?} ttl={}", packet.packet_type, packet.ttl),
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
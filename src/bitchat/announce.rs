@@ -0,0 +1,140 @@
+use heapless::{String, Vec};
+use crate::protocol::peer::PeerId;
+
+// TLV types used in announce payloads (type u8, length u8, value)
+pub const TLV_NICKNAME: u8 = 0x01;
+pub const TLV_NEIGHBORS: u8 = 0x04;
+
+pub const MAX_NICKNAME_LEN: usize = 32;
+// One TLV value holds at most 255 bytes; keep the whole announce in one frame
+pub const MAX_ANNOUNCE_NEIGHBORS: usize = 16;
+
+// Announce body. Older peers send the bare nickname; anything that doesn't
+// parse as TLVs is read that way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnouncePayload {
+    pub nickname: String<MAX_NICKNAME_LEN>,
+    pub neighbors: Vec<PeerId, MAX_ANNOUNCE_NEIGHBORS>,
+}
+
+impl AnnouncePayload {
+    pub fn new(nickname: &str) -> Self {
+        let mut name = String::new();
+        for c in nickname.chars() {
+            if name.push(c).is_err() {
+                break;
+            }
+        }
+        Self {
+            nickname: name,
+            neighbors: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8, 244>, &'static str> {
+        let mut data = Vec::new();
+
+        data.push(TLV_NICKNAME).map_err(|_| "Buffer full")?;
+        data.push(self.nickname.len() as u8).map_err(|_| "Buffer full")?;
+        data.extend_from_slice(self.nickname.as_bytes()).map_err(|_| "Buffer full")?;
+
+        if !self.neighbors.is_empty() {
+            data.push(TLV_NEIGHBORS).map_err(|_| "Buffer full")?;
+            data.push((self.neighbors.len() * 8) as u8).map_err(|_| "Buffer full")?;
+            for neighbor in &self.neighbors {
+                data.extend_from_slice(neighbor).map_err(|_| "Buffer full")?;
+            }
+        }
+
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Self {
+        Self::decode_tlv(data).unwrap_or_else(|| {
+            Self::new(core::str::from_utf8(data).unwrap_or(""))
+        })
+    }
+
+    fn decode_tlv(data: &[u8]) -> Option<Self> {
+        let mut payload = Self::new("");
+        let mut has_nickname = false;
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let tlv_type = data[offset];
+            let len = *data.get(offset + 1)? as usize;
+            let value = data.get(offset + 2..offset + 2 + len)?;
+            offset += 2 + len;
+
+            match tlv_type {
+                TLV_NICKNAME => {
+                    payload.nickname = Self::new(core::str::from_utf8(value).ok()?).nickname;
+                    has_nickname = true;
+                }
+                TLV_NEIGHBORS => {
+                    let chunks = value.chunks_exact(8);
+                    if !chunks.remainder().is_empty() {
+                        return None;
+                    }
+                    for chunk in chunks {
+                        let mut peer = [0u8; 8];
+                        peer.copy_from_slice(chunk);
+                        if payload.neighbors.push(peer).is_err() {
+                            break;
+                        }
+                    }
+                }
+                // Keys and future fields we don't use
+                _ => {}
+            }
+        }
+
+        has_nickname.then_some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_nickname_and_neighbors() {
+        let mut payload = AnnouncePayload::new("alice");
+        payload.neighbors.push([1; 8]).unwrap();
+        payload.neighbors.push([2; 8]).unwrap();
+        assert_eq!(AnnouncePayload::decode(&payload.encode().unwrap()), payload);
+
+        let bare = AnnouncePayload::new("bob");
+        assert_eq!(bare.encode().unwrap()[..], [TLV_NICKNAME, 3, b'b', b'o', b'b']);
+        assert_eq!(AnnouncePayload::decode(&bare.encode().unwrap()), bare);
+    }
+
+    #[test]
+    fn bare_nicknames_are_still_read() {
+        let payload = AnnouncePayload::decode(b"carol");
+        assert_eq!(payload.nickname, "carol");
+        assert!(payload.neighbors.is_empty());
+    }
+
+    #[test]
+    fn unknown_tlvs_are_skipped() {
+        let data = [0x02, 2, 0xAA, 0xBB, TLV_NICKNAME, 3, b'd', b'a', b'n'];
+        assert_eq!(AnnouncePayload::decode(&data), AnnouncePayload::new("dan"));
+    }
+
+    #[test]
+    fn neighbor_lists_must_be_whole_ids() {
+        let mut data = std::vec![TLV_NICKNAME, 3, b'e', b'v', b'e', TLV_NEIGHBORS, 12];
+        data.extend_from_slice(&[0xFF; 12]);
+        // Not TLVs after all, and not UTF-8 either
+        let payload = AnnouncePayload::decode(&data);
+        assert!(payload.nickname.is_empty());
+        assert!(payload.neighbors.is_empty());
+    }
+
+    #[test]
+    fn long_nicknames_are_cut_short() {
+        let long = "x".repeat(MAX_NICKNAME_LEN + 5);
+        assert_eq!(AnnouncePayload::new(&long).nickname.len(), MAX_NICKNAME_LEN);
+    }
+}
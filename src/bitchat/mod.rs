@@ -1,3 +1,7 @@
 pub mod packet;
+pub mod announce;
+pub mod ping;
 
-pub use packet::{BitchatPacket, PacketType, Flags};
\ No newline at end of file
+pub use announce::AnnouncePayload;
+pub use ping::{PingPayload, PingResult, Pinger, TraceHop};
+pub use packet::{BitchatPacket, PacketType, Flags, BROADCAST_ID, MAX_ROUTE_HOPS};
\ No newline at end of file
@@ -1,13 +1,24 @@
 use heapless::Vec;
-use defmt::Format;
+use crate::config::DEFAULT_TTL;
+use crate::bitchat::ping::PING_FIXED_SIZE;
+use crate::protocol::message_id::MessageId;
+use crate::protocol::peer::PeerId;
+use crate::protocol::router::Relayable;
 
 const HEADER_SIZE: usize = 13;
 const SENDER_ID_SIZE: usize = 8;
 const RECIPIENT_ID_SIZE: usize = 8;
 const SIGNATURE_SIZE: usize = 64;
 
+// Longest source route a packet can carry
+pub const MAX_ROUTE_HOPS: usize = 8;
+
+// Recipient used by the iOS app for broadcasts
+pub const BROADCAST_ID: [u8; 8] = [0xFF; 8];
+
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Format)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PacketType {
     Text = 1,
     Announce = 2,
@@ -16,6 +27,11 @@ pub enum PacketType {
     Discovery = 5,
     KeepAlive = 6,
     Error = 7,
+    Ping = 8,
+    PingReply = 9,
+    // Counter snapshot request and reply, see crate::stats
+    Stats = 10,
+    StatsReply = 11,
 }
 
 impl From<u8> for PacketType {
@@ -28,6 +44,10 @@ impl From<u8> for PacketType {
             5 => PacketType::Discovery,
             6 => PacketType::KeepAlive,
             7 => PacketType::Error,
+            8 => PacketType::Ping,
+            9 => PacketType::PingReply,
+            10 => PacketType::Stats,
+            11 => PacketType::StatsReply,
             _ => PacketType::Text,
         }
     }
@@ -38,8 +58,11 @@ impl Flags {
     pub const HAS_RECIPIENT: u8 = 0x01;
     pub const HAS_SIGNATURE: u8 = 0x02;
     pub const IS_COMPRESSED: u8 = 0x04;
+    // Route count byte and that many 8-byte hop IDs follow the recipient
+    pub const HAS_ROUTE: u8 = 0x08;
 }
 
+#[derive(Clone)]
 pub struct BitchatPacket {
     pub version: u8,
     pub packet_type: PacketType,
@@ -48,6 +71,8 @@ pub struct BitchatPacket {
     pub flags: u8,
     pub sender_id: [u8; 8],
     pub recipient_id: Option<[u8; 8]>,
+    // Source route from the originator's first hop towards the recipient; empty if none
+    pub route: Vec<PeerId, MAX_ROUTE_HOPS>,
     pub payload: Vec<u8, 244>,
     pub signature: Option<[u8; 64]>,
 }
@@ -66,11 +91,12 @@ impl BitchatPacket {
         Ok(Self {
             version: 1,
             packet_type,
-            ttl: 3,
+            ttl: DEFAULT_TTL,
             timestamp: 0,
             flags: 0,
             sender_id,
             recipient_id: None,
+            route: Vec::new(),
             payload: payload_vec,
             signature: None,
         })
@@ -94,6 +120,11 @@ impl BitchatPacket {
         if self.signature.is_some() {
             flags |= Flags::HAS_SIGNATURE;
         }
+        if self.route.is_empty() {
+            flags &= !Flags::HAS_ROUTE;
+        } else {
+            flags |= Flags::HAS_ROUTE;
+        }
         data.push(flags).map_err(|_| "Buffer full")?;
 
         let payload_length = self.payload.len() as u16;
@@ -106,6 +137,13 @@ impl BitchatPacket {
             data.extend_from_slice(&recipient).map_err(|_| "Buffer full")?;
         }
 
+        if !self.route.is_empty() {
+            data.push(self.route.len() as u8).map_err(|_| "Buffer full")?;
+            for hop in &self.route {
+                data.extend_from_slice(hop).map_err(|_| "Buffer full")?;
+            }
+        }
+
         data.extend_from_slice(&self.payload).map_err(|_| "Buffer full")?;
 
         if let Some(signature) = self.signature {
@@ -151,7 +189,7 @@ impl BitchatPacket {
         offset += 8;
 
         let recipient_id = if flags & Flags::HAS_RECIPIENT != 0 {
-            if data.len() < offset + 8 {
+            if data.len() < offset + RECIPIENT_ID_SIZE {
                 return Err("Incomplete recipient ID");
             }
             let mut recipient = [0u8; 8];
@@ -162,6 +200,27 @@ impl BitchatPacket {
             None
         };
 
+        let mut route = Vec::new();
+        if flags & Flags::HAS_ROUTE != 0 {
+            if data.len() < offset + 1 {
+                return Err("Incomplete route");
+            }
+            let hops = data[offset] as usize;
+            offset += 1;
+            if hops > MAX_ROUTE_HOPS {
+                return Err("Route too long");
+            }
+            if data.len() < offset + hops * 8 {
+                return Err("Incomplete route");
+            }
+            for _ in 0..hops {
+                let mut hop = [0u8; 8];
+                hop.copy_from_slice(&data[offset..offset + 8]);
+                let _ = route.push(hop);
+                offset += 8;
+            }
+        }
+
         if data.len() < offset + payload_length as usize {
             return Err("Incomplete payload");
         }
@@ -173,7 +232,7 @@ impl BitchatPacket {
         offset += payload_length as usize;
 
         let signature = if flags & Flags::HAS_SIGNATURE != 0 {
-            if data.len() < offset + 64 {
+            if data.len() < offset + SIGNATURE_SIZE {
                 return Err("Incomplete signature");
             }
             let mut sig = [0u8; 64];
@@ -191,25 +250,56 @@ impl BitchatPacket {
             flags,
             sender_id,
             recipient_id,
+            route,
             payload,
             signature,
         })
     }
 
-    pub fn create_announce(sender_id: [u8; 8], message: &[u8]) -> Result<Self, &'static str> {
+    pub fn create_announce(sender_id: [u8; 8], timestamp: u64, message: &[u8]) -> Result<Self, &'static str> {
         let mut packet = Self::new(PacketType::Announce, sender_id, message)?;
-        packet.timestamp = Self::current_timestamp_millis();
+        packet.timestamp = timestamp;
         Ok(packet)
     }
 
-    pub fn create_text(sender_id: [u8; 8], text: &[u8]) -> Result<Self, &'static str> {
+    pub fn create_text(sender_id: [u8; 8], timestamp: u64, text: &[u8]) -> Result<Self, &'static str> {
         let mut packet = Self::new(PacketType::Text, sender_id, text)?;
-        packet.timestamp = Self::current_timestamp_millis();
+        packet.timestamp = timestamp;
         Ok(packet)
     }
 
-    fn current_timestamp_millis() -> u64 {
-        0
+    // We have no wall clock, so the boot epoch fills the high word and uptime the low
+    // word. Timestamps from one sender then keep increasing across reboots.
+    pub fn epoch_timestamp(epoch: u32, uptime_ms: u64) -> u64 {
+        ((epoch as u64) << 32) | (uptime_ms & 0xFFFF_FFFF)
+    }
+
+    pub fn epoch(&self) -> u32 {
+        (self.timestamp >> 32) as u32
+    }
+
+    // Relays append to a traceroute's hop list, so like the TTL it is left out
+    pub fn message_id(&self) -> MessageId {
+        let payload = match self.packet_type {
+            PacketType::Ping => &self.payload[..self.payload.len().min(PING_FIXED_SIZE)],
+            _ => &self.payload[..],
+        };
+        MessageId::compute(&self.sender_id, self.timestamp, self.packet_type as u8, payload)
+    }
+
+    // Recipient of an addressed packet; None for broadcasts
+    pub fn directed_to(&self) -> Option<PeerId> {
+        self.recipient_id.filter(|r| *r != BROADCAST_ID)
+    }
+
+    // Hop after `peer` on the source route, ending at the recipient.
+    // None if the packet has no route or `peer` isn't on it.
+    pub fn route_next_hop(&self, peer: &PeerId) -> Option<PeerId> {
+        let position = self.route.iter().position(|hop| hop == peer)?;
+        match self.route.get(position + 1) {
+            Some(hop) => Some(*hop),
+            None => self.directed_to(),
+        }
     }
 
     pub fn decrement_ttl(&mut self) -> bool {
@@ -220,4 +310,27 @@ impl BitchatPacket {
             false
         }
     }
-}
\ No newline at end of file
+}
+
+// The iOS format has no relayed flag; a relay only spends TTL
+impl Relayable for BitchatPacket {
+    fn origin(&self) -> PeerId {
+        self.sender_id
+    }
+
+    fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    fn id(&self) -> MessageId {
+        self.message_id()
+    }
+
+    fn is_ack(&self) -> bool {
+        self.packet_type == PacketType::Ack
+    }
+
+    fn mark_relayed(&mut self) {
+        self.decrement_ttl();
+    }
+}
@@ -0,0 +1,194 @@
+use heapless::Vec;
+use crate::bitchat::packet::{BitchatPacket, PacketType};
+use crate::protocol::peer::PeerId;
+use crate::fmt::Bytes;
+
+// kind | nonce u32 | initial TTL | TTL at target | hop count, then the hops
+pub const PING_HEADER_SIZE: usize = 8;
+// Everything before the hop count; relays never change it
+pub const PING_FIXED_SIZE: usize = 7;
+const HOP_SIZE: usize = 12;
+pub const MAX_TRACE_HOPS: usize = 8;
+const MAX_PENDING_PINGS: usize = 4;
+
+pub const PING_TTL: u8 = 7;
+
+const KIND_ECHO: u8 = 0;
+const KIND_TRACE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TraceHop {
+    pub peer: PeerId,
+    // Low 32 bits of the hop's uptime when it forwarded the ping
+    pub time_ms: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingPayload {
+    pub traceroute: bool,
+    pub nonce: u32,
+    pub initial_ttl: u8,
+    // Filled in by the target when it answers
+    pub target_ttl: u8,
+    pub hops: Vec<TraceHop, MAX_TRACE_HOPS>,
+}
+
+impl PingPayload {
+    pub fn encode(&self) -> Result<Vec<u8, 244>, &'static str> {
+        let mut data: Vec<u8, 244> = Vec::new();
+        data.push(if self.traceroute { KIND_TRACE } else { KIND_ECHO }).map_err(|_| "Buffer full")?;
+        data.extend_from_slice(&self.nonce.to_be_bytes()).map_err(|_| "Buffer full")?;
+        data.push(self.initial_ttl).map_err(|_| "Buffer full")?;
+        data.push(self.target_ttl).map_err(|_| "Buffer full")?;
+        data.push(self.hops.len() as u8).map_err(|_| "Buffer full")?;
+        for hop in &self.hops {
+            data.extend_from_slice(&hop.peer).map_err(|_| "Buffer full")?;
+            data.extend_from_slice(&hop.time_ms.to_be_bytes()).map_err(|_| "Buffer full")?;
+        }
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < PING_HEADER_SIZE {
+            return Err("Ping too small");
+        }
+        let count = data[7] as usize;
+        if count > MAX_TRACE_HOPS {
+            return Err("Too many hops");
+        }
+        if data.len() < PING_HEADER_SIZE + count * HOP_SIZE {
+            return Err("Incomplete trace");
+        }
+
+        let mut hops = Vec::new();
+        for record in data[PING_HEADER_SIZE..].chunks_exact(HOP_SIZE).take(count) {
+            let mut peer = [0u8; 8];
+            peer.copy_from_slice(&record[..8]);
+            let time_ms = u32::from_be_bytes([record[8], record[9], record[10], record[11]]);
+            let _ = hops.push(TraceHop { peer, time_ms });
+        }
+
+        Ok(Self {
+            traceroute: data[0] == KIND_TRACE,
+            nonce: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+            initial_ttl: data[5],
+            target_ttl: data[6],
+            hops,
+        })
+    }
+
+    // Hops the ping took to reach the target
+    pub fn forward_hops(&self) -> u8 {
+        self.initial_ttl.saturating_sub(self.target_ttl).saturating_add(1)
+    }
+}
+
+// Called by relays on their copy of a traceroute ping. A full trace is
+// forwarded unchanged rather than dropped.
+pub fn append_trace_hop(packet: &mut BitchatPacket, peer: PeerId, now_ms: u64) {
+    if packet.packet_type != PacketType::Ping {
+        return;
+    }
+    let Ok(mut ping) = PingPayload::decode(&packet.payload) else { return };
+    if !ping.traceroute || ping.hops.push(TraceHop { peer, time_ms: now_ms as u32 }).is_err() {
+        return;
+    }
+    if let Ok(payload) = ping.encode() {
+        packet.payload = payload;
+    }
+}
+
+// The echo a target sends back for a ping addressed to it
+pub fn create_reply(request: &BitchatPacket, local_id: PeerId, timestamp: u64) -> Result<BitchatPacket, &'static str> {
+    let mut ping = PingPayload::decode(&request.payload)?;
+    ping.target_ttl = request.ttl;
+
+    let mut reply = BitchatPacket::new(PacketType::PingReply, local_id, &ping.encode()?)?;
+    reply.timestamp = timestamp;
+    reply.ttl = PING_TTL;
+    reply.recipient_id = Some(request.sender_id);
+    Ok(reply)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingResult {
+    pub target: PeerId,
+    pub rtt_ms: u32,
+    pub hops: u8,
+    // Relays the ping passed through, in order; empty for plain echoes
+    pub path: Vec<TraceHop, MAX_TRACE_HOPS>,
+}
+
+struct PendingPing {
+    target: PeerId,
+    nonce: u32,
+    sent_ms: u64,
+}
+
+// Originator side: remembers outstanding pings and turns replies into results
+pub struct Pinger {
+    timeout_ms: u32,
+    next_nonce: u32,
+    pending: Vec<PendingPing, MAX_PENDING_PINGS>,
+}
+
+impl Pinger {
+    pub fn new(timeout_ms: u32) -> Self {
+        Self {
+            timeout_ms,
+            next_nonce: 1,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn create_ping(&mut self, local_id: PeerId, target: PeerId, traceroute: bool, timestamp: u64, now_ms: u64) -> Result<BitchatPacket, &'static str> {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+
+        let payload = PingPayload {
+            traceroute,
+            nonce,
+            initial_ttl: PING_TTL,
+            target_ttl: 0,
+            hops: Vec::new(),
+        };
+        let mut packet = BitchatPacket::new(PacketType::Ping, local_id, &payload.encode()?)?;
+        packet.timestamp = timestamp;
+        packet.ttl = PING_TTL;
+        packet.recipient_id = Some(target);
+
+        if self.pending.is_full() {
+            warn!("Too many pings outstanding, forgetting the oldest");
+            self.pending.remove(0);
+        }
+        let _ = self.pending.push(PendingPing { target, nonce, sent_ms: now_ms });
+        Ok(packet)
+    }
+
+    pub fn on_reply(&mut self, reply: &BitchatPacket, now_ms: u64) -> Option<PingResult> {
+        let ping = PingPayload::decode(&reply.payload).ok()?;
+        let index = self.pending.iter().position(|p| p.nonce == ping.nonce && p.target == reply.sender_id)?;
+        let pending = self.pending.remove(index);
+
+        let result = PingResult {
+            target: pending.target,
+            rtt_ms: now_ms.saturating_sub(pending.sent_ms) as u32,
+            hops: ping.forward_hops(),
+            path: ping.hops,
+        };
+        info!("Ping reply from {:02x}: {} ms, {} hops", Bytes(&result.target), result.rtt_ms, result.hops);
+        Some(result)
+    }
+
+    // Target of the next ping that went unanswered
+    pub fn next_timeout(&mut self, now_ms: u64) -> Option<PeerId> {
+        let timeout = self.timeout_ms as u64;
+        let index = self.pending.iter().position(|p| now_ms.saturating_sub(p.sent_ms) >= timeout)?;
+        Some(self.pending.remove(index).target)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.pending.iter().map(|p| p.sent_ms + self.timeout_ms as u64).min()
+    }
+}
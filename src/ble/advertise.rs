@@ -1,12 +1,16 @@
 use defmt::info;
-use nrf_softdevice::ble::{peripheral, Connection};
+use nrf_softdevice::ble::{peripheral, Connection, TxPower};
 use nrf_softdevice::ble::peripheral::ConnectableAdvertisement;
 use nrf_softdevice::Softdevice;
 
-use crate::config::BITCHAT_SERVICE_UUID;
+use bitchat_metal::config::BITCHAT_SERVICE_UUID;
 
-pub async fn advertise(sd: &Softdevice) -> Result<Connection, peripheral::AdvertiseError> {
-    let config = peripheral::Config::default();
+// `tx_power_dbm` is one of settings::TX_POWER_LEVELS
+pub async fn advertise(sd: &Softdevice, tx_power_dbm: i8) -> Result<Connection, peripheral::AdvertiseError> {
+    let config = peripheral::Config {
+        tx_power: tx_power(tx_power_dbm),
+        ..Default::default()
+    };
 
     // Match Bitchat: No device name for privacy, only service UUID
     // BLE requires little-endian (reversed) byte order for 128-bit UUIDs
@@ -31,4 +35,23 @@ pub async fn advertise(sd: &Softdevice) -> Result<Connection, peripheral::Advert
     info!("Service UUID: F47B5E2D-4A9E-4C5A-9B3F-8E1D2C3A4B5C (Mainnet/Release)");
 
     peripheral::advertise_connectable(sd, adv, &config).await
-}
\ No newline at end of file
+}
+
+fn tx_power(dbm: i8) -> TxPower {
+    match dbm {
+        -40 => TxPower::Minus40dBm,
+        -20 => TxPower::Minus20dBm,
+        -16 => TxPower::Minus16dBm,
+        -12 => TxPower::Minus12dBm,
+        -8 => TxPower::Minus8dBm,
+        -4 => TxPower::Minus4dBm,
+        2 => TxPower::Plus2dBm,
+        3 => TxPower::Plus3dBm,
+        4 => TxPower::Plus4dBm,
+        5 => TxPower::Plus5dBm,
+        6 => TxPower::Plus6dBm,
+        7 => TxPower::Plus7dBm,
+        8 => TxPower::Plus8dBm,
+        _ => TxPower::ZerodBm,
+    }
+}
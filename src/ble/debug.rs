@@ -0,0 +1,85 @@
+// Diagnostics over GATT, not part of the Bitchat protocol. Any central that
+// connects can use them, so they are only built with the `debug-service`
+// feature.
+
+use heapless::Vec;
+
+use bitchat_metal::capture::pcapng::MAX_BLOCK_SIZE;
+use bitchat_metal::capture::{CaptureRing, PcapngExport};
+use bitchat_metal::stats::STATS_SIZE;
+
+#[nrf_softdevice::gatt_service(uuid = "F47B5E2D-4A9E-4C5A-9B3F-8E1D2C3A4B60")]
+pub struct DebugService {
+    // Write CAPTURE_CMD_*; a dump comes back as notifications of CAPTURE_MORE
+    // plus a slice of the pcapng file, ending with a lone CAPTURE_END
+    #[characteristic(uuid = "A1B2C3D4-E5F6-4A5B-8C9D-0E1F2A3B4C61", write, notify)]
+    pub capture: Vec<u8, CAPTURE_CHUNK>,
+    // Latest counter snapshot, encoded as in bitchat_metal::stats
+    #[characteristic(uuid = "A1B2C3D4-E5F6-4A5B-8C9D-0E1F2A3B4C62", read)]
+    pub stats: Vec<u8, STATS_SIZE>,
+    // Write `<key> <value>` to change a setting; reads return every setting
+    // as `<key> <value>` lines, or `error: ...` if the last write failed
+    #[characteristic(uuid = "A1B2C3D4-E5F6-4A5B-8C9D-0E1F2A3B4C63", read, write)]
+    pub settings: Vec<u8, SETTINGS_TEXT>,
+}
+
+pub const CAPTURE_CHUNK: usize = 244;
+pub const SETTINGS_TEXT: usize = 244;
+pub const CAPTURE_CMD_DUMP: u8 = 0x01;
+pub const CAPTURE_CMD_CLEAR: u8 = 0x02;
+const CAPTURE_MORE: u8 = 0x00;
+const CAPTURE_END: u8 = 0x01;
+
+// A pcapng dump in progress over the capture characteristic. Blocks are cut
+// into notifications behind a one-byte header.
+pub struct CaptureDump {
+    export: PcapngExport,
+    block: [u8; MAX_BLOCK_SIZE],
+    len: usize,
+    sent: usize,
+    finished: bool,
+}
+
+impl CaptureDump {
+    pub fn new(export: PcapngExport) -> Self {
+        Self {
+            export,
+            block: [0; MAX_BLOCK_SIZE],
+            len: 0,
+            sent: 0,
+            finished: false,
+        }
+    }
+
+    // The next notification; the same one again until consumed()
+    pub fn chunk<const N: usize>(&mut self, ring: &CaptureRing<N>) -> Vec<u8, CAPTURE_CHUNK> {
+        if self.sent == self.len && !self.finished {
+            match self.export.next_block(ring, &mut self.block) {
+                Some(len) => {
+                    self.len = len;
+                    self.sent = 0;
+                }
+                None => self.finished = true,
+            }
+        }
+
+        let mut chunk = Vec::new();
+        if self.finished {
+            let _ = chunk.push(CAPTURE_END);
+        } else {
+            let end = (self.sent + CAPTURE_CHUNK - 1).min(self.len);
+            let _ = chunk.push(CAPTURE_MORE);
+            let _ = chunk.extend_from_slice(&self.block[self.sent..end]);
+        }
+        chunk
+    }
+
+    // Returns true once the end marker is out
+    pub fn consumed(&mut self, chunk_len: usize) -> bool {
+        if self.finished {
+            return true;
+        }
+        self.sent += chunk_len - 1;
+        false
+    }
+}
@@ -1,9 +1,15 @@
 pub mod advertise;
+// The generated event enum's variants all end in `Write`
+#[cfg(feature = "debug-service")]
+#[allow(clippy::enum_variant_names)]
+pub mod debug;
 pub mod service;
 
 use embassy_executor::Spawner;
 use nrf_softdevice::{raw, Softdevice};
 
+use bitchat_metal::config::MAX_CONNECTIONS;
+
 
 pub fn init(spawner: &Spawner) -> &'static mut Softdevice {
     use defmt::info;
@@ -19,7 +25,7 @@ pub fn init(spawner: &Spawner) -> &'static mut Softdevice {
             accuracy: raw::NRF_CLOCK_LF_ACCURACY_250_PPM as u8,
         }),
         conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 1,
+            conn_count: MAX_CONNECTIONS,
             event_length: 24,
         }),
         conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
@@ -30,7 +36,7 @@ pub fn init(spawner: &Spawner) -> &'static mut Softdevice {
         }),
         gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
             adv_set_count: 1,
-            periph_role_count: 1,
+            periph_role_count: MAX_CONNECTIONS,
             central_role_count: 0,
             central_sec_count: 0,
             _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
@@ -43,7 +49,7 @@ pub fn init(spawner: &Spawner) -> &'static mut Softdevice {
     info!("Softdevice enabled successfully!");
 
     unsafe {
-        let ptr = sd as *const _ as *const Softdevice;
+        let ptr = sd as *const Softdevice;
         info!("Spawning softdevice task...");
         spawner.must_spawn(softdevice_task(&*ptr));
         let mutable_ptr = sd as *const _ as *mut Softdevice;
@@ -54,5 +60,7 @@ pub fn init(spawner: &Spawner) -> &'static mut Softdevice {
 
 #[embassy_executor::task]
 async fn softdevice_task(sd: &'static Softdevice) -> ! {
-    sd.run().await
+    // USB power events feed the console's VBUS detection
+    let vbus = crate::usb::vbus_detect();
+    sd.run_with_callback(|event| crate::usb::on_soc_event(vbus, event)).await
 }
\ No newline at end of file
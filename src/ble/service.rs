@@ -1,32 +1,80 @@
 use defmt::{info, warn};
 use nrf_softdevice::ble::{gatt_server, Connection};
 use nrf_softdevice::ble::gatt_server::RegisterError;
-use nrf_softdevice::Softdevice;
+use nrf_softdevice::{raw, Softdevice};
 use heapless::Vec;
-use embassy_time::Timer;
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use core::pin::pin;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
 
-use crate::bitchat::{BitchatPacket, PacketType};
+use bitchat_metal::capture::{CaptureRing, Direction, PcapngExport};
+use bitchat_metal::capture::pcapng::MAX_BLOCK_SIZE;
+use bitchat_metal::config::{CAPTURE_FRAMES, MAX_CONNECTIONS};
+use bitchat_metal::console::{self, Outcome};
+use bitchat_metal::node::{AppEvent, LinkEvent, NodeCore};
+use bitchat_metal::protocol::message::MAX_FRAGMENT_SIZE;
+use bitchat_metal::protocol::relay::LinkId;
+use bitchat_metal::settings::Settings;
+use bitchat_metal::stats::StatsSnapshot;
+use bitchat_metal::storage::FlashOp;
+
+#[cfg(feature = "debug-service")]
+use super::debug::{CaptureDump, DebugService, DebugServiceEvent, CAPTURE_CMD_CLEAR, CAPTURE_CMD_DUMP, SETTINGS_TEXT};
 
 // Using actual Bitchat UUIDs from iOS app
 #[nrf_softdevice::gatt_service(uuid = "F47B5E2D-4A9E-4C5A-9B3F-8E1D2C3A4B5C")]
 pub struct BitchatService {
     // Bitchat uses a single characteristic for bidirectional communication
+    // Variable length: frames carry a CRC over exactly their own bytes
     #[characteristic(uuid = "A1B2C3D4-E5F6-4A5B-8C9D-0E1F2A3B4C5D", write_without_response, notify)]
-    pub data: [u8; 244],
+    pub data: Vec<u8, MAX_FRAGMENT_SIZE>,
 }
 
+#[cfg(feature = "debug-service")]
 #[nrf_softdevice::gatt_server]
 pub struct Server {
     pub bitchat: BitchatService,
+    pub debug: DebugService,
 }
 
+#[cfg(not(feature = "debug-service"))]
+#[nrf_softdevice::gatt_server]
+pub struct Server {
+    pub bitchat: BitchatService,
+}
+
+pub const MAX_LINKS: usize = MAX_CONNECTIONS as usize;
+
+// Retry delay for frames the softdevice wouldn't take (no CCCD yet, buffers full)
+const NOTIFY_RETRY_MS: u64 = 100;
+
+// Adapts NodeCore to the softdevice: one task per connection feeds it link
+// events and writes out whatever it queued for that link.
 pub struct BitchatServer {
     server: Server,
-    device_id: [u8; 8],
+    node: Mutex<NoopRawMutex, RefCell<NodeCore>>,
+    capture: Mutex<NoopRawMutex, RefCell<CaptureRing<CAPTURE_FRAMES>>>,
+    boot_epoch: u32,
+    // One per link, raised when something was queued for it
+    wake: [Signal<NoopRawMutex, ()>; MAX_LINKS],
+    // Raised when the settings need saving to flash
+    settings_changed: Signal<NoopRawMutex, ()>,
+    // Set by a change until the settings task has written it
+    settings_unsaved: Mutex<NoopRawMutex, Cell<bool>>,
+    // Raised by flush_settings() to cut the debounce short
+    settings_flush: Signal<NoopRawMutex, ()>,
+    settings_saved: Signal<NoopRawMutex, ()>,
+    // Raised when an incoming stream queued flash operations
+    flash_work: Signal<NoopRawMutex, ()>,
 }
 
 impl BitchatServer {
-    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+    pub fn new(sd: &mut Softdevice, boot_epoch: u32, settings: Settings) -> Result<Self, RegisterError> {
         let server = Server::new(sd)?;
 
         // Generate device ID from BLE address (pad to 8 bytes)
@@ -39,146 +87,441 @@ impl BitchatServer {
             device_id[0], device_id[1], device_id[2], device_id[3],
             device_id[4], device_id[5], device_id[6], device_id[7]);
 
-        Ok(Self {
+        // Jitter must differ between boards, so prefer the hardware RNG
+        let mut seed = device_id;
+        if let Err(e) = nrf_softdevice::random_bytes(sd, &mut seed) {
+            warn!("Hardware RNG unavailable, seeding from device ID: {:?}", e);
+        }
+        let mut node = NodeCore::new(addr.bytes, boot_epoch, u64::from_le_bytes(seed));
+        node.apply_settings(settings);
+
+        let this = Self {
             server,
-            device_id,
+            node: Mutex::new(RefCell::new(node)),
+            capture: Mutex::new(RefCell::new(CaptureRing::new())),
+            boot_epoch,
+            wake: core::array::from_fn(|_| Signal::new()),
+            settings_changed: Signal::new(),
+            settings_unsaved: Mutex::new(Cell::new(false)),
+            settings_flush: Signal::new(),
+            settings_saved: Signal::new(),
+            flash_work: Signal::new(),
+        };
+        #[cfg(feature = "debug-service")]
+        this.refresh_settings(None);
+        Ok(this)
+    }
+
+    fn with_node<R>(&self, f: impl FnOnce(&mut NodeCore) -> R) -> R {
+        self.node.lock(|node| f(&mut node.borrow_mut()))
+    }
+
+    fn record(&self, link: LinkId, direction: Direction, frame: &[u8]) {
+        let now = Instant::now().as_millis();
+        self.capture.lock(|ring| ring.borrow_mut().record(link, direction, frame, now));
+    }
+
+    fn wake_links(&self, except: Option<LinkId>) {
+        for (i, wake) in self.wake.iter().enumerate() {
+            if except != Some(LinkId(i as u8)) {
+                wake.signal(());
+            }
+        }
+    }
+
+    // Reserves a link slot for a new connection, None if all are in use
+    pub fn claim_link(&self) -> Option<LinkId> {
+        let now = Instant::now().as_millis();
+        self.with_node(|node| {
+            let link = node.free_link().filter(|l| (l.0 as usize) < MAX_LINKS)?;
+            node.handle(LinkEvent::Connected(link), now);
+            Some(link)
         })
     }
 
-    pub async fn run(&mut self, conn: &Connection) {
-        info!("Client connected, starting GATT server with protocol support");
+    pub fn has_connection(&self) -> bool {
+        self.with_node(|node| node.link_count() > 0)
+    }
 
-        // Handle system attributes to fix BleGattsSysAttrMissing
-        match gatt_server::set_sys_attrs(conn, None) {
-            Ok(_) => info!("System attributes cleared"),
-            Err(e) => warn!("Failed to set system attributes: {:?}", e),
+    pub fn has_free_link(&self) -> bool {
+        self.with_node(|node| node.link_count() < MAX_LINKS)
+    }
+
+    fn release_link(&self, link: LinkId) {
+        let now = Instant::now().as_millis();
+        self.with_node(|node| node.handle(LinkEvent::Disconnected(link), now));
+        self.wake[link.0 as usize].reset();
+    }
+
+    // Runs one console line against the node and writes the reply to `out`
+    pub fn run_command(&self, line: &str, out: &mut impl Write) -> Outcome {
+        let now = Instant::now().as_millis();
+        let outcome = self.with_node(|node| console::run_line(line, node, now, out));
+        match outcome {
+            Outcome::SettingsChanged => self.settings_updated(),
+            Outcome::Done | Outcome::DumpCapture | Outcome::Reboot => {}
         }
+        // `send`, `ping` and config changes may have queued frames
+        self.wake_links(None);
+        outcome
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.with_node(|node| node.settings().clone())
+    }
 
-        let mut notifications_enabled = false;
-        let mut outgoing_queue: Vec<Vec<u8, 244>, 4> = Vec::new();
-
-        // Send announce immediately after connection (iOS expects this)
-        match BitchatPacket::create_announce(
-            self.device_id,
-            b"bitchat-metal"
-        ) {
-            Ok(announce) => {
-                match announce.encode() {
-                    Ok(data) => {
-                        let _ = outgoing_queue.push(data);
-                        info!("Queued initial announce packet");
+    // Resolves once settings changed since the last call; the settings task
+    // saves them then
+    pub async fn wait_settings_changed(&self) {
+        self.settings_changed.wait().await
+    }
+
+    // Resolves when flush_settings() wants the pending settings saved now
+    pub async fn wait_settings_flush(&self) {
+        self.settings_flush.wait().await
+    }
+
+    // Called by the settings task once it has written them, successfully or not
+    pub fn settings_saved(&self) {
+        // A change made during the write needs another one
+        if !self.settings_changed.signaled() {
+            self.settings_unsaved.lock(|unsaved| unsaved.set(false));
+        }
+        self.settings_flush.reset();
+        self.settings_saved.signal(());
+    }
+
+    // Saves changed settings without waiting for the debounce, so a reboot
+    // doesn't lose them; returns once they are written
+    pub async fn flush_settings(&self) {
+        while self.settings_unsaved.lock(Cell::get) {
+            self.settings_saved.reset();
+            self.settings_flush.signal(());
+            self.settings_saved.wait().await;
+        }
+    }
+
+    // Resolves once a stream has flash operations queued; the stream task
+    // carries them out
+    pub async fn wait_flash_work(&self) {
+        self.flash_work.wait().await
+    }
+
+    pub fn next_flash_op(&self) -> Option<FlashOp> {
+        self.with_node(|node| node.next_flash_op().cloned())
+    }
+
+    // Reports the outcome of next_flash_op(); a stream the operation
+    // completed is logged now
+    pub fn flash_op_done(&self, ok: bool) {
+        self.with_node(|node| node.flash_op_done(ok));
+        self.log_events();
+    }
+
+    fn settings_updated(&self) {
+        #[cfg(feature = "debug-service")]
+        self.refresh_settings(None);
+        self.settings_unsaved.lock(|unsaved| unsaved.set(true));
+        self.settings_changed.signal(());
+    }
+
+    // Starts a pcapng export of the capture ring, read with next_capture_block()
+    pub fn capture_export(&self) -> PcapngExport {
+        self.capture.lock(|ring| ring.borrow().export(self.boot_epoch))
+    }
+
+    pub fn next_capture_block(&self, export: &mut PcapngExport, block: &mut [u8; MAX_BLOCK_SIZE]) -> Option<usize> {
+        self.capture.lock(|ring| export.next_block(&ring.borrow(), block))
+    }
+
+    // Passes the negotiated ATT MTU on to the node whenever it changes
+    fn update_mtu(&self, conn: &Connection, link: LinkId, reported: &Cell<u16>) {
+        let mtu = conn.att_mtu();
+        if mtu != reported.get() {
+            reported.set(mtu);
+            let now = Instant::now().as_millis();
+            self.with_node(|node| node.handle(LinkEvent::MtuChanged(link, mtu), now));
+        }
+    }
+
+    // Sends what is queued for this link; returns true if anything is left
+    fn flush(&self, conn: &Connection, link: LinkId) -> bool {
+        self.with_node(|node| {
+            // Try to send queued messages (even without notifications for initial announce)
+            while let Some(frame) = node.next_transmit(link) {
+                let frame = frame.clone();
+                let result = self.server.bitchat.data_notify(conn, &frame);
+                node.transmitted(link, result.is_ok());
+                match result {
+                    Ok(_) => {
+                        info!("Link {}: sent {} bytes", link.0, frame.len());
+                        self.record(link, Direction::Tx, &frame);
                     }
                     Err(e) => {
-                        warn!("Failed to encode announce: {}", e);
+                        // Retried later, unless the node has given up on it
+                        warn!("Link {}: failed to send: {:?}", link.0, e);
+                        return true;
                     }
                 }
             }
-            Err(e) => {
-                warn!("Failed to create announce: {}", e);
+            false
+        })
+    }
+
+    // No application sits on top yet, so events are only logged
+    fn log_events(&self) {
+        while let Some(event) = self.with_node(|node| node.poll_event()) {
+            match event {
+                AppEvent::Text { from, payload } => match core::str::from_utf8(&payload) {
+                    Ok(text) => info!("Message from {:02x}: {}", from, text),
+                    Err(_) => info!("Message from {:02x}: {} bytes", from, payload.len()),
+                },
+                AppEvent::PeerAnnounced { peer, nickname } => info!("Peer {:02x} is {}", peer, nickname.as_str()),
+                AppEvent::Delivery(event) => info!("Delivery: {:?}", event),
+                AppEvent::Ping(result) => {
+                    for (i, hop) in result.path.iter().enumerate() {
+                        info!("  {}: {:02x} at {} ms", i + 1, hop.peer, hop.time_ms);
+                    }
+                }
+                AppEvent::PingTimeout(target) => warn!("Ping to {:02x} timed out", target),
+                AppEvent::Stats { peer, stats } => {
+                    info!("Stats from {:02x}:", peer);
+                    log_snapshot(&stats);
+                }
+                AppEvent::Stream { from, info } => info!("Stream of {} bytes from {:02x}", info.total_len, from),
             }
         }
+    }
 
-        loop {
-            // Process events
-            let result = gatt_server::run(conn, &mut self.server, |e| {
-                match e {
-                    ServerEvent::Bitchat(BitchatServiceEvent::DataWrite(val)) => {
-                        info!("RX: {} bytes", val.len());
-
-                        // Try to decode as Bitchat packet
-                        match BitchatPacket::decode(&val) {
-                            Ok(mut packet) => {
-                                info!("Received packet type: {:?}, TTL: {}", packet.packet_type, packet.ttl);
-
-                                // Handle different packet types
-                                match packet.packet_type {
-                                    PacketType::Announce => {
-                                        info!("Device announce from peer");
-                                        // Could respond with our own announce
-                                    }
-                                    PacketType::Text => {
-                                        info!("Text message received");
-                                        // Log the message content
-                                        if let Ok(text) = core::str::from_utf8(&packet.payload) {
-                                            info!("Message: {}", text);
-                                        }
-                                    }
-                                    PacketType::Discovery => {
-                                        info!("Discovery packet");
-                                        // Respond with announce
-                                    }
-                                    _ => {
-                                        info!("Other packet type");
-                                    }
-                                }
-
-                                // Relay if TTL > 0 (mesh functionality)
-                                if packet.decrement_ttl() {
-                                    info!("Would relay packet with TTL: {}", packet.ttl);
-                                    // In mesh mode, encode and relay to other connections
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Failed to decode packet: {}", e);
-                            }
-                        }
-                    }
-                    ServerEvent::Bitchat(BitchatServiceEvent::DataCccdWrite { notifications }) => {
-                        info!("Data notifications: {}", notifications);
-                        notifications_enabled = notifications;
-
-                        if notifications {
-                            // Send device announce when notifications enabled
-                            match BitchatPacket::create_announce(
-                                self.device_id,
-                                b"bitchat-metal online"
-                            ) {
-                                Ok(announce) => {
-                                    match announce.encode() {
-                                        Ok(data) => {
-                                            let _ = outgoing_queue.push(data);
-                                            info!("Queued device announce");
-                                        }
-                                        Err(e) => {
-                                            warn!("Failed to encode announce: {}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Failed to create announce: {}", e);
-                                }
-                            }
-                        }
+    // Serves one connection on a link slot from claim_link() until it drops
+    pub async fn run(&self, conn: &Connection, link: LinkId) {
+        info!("Client connected on link {}, starting GATT server with protocol support", link.0);
+
+        // Handle system attributes to fix BleGattsSysAttrMissing
+        match gatt_server::set_sys_attrs(conn, None) {
+            Ok(_) => info!("System attributes cleared"),
+            Err(e) => warn!("Failed to set system attributes: {:?}", e),
+        }
+
+        // RSSI samples feed the link quality estimate
+        start_rssi(conn);
+
+        // Shared with the event handler below, which lives as long as the connection
+        #[cfg(feature = "debug-service")]
+        let dump = RefCell::new(None);
+        let mtu = Cell::new(0);
+
+        // One GATT server future for the whole connection; rebuilding it per
+        // loop iteration could drop events that arrive in between
+        let mut events = pin!(gatt_server::run(conn, &self.server, |e| {
+            match e {
+                ServerEvent::Bitchat(BitchatServiceEvent::DataWrite(val)) => {
+                    info!("Link {}: RX {} bytes", link.0, val.len());
+                    self.record(link, Direction::Rx, &val);
+                    let now = Instant::now().as_millis();
+                    // The central may have just raised the MTU
+                    self.update_mtu(conn, link, &mtu);
+                    let flash_work = self.with_node(|node| {
+                        node.handle(LinkEvent::BytesReceived(link, &val), now);
+                        node.next_flash_op().is_some()
+                    });
+                    if flash_work {
+                        self.flash_work.signal(());
                     }
+                    self.log_events();
+                    // Replies may go out on this link, relays on any
+                    self.wake_links(None);
                 }
-            }).await;
+                ServerEvent::Bitchat(BitchatServiceEvent::DataCccdWrite { notifications }) => {
+                    info!("Data notifications: {}", notifications);
 
-            // Try to send queued messages (even without notifications for initial announce)
-            if !outgoing_queue.is_empty() {
-                if let Some(data) = outgoing_queue.get(0) {
-                    let mut tx_data = [0u8; 244];
-                    let len = data.len().min(244);
-                    tx_data[..len].copy_from_slice(&data[..len]);
-                    match self.server.bitchat.data_notify(conn, &tx_data) {
-                        Ok(_) => {
-                            info!("Sent {} bytes", data.len());
-                            outgoing_queue.remove(0);
-                        }
-                        Err(e) => {
-                            warn!("Failed to send: {:?}", e);
-                            // Keep in queue to retry
-                            Timer::after_millis(100).await;
-                        }
+                    if notifications {
+                        // Announce again now that the central is listening
+                        self.with_node(|node| node.announcer_mut().on_connected());
+                        self.wake[link.0 as usize].signal(());
                     }
                 }
-                continue;
+                #[cfg(feature = "debug-service")]
+                ServerEvent::Debug(event) => self.debug_event(event, link, &dump),
+            }
+        }));
+
+        let mut tx_power = None;
+        loop {
+            let wanted = self.with_node(|node| node.settings().tx_power_dbm);
+            if tx_power != Some(wanted) {
+                set_tx_power(conn, wanted);
+                tx_power = Some(wanted);
+            }
+            self.update_mtu(conn, link, &mtu);
+
+            let now = Instant::now().as_millis();
+            let fired = self.with_node(|node| {
+                if let Some(rssi) = read_rssi(conn) {
+                    node.handle(LinkEvent::Rssi(link, rssi), now);
+                }
+                let due = node.poll_timeout() <= now;
+                if due {
+                    node.handle(LinkEvent::TimerFired, now);
+                }
+                due
+            });
+            if fired {
+                // Timers may have queued announces or relays for any link
+                self.log_events();
+                self.wake_links(Some(link));
+            }
+
+            let mut deadline = self.with_node(|node| node.poll_timeout());
+            let queued = self.flush(conn, link);
+            #[cfg(feature = "debug-service")]
+            let queued = {
+                self.refresh_stats();
+                self.send_capture(conn, &mut dump.borrow_mut()) || queued
+            };
+            if queued {
+                deadline = deadline.min(now + NOTIFY_RETRY_MS);
+            }
+
+            // Process events until a timer is due or something was queued for us
+            match select3(events.as_mut(), Timer::at(Instant::from_millis(deadline)), self.wake[link.0 as usize].wait()).await {
+                Either3::First(result) => {
+                    // If run() returned, the connection was closed
+                    warn!("Disconnected: {:?}", result);
+                    break;
+                }
+                Either3::Second(_) | Either3::Third(_) => {}
+            }
+        }
+
+        self.release_link(link);
+        info!("Link {} closed", link.0);
+    }
+}
+
+// Samples are polled from the run loop, so no RSSI events are needed
+fn start_rssi(conn: &Connection) {
+    let Some(handle) = conn.handle() else { return };
+    let ret = unsafe { raw::sd_ble_gap_rssi_start(handle, raw::BLE_GAP_RSSI_THRESHOLD_INVALID as u8, 0) };
+    if ret != raw::NRF_SUCCESS {
+        warn!("Failed to start RSSI sampling: {}", ret);
+    }
+}
+
+// None until the softdevice has a sample for this connection
+fn read_rssi(conn: &Connection) -> Option<i8> {
+    let handle = conn.handle()?;
+    let mut rssi = 0i8;
+    let mut channel = 0u8;
+    let ret = unsafe { raw::sd_ble_gap_rssi_get(handle, &mut rssi, &mut channel) };
+    (ret == raw::NRF_SUCCESS).then_some(rssi)
+}
+
+// Advertising picks up the setting in advertise(); a connection keeps the
+// power it started with unless told otherwise
+fn set_tx_power(conn: &Connection, dbm: i8) {
+    let Some(handle) = conn.handle() else { return };
+    let ret = unsafe {
+        raw::sd_ble_gap_tx_power_set(raw::BLE_GAP_TX_POWER_ROLES_BLE_GAP_TX_POWER_ROLE_CONN as u8, handle, dbm)
+    };
+    if ret != raw::NRF_SUCCESS {
+        warn!("Failed to set TX power to {} dBm: {}", dbm, ret);
+    }
+}
+
+fn log_snapshot(snapshot: &StatsSnapshot) {
+    let mut text: heapless::String<1024> = heapless::String::new();
+    let _ = write!(text, "{}", snapshot);
+    for line in text.lines() {
+        info!("stats {}", line);
+    }
+}
+
+// The debug service's side of the server; see super::debug
+#[cfg(feature = "debug-service")]
+impl BitchatServer {
+    fn debug_event(&self, event: DebugServiceEvent, link: LinkId, dump: &RefCell<Option<CaptureDump>>) {
+        match event {
+            DebugServiceEvent::CaptureWrite(val) => match val.first() {
+                Some(&CAPTURE_CMD_DUMP) => {
+                    let export = self.capture.lock(|ring| ring.borrow().export(self.boot_epoch));
+                    *dump.borrow_mut() = Some(CaptureDump::new(export));
+                    self.wake[link.0 as usize].signal(());
+                }
+                Some(&CAPTURE_CMD_CLEAR) => self.clear_capture(),
+                _ => warn!("Unknown capture command"),
+            },
+            DebugServiceEvent::CaptureCccdWrite { .. } => {}
+            DebugServiceEvent::SettingsWrite(val) => self.change_setting(&val),
+        }
+    }
+
+    // `<key> <value>` written to the settings characteristic
+    fn change_setting(&self, line: &[u8]) {
+        let result = core::str::from_utf8(line).map_err(|_| "not UTF-8").and_then(|line| {
+            self.with_node(|node| {
+                let mut settings = node.settings().clone();
+                settings.set_line(line)?;
+                node.apply_settings(settings);
+                Ok(())
+            })
+        });
+        match result {
+            Ok(()) => {
+                self.settings_updated();
+                self.wake_links(None);
+            }
+            Err(e) => {
+                warn!("Settings write rejected: {}", e);
+                self.refresh_settings(Some(e));
             }
+        }
+    }
+
+    fn refresh_settings(&self, error: Option<&str>) {
+        let mut text: heapless::String<SETTINGS_TEXT> = heapless::String::new();
+        let _ = match error {
+            Some(e) => write!(text, "error: {}", e),
+            None => self.with_node(|node| write!(text, "{}", node.settings())),
+        };
+        if let Err(e) = self.server.debug.settings_set(&text.into_bytes()) {
+            warn!("Failed to update settings characteristic: {:?}", e);
+        }
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        let now = Instant::now().as_millis();
+        self.with_node(|node| node.stats(now))
+    }
 
-            // If run() returned, the connection was closed
-            warn!("Disconnected: {:?}", result);
-            break;
+    // The characteristic has no read callback, so its value is kept current
+    fn refresh_stats(&self) {
+        if let Err(e) = self.server.debug.stats_set(&self.stats().encode()) {
+            warn!("Failed to update stats characteristic: {:?}", e);
         }
+    }
+
+    pub fn clear_capture(&self) {
+        self.capture.lock(|ring| ring.borrow_mut().clear());
+    }
 
-        info!("Connection closed");
+    // Streams a pending capture dump; returns true if the softdevice ran out
+    // of buffers and the rest has to wait
+    fn send_capture(&self, conn: &Connection, dump: &mut Option<CaptureDump>) -> bool {
+        let Some(pending) = dump.as_mut() else {
+            return false;
+        };
+        loop {
+            let chunk = self.capture.lock(|ring| pending.chunk(&ring.borrow()));
+            if let Err(e) = self.server.debug.capture_notify(conn, &chunk) {
+                warn!("Capture dump stalled: {:?}", e);
+                return true;
+            }
+            if pending.consumed(chunk.len()) {
+                info!("Capture dump sent");
+                *dump = None;
+                return false;
+            }
+        }
     }
-}
\ No newline at end of file
+}
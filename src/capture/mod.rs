@@ -0,0 +1,131 @@
+pub mod pcapng;
+
+use heapless::{Deque, Vec};
+use crate::protocol::message::MAX_FRAGMENT_SIZE;
+use crate::protocol::relay::LinkId;
+
+pub use pcapng::{PcapngExport, PcapngReader};
+
+// Longest frame kept; anything longer is cut and flagged as truncated
+pub const CAPTURE_SNAPLEN: usize = MAX_FRAGMENT_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    // Numbers every frame since boot, so an export can tell which frames it
+    // already wrote while the ring keeps moving
+    pub seq: u32,
+    pub timestamp_ms: u64,
+    pub link: LinkId,
+    pub direction: Direction,
+    // Length on the air, which may exceed data.len()
+    pub original_len: u16,
+    pub data: Vec<u8, CAPTURE_SNAPLEN>,
+}
+
+// The last N raw frames in either direction. Oldest frames are overwritten,
+// so the ring always holds whatever led up to a failure.
+pub struct CaptureRing<const N: usize> {
+    frames: Deque<CapturedFrame, N>,
+    next_seq: u32,
+    overwritten: u32,
+    enabled: bool,
+}
+
+impl<const N: usize> CaptureRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            frames: Deque::new(),
+            next_seq: 0,
+            overwritten: 0,
+            enabled: true,
+        }
+    }
+
+    pub fn record(&mut self, link: LinkId, direction: Direction, frame: &[u8], now_ms: u64) {
+        if !self.enabled {
+            return;
+        }
+        if self.frames.is_full() {
+            self.frames.pop_front();
+            self.overwritten += 1;
+        }
+
+        let kept = frame.len().min(CAPTURE_SNAPLEN);
+        let mut data = Vec::new();
+        let _ = data.extend_from_slice(&frame[..kept]);
+        let _ = self.frames.push_back(CapturedFrame {
+            seq: self.next_seq,
+            timestamp_ms: now_ms,
+            link,
+            direction,
+            original_len: frame.len().min(u16::MAX as usize) as u16,
+            data,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    // Paused while a dump is read out, so the frames carrying the dump don't
+    // push out the ones being dumped
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.overwritten = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Frames pushed out by newer ones since the last clear()
+    pub fn overwritten(&self) -> u32 {
+        self.overwritten
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &CapturedFrame> {
+        self.frames.iter()
+    }
+
+    // First frame numbered `seq` or later
+    pub fn frame_from(&self, seq: u32) -> Option<&CapturedFrame> {
+        self.frames.iter().find(|frame| frame.seq >= seq)
+    }
+
+    // pcapng of the frames held right now; frames recorded later are left out
+    pub fn export(&self, boot_epoch: u32) -> PcapngExport {
+        PcapngExport::new(self.next_seq, boot_epoch, self.overwritten)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn write_pcapng(&self, boot_epoch: u32, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        let mut export = self.export(boot_epoch);
+        let mut block = [0u8; pcapng::MAX_BLOCK_SIZE];
+        while let Some(len) = export.next_block(self, &mut block) {
+            out.write_all(&block[..len])?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for CaptureRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,469 @@
+// pcapng writer for capture dumps, one block at a time so a dump can be
+// streamed out over a GATT characteristic or the console without holding the
+// whole file.
+//
+// Frames go out on a single interface with link type LINKTYPE_USER0. Each
+// packet starts with a PSEUDO_HEADER_SIZE header:
+//
+//   [0] header version (PSEUDO_HEADER_VERSION)
+//   [1] direction, 0 = received, 1 = sent
+//   [2] link ID
+//   [3] flags, bit 0 = frame truncated to the snap length
+//
+// followed by the frame as it was on the air. Wireshark opens the dump as is;
+// map DLT User 0 to a dissector (or "data") to see the frames. Timestamps are
+// milliseconds of uptime, the section comment names the boot epoch.
+// PcapngReader reads such a dump back.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+use crate::protocol::relay::LinkId;
+use super::{CaptureRing, CapturedFrame, CAPTURE_SNAPLEN, Direction};
+
+pub const LINKTYPE_USER0: u16 = 147;
+pub const PSEUDO_HEADER_SIZE: usize = 4;
+pub const PSEUDO_HEADER_VERSION: u8 = 1;
+pub const PSEUDO_FLAG_TRUNCATED: u8 = 0x01;
+
+// Largest block next_block() produces: an enhanced packet block carrying a
+// full-size frame
+pub const MAX_BLOCK_SIZE: usize = EPB_FIXED_SIZE + pad4(PSEUDO_HEADER_SIZE + CAPTURE_SNAPLEN) + EPB_OPTIONS_SIZE;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+// epb_flags direction bits
+const EPB_INBOUND: u32 = 0x1;
+const EPB_OUTBOUND: u32 = 0x2;
+
+// Type, length, interface, timestamp (2 words), captured and original length,
+// trailing length
+const EPB_FIXED_SIZE: usize = 32;
+// epb_flags plus the end of options
+const EPB_OPTIONS_SIZE: usize = 8 + 4;
+
+const fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    SectionHeader,
+    Interface,
+    Frames(u32),
+    Done,
+}
+
+// Produces a pcapng file block by block from a CaptureRing. Holds no
+// reference to the ring, so the ring can stay behind a lock between blocks.
+#[derive(Debug, Clone)]
+pub struct PcapngExport {
+    stage: Stage,
+    // Frames numbered end or later arrived after the export started
+    end: u32,
+    boot_epoch: u32,
+    overwritten: u32,
+}
+
+impl PcapngExport {
+    pub(super) fn new(end: u32, boot_epoch: u32, overwritten: u32) -> Self {
+        Self {
+            stage: Stage::SectionHeader,
+            end,
+            boot_epoch,
+            overwritten,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    // Writes the next block into `block` and returns its length, or None once
+    // the file is complete. Frames overwritten since the export started are
+    // skipped.
+    pub fn next_block<const N: usize>(&mut self, ring: &CaptureRing<N>, block: &mut [u8; MAX_BLOCK_SIZE]) -> Option<usize> {
+        let mut out = BlockWriter { buf: block, len: 0 };
+        match self.stage {
+            Stage::SectionHeader => {
+                self.section_header(&mut out);
+                self.stage = Stage::Interface;
+            }
+            Stage::Interface => {
+                interface_description(&mut out);
+                self.stage = Stage::Frames(0);
+            }
+            Stage::Frames(next) => match ring.frame_from(next).filter(|f| f.seq < self.end) {
+                Some(frame) => {
+                    enhanced_packet(&mut out, frame);
+                    self.stage = Stage::Frames(frame.seq + 1);
+                }
+                None => {
+                    self.stage = Stage::Done;
+                    return None;
+                }
+            },
+            Stage::Done => return None,
+        }
+        Some(out.len)
+    }
+
+    fn section_header(&self, out: &mut BlockWriter) {
+        let mut comment: String<64> = String::new();
+        let _ = write!(comment, "boot epoch {}, {} frames overwritten", self.boot_epoch, self.overwritten);
+
+        out.u32(BLOCK_SECTION_HEADER);
+        out.u32(0); // Length, patched by finish()
+        out.u32(BYTE_ORDER_MAGIC);
+        out.u16(1); // Major version
+        out.u16(0); // Minor version
+        out.bytes(&(-1i64).to_le_bytes()); // Section length unknown
+        out.option(OPT_SHB_USERAPPL, crate::config::DEVICE_NAME.as_bytes());
+        out.option(OPT_COMMENT, comment.as_bytes());
+        out.option(OPT_END, &[]);
+        out.finish();
+    }
+}
+
+fn interface_description(out: &mut BlockWriter) {
+    out.u32(BLOCK_INTERFACE);
+    out.u32(0);
+    out.u16(LINKTYPE_USER0);
+    out.u16(0); // Reserved
+    out.u32((PSEUDO_HEADER_SIZE + CAPTURE_SNAPLEN) as u32);
+    out.option(OPT_IF_NAME, b"bitchat");
+    out.option(OPT_IF_TSRESOL, &[3]); // 10^-3 s
+    out.option(OPT_END, &[]);
+    out.finish();
+}
+
+fn enhanced_packet(out: &mut BlockWriter, frame: &CapturedFrame) {
+    let truncated = (frame.original_len as usize) > frame.data.len();
+    let header = [
+        PSEUDO_HEADER_VERSION,
+        match frame.direction { Direction::Rx => 0, Direction::Tx => 1 },
+        frame.link.0,
+        if truncated { PSEUDO_FLAG_TRUNCATED } else { 0 },
+    ];
+    let captured = PSEUDO_HEADER_SIZE + frame.data.len();
+    let direction = match frame.direction {
+        Direction::Rx => EPB_INBOUND,
+        Direction::Tx => EPB_OUTBOUND,
+    };
+
+    out.u32(BLOCK_ENHANCED_PACKET);
+    out.u32(0);
+    out.u32(0); // Interface ID
+    out.u32((frame.timestamp_ms >> 32) as u32);
+    out.u32(frame.timestamp_ms as u32);
+    out.u32(captured as u32);
+    out.u32((PSEUDO_HEADER_SIZE + frame.original_len as usize) as u32);
+    out.bytes(&header);
+    out.bytes(&frame.data);
+    out.pad();
+    out.option(OPT_EPB_FLAGS, &direction.to_le_bytes());
+    out.option(OPT_END, &[]);
+    out.finish();
+}
+
+// Frames of a dump, in file order. Only what PcapngExport writes is
+// understood: little-endian sections and frames with our pseudo-header on
+// LINKTYPE_USER0 interfaces. Other blocks and interfaces are skipped.
+pub struct PcapngReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    // Link type per interface of the current section
+    interfaces: Vec<u16, MAX_INTERFACES>,
+    frames: u32,
+}
+
+const MAX_INTERFACES: usize = 8;
+
+impl<'a> PcapngReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            interfaces: Vec::new(),
+            frames: 0,
+        }
+    }
+
+    pub fn is_pcapng(data: &[u8]) -> bool {
+        data.len() >= 12 && word(data, 0) == BLOCK_SECTION_HEADER && word(data, 8) == BYTE_ORDER_MAGIC
+    }
+
+    fn next_block(&mut self) -> Result<Option<&'a [u8]>, &'static str> {
+        let rest = &self.data[self.offset..];
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        if rest.len() < 12 {
+            return Err("Truncated block");
+        }
+        let len = word(rest, 4) as usize;
+        if len < 12 || !len.is_multiple_of(4) || len > rest.len() {
+            return Err("Bad block length");
+        }
+        self.offset += len;
+        Ok(Some(&rest[..len]))
+    }
+
+    fn frame(&mut self, block: &[u8]) -> Result<Option<CapturedFrame>, &'static str> {
+        if block.len() < EPB_FIXED_SIZE {
+            return Err("Truncated packet block");
+        }
+        let interface = word(block, 8) as usize;
+        if self.interfaces.get(interface) != Some(&LINKTYPE_USER0) {
+            return Ok(None);
+        }
+        let captured = word(block, 20) as usize;
+        let original = word(block, 24) as usize;
+        let data = block.get(28..28 + captured).ok_or("Captured length past block end")?;
+        if data.len() < PSEUDO_HEADER_SIZE || data[0] != PSEUDO_HEADER_VERSION {
+            return Err("Unknown pseudo-header");
+        }
+
+        let seq = self.frames;
+        self.frames += 1;
+        Ok(Some(CapturedFrame {
+            seq,
+            timestamp_ms: (word(block, 12) as u64) << 32 | word(block, 16) as u64,
+            link: LinkId(data[2]),
+            direction: if data[1] == 0 { Direction::Rx } else { Direction::Tx },
+            original_len: original.saturating_sub(PSEUDO_HEADER_SIZE).min(u16::MAX as usize) as u16,
+            data: Vec::from_slice(&data[PSEUDO_HEADER_SIZE..]).map_err(|_| "Frame longer than the snap length")?,
+        }))
+    }
+}
+
+impl Iterator for PcapngReader<'_> {
+    type Item = Result<CapturedFrame, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let block = match self.next_block() {
+                Ok(Some(block)) => block,
+                Ok(None) => return None,
+                Err(e) => {
+                    // Nothing after a broken block can be trusted
+                    self.offset = self.data.len();
+                    return Some(Err(e));
+                }
+            };
+            match word(block, 0) {
+                BLOCK_SECTION_HEADER => {
+                    if word(block, 8) != BYTE_ORDER_MAGIC {
+                        self.offset = self.data.len();
+                        return Some(Err("Big-endian section"));
+                    }
+                    self.interfaces.clear();
+                }
+                BLOCK_INTERFACE => {
+                    let link_type = u16::from_le_bytes([block[8], block[9]]);
+                    if self.interfaces.push(link_type).is_err() {
+                        return Some(Err("Too many interfaces"));
+                    }
+                }
+                BLOCK_ENHANCED_PACKET => match self.frame(block) {
+                    Ok(Some(frame)) => return Some(Ok(frame)),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// Little-endian, which the byte-order magic announces. Every block fits in
+// MAX_BLOCK_SIZE, so writes can't overrun.
+struct BlockWriter<'a> {
+    buf: &'a mut [u8; MAX_BLOCK_SIZE],
+    len: usize,
+}
+
+impl BlockWriter<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn pad(&mut self) {
+        let padded = pad4(self.len);
+        self.buf[self.len..padded].fill(0);
+        self.len = padded;
+    }
+
+    fn option(&mut self, code: u16, value: &[u8]) {
+        self.u16(code);
+        self.u16(value.len() as u16);
+        self.bytes(value);
+        self.pad();
+    }
+
+    // Appends the trailing length and fills in the leading one
+    fn finish(&mut self) {
+        let total = (self.len + 4) as u32;
+        self.u32(total);
+        self.buf[4..8].copy_from_slice(&total.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn dump<const N: usize>(ring: &CaptureRing<N>) -> Vec<u8> {
+        let mut export = ring.export(7);
+        let mut block = [0u8; MAX_BLOCK_SIZE];
+        let mut file = Vec::new();
+        while let Some(len) = export.next_block(ring, &mut block) {
+            file.extend_from_slice(&block[..len]);
+        }
+        assert!(export.is_done());
+        file
+    }
+
+    // (type, block) for every block in the file
+    fn blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut rest = file;
+        while !rest.is_empty() {
+            let len = word(rest, 4) as usize;
+            blocks.push((word(rest, 0), &rest[..len]));
+            rest = &rest[len..];
+        }
+        blocks
+    }
+
+    fn ring() -> CaptureRing<8> {
+        let mut ring = CaptureRing::new();
+        // Every length modulo 4, a full-size frame and one over the snap length
+        for (i, len) in [1, 2, 3, 4, 5, CAPTURE_SNAPLEN, CAPTURE_SNAPLEN + 10].into_iter().enumerate() {
+            let frame: Vec<u8> = (0..len).map(|b| b as u8 ^ i as u8).collect();
+            let direction = if i % 2 == 0 { Direction::Rx } else { Direction::Tx };
+            ring.record(LinkId(i as u8 % 3), direction, &frame, (1 << 32) + i as u64 * 1_000);
+        }
+        ring
+    }
+
+    #[test]
+    fn dumps_read_back_frame_for_frame() {
+        let ring = ring();
+        let file = dump(&ring);
+        assert!(PcapngReader::is_pcapng(&file));
+
+        let read: Vec<CapturedFrame> = PcapngReader::new(&file).collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), ring.len());
+        for (read, recorded) in read.iter().zip(ring.frames()) {
+            assert_eq!(read.timestamp_ms, recorded.timestamp_ms);
+            assert_eq!(read.link, recorded.link);
+            assert_eq!(read.direction, recorded.direction);
+            assert_eq!(read.original_len, recorded.original_len);
+            assert_eq!(read.data, recorded.data);
+        }
+        assert_eq!(read.last().unwrap().original_len as usize, CAPTURE_SNAPLEN + 10);
+    }
+
+    #[test]
+    fn block_lengths_frame_every_block() {
+        let file = dump(&ring());
+        let blocks = blocks(&file);
+        let types: Vec<u32> = blocks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(types[..2], [BLOCK_SECTION_HEADER, BLOCK_INTERFACE]);
+        assert!(types[2..].iter().all(|kind| *kind == BLOCK_ENHANCED_PACKET));
+        assert_eq!(types.len(), 2 + ring().len());
+
+        for (_, block) in blocks {
+            let len = block.len();
+            assert_eq!(len % 4, 0);
+            assert!(len <= MAX_BLOCK_SIZE);
+            assert_eq!(word(block, len - 4) as usize, len, "trailing length");
+        }
+    }
+
+    #[test]
+    fn packet_data_and_options_are_padded_to_four_bytes() {
+        let file = dump(&ring());
+        for (_, block) in blocks(&file).into_iter().filter(|(kind, _)| *kind == BLOCK_ENHANCED_PACKET) {
+            let captured = word(block, 20) as usize;
+            let padded = 28 + pad4(captured);
+            assert!(block[28 + captured..padded].iter().all(|b| *b == 0), "padding is zeroed");
+
+            // epb_flags starts right after the padding, then the end of options
+            assert_eq!(u16::from_le_bytes([block[padded], block[padded + 1]]), OPT_EPB_FLAGS);
+            assert_eq!(u16::from_le_bytes([block[padded + 2], block[padded + 3]]), 4);
+            let direction = word(block, padded + 4);
+            let sent = block[28 + 1] == 1;
+            assert_eq!(direction, if sent { EPB_OUTBOUND } else { EPB_INBOUND });
+            assert_eq!(word(block, padded + 8), OPT_END as u32);
+            assert_eq!(padded + EPB_OPTIONS_SIZE + 4, block.len());
+        }
+    }
+
+    #[test]
+    fn exports_leave_out_frames_recorded_after_they_started() {
+        let mut ring: CaptureRing<4> = CaptureRing::new();
+        ring.record(LinkId(0), Direction::Rx, &[1], 0);
+        ring.record(LinkId(0), Direction::Rx, &[2], 1);
+        let mut export = ring.export(1);
+        let mut block = [0u8; MAX_BLOCK_SIZE];
+        let mut file = Vec::new();
+
+        // Header blocks and the first frame, then the ring moves on
+        for _ in 0..3 {
+            let len = export.next_block(&ring, &mut block).unwrap();
+            file.extend_from_slice(&block[..len]);
+        }
+        for n in 3..7 {
+            ring.record(LinkId(0), Direction::Rx, &[n], n as u64);
+        }
+        while let Some(len) = export.next_block(&ring, &mut block) {
+            file.extend_from_slice(&block[..len]);
+        }
+
+        // Frame 2 was overwritten before it was written, 3 to 6 came too late
+        let data: Vec<u8> = PcapngReader::new(&file).map(|frame| frame.unwrap().data[0]).collect();
+        assert_eq!(data, [1]);
+    }
+
+    #[test]
+    fn broken_files_are_reported() {
+        let mut file = dump(&ring());
+        assert!(!PcapngReader::is_pcapng(&file[1..]));
+
+        // A block length that is not a multiple of 4 ends the read
+        let second = word(&file, 4) as usize;
+        file[second + 4] += 1;
+        let results: Vec<_> = PcapngReader::new(&file).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().err(), Some(&"Bad block length"));
+
+        let file = dump(&ring());
+        let results: Vec<_> = PcapngReader::new(&file[..file.len() - 2]).collect();
+        assert!(results.last().unwrap().is_err());
+    }
+}
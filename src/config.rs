@@ -1,4 +1,7 @@
+// Defaults for a board that has no saved settings yet; see settings.rs
 pub const DEVICE_NAME: &str = "bitchat-metal";
+pub const DEFAULT_TTL: u8 = 3;
+pub const RELAY_ENABLED: bool = true;
 
 // Actual Bitchat UUIDs from the iOS app source
 // Testnet (DEBUG): F47B5E2D-4A9E-4C5A-9B3F-8E1D2C3A4B5A
@@ -30,4 +33,17 @@ pub const MAX_MTU_SIZE: u16 = 247;
 
 pub const MAX_MESSAGE_SIZE: usize = 244;
 
-pub const MAX_CONNECTIONS: u8 = 1;
\ No newline at end of file
+// Simultaneous centrals; relays bridge between them. The softdevice sizes its
+// RAM for this at boot, so unlike settings.rs it can't change at runtime.
+pub const MAX_CONNECTIONS: u8 = 3;
+
+// Raw frames kept for capture dumps, about 260 bytes of RAM each
+pub const CAPTURE_FRAMES: usize = 32;
+
+// Answer stats requests from other nodes; counters say a lot about our
+// traffic. Default for the stats-replies setting.
+pub const STATS_REPLIES: bool = false;
+
+// RAM for one incoming stream off the device, where streams go to flash
+// (storage::STREAM_REGION); longer streams are refused at the manifest
+pub const STREAM_BUFFER_SIZE: usize = 4096;
@@ -0,0 +1,454 @@
+// Line-oriented command shell. Knows nothing about the transport: bytes go
+// into a LineBuffer, complete lines into run_line(), and the reply is written
+// to any fmt::Write. The firmware serves it over USB; host tests drive it
+// with strings.
+//
+//   help                      list commands
+//   send <text>               broadcast a text message
+//   msg <peer> <text>         text one peer, retried until it ACKs
+//   peers                     links and peers heard announcing
+//   stats [<peer>]            counter snapshot, or ask a peer for its own
+//   ping <peer>               round trip time to a peer
+//   trace <peer>              route to a peer, hop by hop
+//   config [get] [<key>]      show one or all settings
+//   config set <key> <value>  change and save a setting
+//   topology                  mesh edges and hop counts
+//   capture                   dump the frame capture as pcapng
+//   reboot                    restart the node
+
+use core::fmt::{self, Write};
+use heapless::Vec;
+
+use crate::node::{NodeCore, SendError};
+use crate::protocol::message_id::MessageId;
+use crate::protocol::peer::PeerId;
+use crate::settings::KEYS;
+
+pub const MAX_LINE: usize = 128;
+
+const HELP: &str = "\
+help                      list commands
+send <text>               broadcast a text message
+msg <peer> <text>         text one peer, retried until it ACKs
+peers                     links and peers heard announcing
+stats [<peer>]            counter snapshot, or ask a peer for its own
+ping <peer>               round trip time to a peer
+trace <peer>              route to a peer, hop by hop
+config [get] [<key>]      show one or all settings
+config set <key> <value>  change and save a setting
+topology                  mesh edges and hop counts
+capture                   dump the frame capture as pcapng
+reboot                    restart the node";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    Send(&'a str),
+    Message(PeerId, &'a str),
+    Peers,
+    Stats,
+    // Ask a peer for its counters
+    StatsRequest(PeerId),
+    // true for a traceroute
+    Ping(PeerId, bool),
+    ConfigGet(Option<&'a str>),
+    ConfigSet(&'a str, &'a str),
+    Topology,
+    Capture,
+    Reboot,
+}
+
+impl<'a> Command<'a> {
+    // None for a blank line
+    pub fn parse(line: &'a str) -> Result<Option<Self>, &'static str> {
+        let (word, rest) = split_word(line.trim());
+        let command = match word {
+            "" => return Ok(None),
+            "help" | "?" => Command::Help,
+            "send" if rest.is_empty() => return Err("send needs some text"),
+            "send" => Command::Send(rest),
+            "msg" => {
+                let (peer, text) = split_word(rest);
+                if text.is_empty() {
+                    return Err("usage: msg <peer> <text>");
+                }
+                Command::Message(parse_peer(peer)?, text)
+            }
+            "peers" => Command::Peers,
+            "stats" if rest.is_empty() => Command::Stats,
+            "stats" => Command::StatsRequest(parse_peer(rest)?),
+            "ping" => Command::Ping(parse_peer(rest)?, false),
+            "trace" => Command::Ping(parse_peer(rest)?, true),
+            "topology" => Command::Topology,
+            "capture" => Command::Capture,
+            "reboot" => Command::Reboot,
+            "config" => {
+                let (action, args) = split_word(rest);
+                match action {
+                    "" => Command::ConfigGet(None),
+                    "get" if args.is_empty() => Command::ConfigGet(None),
+                    "get" => Command::ConfigGet(Some(args)),
+                    "set" => {
+                        let (key, value) = split_word(args);
+                        if value.is_empty() {
+                            return Err("usage: config set <key> <value>");
+                        }
+                        Command::ConfigSet(key, value)
+                    }
+                    key => Command::ConfigGet(Some(key)),
+                }
+            }
+            _ => return Err("unknown command, try `help`"),
+        };
+        Ok(Some(command))
+    }
+}
+
+// What the transport has to do after a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Done,
+    // The node's settings changed and should be saved
+    SettingsChanged,
+    // The capture ring should be written out after the reply
+    DumpCapture,
+    Reboot,
+}
+
+// Parses and runs one line, writing the reply (or the error) to `out`
+pub fn run_line(line: &str, node: &mut NodeCore, now_ms: u64, out: &mut impl Write) -> Outcome {
+    match Command::parse(line) {
+        Ok(Some(command)) => execute(command, node, now_ms, out),
+        Ok(None) => Outcome::Done,
+        Err(e) => {
+            let _ = writeln!(out, "error: {}", e);
+            Outcome::Done
+        }
+    }
+}
+
+pub fn execute(command: Command<'_>, node: &mut NodeCore, now_ms: u64, out: &mut impl Write) -> Outcome {
+    match command {
+        Command::Reboot => {
+            let _ = writeln!(out, "rebooting");
+            Outcome::Reboot
+        }
+        Command::Capture => {
+            let _ = writeln!(out, "capture follows");
+            Outcome::DumpCapture
+        }
+        Command::ConfigSet(key, value) => match node.change_setting(key, value) {
+            Ok(()) => {
+                let _ = config_get(node, key, out);
+                Outcome::SettingsChanged
+            }
+            Err(e) => {
+                let _ = writeln!(out, "error: {}", e);
+                Outcome::Done
+            }
+        },
+        _ => {
+            // A full output buffer only cuts the reply short
+            let _ = reply(command, node, now_ms, out);
+            Outcome::Done
+        }
+    }
+}
+
+fn reply(command: Command<'_>, node: &mut NodeCore, now_ms: u64, out: &mut impl Write) -> fmt::Result {
+    match command {
+        Command::Help => writeln!(out, "{}", HELP),
+        Command::Send(text) => sent(node.send_text(text, now_ms), out),
+        Command::Message(peer, text) => sent(node.send_reliable(peer, text, now_ms), out),
+        Command::Peers => peers(node, now_ms, out),
+        Command::Stats => writeln!(out, "{}", node.stats(now_ms)),
+        Command::StatsRequest(peer) => {
+            node.request_stats(peer, now_ms);
+            writeln!(out, "stats requested from {}, the reply is logged", Hex(&peer))
+        }
+        Command::Ping(peer, traceroute) => {
+            node.ping(peer, traceroute, now_ms);
+            writeln!(out, "ping sent to {}, the reply is logged", Hex(&peer))
+        }
+        Command::ConfigGet(None) => KEYS.iter().try_for_each(|key| config_get(node, key, out)),
+        Command::ConfigGet(Some(key)) => config_get(node, key, out),
+        Command::Topology => topology(node, out),
+        // Handled by execute()
+        Command::ConfigSet(..) | Command::Capture | Command::Reboot => Ok(()),
+    }
+}
+
+fn sent(result: Result<MessageId, SendError>, out: &mut impl Write) -> fmt::Result {
+    match result {
+        Ok(id) => writeln!(out, "sent {}", Hex(&id.0)),
+        Err(SendError::NoLinks) => writeln!(out, "error: no links"),
+        Err(SendError::TooLarge) => writeln!(out, "error: text too long"),
+        Err(SendError::Busy) => writeln!(out, "error: too many messages awaiting an ACK"),
+    }
+}
+
+fn peers(node: &NodeCore, now_ms: u64, out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "links {}", node.link_count())?;
+    for metrics in node.link_metrics() {
+        write!(out, "  link {}", metrics.link.0)?;
+        if let Some(peer) = node.router().peer_on(metrics.link) {
+            write!(out, " {}", Hex(&peer))?;
+        }
+        if let Some(rssi) = metrics.rssi_dbm {
+            write!(out, " rssi={}", rssi)?;
+        }
+        writeln!(out, " etx={}.{:02}", metrics.etx_x100 / 100, metrics.etx_x100 % 100)?;
+    }
+
+    writeln!(out, "peers {}", node.peers().len())?;
+    for peer in node.peers() {
+        write!(out, "  {} {}", Hex(&peer.id), peer.nickname.as_str())?;
+        if let Some(hops) = node.router().topology().hop_count(&peer.id) {
+            write!(out, " hops={}", hops)?;
+        }
+        if let Some(link) = node.router().link_towards(&peer.id, now_ms) {
+            write!(out, " via={}", link.0)?;
+        }
+        writeln!(out, " seen={}s", now_ms.saturating_sub(peer.last_seen_ms) / 1000)?;
+    }
+    Ok(())
+}
+
+fn topology(node: &NodeCore, out: &mut impl Write) -> fmt::Result {
+    let topology = node.router().topology();
+    writeln!(out, "edges {}", topology.edges().len())?;
+    for edge in topology.edges() {
+        writeln!(out, "  {} -> {}", Hex(&edge.reporter), Hex(&edge.neighbor))?;
+    }
+    for (peer, hops) in topology.hop_counts() {
+        writeln!(out, "  {} hops={}", Hex(&peer), hops)?;
+    }
+    Ok(())
+}
+
+fn config_get(node: &NodeCore, key: &str, out: &mut impl Write) -> fmt::Result {
+    match node.settings().get(key) {
+        Some(value) => writeln!(out, "{} {}", key, value),
+        None => writeln!(out, "error: unknown key `{}`", key),
+    }
+}
+
+// Peer IDs as `peers` prints them; our own nodes leave the last two bytes zero
+fn parse_peer(text: &str) -> Result<PeerId, &'static str> {
+    let mut peer = PeerId::default();
+    if !text.is_ascii() || (text.len() != 12 && text.len() != 16) {
+        return Err("peer is 12 or 16 hex digits");
+    }
+    for (byte, pair) in peer.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).map_err(|_| "peer is not hex")?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| "peer is not hex")?;
+    }
+    Ok(peer)
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+// Collects typed bytes into lines. Backspace edits, CR or LF ends a line,
+// and a line that outgrows the buffer is thrown away whole.
+pub struct LineBuffer<const N: usize = MAX_LINE> {
+    line: Vec<u8, N>,
+    overflowed: bool,
+    // The last push returned the line; it is cleared on the next one
+    complete: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            overflowed: false,
+            complete: false,
+        }
+    }
+
+    // Some once a line is complete
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, &'static str>> {
+        if core::mem::take(&mut self.complete) {
+            self.line.clear();
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                if core::mem::take(&mut self.overflowed) {
+                    self.complete = true;
+                    return Some(Err("line too long"));
+                }
+                // The LF of a CRLF ends an empty line; nothing to run
+                if self.line.is_empty() {
+                    return None;
+                }
+                self.complete = true;
+                Some(core::str::from_utf8(&self.line).map_err(|_| "line is not UTF-8"))
+            }
+            0x08 | 0x7f => {
+                self.line.pop();
+                None
+            }
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.overflowed = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::LinkEvent;
+    use crate::protocol::relay::LinkId;
+
+    const PEER: PeerId = [0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6, 0, 0];
+
+    fn node() -> NodeCore {
+        NodeCore::new([0xA0; 6], 1, 7)
+    }
+
+    fn run(line: &str, node: &mut NodeCore) -> (Outcome, std::string::String) {
+        let mut out = std::string::String::new();
+        let outcome = run_line(line, node, 1_000, &mut out);
+        (outcome, out)
+    }
+
+    type Line = Result<std::string::String, &'static str>;
+
+    fn lines<const N: usize>(buffer: &mut LineBuffer<N>, bytes: &[u8]) -> std::vec::Vec<Line> {
+        bytes
+            .iter()
+            .filter_map(|&b| buffer.push(b).map(|line| line.map(str::to_owned)))
+            .collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  "), Ok(None));
+        assert_eq!(Command::parse("?"), Ok(Some(Command::Help)));
+        assert_eq!(Command::parse("send hello  mesh"), Ok(Some(Command::Send("hello  mesh"))));
+        assert_eq!(Command::parse("msg a1b2c3d4e5f6 hi"), Ok(Some(Command::Message(PEER, "hi"))));
+        assert_eq!(Command::parse("msg a1b2c3d4e5f60000 hi"), Ok(Some(Command::Message(PEER, "hi"))));
+        assert_eq!(Command::parse("stats"), Ok(Some(Command::Stats)));
+        assert_eq!(Command::parse("stats a1b2c3d4e5f6"), Ok(Some(Command::StatsRequest(PEER))));
+        assert_eq!(Command::parse("ping a1b2c3d4e5f6"), Ok(Some(Command::Ping(PEER, false))));
+        assert_eq!(Command::parse("trace a1b2c3d4e5f6"), Ok(Some(Command::Ping(PEER, true))));
+        assert_eq!(Command::parse("config"), Ok(Some(Command::ConfigGet(None))));
+        assert_eq!(Command::parse("config get"), Ok(Some(Command::ConfigGet(None))));
+        assert_eq!(Command::parse("config ttl"), Ok(Some(Command::ConfigGet(Some("ttl")))));
+        assert_eq!(Command::parse("config set nickname a b"), Ok(Some(Command::ConfigSet("nickname", "a b"))));
+        assert_eq!(Command::parse("capture"), Ok(Some(Command::Capture)));
+        assert_eq!(Command::parse("reboot"), Ok(Some(Command::Reboot)));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(Command::parse("frobnicate"), Err("unknown command, try `help`"));
+        assert_eq!(Command::parse("send"), Err("send needs some text"));
+        assert_eq!(Command::parse("msg a1b2c3d4e5f6"), Err("usage: msg <peer> <text>"));
+        assert_eq!(Command::parse("msg a1b2 hi"), Err("peer is 12 or 16 hex digits"));
+        assert_eq!(Command::parse("ping a1b2c3d4e5fz"), Err("peer is not hex"));
+        assert_eq!(Command::parse("ping"), Err("peer is 12 or 16 hex digits"));
+        assert_eq!(Command::parse("config set ttl"), Err("usage: config set <key> <value>"));
+    }
+
+    #[test]
+    fn crlf_ends_one_line() {
+        let mut buffer: LineBuffer = LineBuffer::new();
+        assert_eq!(lines(&mut buffer, b"peers\r\nstats\n\r\n"), [Ok("peers".into()), Ok("stats".into())]);
+    }
+
+    #[test]
+    fn backspace_edits_the_line() {
+        let mut buffer: LineBuffer = LineBuffer::new();
+        assert_eq!(lines(&mut buffer, b"pex\x08ers\r"), [Ok("peers".into())]);
+        // Backspace on an empty line does nothing
+        assert_eq!(lines(&mut buffer, b"\x7f\x7fhelp\x7f\x7fxx\r"), [Ok("hexx".into())]);
+    }
+
+    #[test]
+    fn an_overflowing_line_is_thrown_away() {
+        let mut buffer: LineBuffer<4> = LineBuffer::new();
+        assert_eq!(lines(&mut buffer, b"toolong\rhelp\r"), [Err("line too long"), Ok("help".into())]);
+        assert_eq!(lines(&mut buffer, b"\xff\r"), [Err("line is not UTF-8")]);
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let (outcome, out) = run("help", &mut node());
+        assert_eq!(outcome, Outcome::Done);
+        assert_eq!(out, std::format!("{}\n", HELP));
+    }
+
+    #[test]
+    fn errors_are_written_out() {
+        let mut node = node();
+        assert_eq!(run("bogus", &mut node), (Outcome::Done, "error: unknown command, try `help`\n".into()));
+        assert_eq!(run("config nope", &mut node), (Outcome::Done, "error: unknown key `nope`\n".into()));
+        assert_eq!(run("send hi", &mut node), (Outcome::Done, "error: no links\n".into()));
+    }
+
+    #[test]
+    fn config_set_changes_the_node() {
+        let mut node = node();
+        let (outcome, out) = run("config set ttl 3", &mut node);
+        assert_eq!(outcome, Outcome::SettingsChanged);
+        assert_eq!(out, "ttl 3\n");
+        assert_eq!(node.settings().default_ttl, 3);
+
+        let (outcome, out) = run("config set ttl many", &mut node);
+        assert_eq!(outcome, Outcome::Done);
+        assert!(out.starts_with("error: "));
+        assert_eq!(node.settings().default_ttl, 3);
+    }
+
+    #[test]
+    fn commands_queue_frames() {
+        let mut node = node();
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+        while node.next_transmit(LinkId(0)).is_some() {
+            node.transmitted(LinkId(0), true);
+        }
+
+        let (outcome, out) = run("send hello", &mut node);
+        assert_eq!(outcome, Outcome::Done);
+        assert!(out.starts_with("sent "));
+        assert!(node.next_transmit(LinkId(0)).is_some());
+        node.transmitted(LinkId(0), true);
+
+        let (_, out) = run("ping a1b2c3d4e5f6", &mut node);
+        assert_eq!(out, "ping sent to a1b2c3d4e5f60000, the reply is logged\n");
+        assert!(node.next_transmit(LinkId(0)).is_some());
+    }
+
+    #[test]
+    fn transport_outcomes() {
+        let mut node = node();
+        assert_eq!(run("capture", &mut node).0, Outcome::DumpCapture);
+        assert_eq!(run("reboot", &mut node), (Outcome::Reboot, "rebooting\n".into()));
+        assert_eq!(run("", &mut node), (Outcome::Done, std::string::String::new()));
+    }
+}
@@ -0,0 +1,19 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Protocol stack shared by the firmware and host tools. Nothing in here
+// touches the radio or the executor, so it builds for x86 with
+// `--no-default-features --features std`.
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+pub mod bitchat;
+pub mod capture;
+pub mod config;
+pub mod console;
+pub mod node;
+pub mod protocol;
+pub mod replay;
+pub mod settings;
+pub mod stats;
+pub mod storage;
@@ -2,17 +2,29 @@
 #![no_main]
 
 mod ble;
-mod config;
-mod protocol;
-mod bitchat;
+mod usb;
 
 use defmt::{info, warn};
 use embassy_executor::Spawner;
 use embassy_nrf::gpio::{Level, Output, OutputDrive};
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
 use embassy_time::Timer;
 use {defmt_rtt as _, panic_probe as _};
 
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::Flash;
+use static_cell::StaticCell;
+
 use ble::service::BitchatServer;
+use usb::UsbDriver;
+use bitchat_metal::protocol::relay::LinkId;
+use bitchat_metal::settings::Settings;
+use bitchat_metal::storage;
+
+// The settings and stream tasks take turns with the flash
+type SharedFlash = Mutex<NoopRawMutex, Flash>;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -41,9 +53,29 @@ async fn main(spawner: Spawner) {
     }
 
     let sd = ble::init(&spawner);
+    let vbus = usb::vbus_detect();
+    usb::enable_power_events(vbus);
+    let usb_driver = usb::driver(p.USBD, vbus);
+
+    // Flash can only be taken once; the settings and stream tasks share it afterwards
+    let mut flash = Flash::take(sd);
+    let boot_epoch = match storage::next_boot_epoch(&mut flash).await {
+        Ok(epoch) => epoch,
+        Err(e) => {
+            warn!("Failed to persist boot epoch: {:?}", e);
+            0
+        }
+    };
+    let settings = match storage::load_settings(&mut flash).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!("Failed to load settings: {:?}", e);
+            Settings::default()
+        }
+    };
 
     info!("Softdevice enabled, spawning BLE task...");
-    spawner.must_spawn(ble_task(sd, connect_led));
+    spawner.must_spawn(ble_task(sd, connect_led, boot_epoch, settings, flash, usb_driver));
 
     info!("BLE task spawned. Starting heartbeat...");
 
@@ -56,21 +88,44 @@ async fn main(spawner: Spawner) {
 }
 
 #[embassy_executor::task]
-async fn ble_task(sd: &'static mut nrf_softdevice::Softdevice, mut connect_led: Output<'static>) {
-    let mut server = match BitchatServer::new(sd) {
-        Ok(s) => s,
+async fn ble_task(
+    sd: &'static mut nrf_softdevice::Softdevice,
+    mut connect_led: Output<'static>,
+    boot_epoch: u32,
+    settings: Settings,
+    flash: Flash,
+    usb_driver: UsbDriver,
+) {
+    static SERVER: StaticCell<BitchatServer> = StaticCell::new();
+    let server: &'static BitchatServer = match BitchatServer::new(sd, boot_epoch, settings) {
+        Ok(s) => SERVER.init(s),
         Err(e) => {
             warn!("Failed to create GATT server: {:?}", e);
             return;
         }
     };
+    let sd: &'static nrf_softdevice::Softdevice = sd;
+    let spawner = Spawner::for_current_executor().await;
+
+    // The console needs the server, so it comes up once that exists
+    usb::start(&spawner, usb_driver, server);
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash: &'static SharedFlash = FLASH.init(Mutex::new(flash));
+    spawner.must_spawn(settings_task(server, flash));
+    spawner.must_spawn(stream_task(server, flash));
 
     info!("GATT server created. Starting advertisement loop...");
 
     loop {
-        connect_led.set_low();
+        // Keep advertising until every link slot is taken, so we can bridge peers
+        if !server.has_free_link() {
+            Timer::after_millis(1000).await;
+            continue;
+        }
+        connect_led.set_level(if server.has_connection() { Level::High } else { Level::Low });
 
-        let conn = match ble::advertise::advertise(sd).await {
+        let tx_power = server.settings().tx_power_dbm;
+        let conn = match ble::advertise::advertise(sd, tx_power).await {
             Ok(conn) => {
                 info!("Connection established!");
                 connect_led.set_high();
@@ -83,9 +138,49 @@ async fn ble_task(sd: &'static mut nrf_softdevice::Softdevice, mut connect_led:
             }
         };
 
-        // Run server (removed timeout for now since run() doesn't return Result)
-        server.run(&conn).await;
+        let Some(link) = server.claim_link() else {
+            warn!("No free link for new connection, dropping it");
+            let _ = conn.disconnect();
+            continue;
+        };
+        if let Err(e) = spawner.spawn(link_task(server, conn, link)) {
+            warn!("Failed to spawn link task: {:?}", e);
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = ble::service::MAX_LINKS)]
+async fn link_task(server: &'static BitchatServer, conn: Connection, link: LinkId) {
+    server.run(&conn, link).await;
+    info!("Connection lost or timed out on link {}", link.0);
+}
+
+// Saves the settings whenever the console or the settings characteristic
+// changed them
+#[embassy_executor::task]
+async fn settings_task(server: &'static BitchatServer, flash: &'static SharedFlash) -> ! {
+    loop {
+        server.wait_settings_changed().await;
+        // A burst of changes ends up in one write, unless a reboot is waiting
+        select(Timer::after_millis(500), server.wait_settings_flush()).await;
+        if let Err(e) = storage::save_settings(&mut *flash.lock().await, &server.settings()).await {
+            warn!("Failed to save settings: {:?}", e);
+        }
+        server.settings_saved();
+    }
+}
 
-        info!("Connection lost or timed out, restarting advertisement");
+// Writes incoming streams to their flash region as the node queues the work
+#[embassy_executor::task]
+async fn stream_task(server: &'static BitchatServer, flash: &'static SharedFlash) -> ! {
+    loop {
+        server.wait_flash_work().await;
+        while let Some(op) = server.next_flash_op() {
+            let result = storage::apply(&mut *flash.lock().await, &op).await;
+            if let Err(e) = &result {
+                warn!("Failed to write stream to flash: {:?}", e);
+            }
+            server.flash_op_done(result.is_ok());
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,1012 @@
+use heapless::{Deque, String, Vec};
+
+use crate::bitchat::announce::{AnnouncePayload, MAX_NICKNAME_LEN};
+use crate::bitchat::ping::{self, PingResult, Pinger};
+use crate::bitchat::{BitchatPacket, PacketType};
+use crate::config::MAX_MTU_SIZE;
+#[cfg(not(feature = "firmware"))]
+use crate::config::STREAM_BUFFER_SIZE;
+use crate::fmt::Bytes;
+use crate::protocol::action::{Action, Frame, PeerUpdate};
+use crate::protocol::dedupe::{DedupeConfig, DuplicateFilter};
+use crate::protocol::epoch::{EpochCheck, EpochTracker};
+use crate::protocol::link_quality::LinkMetrics;
+use crate::protocol::message::{max_frame_size, Message, MessageType, MAX_MESSAGE_SIZE, RECIPIENT_SIZE};
+use crate::protocol::message_id::MessageId;
+use crate::protocol::peer::{peer_id, PeerId};
+use crate::protocol::relay::{LinkId, RelayTarget};
+use crate::protocol::reliability::DeliveryEvent;
+use crate::protocol::text::TextMessage;
+use crate::protocol::stream::{StreamInfo, DIGEST_SIZE};
+#[cfg(not(feature = "firmware"))]
+use crate::protocol::stream::RamSink;
+use crate::protocol::announce::AnnounceScheduler;
+use crate::protocol::{MessageHandler, MessageRouter};
+use crate::settings::Settings;
+use crate::stats::{self, DecodeError, Stats, StatsSnapshot};
+#[cfg(feature = "firmware")]
+use crate::storage::{FlashOp, FlashSink, MappedFlash, STREAM_REGION, STREAM_REGION_SIZE};
+
+pub const MAX_LINKS: usize = 8;
+const LINK_QUEUE_DEPTH: usize = 8;
+const MAX_APP_EVENTS: usize = 4;
+const MAX_KNOWN_PEERS: usize = 16;
+const PING_TIMEOUT_MS: u32 = 10_000;
+// Writes of one frame that may fail before it is dropped, so a frame the
+// link keeps refusing can't hold up the rest of its queue
+const MAX_WRITE_ATTEMPTS: u8 = 8;
+
+// The device writes streams to flash, the simulator and tests keep them in RAM
+#[cfg(feature = "firmware")]
+type StreamSink = FlashSink<MappedFlash>;
+#[cfg(not(feature = "firmware"))]
+type StreamSink = RamSink<STREAM_BUFFER_SIZE>;
+
+// Everything the transport tells the node. Timestamps come with handle().
+#[derive(Debug, Clone, Copy)]
+pub enum LinkEvent<'a> {
+    Connected(LinkId),
+    BytesReceived(LinkId, &'a [u8]),
+    MtuChanged(LinkId, u16),
+    Rssi(LinkId, i8),
+    Disconnected(LinkId),
+    // poll_timeout() has passed
+    TimerFired,
+}
+
+// What the node reports to the application. Texts are carried inline since
+// there is no heap; the queue is kept short for that reason.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum AppEvent {
+    Text { from: PeerId, payload: Vec<u8, MAX_MESSAGE_SIZE> },
+    PeerAnnounced { peer: PeerId, nickname: String<MAX_NICKNAME_LEN> },
+    Delivery(DeliveryEvent),
+    Ping(PingResult),
+    PingTimeout(PeerId),
+    Stats { peer: PeerId, stats: StatsSnapshot },
+    // The data is in stream_data() until the next stream starts
+    Stream { from: PeerId, info: StreamInfo },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SendError {
+    TooLarge,
+    NoLinks,
+    // Too many reliable sends still waiting for their ACK
+    Busy,
+}
+
+// A peer that announced itself, direct neighbour or not
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownPeer {
+    pub id: PeerId,
+    pub nickname: String<MAX_NICKNAME_LEN>,
+    pub last_seen_ms: u64,
+}
+
+struct LinkState {
+    mtu: u16,
+    queue: Deque<Frame, LINK_QUEUE_DEPTH>,
+    // Failed writes of the frame at the front of the queue
+    failures: u8,
+}
+
+impl LinkState {
+    // Frames longer than one notification at the link's MTU are dropped
+    fn push(&mut self, link: usize, frame: &[u8]) -> bool {
+        if frame.len() > max_frame_size(self.mtu) {
+            warn!("Link {}: {} byte frame does not fit MTU {}, dropping", link, frame.len(), self.mtu);
+            return false;
+        }
+        let Ok(data) = Vec::from_slice(frame) else { return false };
+        if self.queue.push_back(data).is_err() {
+            warn!("Outgoing queue for link {} full, dropping frame", link);
+            return false;
+        }
+        true
+    }
+}
+
+// The whole protocol stack as a state machine without I/O: link events go
+// in, outbound frames per link and application events come out, and the
+// transport calls back once poll_timeout() is reached. The BLE service, the
+// host simulator and tests all drive this the same way.
+pub struct NodeCore {
+    device_id: PeerId,
+    boot_epoch: u32,
+    // Applied to the handler and router whenever they change
+    settings: Settings,
+    // Owns the router and announce scheduler both packet formats share
+    handler: MessageHandler,
+    stream_sink: StreamSink,
+    // A completed stream waiting for its data to reach flash
+    #[cfg(feature = "firmware")]
+    finished_stream: Option<AppEvent>,
+    pinger: Pinger,
+    // Bitchat packets already handed to the application
+    delivered: DuplicateFilter,
+    // High timestamp word per bitchat sender; see on_packet
+    packet_epochs: EpochTracker<u32>,
+    links: [Option<LinkState>; MAX_LINKS],
+    events: Deque<AppEvent, MAX_APP_EVENTS>,
+    peers: Vec<KnownPeer, MAX_KNOWN_PEERS>,
+    // Counters the handler and router keep themselves are added in stats()
+    stats: Stats,
+}
+
+impl NodeCore {
+    pub fn new(address: [u8; 6], boot_epoch: u32, seed: u64) -> Self {
+        // The message header has room for the low 16 bits, which peers
+        // compare as a 16-bit serial number
+        let mut handler = MessageHandler::new(address, boot_epoch as u16);
+        handler.seed_rng(seed);
+
+        let mut node = Self {
+            device_id: peer_id(&address),
+            boot_epoch,
+            settings: Settings::default(),
+            handler,
+            stream_sink: new_stream_sink(),
+            #[cfg(feature = "firmware")]
+            finished_stream: None,
+            pinger: Pinger::new(PING_TIMEOUT_MS),
+            delivered: DuplicateFilter::new(DedupeConfig::default()),
+            packet_epochs: EpochTracker::new(),
+            links: Default::default(),
+            events: Deque::new(),
+            peers: Vec::new(),
+            stats: Stats::new(),
+        };
+        node.apply_settings(Settings::default());
+        node
+    }
+
+    pub fn device_id(&self) -> PeerId {
+        self.device_id
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    // Takes effect at once: relaying, the announce interval and the TTL of
+    // the next packet we originate. The caller validates and persists.
+    pub fn apply_settings(&mut self, settings: Settings) {
+        if self.router().relay_enabled() != settings.relays() {
+            self.router_mut().set_relay_enabled(settings.relays());
+        }
+        let mut announce = self.handler.announce_config();
+        announce.interval_ms = settings.announce_interval_ms();
+        self.handler.set_announce_config(announce);
+        self.handler.set_device_name(&settings.nickname);
+        self.handler.set_default_ttl(settings.default_ttl);
+        self.settings = settings;
+    }
+
+    // One setting in its text form, as the console and the settings
+    // characteristic send it
+    pub fn change_setting(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        let mut settings = self.settings.clone();
+        settings.set(key, value)?;
+        self.apply_settings(settings);
+        Ok(())
+    }
+
+    pub fn nickname(&self) -> &str {
+        self.settings.nickname.as_str()
+    }
+
+    pub fn set_nickname(&mut self, nickname: &str) {
+        let mut settings = self.settings.clone();
+        settings.nickname = AnnouncePayload::new(nickname).nickname;
+        self.apply_settings(settings);
+    }
+
+    pub fn stats_replies(&self) -> bool {
+        self.settings.stats_replies
+    }
+
+    pub fn set_stats_replies(&mut self, enabled: bool) {
+        self.settings.stats_replies = enabled;
+    }
+
+    pub fn stats(&self, now_ms: u64) -> StatsSnapshot {
+        let mut snapshot = self.stats.snapshot(now_ms);
+        let stats = &mut snapshot.stats;
+        stats.duplicates = stats.duplicates.wrapping_add(self.handler.duplicates());
+        stats.fragment_timeouts = self.handler.fragment_timeouts();
+        stats.relay_suppressions = self.router().relays_suppressed();
+        snapshot
+    }
+
+    // The last stream received; see AppEvent::Stream
+    #[cfg(not(feature = "firmware"))]
+    pub fn stream_data(&self) -> &[u8] {
+        self.stream_sink.as_slice()
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn stream_data(&self) -> &[u8] {
+        &self.stream_sink.region().as_slice()[..self.stream_sink.len() as usize]
+    }
+
+    // Next flash operation for an incoming stream; carry it out with
+    // storage::apply and report back with flash_op_done()
+    #[cfg(feature = "firmware")]
+    pub fn next_flash_op(&self) -> Option<&FlashOp> {
+        self.stream_sink.next_op()
+    }
+
+    // A stream that completed meanwhile is reported once all of it is in flash
+    #[cfg(feature = "firmware")]
+    pub fn flash_op_done(&mut self, ok: bool) {
+        self.stream_sink.op_done(ok);
+        if !ok && self.finished_stream.take().is_some() {
+            warn!("Flash write failed, dropping the completed stream");
+        }
+        if self.stream_sink.is_flushed() {
+            if let Some(event) = self.finished_stream.take() {
+                self.push_event(event);
+            }
+        }
+    }
+
+    pub fn handler_mut(&mut self) -> &mut MessageHandler {
+        &mut self.handler
+    }
+
+    pub fn router(&self) -> &MessageRouter {
+        self.handler.router()
+    }
+
+    pub fn router_mut(&mut self) -> &mut MessageRouter {
+        self.handler.router_mut()
+    }
+
+    pub fn announcer_mut(&mut self) -> &mut AnnounceScheduler {
+        self.handler.announcer_mut()
+    }
+
+    // Lowest link ID not in use, for transports that don't number links themselves
+    pub fn free_link(&self) -> Option<LinkId> {
+        self.links.iter().position(|l| l.is_none()).map(|i| LinkId(i as u8))
+    }
+
+    pub fn is_connected(&self, link: LinkId) -> bool {
+        self.link(link).is_some()
+    }
+
+    pub fn link_count(&self) -> usize {
+        self.links.iter().filter(|l| l.is_some()).count()
+    }
+
+    pub fn link_metrics(&self) -> impl Iterator<Item = LinkMetrics> + '_ {
+        self.router().link_quality().iter()
+    }
+
+    // Peers heard announcing, in the order first heard
+    pub fn peers(&self) -> &[KnownPeer] {
+        &self.peers
+    }
+
+    pub fn handle(&mut self, event: LinkEvent<'_>, now_ms: u64) {
+        match event {
+            LinkEvent::Connected(link) => {
+                let Some(slot) = self.links.get_mut(link.0 as usize) else {
+                    warn!("Link {} out of range", link.0);
+                    return;
+                };
+                *slot = Some(LinkState { mtu: MAX_MTU_SIZE, queue: Deque::new(), failures: 0 });
+                let connected = self.link_count() as u8;
+                self.router_mut().set_neighbor_count(connected);
+                // Announce immediately after connection (iOS expects this)
+                self.announcer_mut().on_connected();
+            }
+            LinkEvent::BytesReceived(link, data) => self.on_frame(link, data, now_ms),
+            LinkEvent::MtuChanged(link, mtu) => {
+                if let Some(state) = self.link_mut(link) {
+                    info!("Link {}: MTU {}", link.0, mtu);
+                    state.mtu = mtu;
+                    state.failures = 0;
+                    // Frames queued for a larger MTU could never be written
+                    let queued = core::mem::take(&mut state.queue);
+                    for frame in queued {
+                        state.push(link.0 as usize, &frame);
+                    }
+                }
+            }
+            LinkEvent::Rssi(link, rssi) => self.router_mut().link_quality_mut().on_rssi(link, rssi),
+            LinkEvent::Disconnected(link) => {
+                if let Some(slot) = self.links.get_mut(link.0 as usize) {
+                    *slot = None;
+                }
+                self.router_mut().on_link_failed(link);
+                let connected = self.link_count() as u8;
+                self.router_mut().set_neighbor_count(connected);
+            }
+            LinkEvent::TimerFired => self.on_timer(now_ms),
+        }
+    }
+
+    // Next frame to write on `link`; call transmitted() once the write was attempted
+    pub fn next_transmit(&self, link: LinkId) -> Option<&Frame> {
+        self.link(link)?.queue.front()
+    }
+
+    // Outcome of writing the frame from next_transmit(). Failed frames stay
+    // queued for the next attempt, up to MAX_WRITE_ATTEMPTS.
+    pub fn transmitted(&mut self, link: LinkId, ok: bool) {
+        self.router_mut().link_quality_mut().on_write(link, ok);
+        if !ok {
+            self.stats.notify_failures = self.stats.notify_failures.wrapping_add(1);
+        }
+        let Some(state) = self.link_mut(link) else { return };
+        if !ok {
+            state.failures += 1;
+            if state.failures < MAX_WRITE_ATTEMPTS {
+                return;
+            }
+            warn!("Link {}: dropping a frame after {} failed writes", link.0, state.failures);
+        }
+        state.failures = 0;
+        if let Some(frame) = state.queue.pop_front() {
+            if ok {
+                self.stats.tx.count(&frame);
+            }
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<AppEvent> {
+        self.events.pop_front()
+    }
+
+    // When TimerFired is next due
+    pub fn poll_timeout(&self) -> u64 {
+        [self.router().next_deadline(), self.pinger.next_deadline()]
+            .into_iter()
+            .flatten()
+            .fold(self.handler.next_deadline(), u64::min)
+    }
+
+    // Broadcasts a text message in the iOS packet format
+    pub fn send_text(&mut self, text: &str, now_ms: u64) -> Result<MessageId, SendError> {
+        if self.link_count() == 0 {
+            return Err(SendError::NoLinks);
+        }
+        let mut packet = BitchatPacket::create_text(self.device_id, self.timestamp(now_ms), text.as_bytes())
+            .map_err(|_| SendError::TooLarge)?;
+        packet.ttl = self.settings.default_ttl;
+        // Our own packet echoed back by a neighbour must not reach the app
+        self.delivered.insert(&packet.message_id(), now_ms);
+        self.send_packet(&packet, now_ms);
+        Ok(packet.message_id())
+    }
+
+    // Sends text to one peer in our own format and retries until it ACKs.
+    // The outcome arrives as AppEvent::Delivery with the returned ID.
+    pub fn send_reliable(&mut self, to: PeerId, text: &str, now_ms: u64) -> Result<MessageId, SendError> {
+        if self.link_count() == 0 {
+            return Err(SendError::NoLinks);
+        }
+        let mut recipient = [0u8; RECIPIENT_SIZE];
+        recipient.copy_from_slice(&to[..RECIPIENT_SIZE]);
+        let mut message = TextMessage::create_direct(self.handler.device_id(), recipient, 0, text)
+            .map_err(|_| SendError::TooLarge)?;
+        message.header.ttl = self.settings.default_ttl;
+        let id = self.handler.send_reliable(&mut message, now_ms).map_err(|_| SendError::Busy)?;
+        self.send_message(&message, now_ms);
+        Ok(id)
+    }
+
+    pub fn ping(&mut self, target: PeerId, traceroute: bool, now_ms: u64) {
+        let timestamp = self.timestamp(now_ms);
+        match self.pinger.create_ping(self.device_id, target, traceroute, timestamp, now_ms) {
+            Ok(packet) => self.send_packet(&packet, now_ms),
+            Err(e) => warn!("Failed to create ping: {}", e),
+        }
+    }
+
+    // The answer arrives as AppEvent::Stats, if the target replies at all
+    pub fn request_stats(&mut self, target: PeerId, now_ms: u64) {
+        match stats::create_request(self.device_id, target, self.timestamp(now_ms)) {
+            Ok(packet) => self.send_packet(&packet, now_ms),
+            Err(e) => warn!("Failed to create stats request: {}", e),
+        }
+    }
+
+    fn timestamp(&self, now_ms: u64) -> u64 {
+        BitchatPacket::epoch_timestamp(self.boot_epoch, now_ms)
+    }
+
+    fn link(&self, link: LinkId) -> Option<&LinkState> {
+        self.links.get(link.0 as usize)?.as_ref()
+    }
+
+    fn link_mut(&mut self, link: LinkId) -> Option<&mut LinkState> {
+        self.links.get_mut(link.0 as usize)?.as_mut()
+    }
+
+    // Queues a frame on every connected link the target includes, all if None.
+    // Returns true if any link took it.
+    fn send(&mut self, frame: &[u8], target: Option<RelayTarget>) -> bool {
+        let mut queued = false;
+        for (i, state) in self.links.iter_mut().enumerate() {
+            let Some(state) = state else { continue };
+            if target.is_some_and(|t| !t.includes(LinkId(i as u8))) {
+                continue;
+            }
+            queued |= state.push(i, frame);
+        }
+        queued
+    }
+
+    // Queues every fragment of a message we originate, towards its recipient
+    // if we know the way. Each link gets fragments sized to its own MTU.
+    fn send_message(&mut self, message: &Message, now_ms: u64) {
+        let target = self.next_hop(message, now_ms).map(RelayTarget::Link);
+        for (i, state) in self.links.iter_mut().enumerate() {
+            let Some(state) = state else { continue };
+            if target.is_some_and(|t| !t.includes(LinkId(i as u8))) {
+                continue;
+            }
+            match self.handler.fragments(message, state.mtu) {
+                Ok(fragments) => {
+                    for frame in fragments {
+                        state.push(i, &frame);
+                    }
+                }
+                Err(e) => warn!("Failed to fragment {:?} for link {}: {:?}", message.header.msg_type, i, e),
+            }
+        }
+    }
+
+    // The link towards a directed message's recipient, if the route is known
+    fn next_hop(&self, message: &Message, now_ms: u64) -> Option<LinkId> {
+        let to = message.recipient()?;
+        self.router().link_towards(&peer_id(&to), now_ms)
+    }
+
+    // Queues a packet we originate, on the next hop's link if one is known
+    fn send_packet(&mut self, packet: &BitchatPacket, now_ms: u64) {
+        let target = packet.directed_to()
+            .and_then(|peer| self.router().link_towards(&peer, now_ms))
+            .map(RelayTarget::Link);
+        match packet.encode() {
+            Ok(frame) => {
+                self.send(&frame, target);
+            }
+            Err(e) => warn!("Failed to encode {:?}: {}", packet.packet_type, e),
+        }
+    }
+
+    fn on_frame(&mut self, link: LinkId, data: &[u8], now_ms: u64) {
+        if !self.is_connected(link) {
+            warn!("Frame on unknown link {}", link.0);
+            return;
+        }
+
+        // Our own format carries a CRC, so a frame that checks out as one is
+        // one; everything else is tried as an iOS packet
+        let rejected = match self.handler.process_incoming_with_sink(data, now_ms, &mut self.stream_sink) {
+            Ok(Some(message)) => {
+                self.stats.rx.count(data);
+                return self.on_message(&message, link, now_ms);
+            }
+            Ok(None) => {
+                self.stats.rx.count(data);
+                return self.flush_acks(link, now_ms);
+            }
+            Err(e) => e,
+        };
+
+        match BitchatPacket::decode(data) {
+            Ok(packet) => {
+                self.stats.rx.count(data);
+                self.on_packet(&packet, link, now_ms);
+            }
+            Err(e) => {
+                warn!("Link {}: undecodable frame: {}", link.0, e);
+                self.stats.rejected(&rejected);
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: &Message, link: LinkId, now_ms: u64) {
+        let actions = match self.handler.handle_message(message, link, now_ms) {
+            Ok(actions) => actions,
+            Err(e) => {
+                warn!("Failed to handle message: {:?}", e);
+                self.stats.rejected(&e);
+                return;
+            }
+        };
+
+        for action in actions {
+            match action {
+                Action::Deliver(_) => {
+                    if message.header.is_stream() {
+                        // The payload is the manifest, the data is in our sink
+                        if let Some(info) = stream_info(message) {
+                            self.on_stream(AppEvent::Stream { from: peer_id(&message.header.sender_id), info });
+                        }
+                    } else if message.header.msg_type == MessageType::Text {
+                        let mut payload = Vec::new();
+                        let _ = payload.extend_from_slice(message.body());
+                        self.push_event(AppEvent::Text { from: peer_id(&message.header.sender_id), payload });
+                    }
+                }
+                Action::Reply(frame) => {
+                    self.send(&frame, Some(RelayTarget::Link(link)));
+                }
+                // Sent from on_timer once the delay is up
+                Action::Relay { target, delay_ms, layout } => {
+                    let due_ms = now_ms + delay_ms as u64;
+                    if !self.router_mut().queue_relay(message, &layout, link, target, due_ms) {
+                        warn!("Relay queue full, dropping relay of {:?}", message.message_id());
+                    }
+                }
+                Action::AckTo { peer, frame } => {
+                    let next_hop = self.router().link_towards(&peer, now_ms).unwrap_or(link);
+                    self.send(&frame, Some(RelayTarget::Link(next_hop)));
+                }
+                Action::UpdatePeer { peer, update } => {
+                    info!("Peer {:02x}: {:?}", Bytes(&peer), update);
+                    // The ACK came back the way our message went
+                    if let PeerUpdate::AckReceived(_) = update {
+                        self.router_mut().link_quality_mut().on_ack(link, true);
+                    }
+                }
+                Action::Drop(reason) => info!("Dropped message: {:?}", reason),
+            }
+        }
+        // An ACK we were waiting on
+        self.poll_deliveries();
+    }
+
+    // Duplicates produce no actions but may still be owed an ACK
+    fn flush_acks(&mut self, link: LinkId, now_ms: u64) {
+        while let Some((peer, frame)) = self.handler.next_ack_to() {
+            let next_hop = self.router().link_towards(&peer, now_ms).unwrap_or(link);
+            self.send(&frame, Some(RelayTarget::Link(next_hop)));
+        }
+    }
+
+    fn on_packet(&mut self, packet: &BitchatPacket, link: LinkId, now_ms: u64) {
+        info!("Received packet type: {:?}, TTL: {}", packet.packet_type, packet.ttl);
+
+        // Our nodes put the boot epoch in the high timestamp word and iOS its
+        // wall clock, so for any one sender it never goes back: a lower one is
+        // a replay. Message IDs cover the timestamp, so packets from before a
+        // reset can't be mistaken for new ones and nothing needs forgetting.
+        //
+        // Nothing vouches for who sent a packet, so a far jump needs confirming
+        let check = self.packet_epochs.observe_unverified(&packet.sender_id, packet.epoch());
+        if matches!(check, EpochCheck::Stale | EpochCheck::Unconfirmed) {
+            self.stats.decode_error(DecodeError::StaleEpoch);
+            return;
+        }
+
+        // Forward to the rest of the mesh; on_timer sends it once its delay is
+        // up. Duplicates still go through here so they count towards suppression.
+        self.router_mut().relay_packet(packet, link, now_ms);
+
+        if packet.sender_id == self.device_id {
+            return;
+        }
+        if self.delivered.check_and_insert(&packet.message_id(), now_ms) {
+            self.stats.duplicates = self.stats.duplicates.wrapping_add(1);
+            return;
+        }
+        let for_us = packet.directed_to().is_none_or(|to| to == self.device_id);
+
+        match packet.packet_type {
+            PacketType::Announce => {
+                let announce = AnnouncePayload::decode(&packet.payload);
+                info!("Device announce from {}, {} neighbors", announce.nickname.as_str(), announce.neighbors.len());
+                self.announcer_mut().on_peer_announce(&packet.sender_id, now_ms);
+                self.remember_peer(packet.sender_id, &announce.nickname, now_ms);
+                self.push_event(AppEvent::PeerAnnounced { peer: packet.sender_id, nickname: announce.nickname });
+            }
+            PacketType::Text if for_us => {
+                let mut payload = Vec::new();
+                let _ = payload.extend_from_slice(&packet.payload);
+                self.push_event(AppEvent::Text { from: packet.sender_id, payload });
+            }
+            PacketType::Discovery => {
+                info!("Discovery packet");
+                self.announcer_mut().on_discovery(&packet.sender_id, now_ms);
+            }
+            PacketType::Ping if packet.directed_to() == Some(self.device_id) => {
+                info!("Ping from {:02x}, replying", Bytes(&packet.sender_id));
+                match ping::create_reply(packet, self.device_id, self.timestamp(now_ms)) {
+                    Ok(reply) => self.send_packet(&reply, now_ms),
+                    Err(e) => warn!("Failed to create ping reply: {}", e),
+                }
+            }
+            PacketType::PingReply if packet.directed_to() == Some(self.device_id) => {
+                if let Some(result) = self.pinger.on_reply(packet, now_ms) {
+                    self.push_event(AppEvent::Ping(result));
+                }
+            }
+            PacketType::Stats if packet.directed_to() == Some(self.device_id) => {
+                if !self.settings.stats_replies {
+                    info!("Stats request from {:02x}, replies disabled", Bytes(&packet.sender_id));
+                    return;
+                }
+                let snapshot = self.stats(now_ms);
+                match stats::create_reply(packet, self.device_id, self.timestamp(now_ms), &snapshot) {
+                    Ok(reply) => self.send_packet(&reply, now_ms),
+                    Err(e) => warn!("Failed to create stats reply: {}", e),
+                }
+            }
+            PacketType::StatsReply if packet.directed_to() == Some(self.device_id) => {
+                match StatsSnapshot::decode(&packet.payload) {
+                    Ok(stats) => self.push_event(AppEvent::Stats { peer: packet.sender_id, stats }),
+                    Err(e) => {
+                        warn!("Bad stats reply from {:02x}: {}", Bytes(&packet.sender_id), e);
+                        self.stats.decode_error(DecodeError::Malformed);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_timer(&mut self, now_ms: u64) {
+        if let Some(reason) = self.announcer_mut().poll(now_ms) {
+            info!("Sending announce ({:?})", reason);
+            self.send_announce(now_ms);
+        }
+
+        while let Some(relay) = self.router_mut().next_relay(now_ms) {
+            info!("Relaying {} bytes from link {} to {:?}", relay.frame.len(), relay.from.0, relay.target);
+            if self.send(&relay.frame, Some(relay.target)) {
+                self.stats.relays = self.stats.relays.wrapping_add(1);
+            }
+        }
+
+        while let Some(target) = self.pinger.next_timeout(now_ms) {
+            warn!("Ping to {:02x} timed out", Bytes(&target));
+            self.push_event(AppEvent::PingTimeout(target));
+        }
+
+        while let Some(message) = self.handler.next_retransmit(now_ms) {
+            let message = message.clone();
+            // A timeout only counts against a link if the message took one link
+            if let Some(link) = self.next_hop(&message, now_ms) {
+                self.router_mut().link_quality_mut().on_ack(link, false);
+            }
+            self.send_message(&message, now_ms);
+        }
+        self.poll_deliveries();
+    }
+
+    fn poll_deliveries(&mut self) {
+        while let Some(event) = self.handler.next_delivery_event() {
+            self.push_event(AppEvent::Delivery(event));
+        }
+    }
+
+    fn send_announce(&mut self, now_ms: u64) {
+        let mut payload = AnnouncePayload::new(self.settings.nickname.as_str());
+        self.router_mut().refresh_local_edges(now_ms);
+        payload.neighbors = self.router().neighbor_ids();
+
+        let packet = payload.encode()
+            .and_then(|payload| BitchatPacket::create_announce(self.device_id, self.timestamp(now_ms), &payload));
+        match packet {
+            Ok(mut packet) => {
+                packet.ttl = self.settings.default_ttl;
+                self.send_packet(&packet, now_ms);
+            }
+            Err(e) => warn!("Failed to create announce: {}", e),
+        }
+    }
+
+    fn remember_peer(&mut self, id: PeerId, nickname: &str, now_ms: u64) {
+        let mut nick = String::new();
+        let _ = nick.push_str(nickname);
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.id == id) {
+            peer.nickname = nick;
+            peer.last_seen_ms = now_ms;
+            return;
+        }
+        if self.peers.is_full() {
+            // Make room by forgetting whoever was heard from least recently
+            if let Some(stalest) = self.peers.iter().enumerate().min_by_key(|(_, p)| p.last_seen_ms).map(|(i, _)| i) {
+                self.peers.remove(stalest);
+            }
+        }
+        let _ = self.peers.push(KnownPeer { id, nickname: nick, last_seen_ms: now_ms });
+    }
+
+    // The sink may still be writing the stream out
+    fn on_stream(&mut self, event: AppEvent) {
+        #[cfg(feature = "firmware")]
+        if !self.stream_sink.is_flushed() {
+            self.finished_stream = Some(event);
+            return;
+        }
+        self.push_event(event);
+    }
+
+    fn push_event(&mut self, event: AppEvent) {
+        if self.events.is_full() {
+            warn!("App event queue full, dropping the oldest");
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+}
+
+#[cfg(feature = "firmware")]
+fn new_stream_sink() -> StreamSink {
+    FlashSink::new(MappedFlash::new(STREAM_REGION, STREAM_REGION_SIZE), STREAM_REGION)
+}
+
+#[cfg(not(feature = "firmware"))]
+fn new_stream_sink() -> StreamSink {
+    RamSink::new()
+}
+
+// Back from the manifest message the handler builds for a completed stream
+fn stream_info(message: &Message) -> Option<StreamInfo> {
+    let payload = &message.payload;
+    let total_len = u32::from_be_bytes(payload.get(..4)?.try_into().ok()?);
+    let digest = payload.get(4..4 + DIGEST_SIZE)?.try_into().ok()?;
+    Some(StreamInfo {
+        sender_id: message.header.sender_id,
+        sequence: message.header.sequence,
+        total_len,
+        digest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::link_quality::ETX_PERFECT;
+    use crate::protocol::message::MessageHeader;
+    use crate::protocol::stream::StreamFragments;
+
+    const A: [u8; 6] = [0xA0; 6];
+    const B: [u8; 6] = [0xB0; 6];
+
+    fn drain(node: &mut NodeCore, link: LinkId) -> std::vec::Vec<Frame> {
+        let mut frames = std::vec::Vec::new();
+        while let Some(frame) = node.next_transmit(link) {
+            frames.push(frame.clone());
+            node.transmitted(link, true);
+        }
+        frames
+    }
+
+    // Nodes in a row, each one's link 1 joined to the next one's link 0
+    fn line(addresses: &[[u8; 6]]) -> std::vec::Vec<NodeCore> {
+        let mut nodes: std::vec::Vec<_> = addresses.iter().map(|a| NodeCore::new(*a, 1, a[0] as u64)).collect();
+        let last = nodes.len() - 1;
+        for (i, node) in nodes.iter_mut().enumerate() {
+            if i > 0 {
+                node.handle(LinkEvent::Connected(LinkId(0)), 0);
+            }
+            if i < last {
+                node.handle(LinkEvent::Connected(LinkId(1)), 0);
+            }
+        }
+        nodes
+    }
+
+    // Advances every node to `until`, carrying frames along the line as they
+    // are queued; returns each node's application events
+    fn run(nodes: &mut [NodeCore], from_ms: u64, until: u64) -> std::vec::Vec<std::vec::Vec<AppEvent>> {
+        let mut events = vec![std::vec::Vec::new(); nodes.len()];
+        for now in (from_ms..=until).step_by(10) {
+            for node in nodes.iter_mut() {
+                if node.poll_timeout() <= now {
+                    node.handle(LinkEvent::TimerFired, now);
+                }
+            }
+            loop {
+                let mut moved = false;
+                for i in 0..nodes.len() {
+                    for frame in drain(&mut nodes[i], LinkId(1)) {
+                        if let Some(next) = nodes.get_mut(i + 1) {
+                            next.handle(LinkEvent::BytesReceived(LinkId(0), &frame), now);
+                        }
+                        moved = true;
+                    }
+                    for frame in drain(&mut nodes[i], LinkId(0)) {
+                        if i > 0 {
+                            nodes[i - 1].handle(LinkEvent::BytesReceived(LinkId(1), &frame), now);
+                        }
+                        moved = true;
+                    }
+                }
+                if !moved {
+                    break;
+                }
+            }
+            for (node, events) in nodes.iter_mut().zip(&mut events) {
+                events.extend(core::iter::from_fn(|| node.poll_event()));
+            }
+        }
+        events
+    }
+
+    fn texts(events: &[AppEvent]) -> std::vec::Vec<&[u8]> {
+        events.iter().filter_map(|e| match e {
+            AppEvent::Text { payload, .. } => Some(&payload[..]),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn reliable_send_is_acked_across_a_relay() {
+        const C: [u8; 6] = [0xC0; 6];
+        let mut nodes = line(&[A, B, C]);
+        let id = nodes[0].send_reliable(peer_id(&C), "hello", 0).unwrap();
+
+        let events = run(&mut nodes, 0, 20_000);
+        assert_eq!(texts(&events[2]), [&b"hello"[..]]);
+        assert!(texts(&events[1]).is_empty());
+        let deliveries: std::vec::Vec<_> = events[0].iter().filter(|e| matches!(e, AppEvent::Delivery(_))).collect();
+        assert_eq!(deliveries, [&AppEvent::Delivery(DeliveryEvent::Delivered(id))]);
+    }
+
+    #[test]
+    fn ack_timeouts_count_against_the_route() {
+        const C: [u8; 6] = [0xC0; 6];
+        let mut nodes = line(&[A, B, C]);
+        // Announces teach A that C is behind link 1
+        run(&mut nodes, 0, 5_000);
+        assert_eq!(nodes[0].router().link_towards(&peer_id(&C), 5_000), Some(LinkId(1)));
+
+        nodes[1].handle(LinkEvent::Disconnected(LinkId(1)), 5_000);
+        nodes.truncate(2);
+        nodes[0].send_reliable(peer_id(&C), "still there?", 5_000).unwrap();
+        run(&mut nodes, 5_000, 8_000);
+        let metrics = nodes[0].router().link_quality().metrics(LinkId(1)).unwrap();
+        assert!(metrics.ack_per_mille < 1000);
+        assert!(metrics.etx_x100 > ETX_PERFECT);
+    }
+
+    #[test]
+    fn reliable_send_fails_without_the_recipient() {
+        const C: [u8; 6] = [0xC0; 6];
+        let mut nodes = line(&[A, B]);
+        let id = nodes[0].send_reliable(peer_id(&C), "anyone?", 0).unwrap();
+
+        let events = run(&mut nodes, 0, 60_000);
+        assert!(texts(&events[1]).is_empty());
+        assert!(events[0].contains(&AppEvent::Delivery(DeliveryEvent::Failed(id))));
+        assert!(!events[0].contains(&AppEvent::Delivery(DeliveryEvent::Delivered(id))));
+    }
+
+    #[test]
+    fn messages_are_fragmented_to_the_link_mtu() {
+        let mut sender = NodeCore::new(A, 1, 1);
+        let mut receiver = NodeCore::new(B, 1, 2);
+        sender.handle(LinkEvent::Connected(LinkId(0)), 0);
+        receiver.handle(LinkEvent::Connected(LinkId(0)), 0);
+        sender.handle(LinkEvent::MtuChanged(LinkId(0), 40), 0);
+
+        let text = "long enough to take several notifications at a 40 byte MTU";
+        sender.send_reliable(peer_id(&B), text, 0).unwrap();
+        let frames = drain(&mut sender, LinkId(0));
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= max_frame_size(40)));
+
+        for frame in &frames {
+            receiver.handle(LinkEvent::BytesReceived(LinkId(0), frame), 10);
+        }
+        let events: std::vec::Vec<_> = core::iter::from_fn(|| receiver.poll_event()).collect();
+        assert_eq!(texts(&events), [text.as_bytes()]);
+    }
+
+    #[test]
+    fn frames_that_no_longer_fit_the_mtu_are_dropped() {
+        let mut node = NodeCore::new(A, 1, 1);
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+        node.send_text("short", 0).unwrap();
+        node.send_text("a text that is too long for one notification at a 64 byte MTU", 0).unwrap();
+
+        node.handle(LinkEvent::MtuChanged(LinkId(0), 64), 0);
+        let frames = drain(&mut node, LinkId(0));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].len() <= max_frame_size(64));
+    }
+
+    #[test]
+    fn frames_that_keep_failing_are_dropped() {
+        let mut node = NodeCore::new(A, 1, 1);
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+        drain(&mut node, LinkId(0));
+        node.send_text("first", 0).unwrap();
+        node.send_text("second", 0).unwrap();
+        let first = node.next_transmit(LinkId(0)).unwrap().clone();
+
+        for _ in 1..MAX_WRITE_ATTEMPTS {
+            node.transmitted(LinkId(0), false);
+            assert_eq!(node.next_transmit(LinkId(0)), Some(&first));
+        }
+        node.transmitted(LinkId(0), false);
+        assert_ne!(node.next_transmit(LinkId(0)), Some(&first));
+
+        // The next frame gets a full set of attempts
+        node.transmitted(LinkId(0), false);
+        assert!(node.next_transmit(LinkId(0)).is_some());
+        assert_eq!(drain(&mut node, LinkId(0)).len(), 1);
+        let stats = node.stats(0).stats;
+        assert_eq!(stats.notify_failures, MAX_WRITE_ATTEMPTS as u32 + 1);
+        assert_eq!(stats.tx.total(), 1);
+    }
+
+    fn text_from(sender: PeerId, epoch: u32, text: &str) -> Frame {
+        let packet = BitchatPacket::create_text(sender, BitchatPacket::epoch_timestamp(epoch, 0), text.as_bytes()).unwrap();
+        Vec::from_slice(&packet.encode().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn packets_from_an_earlier_boot_are_dropped() {
+        let sender = peer_id(&B);
+        let mut node = NodeCore::new(A, 1, 1);
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, 7, "after reboot")), 10);
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, 6, "replayed")), 20);
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, 8, "rebooted again")), 30);
+
+        let texts: std::vec::Vec<_> = core::iter::from_fn(|| node.poll_event())
+            .filter_map(|e| match e {
+                AppEvent::Text { payload, .. } => Some(payload),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, [&b"after reboot"[..], &b"rebooted again"[..]]);
+        assert_eq!(node.stats(30).stats.decode_errors[DecodeError::StaleEpoch as usize], 1);
+    }
+
+    #[test]
+    fn a_spoofed_epoch_does_not_silence_the_sender() {
+        let sender = peer_id(&B);
+        let mut node = NodeCore::new(A, 1, 1);
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, 7, "first")), 10);
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, u32::MAX / 2, "spoofed")), 20);
+        node.handle(LinkEvent::BytesReceived(LinkId(0), &text_from(sender, 7, "second")), 30);
+
+        let texts: std::vec::Vec<_> = core::iter::from_fn(|| node.poll_event())
+            .filter_map(|e| match e {
+                AppEvent::Text { payload, .. } => Some(payload),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, [&b"first"[..], &b"second"[..]]);
+    }
+
+    #[test]
+    fn streams_are_delivered_but_not_relayed() {
+        let mut node = NodeCore::new(A, 1, 1);
+        node.handle(LinkEvent::Connected(LinkId(0)), 0);
+        node.handle(LinkEvent::Connected(LinkId(1)), 0);
+        drain(&mut node, LinkId(1));
+
+        let data: std::vec::Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut message = Message::new(MessageType::Text, B, 4, &[]).unwrap();
+        message.header.ttl = 3;
+        for frame in StreamFragments::new(&message, &data[..], MAX_MTU_SIZE).unwrap() {
+            node.handle(LinkEvent::BytesReceived(LinkId(0), &frame.unwrap()), 10);
+        }
+
+        let event = core::iter::from_fn(|| node.poll_event()).find(|e| matches!(e, AppEvent::Stream { .. }));
+        let Some(AppEvent::Stream { from, info }) = event else { panic!("no stream event") };
+        assert_eq!(from, peer_id(&B));
+        assert_eq!(info.total_len, 1000);
+        assert_eq!(node.stream_data(), &data[..]);
+        node.handle(LinkEvent::TimerFired, 10_000);
+        assert!(drain(&mut node, LinkId(1)).iter().all(|f| MessageHeader::deserialize(f).is_err()));
+    }
+}
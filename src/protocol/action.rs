@@ -0,0 +1,45 @@
+use heapless::Vec;
+use crate::protocol::fragmentation::FragmentLayout;
+use crate::protocol::message::MAX_FRAGMENT_SIZE;
+use crate::protocol::message_id::MessageId;
+use crate::protocol::peer::PeerId;
+use crate::protocol::relay::RelayTarget;
+
+// Enough for delivery, a reply, a relay and the ACKs owed
+pub const MAX_ACTIONS: usize = 8;
+
+pub type Frame = Vec<u8, MAX_FRAGMENT_SIZE>;
+pub type Actions = Vec<Action, MAX_ACTIONS>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PeerUpdate {
+    Announced,
+    AckReceived(MessageId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DropReason {
+    TtlExpired,
+    NotRelayed,
+    UnknownAck,
+    Malformed,
+}
+
+// What the caller should do about a handled message. The handler never does
+// I/O itself, so the BLE service, the simulator and tests all execute these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    // Pass the handled message to the application
+    Deliver(MessageId),
+    // Send back on the link the message arrived on
+    Reply(Frame),
+    // Send the message on as the frames in `layout` after `delay_ms`, e.g.
+    // through MessageRouter::queue_relay, which drops it if enough copies are
+    // overheard meanwhile
+    Relay { target: RelayTarget, delay_ms: u32, layout: FragmentLayout },
+    AckTo { peer: PeerId, frame: Frame },
+    UpdatePeer { peer: PeerId, update: PeerUpdate },
+    Drop(DropReason),
+}
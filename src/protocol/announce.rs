@@ -0,0 +1,146 @@
+use heapless::FnvIndexMap;
+use crate::protocol::peer::{peer_id, PeerId};
+use crate::protocol::rng::Rng;
+
+const MAX_TRACKED_PEERS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AnnounceConfig {
+    pub interval_ms: u32,
+    // Periodic announces are spread by up to +/- this much so neighbours don't sync up
+    pub jitter_ms: u32,
+    // Never announce more often than this, whatever triggers it
+    pub min_gap_ms: u32,
+    // A peer can trigger at most one reactive announce per cooldown
+    pub peer_cooldown_ms: u32,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 30_000,
+            jitter_ms: 5_000,
+            min_gap_ms: 2_000,
+            peer_cooldown_ms: 15_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AnnounceReason {
+    Periodic,
+    Connected,
+    Discovery,
+    NewPeer,
+}
+
+pub struct AnnounceScheduler {
+    config: AnnounceConfig,
+    rng: Rng,
+    next_periodic_ms: u64,
+    last_sent_ms: Option<u64>,
+    reactive: Option<AnnounceReason>,
+    // Last time each peer triggered us; doubles as the set of peers we know
+    peers: FnvIndexMap<PeerId, u64, MAX_TRACKED_PEERS>,
+}
+
+impl AnnounceScheduler {
+    pub fn new(config: AnnounceConfig, rng: Rng) -> Self {
+        Self {
+            config,
+            rng,
+            next_periodic_ms: 0,
+            last_sent_ms: None,
+            reactive: None,
+            peers: FnvIndexMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> AnnounceConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: AnnounceConfig) {
+        self.config = config;
+    }
+
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn on_connected(&mut self) {
+        self.reactive = Some(AnnounceReason::Connected);
+    }
+
+    pub fn on_discovery(&mut self, peer: &[u8], now_ms: u64) {
+        if self.peer_may_trigger(peer, now_ms) {
+            self.reactive.get_or_insert(AnnounceReason::Discovery);
+        }
+    }
+
+    // Only peers we haven't heard before get an immediate reply
+    pub fn on_peer_announce(&mut self, peer: &[u8], now_ms: u64) {
+        let is_new = !self.peers.contains_key(&peer_id(peer));
+        if self.peer_may_trigger(peer, now_ms) && is_new {
+            info!("New peer, scheduling announce");
+            self.reactive.get_or_insert(AnnounceReason::NewPeer);
+        }
+    }
+
+    // Returns why we should announce now, if we should. The caller is
+    // expected to send the announce; the scheduler counts it as sent.
+    pub fn poll(&mut self, now_ms: u64) -> Option<AnnounceReason> {
+        if now_ms < self.earliest_allowed_ms() {
+            return None;
+        }
+
+        let reason = match self.reactive.take() {
+            Some(reason) => reason,
+            None if now_ms >= self.next_periodic_ms => AnnounceReason::Periodic,
+            None => return None,
+        };
+
+        self.last_sent_ms = Some(now_ms);
+        self.schedule_periodic(now_ms);
+        Some(reason)
+    }
+
+    // When poll() will next have something to do
+    pub fn next_deadline(&self) -> u64 {
+        let due = if self.reactive.is_some() { 0 } else { self.next_periodic_ms };
+        due.max(self.earliest_allowed_ms())
+    }
+
+    fn earliest_allowed_ms(&self) -> u64 {
+        self.last_sent_ms.map_or(0, |t| t + self.config.min_gap_ms as u64)
+    }
+
+    fn schedule_periodic(&mut self, now_ms: u64) {
+        let jitter = self.config.jitter_ms.min(self.config.interval_ms);
+        let interval = self.rng.range(self.config.interval_ms - jitter, self.config.interval_ms + jitter);
+        self.next_periodic_ms = now_ms + interval as u64;
+    }
+
+    // Per-peer rate limit so a chatty peer can't keep us announcing
+    fn peer_may_trigger(&mut self, peer: &[u8], now_ms: u64) -> bool {
+        let key = peer_id(peer);
+        if let Some(last) = self.peers.get_mut(&key) {
+            if now_ms.saturating_sub(*last) < self.config.peer_cooldown_ms as u64 {
+                return false;
+            }
+            *last = now_ms;
+            return true;
+        }
+
+        if self.peers.len() >= MAX_TRACKED_PEERS {
+            let oldest = self.peers.iter().min_by_key(|(_, t)| **t).map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                self.peers.remove(&oldest);
+            }
+        }
+        let _ = self.peers.insert(key, now_ms);
+        true
+    }
+}
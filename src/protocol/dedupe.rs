@@ -0,0 +1,221 @@
+use crate::protocol::message_id::MessageId;
+
+// Bits per generation. Two generations are kept, so the filter uses 2 * FILTER_BITS / 8 bytes.
+pub const FILTER_BITS: usize = 4096;
+const FILTER_WORDS: usize = FILTER_BITS / 32;
+const MAX_HASHES: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DedupeConfig {
+    // Acceptable false positives per million lookups within one generation
+    pub false_positives_per_million: u32,
+    // A generation is retired after this long, so IDs are remembered for 1-2 periods
+    pub rotate_after_ms: u64,
+}
+
+impl Default for DedupeConfig {
+    fn default() -> Self {
+        Self {
+            false_positives_per_million: 1000,
+            rotate_after_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Generation {
+    bits: [u32; FILTER_WORDS],
+    items: u16,
+}
+
+impl Generation {
+    const fn new() -> Self {
+        Self { bits: [0; FILTER_WORDS], items: 0 }
+    }
+
+    fn contains(&self, hashes: &Hashes, k: u32) -> bool {
+        (0..k).all(|i| {
+            let bit = hashes.index(i);
+            self.bits[bit / 32] & (1 << (bit % 32)) != 0
+        })
+    }
+
+    fn insert(&mut self, hashes: &Hashes, k: u32) {
+        for i in 0..k {
+            let bit = hashes.index(i);
+            self.bits[bit / 32] |= 1 << (bit % 32);
+        }
+        self.items = self.items.saturating_add(1);
+    }
+}
+
+// Double hashing: the i-th probe is h1 + i * h2
+struct Hashes {
+    h1: u32,
+    h2: u32,
+}
+
+impl Hashes {
+    fn new(id: &MessageId) -> Self {
+        let mixed = splitmix64(u64::from_be_bytes(id.0));
+        Self {
+            h1: mixed as u32,
+            h2: (mixed >> 32) as u32 | 1,
+        }
+    }
+
+    fn index(&self, i: u32) -> usize {
+        (self.h1.wrapping_add(i.wrapping_mul(self.h2)) as usize) % FILTER_BITS
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+// Fixed-memory duplicate filter with two time-rotated Bloom generations.
+// Lookups check both; inserts go to the current one. A generation is also
+// retired early once it holds as many IDs as the false-positive target allows.
+pub struct DuplicateFilter {
+    generations: [Generation; 2],
+    current: usize,
+    hash_count: u32,
+    capacity: u16,
+    rotate_after_ms: u64,
+    rotated_at_ms: u64,
+}
+
+impl DuplicateFilter {
+    pub fn new(config: DedupeConfig) -> Self {
+        // Optimal k is log2(1/p); for that k a generation holds m * ln2 / k items
+        let fp = config.false_positives_per_million.clamp(1, 1_000_000);
+        let hash_count = (u32::BITS - (1_000_000 / fp).leading_zeros()).clamp(1, MAX_HASHES);
+        let capacity = (FILTER_BITS as u32 * 693 / 1000 / hash_count).min(u16::MAX as u32) as u16;
+
+        Self {
+            generations: [Generation::new(), Generation::new()],
+            current: 0,
+            hash_count,
+            capacity,
+            rotate_after_ms: config.rotate_after_ms,
+            rotated_at_ms: 0,
+        }
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        let hashes = Hashes::new(id);
+        self.generations.iter().any(|g| g.contains(&hashes, self.hash_count))
+    }
+
+    pub fn insert(&mut self, id: &MessageId, now_ms: u64) {
+        self.rotate_if_due(now_ms);
+        let hashes = Hashes::new(id);
+        self.generations[self.current].insert(&hashes, self.hash_count);
+    }
+
+    // Returns true if the ID was (probably) already seen, recording it otherwise
+    pub fn check_and_insert(&mut self, id: &MessageId, now_ms: u64) -> bool {
+        self.rotate_if_due(now_ms);
+        if self.contains(id) {
+            return true;
+        }
+        self.insert(id, now_ms);
+        false
+    }
+
+    pub fn clear(&mut self) {
+        self.generations = [Generation::new(), Generation::new()];
+    }
+
+    pub fn capacity(&self) -> u16 {
+        self.capacity
+    }
+
+    fn rotate_if_due(&mut self, now_ms: u64) {
+        let expired = now_ms.saturating_sub(self.rotated_at_ms) >= self.rotate_after_ms;
+        let full = self.generations[self.current].items >= self.capacity;
+        if !expired && !full {
+            return;
+        }
+
+        if full {
+            info!("Dedupe generation full ({} IDs), rotating early", self.capacity);
+        }
+        self.current ^= 1;
+        self.generations[self.current] = Generation::new();
+        self.rotated_at_ms = now_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u32) -> MessageId {
+        let mut bytes = [0u8; 8];
+        bytes[4..].copy_from_slice(&n.to_be_bytes());
+        MessageId(bytes)
+    }
+
+    #[test]
+    fn remembers_what_it_saw() {
+        let mut filter = DuplicateFilter::new(DedupeConfig::default());
+        assert!(!filter.check_and_insert(&id(1), 0));
+        assert!(filter.check_and_insert(&id(1), 10));
+        assert!(filter.contains(&id(1)));
+        assert!(!filter.contains(&id(2)));
+
+        filter.clear();
+        assert!(!filter.contains(&id(1)));
+    }
+
+    #[test]
+    fn ids_are_forgotten_after_two_rotations() {
+        let config = DedupeConfig { rotate_after_ms: 1_000, ..DedupeConfig::default() };
+        let mut filter = DuplicateFilter::new(config);
+        filter.insert(&id(1), 0);
+
+        // Still in the previous generation after one rotation
+        filter.insert(&id(2), 1_000);
+        assert!(filter.contains(&id(1)));
+        filter.insert(&id(3), 2_000);
+        assert!(!filter.contains(&id(1)));
+        assert!(filter.contains(&id(2)));
+    }
+
+    #[test]
+    fn false_positives_stay_near_the_target() {
+        let mut filter = DuplicateFilter::new(DedupeConfig { rotate_after_ms: u64::MAX, ..DedupeConfig::default() });
+        let capacity = filter.capacity() as u32;
+        for n in 0..capacity {
+            filter.insert(&id(n), 0);
+        }
+        let false_positives = (capacity..capacity + 100_000).filter(|n| filter.contains(&id(*n))).count();
+        // Configured for 1000 per million; allow for the filter being full
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn a_full_generation_rotates_early() {
+        let mut filter = DuplicateFilter::new(DedupeConfig { rotate_after_ms: u64::MAX, ..DedupeConfig::default() });
+        let capacity = filter.capacity() as u32;
+        for n in 0..capacity * 2 + 1 {
+            filter.insert(&id(n), 0);
+        }
+        // Two early rotations later the first IDs are gone
+        assert!(!filter.contains(&id(0)));
+        assert!(filter.contains(&id(capacity * 2)));
+    }
+
+    #[test]
+    fn stricter_targets_use_more_hashes_and_hold_fewer_ids() {
+        let loose = DuplicateFilter::new(DedupeConfig { false_positives_per_million: 10_000, ..DedupeConfig::default() });
+        let strict = DuplicateFilter::new(DedupeConfig { false_positives_per_million: 10, ..DedupeConfig::default() });
+        assert!(strict.hash_count > loose.hash_count);
+        assert!(strict.capacity() < loose.capacity());
+    }
+}
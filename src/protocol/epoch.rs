@@ -0,0 +1,243 @@
+use heapless::FnvIndexMap;
+use crate::protocol::peer::{peer_id, PeerId};
+
+const MAX_TRACKED_PEERS: usize = 16;
+// How far an unverified frame may move a sender's epoch on by itself. Our
+// nodes count boots and iOS epochs are the high word of its clock, so either
+// moves on by one or two between frames we hear.
+pub const MAX_EPOCH_JUMP: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EpochCheck {
+    // First time we hear from this sender, or same epoch as before
+    Current,
+    // Sender rebooted since we last heard from it; forget its per-boot state
+    Reset,
+    // Older than what the sender has already shown us, i.e. a replay
+    Stale,
+    // Further ahead than the frame can vouch for: it might be forged to make
+    // the sender's real frames look stale, so it is dropped and the epoch
+    // stays where it was
+    Unconfirmed,
+}
+
+// Epoch widths the tracker can compare. The message header carries 16 bits
+// of the boot epoch and bitchat packets 32, and a counter compared at the
+// wrong width looks stale forever once the narrow one wraps.
+pub trait Epoch: Copy + PartialEq {
+    // True if `self` comes after `other`, allowing for wrap-around
+    fn is_after(self, other: Self) -> bool;
+    // How far `self` is ahead of `other`
+    fn distance(self, other: Self) -> u32;
+    fn as_u32(self) -> u32;
+}
+
+impl Epoch for u16 {
+    fn is_after(self, other: Self) -> bool {
+        (self.wrapping_sub(other) as i16) > 0
+    }
+
+    fn distance(self, other: Self) -> u32 {
+        self.wrapping_sub(other) as u32
+    }
+
+    fn as_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Epoch for u32 {
+    fn is_after(self, other: Self) -> bool {
+        (self.wrapping_sub(other) as i32) > 0
+    }
+
+    fn distance(self, other: Self) -> u32 {
+        self.wrapping_sub(other)
+    }
+
+    fn as_u32(self) -> u32 {
+        self
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Known<E> {
+    epoch: E,
+    // A far jump one unverified frame claimed, waiting for a second
+    claimed: Option<E>,
+}
+
+// Last boot epoch seen from each sender. Epochs are compared with serial
+// arithmetic so the counter may wrap.
+//
+// Anyone can put a sender's ID and a high epoch on a frame, which would make
+// everything the real sender says afterwards stale. So only observe() takes
+// any epoch, for frames authenticated with the mesh key; frames that prove
+// nothing go through observe_unverified(), and check() never moves anything.
+pub struct EpochTracker<E: Epoch = u16> {
+    peers: FnvIndexMap<PeerId, Known<E>, MAX_TRACKED_PEERS>,
+}
+
+impl<E: Epoch> EpochTracker<E> {
+    pub fn new() -> Self {
+        Self {
+            peers: FnvIndexMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, sender_id: &[u8], epoch: E) -> EpochCheck {
+        let check = self.check(sender_id, epoch);
+        match check {
+            EpochCheck::Current if self.epoch_of(sender_id).is_none() => self.insert(sender_id, epoch),
+            EpochCheck::Reset | EpochCheck::Unconfirmed => {
+                self.reset(sender_id, epoch);
+                return EpochCheck::Reset;
+            }
+            _ => {}
+        }
+        check
+    }
+
+    // An epoch up to MAX_EPOCH_JUMP ahead is taken at once. One further ahead
+    // is only taken from the second frame to claim it in a row; a frame at the
+    // old epoch in between shows the sender hasn't rebooted after all.
+    pub fn observe_unverified(&mut self, sender_id: &[u8], epoch: E) -> EpochCheck {
+        let check = self.check(sender_id, epoch);
+        let Some(known) = self.peers.get_mut(&peer_id(sender_id)) else {
+            self.insert(sender_id, epoch);
+            return check;
+        };
+        match check {
+            EpochCheck::Current => known.claimed = None,
+            EpochCheck::Reset => self.reset(sender_id, epoch),
+            EpochCheck::Unconfirmed if known.claimed == Some(epoch) => {
+                self.reset(sender_id, epoch);
+                return EpochCheck::Reset;
+            }
+            EpochCheck::Unconfirmed => {
+                warn!("Epoch {} from peer at {} needs confirming", epoch.as_u32(), known.epoch.as_u32());
+                known.claimed = Some(epoch);
+            }
+            EpochCheck::Stale => {}
+        }
+        check
+    }
+
+    // What observe_unverified() would make of `epoch`, without taking it
+    pub fn check(&self, sender_id: &[u8], epoch: E) -> EpochCheck {
+        let Some(known) = self.peers.get(&peer_id(sender_id)) else {
+            return EpochCheck::Current;
+        };
+        if known.epoch == epoch {
+            EpochCheck::Current
+        } else if !epoch.is_after(known.epoch) {
+            warn!("Stale epoch {} from peer already at {}", epoch.as_u32(), known.epoch.as_u32());
+            EpochCheck::Stale
+        } else if epoch.distance(known.epoch) > MAX_EPOCH_JUMP {
+            EpochCheck::Unconfirmed
+        } else {
+            EpochCheck::Reset
+        }
+    }
+
+    pub fn epoch_of(&self, sender_id: &[u8]) -> Option<E> {
+        self.peers.get(&peer_id(sender_id)).map(|known| known.epoch)
+    }
+
+    fn insert(&mut self, sender_id: &[u8], epoch: E) {
+        if self.peers.len() >= MAX_TRACKED_PEERS {
+            if let Some(evict) = self.peers.keys().next().copied() {
+                self.peers.remove(&evict);
+            }
+        }
+        let _ = self.peers.insert(peer_id(sender_id), Known { epoch, claimed: None });
+    }
+
+    fn reset(&mut self, sender_id: &[u8], epoch: E) {
+        if let Some(known) = self.peers.get_mut(&peer_id(sender_id)) {
+            info!("Peer rebooted: epoch {} -> {}", known.epoch.as_u32(), epoch.as_u32());
+            *known = Known { epoch, claimed: None };
+        }
+    }
+}
+
+impl<E: Epoch> Default for EpochTracker<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    #[test]
+    fn higher_epoch_is_a_reset_and_lower_is_stale() {
+        let mut tracker = EpochTracker::<u16>::new();
+        assert_eq!(tracker.observe(&PEER, 5), EpochCheck::Current);
+        assert_eq!(tracker.observe(&PEER, 5), EpochCheck::Current);
+        assert_eq!(tracker.observe(&PEER, 6), EpochCheck::Reset);
+        assert_eq!(tracker.observe(&PEER, 5), EpochCheck::Stale);
+        assert_eq!(tracker.epoch_of(&PEER), Some(6));
+    }
+
+    #[test]
+    fn u16_epochs_survive_the_wrap() {
+        let mut tracker = EpochTracker::<u16>::new();
+        tracker.observe(&PEER, u16::MAX);
+        assert_eq!(tracker.observe(&PEER, 0), EpochCheck::Reset);
+        assert_eq!(tracker.observe(&PEER, 1), EpochCheck::Reset);
+        assert_eq!(tracker.observe(&PEER, u16::MAX), EpochCheck::Stale);
+    }
+
+    #[test]
+    fn u32_epochs_survive_the_wrap() {
+        let mut tracker = EpochTracker::<u32>::new();
+        tracker.observe(&PEER, u32::MAX);
+        assert_eq!(tracker.observe(&PEER, 1), EpochCheck::Reset);
+        assert_eq!(tracker.observe(&PEER, u32::MAX), EpochCheck::Stale);
+    }
+
+    #[test]
+    fn authenticated_frames_may_jump_ahead() {
+        let mut tracker = EpochTracker::<u16>::new();
+        tracker.observe(&PEER, 5);
+        assert_eq!(tracker.observe(&PEER, 5000), EpochCheck::Reset);
+        assert_eq!(tracker.epoch_of(&PEER), Some(5000));
+    }
+
+    #[test]
+    fn one_spoofed_frame_cannot_silence_a_peer() {
+        let mut tracker = EpochTracker::<u16>::new();
+        tracker.observe_unverified(&PEER, 5);
+        assert_eq!(tracker.observe_unverified(&PEER, u16::MAX / 2), EpochCheck::Unconfirmed);
+        assert_eq!(tracker.observe_unverified(&PEER, 5), EpochCheck::Current);
+        // The real sender spoke in between, so the claim starts over
+        assert_eq!(tracker.observe_unverified(&PEER, u16::MAX / 2), EpochCheck::Unconfirmed);
+        assert_eq!(tracker.epoch_of(&PEER), Some(5));
+    }
+
+    #[test]
+    fn far_jumps_need_a_second_frame() {
+        let mut tracker = EpochTracker::<u32>::new();
+        tracker.observe_unverified(&PEER, 5);
+        assert_eq!(tracker.observe_unverified(&PEER, 5 + MAX_EPOCH_JUMP), EpochCheck::Reset);
+        assert_eq!(tracker.observe_unverified(&PEER, 1000), EpochCheck::Unconfirmed);
+        assert_eq!(tracker.observe_unverified(&PEER, 1000), EpochCheck::Reset);
+        assert_eq!(tracker.observe_unverified(&PEER, 5), EpochCheck::Stale);
+    }
+
+    #[test]
+    fn check_moves_nothing() {
+        let mut tracker = EpochTracker::<u16>::new();
+        assert_eq!(tracker.check(&PEER, 9), EpochCheck::Current);
+        assert_eq!(tracker.epoch_of(&PEER), None);
+        tracker.observe(&PEER, 5);
+        assert_eq!(tracker.check(&PEER, 6), EpochCheck::Reset);
+        assert_eq!(tracker.check(&PEER, 4), EpochCheck::Stale);
+        assert_eq!(tracker.epoch_of(&PEER), Some(5));
+    }
+}
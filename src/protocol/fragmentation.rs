@@ -1,11 +1,37 @@
-use defmt::{info, warn, Format};
 use heapless::{FnvIndexMap, Vec};
-use crate::protocol::message::{Message, MessageHeader};
+use crate::protocol::integrity::{seal_crc, AuthStatus, TAG_SIZE};
+use crate::protocol::message::{
+    Message, MessageHeader, FLAG_AUTHENTICATED, FLAG_RELAYED, MAX_FRAGMENT_SIZE, MAX_MESSAGE_SIZE, MAX_PAYLOAD_SIZE,
+};
 
 const MAX_CONCURRENT_MESSAGES: usize = 4;
-const MAX_FRAGMENTS_PER_MESSAGE: usize = 8;
+pub const MAX_FRAGMENTS_PER_MESSAGE: usize = 8;
+const MAX_BUFFERS_PER_SENDER: usize = 2; // One sender can't hold every reassembly slot
+const MAX_BUFFERED_BYTES: usize = 2048; // Total payload bytes held across all buffers
+// Payload bytes one sender may hold, so it can't starve the others of the budget
+const SENDER_BYTE_SHARE: usize = MAX_BUFFERED_BYTES / MAX_BUFFERS_PER_SENDER;
+// Outlasts a sender's full retry schedule, so retransmissions can still fill the gaps
+pub const FRAGMENT_TIMEOUT_MS: u64 = 30_000;
 
-#[derive(Debug, Format, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FragmentError {
+    IndexOutOfBounds,
+    TooManyFragments,
+    FragmentTooLarge,
+    TotalMismatch,
+    TypeMismatch,
+    TtlMismatch,
+    AuthMismatch,
+    SenderQuotaExceeded,
+    ByteBudgetExceeded,
+    // The fragments add up to more than MAX_MESSAGE_SIZE
+    Overflow,
+    Incomplete,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FragmentKey {
     sender_id: [u8; 6],
     sequence: u16,
@@ -32,32 +58,133 @@ impl core::hash::Hash for FragmentKey {
     }
 }
 
+// How a message was split when it arrived: each fragment's payload length and
+// mesh-key tag. A relay rebuilds the sender's frames from this instead of
+// fragmenting again, which would need the key to re-tag them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FragmentLayout {
+    fragments: Vec<(u8, Option<[u8; TAG_SIZE]>), MAX_FRAGMENTS_PER_MESSAGE>,
+}
+
+impl FragmentLayout {
+    pub fn single(payload_len: usize, tag: Option<[u8; TAG_SIZE]>) -> Self {
+        let mut fragments = Vec::new();
+        let _ = fragments.push((payload_len.min(u8::MAX as usize) as u8, tag));
+        Self { fragments }
+    }
+
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    // The frames `message` arrived in, byte for byte, except for one hop
+    // less TTL, FLAG_RELAYED and the checksum. Tags don't cover those three,
+    // so they still verify.
+    pub fn relay_frames<'a>(&'a self, message: &'a Message) -> impl Iterator<Item = Vec<u8, MAX_FRAGMENT_SIZE>> + 'a {
+        let mut header = message.header;
+        header.ttl = header.ttl.saturating_sub(1);
+        header.flags |= FLAG_RELAYED;
+        header.total_fragments = self.fragments.len() as u8;
+        header.checksum = 0;
+
+        let mut offset = 0;
+        self.fragments.iter().enumerate().filter_map(move |(index, (len, tag))| {
+            let chunk = message.payload.get(offset..offset + *len as usize)?;
+            offset += *len as usize;
+
+            header.fragment_index = index as u8;
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&header.serialize()).ok()?;
+            frame.extend_from_slice(chunk).ok()?;
+            if let Some(tag) = tag {
+                frame.extend_from_slice(tag).ok()?;
+            }
+            seal_crc(&mut frame);
+            Some(frame)
+        })
+    }
+}
+
 struct FragmentBuffer {
     header: MessageHeader,
-    fragments: Vec<Option<Vec<u8, 228>>, MAX_FRAGMENTS_PER_MESSAGE>,
+    fragments: Vec<Option<Vec<u8, MAX_PAYLOAD_SIZE>>, MAX_FRAGMENTS_PER_MESSAGE>,
+    tags: [Option<[u8; TAG_SIZE]>; MAX_FRAGMENTS_PER_MESSAGE],
     received_count: u8,
     total_expected: u8,
+    buffered_bytes: usize,
+    opened_at: u32,
+    opened_ms: u64,
 }
 
 impl FragmentBuffer {
-    fn new(header: MessageHeader) -> Self {
+    fn new(header: MessageHeader, opened_at: u32, opened_ms: u64) -> Result<Self, FragmentError> {
+        if header.total_fragments == 0 || header.total_fragments as usize > MAX_FRAGMENTS_PER_MESSAGE {
+            warn!("Refusing message with {} fragments (max {})",
+                header.total_fragments, MAX_FRAGMENTS_PER_MESSAGE);
+            return Err(FragmentError::TooManyFragments);
+        }
+
         let mut fragments = Vec::new();
         for _ in 0..MAX_FRAGMENTS_PER_MESSAGE {
             let _ = fragments.push(None);
         }
 
-        Self {
+        Ok(Self {
             header,
             fragments,
+            tags: [None; MAX_FRAGMENTS_PER_MESSAGE],
             received_count: 0,
             total_expected: header.total_fragments,
+            buffered_bytes: 0,
+            opened_at,
+            opened_ms,
+        })
+    }
+
+    // Every fragment of a message must agree with the first one we saw
+    fn check_consistent(&self, header: &MessageHeader) -> Result<(), FragmentError> {
+        if header.total_fragments != self.total_expected {
+            warn!("Fragment claims {} total, expected {}", header.total_fragments, self.total_expected);
+            return Err(FragmentError::TotalMismatch);
+        }
+        if header.msg_type != self.header.msg_type {
+            warn!("Fragment type {:?} does not match {:?}", header.msg_type, self.header.msg_type);
+            return Err(FragmentError::TypeMismatch);
+        }
+        if header.ttl != self.header.ttl {
+            warn!("Fragment TTL {} does not match {}", header.ttl, self.header.ttl);
+            return Err(FragmentError::TtlMismatch);
         }
+        if (header.flags ^ self.header.flags) & FLAG_AUTHENTICATED != 0 {
+            warn!("Fragment authentication flag does not match first fragment");
+            return Err(FragmentError::AuthMismatch);
+        }
+        Ok(())
+    }
+
+    fn has_fragment(&self, index: u8) -> bool {
+        self.fragments.get(index as usize).is_some_and(Option::is_some)
     }
 
-    fn add_fragment(&mut self, index: u8, data: &[u8]) -> Result<bool, ()> {
+    // Refuses a fragment that would take the message past MAX_MESSAGE_SIZE.
+    // Every fragment but the last is full size, so `end` can only fall short.
+    fn check_size(&self, index: u8, len: usize) -> Result<(), FragmentError> {
+        let end = (index as usize + 1) * len;
+        if end > MAX_MESSAGE_SIZE || self.buffered_bytes + len > MAX_MESSAGE_SIZE {
+            warn!("Fragment {} of {} bytes would exceed {} bytes", index, len, MAX_MESSAGE_SIZE);
+            return Err(FragmentError::Overflow);
+        }
+        Ok(())
+    }
+
+    fn add_fragment(&mut self, index: u8, data: &[u8], tag: Option<[u8; TAG_SIZE]>) -> Result<bool, FragmentError> {
         if index >= self.total_expected || index >= MAX_FRAGMENTS_PER_MESSAGE as u8 {
             warn!("Fragment index {} out of bounds (expected {})", index, self.total_expected);
-            return Err(());
+            return Err(FragmentError::IndexOutOfBounds);
         }
 
         // Check if we already have this fragment
@@ -68,9 +195,11 @@ impl FragmentBuffer {
 
         // Store the fragment
         let mut fragment_data = Vec::new();
-        fragment_data.extend_from_slice(data).map_err(|_| ())?;
+        fragment_data.extend_from_slice(data).map_err(|_| FragmentError::FragmentTooLarge)?;
         self.fragments[index as usize] = Some(fragment_data);
+        self.tags[index as usize] = tag;
         self.received_count += 1;
+        self.buffered_bytes += data.len();
 
         info!("Stored fragment {}/{} for message seq {}",
             self.received_count, self.total_expected, self.header.sequence);
@@ -79,21 +208,24 @@ impl FragmentBuffer {
         Ok(self.received_count == self.total_expected)
     }
 
-    fn assemble(self) -> Result<Message, ()> {
+    fn assemble(self) -> Result<Message, FragmentError> {
         if self.received_count != self.total_expected {
-            return Err(());
+            return Err(FragmentError::Incomplete);
         }
 
         let mut payload = Vec::new();
+        let mut layout = FragmentLayout::default();
 
         for i in 0..self.total_expected as usize {
             if let Some(fragment) = &self.fragments[i] {
                 payload.extend_from_slice(fragment).map_err(|_| {
                     warn!("Failed to assemble: payload too large");
+                    FragmentError::Overflow
                 })?;
+                let _ = layout.fragments.push((fragment.len() as u8, self.tags[i]));
             } else {
                 warn!("Missing fragment {} during assembly", i);
-                return Err(());
+                return Err(FragmentError::Incomplete);
             }
         }
 
@@ -103,47 +235,101 @@ impl FragmentBuffer {
         Ok(Message {
             header: self.header,
             payload,
+            auth: AuthStatus::Unauthenticated,
+            layout,
         })
     }
 }
 
 pub struct FragmentAssembler {
     buffers: FnvIndexMap<FragmentKey, FragmentBuffer, MAX_CONCURRENT_MESSAGES>,
+    opened_count: u32,
+    // Time of the last expire() call; new buffers are stamped with it
+    now_ms: u64,
+}
+
+impl Default for FragmentAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FragmentAssembler {
     pub fn new() -> Self {
         Self {
             buffers: FnvIndexMap::new(),
+            opened_count: 0,
+            now_ms: 0,
+        }
+    }
+
+    // Drops incomplete messages older than FRAGMENT_TIMEOUT_MS and returns
+    // how many. Only needs calling when a fragment arrives: a stale buffer
+    // costs nothing until something new wants its slot.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        self.now_ms = now_ms;
+        let mut expired = 0;
+        while let Some(key) = self.buffers.iter()
+            .find(|(_, b)| now_ms.saturating_sub(b.opened_ms) >= FRAGMENT_TIMEOUT_MS)
+            .map(|(k, _)| k.clone())
+        {
+            warn!("Incomplete message seq {} timed out", key.sequence);
+            self.buffers.remove(&key);
+            expired += 1;
         }
+        expired
     }
 
-    pub fn add_fragment(&mut self, header: MessageHeader, payload: &[u8]) -> Result<Option<Message>, ()> {
+    // `tag` is the fragment's mesh-key tag, kept in the message's layout so it
+    // can be relayed as it arrived
+    pub fn add_fragment(
+        &mut self,
+        header: MessageHeader,
+        payload: &[u8],
+        tag: Option<[u8; TAG_SIZE]>,
+    ) -> Result<Option<Message>, FragmentError> {
         let key = FragmentKey::new(header.sender_id, header.sequence);
 
-        // Get or create buffer for this message
-        if !self.buffers.contains_key(&key) {
+        if let Some(buffer) = self.buffers.get(&key) {
+            buffer.check_consistent(&header)?;
+        } else {
+            let buffer = FragmentBuffer::new(header, self.opened_count, self.now_ms)?;
+            self.opened_count = self.opened_count.wrapping_add(1);
+
+            // A sender at its quota only ever displaces its own oldest buffer
+            if self.sender_buffer_count(&header.sender_id) >= MAX_BUFFERS_PER_SENDER {
+                warn!("Sender at reassembly quota, dropping its oldest incomplete message");
+                self.evict_oldest(Some(&header.sender_id));
+            }
+
             // Make room if necessary
             if self.buffers.len() >= MAX_CONCURRENT_MESSAGES {
-                // Remove oldest (first) entry
-                if let Some((old_key, _)) = self.buffers.iter().next() {
-                    let old_key = old_key.clone();
-                    self.buffers.remove(&old_key);
-                    warn!("Dropped incomplete message to make room");
-                }
+                warn!("Dropped incomplete message to make room");
+                self.evict_oldest(None);
             }
 
-            let buffer = FragmentBuffer::new(header);
-            self.buffers.insert(key.clone(), buffer).map_err(|_| ())?;
+            self.buffers.insert(key.clone(), buffer)
+                .map_err(|_| FragmentError::SenderQuotaExceeded)?;
+        }
+
+        if let Err(e) = self.check_budget(&key, header.fragment_index, payload.len()) {
+            self.discard_if_empty(&key);
+            return Err(e);
         }
 
         // Add fragment to buffer
-        let buffer = self.buffers.get_mut(&key).ok_or(())?;
-        let is_complete = buffer.add_fragment(header.fragment_index, payload)?;
+        let buffer = self.buffers.get_mut(&key).ok_or(FragmentError::Incomplete)?;
+        let is_complete = match buffer.add_fragment(header.fragment_index, payload, tag) {
+            Ok(is_complete) => is_complete,
+            Err(e) => {
+                self.discard_if_empty(&key);
+                return Err(e);
+            }
+        };
 
         if is_complete {
             // Remove and assemble
-            let buffer = self.buffers.remove(&key).ok_or(())?;
+            let buffer = self.buffers.remove(&key).ok_or(FragmentError::Incomplete)?;
             buffer.assemble().map(Some)
         } else {
             Ok(None)
@@ -154,7 +340,263 @@ impl FragmentAssembler {
         self.buffers.len()
     }
 
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffers.values().map(|b| b.buffered_bytes).sum()
+    }
+
     pub fn clear(&mut self) {
         self.buffers.clear();
     }
-}
\ No newline at end of file
+
+    // Buffers from a sender's previous boot will never complete
+    pub fn discard_sender(&mut self, sender_id: &[u8; 6]) {
+        while let Some(key) = self.buffers.keys().find(|k| k.sender_id == *sender_id).cloned() {
+            self.buffers.remove(&key);
+        }
+    }
+
+    // Duplicates take no room; the buffer ignores them
+    fn check_budget(&self, key: &FragmentKey, index: u8, len: usize) -> Result<(), FragmentError> {
+        let Some(buffer) = self.buffers.get(key) else {
+            return Ok(());
+        };
+        if buffer.has_fragment(index) {
+            return Ok(());
+        }
+        buffer.check_size(index, len)?;
+
+        if self.buffered_bytes() + len > MAX_BUFFERED_BYTES {
+            warn!("Fragment of {} bytes exceeds reassembly budget ({} in use)", len, self.buffered_bytes());
+            return Err(FragmentError::ByteBudgetExceeded);
+        }
+        let sender_bytes = self.sender_buffered_bytes(&key.sender_id);
+        if sender_bytes + len > SENDER_BYTE_SHARE {
+            warn!("Sender holds {} bytes of reassembly, refusing {} more", sender_bytes, len);
+            return Err(FragmentError::SenderQuotaExceeded);
+        }
+        Ok(())
+    }
+
+    fn sender_buffer_count(&self, sender_id: &[u8; 6]) -> usize {
+        self.buffers.keys().filter(|k| k.sender_id == *sender_id).count()
+    }
+
+    fn sender_buffered_bytes(&self, sender_id: &[u8; 6]) -> usize {
+        self.buffers.iter()
+            .filter(|(k, _)| k.sender_id == *sender_id)
+            .map(|(_, b)| b.buffered_bytes)
+            .sum()
+    }
+
+    // IndexMap::remove swaps entries around, so age is tracked explicitly
+    fn evict_oldest(&mut self, sender_id: Option<&[u8; 6]>) {
+        let oldest = self.buffers.iter()
+            .filter(|(k, _)| sender_id.is_none_or(|s| k.sender_id == *s))
+            .max_by_key(|(_, b)| self.opened_count.wrapping_sub(b.opened_at))
+            .map(|(k, _)| k.clone());
+        if let Some(key) = oldest {
+            self.buffers.remove(&key);
+        }
+    }
+
+    // Don't let a rejected first fragment leave an empty buffer holding a slot
+    fn discard_if_empty(&mut self, key: &FragmentKey) {
+        if self.buffers.get(key).is_some_and(|b| b.received_count == 0) {
+            self.buffers.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::integrity::{self, MeshKey};
+    use crate::protocol::message::{MessageType, HEADER_SIZE};
+    use std::vec::Vec;
+
+    const SENDER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn message(sequence: u16, len: usize) -> Message {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        Message::new(MessageType::Text, SENDER, sequence, &payload).unwrap()
+    }
+
+    // Header, payload and tag of every frame `message` is sent as
+    fn split(message: &Message, mtu: u16, key: Option<&MeshKey>) -> Vec<(MessageHeader, Vec<u8>, Option<[u8; TAG_SIZE]>)> {
+        message.fragments_with_key(mtu, key).unwrap().map(|frame| {
+            let header = MessageHeader::deserialize(&frame).unwrap();
+            let (_, body_len) = integrity::verify(&frame, key).unwrap();
+            let tag = frame[body_len..].try_into().ok();
+            (header, frame[HEADER_SIZE..body_len].to_vec(), tag)
+        }).collect()
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let sent = message(1, 100);
+        let mut fragments = split(&sent, 50, None);
+        assert_eq!(fragments.len(), 4);
+        fragments.reverse();
+
+        let mut assembler = FragmentAssembler::new();
+        let last = fragments.pop().unwrap();
+        for (header, payload, tag) in &fragments {
+            assert!(assembler.add_fragment(*header, payload, *tag).unwrap().is_none());
+        }
+        assert_eq!(assembler.pending_count(), 1);
+        let message = assembler.add_fragment(last.0, &last.1, last.2).unwrap().unwrap();
+        assert_eq!(message.payload, sent.payload);
+        assert_eq!(message.layout.len(), 4);
+        assert_eq!(assembler.pending_count(), 0);
+        assert_eq!(assembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn repeated_fragments_are_ignored() {
+        let fragments = split(&message(1, 100), 50, None);
+        let mut assembler = FragmentAssembler::new();
+        let (header, payload, _) = &fragments[0];
+        assert!(assembler.add_fragment(*header, payload, None).unwrap().is_none());
+        assert!(assembler.add_fragment(*header, payload, None).unwrap().is_none());
+        assert_eq!(assembler.buffered_bytes(), payload.len());
+    }
+
+    #[test]
+    fn inconsistent_fragments_are_refused() {
+        let fragments = split(&message(1, 100), 50, None);
+        let mut assembler = FragmentAssembler::new();
+        let (header, payload, _) = &fragments[0];
+        assembler.add_fragment(*header, payload, None).unwrap();
+
+        let (mut other, payload, _) = fragments[1].clone();
+        other.total_fragments += 1;
+        assert_eq!(assembler.add_fragment(other, &payload, None).err(), Some(FragmentError::TotalMismatch));
+        let (mut other, payload, _) = fragments[1].clone();
+        other.ttl -= 1;
+        assert_eq!(assembler.add_fragment(other, &payload, None).err(), Some(FragmentError::TtlMismatch));
+        let (mut other, payload, _) = fragments[1].clone();
+        other.fragment_index = other.total_fragments;
+        assert_eq!(assembler.add_fragment(other, &payload, None).err(), Some(FragmentError::IndexOutOfBounds));
+
+        let mut huge = *header;
+        huge.sequence = 2;
+        huge.total_fragments = MAX_FRAGMENTS_PER_MESSAGE as u8 + 1;
+        assert_eq!(assembler.add_fragment(huge, &payload, None).err(), Some(FragmentError::TooManyFragments));
+    }
+
+    #[test]
+    fn one_sender_cannot_take_every_slot() {
+        let mut assembler = FragmentAssembler::new();
+        for sequence in 0..MAX_CONCURRENT_MESSAGES as u16 {
+            let (header, payload, _) = split(&message(sequence, 100), 50, None).remove(0);
+            assembler.add_fragment(header, &payload, None).unwrap();
+            assert!(assembler.pending_count() <= MAX_BUFFERS_PER_SENDER);
+        }
+
+        let mut other = message(0, 100);
+        other.header.sender_id = [9; 6];
+        let (header, payload, _) = split(&other, 50, None).remove(0);
+        assembler.add_fragment(header, &payload, None).unwrap();
+        assert_eq!(assembler.pending_count(), MAX_BUFFERS_PER_SENDER + 1);
+    }
+
+    #[test]
+    fn one_sender_cannot_take_the_byte_budget() {
+        let mut assembler = FragmentAssembler::new();
+        let mut refused = 0;
+        // Every fragment but the last, so nothing completes
+        for sequence in 0..8 {
+            let fragments = split(&message(sequence, MAX_MESSAGE_SIZE), 247, None);
+            for (header, payload, tag) in &fragments[..fragments.len() - 1] {
+                if assembler.add_fragment(*header, payload, *tag).is_err() {
+                    refused += 1;
+                }
+            }
+        }
+        assert!(refused > 0);
+        assert!(assembler.buffered_bytes() <= SENDER_BYTE_SHARE);
+
+        let mut other = message(0, MAX_MESSAGE_SIZE);
+        other.header.sender_id = [9; 6];
+        let mut complete = None;
+        for (header, payload, tag) in split(&other, 247, None) {
+            complete = assembler.add_fragment(header, &payload, tag).unwrap();
+        }
+        assert_eq!(complete.unwrap().payload, other.payload);
+    }
+
+    #[test]
+    fn fragments_past_the_message_size_are_refused() {
+        let fragments = split(&message(1, MAX_MESSAGE_SIZE), 247, None);
+        let mut assembler = FragmentAssembler::new();
+
+        // A full-size fragment this far in would end past MAX_MESSAGE_SIZE
+        let (mut header, payload, _) = fragments[0].clone();
+        header.fragment_index = (MAX_MESSAGE_SIZE / payload.len()) as u8;
+        assert_eq!(assembler.add_fragment(header, &payload, None).err(), Some(FragmentError::Overflow));
+        assert_eq!(assembler.pending_count(), 0);
+
+        // Short fragments pass that check, but not the running total
+        let (last_header, last_payload, _) = fragments.last().unwrap().clone();
+        let mut result = Ok(None);
+        for index in 0..MAX_FRAGMENTS_PER_MESSAGE {
+            let (mut header, payload) = match fragments.get(index) {
+                Some((header, payload, _)) if index < fragments.len() - 1 => (*header, payload.clone()),
+                _ => (last_header, last_payload.clone()),
+            };
+            header.fragment_index = index as u8;
+            header.total_fragments = MAX_FRAGMENTS_PER_MESSAGE as u8;
+            result = assembler.add_fragment(header, &payload, None);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result.err(), Some(FragmentError::Overflow));
+        assert!(assembler.buffered_bytes() <= MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn incomplete_messages_time_out() {
+        let mut assembler = FragmentAssembler::new();
+        let (header, payload, _) = split(&message(1, 100), 50, None).remove(0);
+        assembler.expire(1_000);
+        assembler.add_fragment(header, &payload, None).unwrap();
+
+        assert_eq!(assembler.expire(1_000 + FRAGMENT_TIMEOUT_MS - 1), 0);
+        assert_eq!(assembler.expire(1_000 + FRAGMENT_TIMEOUT_MS), 1);
+        assert_eq!(assembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn a_reboot_discards_the_senders_buffers() {
+        let mut assembler = FragmentAssembler::new();
+        let (header, payload, _) = split(&message(1, 100), 50, None).remove(0);
+        assembler.add_fragment(header, &payload, None).unwrap();
+        assembler.discard_sender(&SENDER);
+        assert_eq!(assembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn layouts_rebuild_the_frames_for_relaying() {
+        let key = MeshKey::new([3; 32]);
+        let sent = message(1, 100);
+        let frames: Vec<_> = sent.fragments_with_key(50, Some(&key)).unwrap().collect();
+
+        let mut assembler = FragmentAssembler::new();
+        let mut complete = None;
+        for (header, payload, tag) in split(&sent, 50, Some(&key)) {
+            complete = assembler.add_fragment(header, &payload, tag).unwrap();
+        }
+        let message = complete.unwrap();
+
+        let relayed: Vec<_> = message.layout.relay_frames(&message).collect();
+        assert_eq!(relayed.len(), frames.len());
+        for (frame, relayed) in frames.iter().zip(&relayed) {
+            let header = MessageHeader::deserialize(relayed).unwrap();
+            assert_eq!(header.ttl, message.header.ttl - 1);
+            assert_ne!(header.flags & FLAG_RELAYED, 0);
+            assert_eq!(frame[HEADER_SIZE..], relayed[HEADER_SIZE..]);
+            assert!(integrity::verify(relayed, Some(&key)).is_ok());
+        }
+    }
+}
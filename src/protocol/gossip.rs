@@ -0,0 +1,157 @@
+use crate::protocol::rng::Rng;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GossipConfig {
+    // With this many neighbours or fewer every frame is forwarded
+    pub flood_below_neighbors: u8,
+    // In denser neighbourhoods forward with probability target_fanout / neighbours,
+    // so roughly this many neighbours rebroadcast each frame
+    pub target_fanout: u8,
+    // Floor on the forward probability, in per-mille
+    pub min_forward_per_mille: u16,
+    // The wait window grows with density so more duplicates can be overheard
+    pub base_delay_ms: u32,
+    pub delay_per_neighbor_ms: u32,
+    pub max_delay_ms: u32,
+    // Cancel a pending relay after overhearing this many copies in its window
+    pub suppress_after_duplicates: u8,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            flood_below_neighbors: 2,
+            target_fanout: 2,
+            min_forward_per_mille: 250,
+            base_delay_ms: 10,
+            delay_per_neighbor_ms: 30,
+            max_delay_ms: 500,
+            suppress_after_duplicates: 2,
+        }
+    }
+}
+
+// Probabilistic, counter-suppressed flooding. Pure logic: the neighbour count
+// and the RNG are supplied by the caller.
+pub struct GossipPolicy {
+    config: GossipConfig,
+}
+
+impl GossipPolicy {
+    pub fn new(config: GossipConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    pub fn forward_per_mille(&self, neighbors: u8) -> u16 {
+        if neighbors <= self.config.flood_below_neighbors {
+            return 1000;
+        }
+        // Clamped before narrowing, a large fanout would otherwise wrap
+        let p = (1000 * self.config.target_fanout as u32 / neighbors as u32).min(1000) as u16;
+        p.max(self.config.min_forward_per_mille.min(1000))
+    }
+
+    // Upper bound of the random relay delay for this density
+    pub fn window_ms(&self, neighbors: u8) -> u32 {
+        let window = self.config.base_delay_ms
+            .saturating_add(self.config.delay_per_neighbor_ms.saturating_mul(neighbors as u32));
+        window.min(self.config.max_delay_ms).max(self.config.base_delay_ms)
+    }
+
+    // Relay delay for a fresh frame, or None if this node shouldn't forward it
+    pub fn decide(&self, neighbors: u8, rng: &mut Rng) -> Option<u32> {
+        if !rng.chance(self.forward_per_mille(neighbors)) {
+            return None;
+        }
+        Some(rng.range(self.config.base_delay_ms, self.window_ms(neighbors)))
+    }
+
+    pub fn suppressed(&self, duplicates_heard: u8) -> bool {
+        self.config.suppress_after_duplicates > 0 && duplicates_heard >= self.config.suppress_after_duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIALS: u32 = 10_000;
+
+    // How many of TRIALS decisions forward, and the delays they chose
+    fn sample(policy: &GossipPolicy, neighbors: u8, seed: u64) -> (u32, std::vec::Vec<u32>) {
+        let mut rng = Rng::new(seed);
+        let delays: std::vec::Vec<u32> = (0..TRIALS).filter_map(|_| policy.decide(neighbors, &mut rng)).collect();
+        (delays.len() as u32, delays)
+    }
+
+    #[test]
+    fn sparse_neighbourhoods_flood() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        for neighbors in 0..=2 {
+            assert_eq!(policy.forward_per_mille(neighbors), 1000);
+            assert_eq!(sample(&policy, neighbors, 7).0, TRIALS);
+        }
+    }
+
+    #[test]
+    fn forward_probability_follows_the_fanout() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        assert_eq!(policy.forward_per_mille(4), 500);
+        assert_eq!(policy.forward_per_mille(5), 400);
+        // Floored at min_forward_per_mille
+        assert_eq!(policy.forward_per_mille(20), 250);
+    }
+
+    #[test]
+    fn large_fanout_does_not_wrap() {
+        // 1000 * 199 / 3 is past u16::MAX and used to wrap to 797
+        let config = GossipConfig { target_fanout: 199, ..GossipConfig::default() };
+        let policy = GossipPolicy::new(config);
+        assert_eq!(policy.forward_per_mille(3), 1000);
+        for neighbors in 3..=u8::MAX {
+            assert!(policy.forward_per_mille(neighbors) <= 1000);
+        }
+    }
+
+    #[test]
+    fn seeded_decisions_match_the_probability() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        for (neighbors, per_mille) in [(4u8, 500u32), (8, 250)] {
+            let (forwarded, _) = sample(&policy, neighbors, 0xC0FFEE);
+            let expected = TRIALS * per_mille / 1000;
+            assert!(forwarded.abs_diff(expected) < TRIALS / 50, "{} neighbours: {}", neighbors, forwarded);
+        }
+    }
+
+    #[test]
+    fn delays_stay_in_the_window() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        for neighbors in [0u8, 3, 10, 100] {
+            let window = policy.window_ms(neighbors);
+            let (_, delays) = sample(&policy, neighbors, 99);
+            assert!(delays.iter().all(|d| (10..=window).contains(d)));
+            assert!(window <= 500);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_decisions() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        assert_eq!(sample(&policy, 6, 1234), sample(&policy, 6, 1234));
+        assert_ne!(sample(&policy, 6, 1234), sample(&policy, 6, 4321));
+    }
+
+    #[test]
+    fn suppression_counts_duplicates() {
+        let policy = GossipPolicy::new(GossipConfig::default());
+        assert!(!policy.suppressed(1));
+        assert!(policy.suppressed(2));
+        let never = GossipPolicy::new(GossipConfig { suppress_after_duplicates: 0, ..GossipConfig::default() });
+        assert!(!never.suppressed(u8::MAX));
+    }
+}
@@ -1,40 +1,248 @@
-use defmt::{info, warn, Format};
-use heapless::Vec;
-use crate::protocol::message::{Message, MessageHeader, MessageType, HEADER_SIZE, MAX_MESSAGE_SIZE};
-use crate::protocol::fragmentation::FragmentAssembler;
+use heapless::{Deque, String, Vec};
+use crate::bitchat::announce::MAX_NICKNAME_LEN;
+use crate::config::{DEFAULT_TTL, DEVICE_NAME, MAX_MTU_SIZE};
+use crate::protocol::message::{Message, MessageError, MessageHeader, MessageType, FLAG_ACK_REQUESTED, HEADER_SIZE, MAX_FRAGMENT_SIZE};
+use crate::protocol::dedupe::{DedupeConfig, DuplicateFilter};
+use crate::protocol::epoch::{EpochCheck, EpochTracker};
+use crate::protocol::integrity::{self, AuthStatus, IntegrityError, MeshKey, TAG_SIZE};
+use crate::protocol::message::Fragments;
+use crate::protocol::message_id::MessageId;
+use crate::protocol::reliability::{DeliveryEvent, ReliabilityError, ReliableSender, RetryPolicy};
+use crate::protocol::rng::Rng;
+use crate::protocol::announce::{AnnounceConfig, AnnounceScheduler};
+use crate::protocol::action::{Action, Actions, DropReason, PeerUpdate, MAX_ACTIONS};
+use crate::protocol::peer::{peer_id, PeerId};
+use crate::protocol::relay::LinkId;
+use crate::protocol::router::MessageRouter;
+use crate::protocol::fragmentation::{FragmentAssembler, FragmentError, FragmentLayout};
 use crate::protocol::text::TextMessage;
+use crate::protocol::stream::{FragmentSink, NullSink, StreamAssembler, StreamError, StreamInfo};
 
-#[derive(Debug, Format)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum HandlerError {
     InvalidMessage,
-    FragmentationError,
+    FragmentationError(FragmentError),
     ChecksumError,
+    AuthenticationError,
+    StreamError(StreamError),
+    StaleEpoch,
+    ReliabilityError(ReliabilityError),
 }
 
+const MAX_QUEUED_ACKS: usize = 8;
+
 pub struct MessageHandler {
     device_id: [u8; 6],
+    boot_epoch: u16,
     sequence_counter: u16,
     fragment_assembler: FragmentAssembler,
-    last_seen_sequences: Vec<(u16, [u8; 6]), 32>, // Track last 32 messages to prevent duplicates
+    stream_assembler: StreamAssembler,
+    seen_messages: DuplicateFilter,
+    peer_epochs: EpochTracker,
+    mesh_key: Option<MeshKey>,
+    // Take untagged frames even with a key, e.g. while one is rolled out
+    accept_untagged: bool,
+    reliability: ReliableSender,
+    // Sender's device ID and the message it asked us to ACK
+    acks_due: Deque<([u8; 6], MessageId), MAX_QUEUED_ACKS>,
+    announcer: AnnounceScheduler,
+    router: MessageRouter,
+    rng: Rng,
+    // Name and TTL for the announces and ACKs we originate
+    device_name: String<MAX_NICKNAME_LEN>,
+    default_ttl: u8,
+    // Counted here since process_incoming reports both as Ok(None)
+    duplicates: u32,
+    fragment_timeouts: u32,
 }
 
 impl MessageHandler {
-    pub fn new(device_id: [u8; 6]) -> Self {
+    pub fn new(device_id: [u8; 6], boot_epoch: u16) -> Self {
+        Self::with_dedupe(device_id, boot_epoch, DedupeConfig::default())
+    }
+
+    pub fn with_dedupe(device_id: [u8; 6], boot_epoch: u16, dedupe: DedupeConfig) -> Self {
         Self {
             device_id,
+            boot_epoch,
             sequence_counter: 0,
             fragment_assembler: FragmentAssembler::new(),
-            last_seen_sequences: Vec::new(),
+            stream_assembler: StreamAssembler::new(),
+            seen_messages: DuplicateFilter::new(dedupe),
+            peer_epochs: EpochTracker::new(),
+            mesh_key: None,
+            accept_untagged: false,
+            reliability: ReliableSender::new(RetryPolicy::default()),
+            acks_due: Deque::new(),
+            announcer: AnnounceScheduler::new(AnnounceConfig::default(), Rng::new(!default_seed(&device_id))),
+            router: MessageRouter::with_dedupe(device_id, dedupe),
+            rng: Rng::new(default_seed(&device_id)),
+            device_name: String::try_from(DEVICE_NAME).unwrap_or_default(),
+            default_ttl: DEFAULT_TTL,
+            duplicates: 0,
+            fragment_timeouts: 0,
         }
     }
 
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.reliability = ReliableSender::new(policy);
+    }
+
+    // Reseeds the retry, announce and relay jitter from one hardware seed
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+        self.announcer.seed_rng(seed.rotate_left(32));
+        self.router.seed_rng(!seed);
+    }
+
+    pub fn router(&self) -> &MessageRouter {
+        &self.router
+    }
+
+    pub fn router_mut(&mut self) -> &mut MessageRouter {
+        &mut self.router
+    }
+
+    pub fn announcer_mut(&mut self) -> &mut AnnounceScheduler {
+        &mut self.announcer
+    }
+
+    pub fn announce_config(&self) -> AnnounceConfig {
+        self.announcer.config()
+    }
+
+    pub fn set_announce_config(&mut self, config: AnnounceConfig) {
+        self.announcer.set_config(config);
+    }
+
+    // Longer names are cut to what an announce carries
+    pub fn set_device_name(&mut self, name: &str) {
+        self.device_name.clear();
+        for c in name.chars() {
+            if self.device_name.push(c).is_err() {
+                break;
+            }
+        }
+    }
+
+    pub fn set_default_ttl(&mut self, ttl: u8) {
+        self.default_ttl = ttl;
+    }
+
+    pub fn on_connected(&mut self) {
+        self.announcer.on_connected();
+    }
+
+    // Serialized announce if one is due now (periodic, new peer or new link)
+    pub fn next_announce(&mut self, now_ms: u64) -> Option<Vec<u8, MAX_FRAGMENT_SIZE>> {
+        let reason = self.announcer.poll(now_ms)?;
+        info!("Sending announce ({:?})", reason);
+        let mut announce = TextMessage::create_announce(self.device_id, 0, &self.device_name).ok()?;
+        announce.header.ttl = self.default_ttl;
+        self.prepare_outgoing(&mut announce);
+        self.fragments(&announce, MAX_MTU_SIZE).ok()?.next()
+    }
+
+    // Earliest time any timer-driven work (retransmits, announces) is due
+    pub fn next_deadline(&self) -> u64 {
+        let announce = self.announcer.next_deadline();
+        self.reliability.next_deadline().map_or(announce, |retry| retry.min(announce))
+    }
+
+    // Without a key, tagged messages are still accepted and relayed but marked
+    // unauthenticated. With one, untagged frames are dropped unless
+    // set_accept_untagged() says otherwise.
+    pub fn set_mesh_key(&mut self, key: Option<MeshKey>) {
+        self.mesh_key = key;
+    }
+
+    // Lets a keyed node take untagged frames, marked unauthenticated, for a
+    // mesh where not every node has the key yet
+    pub fn set_accept_untagged(&mut self, accept: bool) {
+        self.accept_untagged = accept;
+    }
+
+    pub fn fragments<'a>(&'a self, message: &'a Message, mtu: u16) -> Result<Fragments<'a>, MessageError> {
+        message.fragments_with_key(mtu, self.mesh_key.as_ref())
+    }
+
+    // Complete messages dropped because we had seen them before
+    pub fn duplicates(&self) -> u32 {
+        self.duplicates
+    }
+
+    // Incomplete messages given up on after FRAGMENT_TIMEOUT_MS
+    pub fn fragment_timeouts(&self) -> u32 {
+        self.fragment_timeouts
+    }
+
+    pub fn device_id(&self) -> [u8; 6] {
+        self.device_id
+    }
+
+    pub fn boot_epoch(&self) -> u16 {
+        self.boot_epoch
+    }
+
     pub fn get_next_sequence(&mut self) -> u16 {
         let seq = self.sequence_counter;
         self.sequence_counter = self.sequence_counter.wrapping_add(1);
         seq
     }
 
-    pub fn process_incoming(&mut self, data: &[u8]) -> Result<Option<Message>, HandlerError> {
+    // Stamps an outgoing message with our next sequence and boot epoch
+    pub fn prepare_outgoing(&mut self, message: &mut Message) {
+        message.header.sequence = self.get_next_sequence();
+        message.header.epoch = self.boot_epoch;
+    }
+
+    // Stamps `message`, asks for an ACK and tracks it for retransmission.
+    // Call before the first send so that copy carries the request too; until
+    // the ACK arrives next_retransmit hands the message back.
+    pub fn send_reliable(&mut self, message: &mut Message, now_ms: u64) -> Result<MessageId, HandlerError> {
+        self.prepare_outgoing(message);
+        message.header.flags |= FLAG_ACK_REQUESTED;
+        self.reliability.track(message, now_ms, &mut self.rng)
+            .map_err(HandlerError::ReliabilityError)
+    }
+
+    // Next message whose ACK timed out; send it again and call until None
+    pub fn next_retransmit(&mut self, now_ms: u64) -> Option<&Message> {
+        self.reliability.next_retransmit(now_ms, &mut self.rng)
+    }
+
+    pub fn next_delivery_event(&mut self) -> Option<DeliveryEvent> {
+        self.reliability.next_event()
+    }
+
+    // Serialized ACK for the oldest message still owed one
+    pub fn next_ack(&mut self) -> Option<Vec<u8, MAX_FRAGMENT_SIZE>> {
+        self.next_ack_to().map(|(_, frame)| frame)
+    }
+
+    // Like next_ack, with the peer the ACK is meant for
+    pub fn next_ack_to(&mut self) -> Option<(PeerId, Vec<u8, MAX_FRAGMENT_SIZE>)> {
+        let (to, id) = self.acks_due.pop_front()?;
+        let mut ack = TextMessage::create_ack(self.device_id, 0, to, &id).ok()?;
+        ack.header.ttl = self.default_ttl;
+        self.prepare_outgoing(&mut ack);
+        let frame = self.fragments(&ack, MAX_MTU_SIZE).ok()?.next()?;
+        Some((peer_id(&to), frame))
+    }
+
+    pub fn process_incoming(&mut self, data: &[u8], now_ms: u64) -> Result<Option<Message>, HandlerError> {
+        self.process_incoming_with_sink(data, now_ms, &mut NullSink)
+    }
+
+    // Streamed fragments are written to `sink`; when a stream completes the
+    // returned message carries its manifest (length and digest) as payload.
+    pub fn process_incoming_with_sink<S: FragmentSink>(
+        &mut self,
+        data: &[u8],
+        now_ms: u64,
+        sink: &mut S,
+    ) -> Result<Option<Message>, HandlerError> {
         if data.len() < HEADER_SIZE {
             warn!("Received data too small for header: {} bytes", data.len());
             return Err(HandlerError::InvalidMessage);
@@ -52,61 +260,112 @@ impl MessageHandler {
             header.fragment_index + 1, header.total_fragments
         );
 
-        // Check for duplicate
-        if self.is_duplicate(&header) {
-            info!("Duplicate message detected, ignoring");
-            return Ok(None);
-        }
+        // Verify checksum and, if tagged, the mesh key tag
+        let (auth, body_len) = self.verify_checksum(data)?;
 
-        // Verify checksum
-        if !self.verify_checksum(data) {
-            warn!("Checksum verification failed");
-            return Err(HandlerError::ChecksumError);
+        // A higher epoch means the sender rebooted, not that it is replaying.
+        // With a mesh key only frames that carry its tag may say so.
+        let epoch = if auth == AuthStatus::Authenticated {
+            self.peer_epochs.observe(&header.sender_id, header.epoch)
+        } else if self.mesh_key.is_some() {
+            match self.peer_epochs.check(&header.sender_id, header.epoch) {
+                EpochCheck::Reset => EpochCheck::Unconfirmed,
+                check => check,
+            }
+        } else {
+            self.peer_epochs.observe_unverified(&header.sender_id, header.epoch)
+        };
+        match epoch {
+            EpochCheck::Current => {}
+            EpochCheck::Reset => self.fragment_assembler.discard_sender(&header.sender_id),
+            // Counted with stale ones: both are dropped for their epoch
+            EpochCheck::Stale | EpochCheck::Unconfirmed => return Err(HandlerError::StaleEpoch),
         }
 
-        // Extract payload
-        let payload = &data[HEADER_SIZE..];
+        // Extract payload, and the tag to relay it with
+        let payload = &data[HEADER_SIZE..body_len];
+        let tag: Option<[u8; TAG_SIZE]> = data[body_len..].try_into().ok();
 
-        // Handle fragmentation
-        if header.total_fragments > 1 {
-            match self.fragment_assembler.add_fragment(header, payload) {
-                Ok(Some(complete_message)) => {
-                    self.record_sequence(&header);
-                    Ok(Some(complete_message))
-                }
+        // Message IDs cover the whole payload, so duplicates are only
+        // recognised once a message is complete
+        let mut message = if header.is_stream() {
+            match self.stream_assembler.add_fragment(header, payload, now_ms, sink) {
+                Ok(Some(info)) => stream_manifest(header, &info)?,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(HandlerError::StreamError(e)),
+            }
+        } else if header.total_fragments > 1 {
+            let expired = self.fragment_assembler.expire(now_ms);
+            self.fragment_timeouts = self.fragment_timeouts.wrapping_add(expired as u32);
+            match self.fragment_assembler.add_fragment(header, payload, tag) {
+                Ok(Some(complete)) => complete,
                 Ok(None) => {
                     // Fragment stored, waiting for more
-                    Ok(None)
+                    return Ok(None);
                 }
-                Err(_) => Err(HandlerError::FragmentationError),
+                Err(e) => return Err(HandlerError::FragmentationError(e)),
             }
         } else {
             // Single fragment message
-            self.record_sequence(&header);
-
             let mut payload_vec = Vec::new();
             payload_vec.extend_from_slice(payload)
                 .map_err(|_| HandlerError::InvalidMessage)?;
 
-            Ok(Some(Message {
+            Message {
                 header,
                 payload: payload_vec,
-            }))
+                auth,
+                layout: FragmentLayout::single(payload.len(), tag),
+            }
+        };
+
+        // Every fragment carried the same flag and was checked on arrival
+        message.auth = auth;
+
+        // Retransmissions are ACKed again in case our first ACK was lost
+        let id = message.message_id();
+        if message.header.flags & FLAG_ACK_REQUESTED != 0
+            && message.header.sender_id != self.device_id
+            && message.is_for(&self.device_id)
+        {
+            self.queue_ack(message.header.sender_id, id);
         }
+
+        // Check for duplicate
+        if self.is_duplicate(&id) {
+            info!("Duplicate message {:?} detected, ignoring", id);
+            self.duplicates = self.duplicates.wrapping_add(1);
+            // Counts towards suppressing our own copy if one is queued
+            self.router.note_duplicate(&id);
+            return Ok(None);
+        }
+        self.record_message(&id, now_ms);
+
+        Ok(Some(message))
     }
 
-    pub fn handle_message(&mut self, message: &Message) -> Result<Option<Vec<u8, MAX_MESSAGE_SIZE>>, HandlerError> {
+    // Decides what to do with a complete message from process_incoming.
+    // `from` is the link it arrived on; nothing is sent here, the caller
+    // executes the returned actions.
+    pub fn handle_message(&mut self, message: &Message, from: LinkId, now_ms: u64) -> Result<Actions, HandlerError> {
+        let mut actions = Actions::new();
+        let sender = peer_id(&message.header.sender_id);
+
         match message.header.msg_type {
             MessageType::Text => {
                 info!("Text message received: {} bytes", message.payload.len());
 
                 // Parse and log the text
-                if let Ok(text) = TextMessage::parse(&message.payload) {
+                if let Ok(text) = TextMessage::parse(message.body()) {
                     info!("Text: \"{}\"", text.as_str());
                 }
 
-                // Could generate ACK here if needed
-                Ok(None)
+                if message.is_for(&self.device_id) {
+                    push(&mut actions, Action::Deliver(message.message_id()));
+                }
+                if message.recipient() != Some(self.device_id) {
+                    self.relay(message, from, now_ms, &mut actions);
+                }
             }
             MessageType::Announce => {
                 info!("Device announce from {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
@@ -114,67 +373,326 @@ impl MessageHandler {
                     message.header.sender_id[2], message.header.sender_id[3],
                     message.header.sender_id[4], message.header.sender_id[5]
                 );
-                // Could respond with our own announce
-                Ok(None)
+                push(&mut actions, Action::UpdatePeer { peer: sender, update: PeerUpdate::Announced });
+
+                // A new neighbour gets our announce straight back
+                self.announcer.on_peer_announce(&message.header.sender_id, now_ms);
+                if let Some(frame) = self.next_announce(now_ms) {
+                    push(&mut actions, Action::Reply(frame));
+                }
+                self.relay(message, from, now_ms, &mut actions);
+            }
+            MessageType::Ack if !message.is_for(&self.device_id) => {
+                // On its way back to whoever asked for it
+                if !self.relay(message, from, now_ms, &mut actions) {
+                    push(&mut actions, Action::Drop(DropReason::NotRelayed));
+                }
             }
             MessageType::Ack => {
-                info!("ACK received for sequence {}", message.header.sequence);
-                Ok(None)
+                match MessageId::from_bytes(message.body()) {
+                    Some(id) => {
+                        info!("ACK received for message {:?}", id);
+                        if self.reliability.on_ack(&id) {
+                            push(&mut actions, Action::UpdatePeer { peer: sender, update: PeerUpdate::AckReceived(id) });
+                        } else {
+                            info!("ACK for a message we are not waiting on");
+                            push(&mut actions, Action::Drop(DropReason::UnknownAck));
+                        }
+                    }
+                    None => {
+                        warn!("ACK without a message ID");
+                        push(&mut actions, Action::Drop(DropReason::Malformed));
+                    }
+                }
             }
             MessageType::Relay => {
-                if message.header.ttl > 0 {
-                    info!("Relay message with TTL {}", message.header.ttl);
-                    // Relay logic would go here
+                if message.header.ttl == 0 {
+                    push(&mut actions, Action::Drop(DropReason::TtlExpired));
+                } else if !self.relay(message, from, now_ms, &mut actions) {
+                    push(&mut actions, Action::Drop(DropReason::NotRelayed));
                 }
-                Ok(None)
             }
         }
-    }
 
-    fn is_duplicate(&self, header: &MessageHeader) -> bool {
-        self.last_seen_sequences.iter().any(|(seq, sender)| {
-            *seq == header.sequence && *sender == header.sender_id
-        })
+        // ACKs owed for anything received so far, duplicates included
+        while actions.len() < MAX_ACTIONS {
+            let Some((peer, frame)) = self.next_ack_to() else { break };
+            push(&mut actions, Action::AckTo { peer, frame });
+        }
+
+        Ok(actions)
     }
 
-    fn record_sequence(&mut self, header: &MessageHeader) {
-        if self.last_seen_sequences.len() >= 32 {
-            self.last_seen_sequences.remove(0);
+    // Adds a Relay action for the frames the message arrived in if the router
+    // lets it travel on. Returns true if it did.
+    fn relay(&mut self, message: &Message, from: LinkId, now_ms: u64, actions: &mut Actions) -> bool {
+        // Only the manifest of a stream reaches this point; the data went to
+        // our sink, so there is nothing the next hop could reassemble
+        if message.header.is_stream() {
+            return false;
+        }
+        // Only a message from process_incoming can be relayed as it arrived
+        if message.layout.is_empty() {
+            warn!("No received frames for {:?}, not relaying", message.message_id());
+            return false;
         }
-        let _ = self.last_seen_sequences.push((header.sequence, header.sender_id));
+        let Some((target, delay_ms)) = self.router.plan_relay(message, from, now_ms) else {
+            return false;
+        };
+        push(actions, Action::Relay { target, delay_ms, layout: message.layout.clone() });
+        true
     }
 
-    fn verify_checksum(&self, data: &[u8]) -> bool {
-        if data.len() < HEADER_SIZE {
-            return false;
+    fn queue_ack(&mut self, to: [u8; 6], id: MessageId) {
+        if self.acks_due.iter().any(|(_, due)| *due == id) {
+            return;
+        }
+        if self.acks_due.is_full() {
+            self.acks_due.pop_front();
         }
+        let _ = self.acks_due.push_back((to, id));
+    }
 
-        // Extract the checksum from header
-        let stored_checksum = ((data[14] as u16) << 8) | (data[15] as u16);
+    fn is_duplicate(&self, id: &MessageId) -> bool {
+        self.seen_messages.contains(id)
+    }
 
-        // Calculate checksum on header (excluding checksum bytes) and payload
-        let header_part = &data[0..14];
-        let payload_part = &data[16..];
+    fn record_message(&mut self, id: &MessageId, now_ms: u64) {
+        self.seen_messages.insert(id, now_ms);
+    }
 
-        let calculated = calculate_crc16(header_part, payload_part);
+    fn verify_checksum(&self, data: &[u8]) -> Result<(AuthStatus, usize), HandlerError> {
+        let (auth, body_len) = integrity::verify(data, self.mesh_key.as_ref()).map_err(|e| match e {
+            IntegrityError::BadTag => {
+                warn!("Mesh key tag verification failed");
+                HandlerError::AuthenticationError
+            }
+            _ => {
+                warn!("Checksum verification failed");
+                HandlerError::ChecksumError
+            }
+        })?;
+        if auth == AuthStatus::Unauthenticated && self.mesh_key.is_some() && !self.accept_untagged {
+            warn!("Untagged frame dropped, the mesh key is required");
+            return Err(HandlerError::AuthenticationError);
+        }
+        Ok((auth, body_len))
+    }
+}
 
-        calculated == stored_checksum
+// Callers size their action lists so this never drops anything important;
+// ACKs are pushed last and are the first to go
+fn push(actions: &mut Actions, action: Action) {
+    if actions.push(action).is_err() {
+        warn!("Action list full");
     }
 }
 
-fn calculate_crc16(header: &[u8], payload: &[u8]) -> u16 {
-    let mut crc: u16 = 0xFFFF;
+// Callers with a hardware RNG should reseed; this only keeps nodes apart
+fn default_seed(device_id: &[u8; 6]) -> u64 {
+    let mut seed = [0u8; 8];
+    seed[2..].copy_from_slice(device_id);
+    u64::from_be_bytes(seed)
+}
+
+fn stream_manifest(header: MessageHeader, info: &StreamInfo) -> Result<Message, HandlerError> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&info.total_len.to_be_bytes())
+        .and_then(|_| payload.extend_from_slice(&info.digest))
+        .map_err(|_| HandlerError::InvalidMessage)?;
+    Ok(Message { header, payload, auth: AuthStatus::Unauthenticated, layout: FragmentLayout::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{FLAG_AUTHENTICATED, FLAG_RELAYED};
+    use crate::protocol::relay::RelayTarget;
+    use std::vec::Vec;
+
+    const A: [u8; 6] = [0x5e, 0x11, 0, 0, 0, 1];
+    const B: [u8; 6] = [0x5e, 0x11, 0, 0, 0, 2];
+    const C: [u8; 6] = [0x5e, 0x11, 0, 0, 0, 3];
+
+    fn keyed(device_id: [u8; 6], key: Option<[u8; 32]>) -> MessageHandler {
+        let mut handler = MessageHandler::new(device_id, 1);
+        handler.set_mesh_key(key.map(MeshKey::new));
+        handler
+    }
+
+    // Frames `sender` puts on the air for `text`
+    fn frames(sender: &mut MessageHandler, text: &str, mtu: u16) -> Vec<Vec<u8>> {
+        let mut message = TextMessage::create(sender.device_id(), 0, text).unwrap();
+        sender.prepare_outgoing(&mut message);
+        sender.fragments(&message, mtu).unwrap().map(|frame| frame.to_vec()).collect()
+    }
+
+    // Handles a message from link 0 and queues its relay, as NodeCore does
+    fn handle(node: &mut MessageHandler, message: &Message) {
+        for action in node.handle_message(message, LinkId(0), 0).unwrap() {
+            if let Action::Relay { target, delay_ms, layout } = action {
+                assert_eq!(layout, message.layout);
+                assert!(node.router_mut().queue_relay(message, &layout, LinkId(0), target, delay_ms as u64));
+            }
+        }
+    }
 
-    for &byte in header.iter().chain(payload.iter()) {
-        crc ^= (byte as u16) << 8;
-        for _ in 0..8 {
-            if (crc & 0x8000) != 0 {
-                crc = (crc << 1) ^ 0x1021;
-            } else {
-                crc <<= 1;
+    // Feeds `frames` to `node` on link 0 and returns what it relays once the
+    // relay delay is up
+    fn relay(node: &mut MessageHandler, frames: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        for frame in frames {
+            if let Some(message) = node.process_incoming(frame, 0).unwrap() {
+                handle(node, &message);
             }
         }
+        assert!(node.router_mut().next_relay(0).is_none(), "relays wait out their delay");
+        let mut relayed = Vec::new();
+        while let Some(relay) = node.router_mut().next_relay(1_000) {
+            assert_eq!(relay.target, RelayTarget::AllExcept(LinkId(0)));
+            relayed.push(relay.frame.to_vec());
+        }
+        relayed
+    }
+
+    fn receive(node: &mut MessageHandler, frames: &[Vec<u8>]) -> Option<Message> {
+        frames.iter().filter_map(|frame| node.process_incoming(frame, 0).unwrap()).next()
+    }
+
+    #[test]
+    fn keyless_relays_keep_the_senders_tag() {
+        let key = [7; 32];
+        let sent = frames(&mut keyed(A, Some(key)), "hello", MAX_MTU_SIZE);
+        let relayed = relay(&mut keyed(B, None), &sent);
+
+        assert_eq!(relayed.len(), 1);
+        let header = MessageHeader::deserialize(&relayed[0]).unwrap();
+        assert_eq!(header.flags & (FLAG_AUTHENTICATED | FLAG_RELAYED), FLAG_AUTHENTICATED | FLAG_RELAYED);
+        assert_eq!(header.ttl, DEFAULT_TTL - 1);
+        assert_eq!(relayed[0].len(), sent[0].len());
+
+        let message = receive(&mut keyed(C, Some(key)), &relayed).unwrap();
+        assert_eq!(message.auth, AuthStatus::Authenticated);
+        assert_eq!(message.body(), b"hello");
+    }
+
+    #[test]
+    fn keyed_relays_do_not_sign_for_the_sender() {
+        let sent = frames(&mut keyed(A, None), "hello", MAX_MTU_SIZE);
+        let mut relay_node = keyed(B, Some([7; 32]));
+        relay_node.set_accept_untagged(true);
+        let relayed = relay(&mut relay_node, &sent);
+
+        let header = MessageHeader::deserialize(&relayed[0]).unwrap();
+        assert_eq!(header.flags & FLAG_AUTHENTICATED, 0);
+        assert_eq!(relayed[0].len(), sent[0].len());
+        let mut receiver = keyed(C, Some([7; 32]));
+        receiver.set_accept_untagged(true);
+        let message = receive(&mut receiver, &relayed).unwrap();
+        assert_eq!(message.auth, AuthStatus::Unauthenticated);
+    }
+
+    #[test]
+    fn keyed_nodes_drop_untagged_frames() {
+        let sent = frames(&mut keyed(A, None), "hello", MAX_MTU_SIZE);
+        let mut node = keyed(B, Some([7; 32]));
+        assert!(matches!(node.process_incoming(&sent[0], 0), Err(HandlerError::AuthenticationError)));
+        // Neither delivered, nor relayed, nor taken as A's epoch
+        assert!(node.router().next_deadline().is_none());
+        assert_eq!(node.peer_epochs.epoch_of(&A), None);
+    }
+
+    #[test]
+    fn fragmented_messages_are_relayed_as_they_arrived() {
+        let key = [9; 32];
+        let text = "a message long enough to need several fragments at a small MTU";
+        let sent = frames(&mut keyed(A, Some(key)), text, 64);
+        assert!(sent.len() > 1);
+
+        let relayed = relay(&mut keyed(B, None), &sent);
+        assert_eq!(relayed.len(), sent.len());
+        for (sent, relayed) in sent.iter().zip(&relayed) {
+            assert_eq!(sent.len(), relayed.len());
+            // Only TTL, the relayed flag and the CRC change
+            assert_eq!(sent[..12], relayed[..12]);
+            assert_eq!(sent[16..], relayed[16..]);
+        }
+
+        let message = receive(&mut keyed(C, Some(key)), &relayed).unwrap();
+        assert_eq!(message.auth, AuthStatus::Authenticated);
+        assert_eq!(message.body(), text.as_bytes());
+    }
+
+    #[test]
+    fn relays_are_suppressed_by_overheard_copies() {
+        let sent = frames(&mut keyed(A, None), "hello", MAX_MTU_SIZE);
+        let mut node = keyed(B, None);
+        let message = node.process_incoming(&sent[0], 0).unwrap().unwrap();
+        handle(&mut node, &message);
+
+        // Two more neighbours forward it before our delay is up
+        for _ in 0..2 {
+            assert!(node.process_incoming(&sent[0], 1).unwrap().is_none());
+        }
+        assert_eq!(node.duplicates(), 2);
+        assert!(node.router_mut().next_relay(1_000).is_none());
+        assert_eq!(node.router().relays_suppressed(), 1);
+    }
+
+    #[test]
+    fn our_own_messages_are_not_relayed() {
+        let mut node = keyed(A, None);
+        let sent = frames(&mut keyed(A, None), "echo", MAX_MTU_SIZE);
+        let message = node.process_incoming(&sent[0], 0).unwrap().unwrap();
+        let actions = node.handle_message(&message, LinkId(0), 0).unwrap();
+        assert!(!actions.iter().any(|action| matches!(action, Action::Relay { .. })));
+    }
+
+    #[test]
+    fn relays_are_returned_not_queued() {
+        let text = "a message long enough to need several fragments at a small MTU";
+        let sent = frames(&mut keyed(A, None), text, 64);
+        let mut node = keyed(B, None);
+        let message = receive(&mut node, &sent).unwrap();
+
+        let actions = node.handle_message(&message, LinkId(2), 0).unwrap();
+        let relay = actions.iter().find_map(|action| match action {
+            Action::Relay { target, layout, .. } => Some((*target, layout.len())),
+            _ => None,
+        });
+        assert_eq!(relay, Some((RelayTarget::AllExcept(LinkId(2)), sent.len())));
+        assert!(node.router().next_deadline().is_none());
     }
 
-    crc
-}
\ No newline at end of file
+    #[test]
+    fn untagged_frames_cannot_move_a_keyed_nodes_epochs() {
+        let key = [7; 32];
+        let mut node = keyed(B, Some(key));
+        // Strict nodes drop the spoofed frame before its epoch is looked at
+        node.set_accept_untagged(true);
+        let mut sender = keyed(A, Some(key));
+        assert!(receive(&mut node, &frames(&mut sender, "one", MAX_MTU_SIZE)).is_some());
+
+        // Someone else's frame with A's ID and an epoch far ahead
+        let spoofed = frames(&mut MessageHandler::new(A, u16::MAX / 2), "spoofed", MAX_MTU_SIZE);
+        assert!(matches!(node.process_incoming(&spoofed[0], 0), Err(HandlerError::StaleEpoch)));
+        assert!(receive(&mut node, &frames(&mut sender, "two", MAX_MTU_SIZE)).is_some());
+
+        // A genuine reboot carries the tag
+        let mut rebooted = MessageHandler::new(A, 40);
+        rebooted.set_mesh_key(Some(MeshKey::new(key)));
+        assert!(receive(&mut node, &frames(&mut rebooted, "three", MAX_MTU_SIZE)).is_some());
+        assert!(matches!(node.process_incoming(&frames(&mut sender, "four", MAX_MTU_SIZE)[0], 0),
+            Err(HandlerError::StaleEpoch)));
+    }
+
+    #[test]
+    fn one_spoofed_frame_does_not_silence_a_keyless_sender() {
+        let mut node = keyed(B, None);
+        let mut sender = keyed(A, None);
+        assert!(receive(&mut node, &frames(&mut sender, "one", MAX_MTU_SIZE)).is_some());
+
+        let spoofed = frames(&mut MessageHandler::new(A, u16::MAX / 2), "spoofed", MAX_MTU_SIZE);
+        assert!(matches!(node.process_incoming(&spoofed[0], 0), Err(HandlerError::StaleEpoch)));
+        assert!(receive(&mut node, &frames(&mut sender, "two", MAX_MTU_SIZE)).is_some());
+    }
+}
@@ -0,0 +1,181 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::protocol::message::{FLAG_AUTHENTICATED, FLAG_RELAYED, HEADER_SIZE};
+
+// Truncated HMAC-SHA256 carried as a trailer after the payload when the header
+// has FLAG_AUTHENTICATED set. The CRC16 still covers everything, trailer included.
+pub const TAG_SIZE: usize = 8;
+
+const CHECKSUM_OFFSET: usize = 14;
+const TTL_OFFSET: usize = 12;
+const FLAGS_OFFSET: usize = 13;
+
+// Relays rewrite these flag bits, so they are left out of the tag
+const MUTABLE_FLAGS: u8 = FLAG_RELAYED;
+
+#[derive(Clone)]
+pub struct MeshKey([u8; 32]);
+
+impl MeshKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AuthStatus {
+    // No tag, or a tag we hold no key to check
+    Unauthenticated,
+    Authenticated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IntegrityError {
+    TooShort,
+    Checksum,
+    BadTag,
+}
+
+pub fn crc16(header: &[u8], payload: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for &byte in header.iter().chain(payload.iter()) {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+// Recomputes the CRC of a serialized frame in place, e.g. after a TTL change
+pub fn seal_crc(frame: &mut [u8]) {
+    let checksum = crc16(&frame[0..CHECKSUM_OFFSET], &frame[CHECKSUM_OFFSET + 2..]);
+    frame[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+// Tag over header and payload with TTL, checksum and relay-mutable flags zeroed,
+// so relays without the key can still forward the frame
+pub fn compute_tag(key: &MeshKey, frame: &[u8]) -> [u8; TAG_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header.copy_from_slice(&frame[..HEADER_SIZE]);
+    header[TTL_OFFSET] = 0;
+    header[FLAGS_OFFSET] &= !MUTABLE_FLAGS;
+    header[CHECKSUM_OFFSET] = 0;
+    header[CHECKSUM_OFFSET + 1] = 0;
+
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.0)
+        .expect("HMAC accepts any key length");
+    mac.update(&header);
+    mac.update(&frame[HEADER_SIZE..]);
+    let digest = mac.finalize().into_bytes();
+
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(&digest[..TAG_SIZE]);
+    tag
+}
+
+// Checks the CRC and, if present, the tag. Returns the auth status and the
+// length of the frame without its trailer.
+pub fn verify(frame: &[u8], key: Option<&MeshKey>) -> Result<(AuthStatus, usize), IntegrityError> {
+    if frame.len() < HEADER_SIZE {
+        return Err(IntegrityError::TooShort);
+    }
+
+    let stored = u16::from_be_bytes([frame[CHECKSUM_OFFSET], frame[CHECKSUM_OFFSET + 1]]);
+    if crc16(&frame[0..CHECKSUM_OFFSET], &frame[CHECKSUM_OFFSET + 2..]) != stored {
+        return Err(IntegrityError::Checksum);
+    }
+
+    if frame[FLAGS_OFFSET] & FLAG_AUTHENTICATED == 0 {
+        return Ok((AuthStatus::Unauthenticated, frame.len()));
+    }
+
+    if frame.len() < HEADER_SIZE + TAG_SIZE {
+        return Err(IntegrityError::TooShort);
+    }
+    let body_len = frame.len() - TAG_SIZE;
+
+    let key = match key {
+        Some(key) => key,
+        None => return Ok((AuthStatus::Unauthenticated, body_len)),
+    };
+
+    let expected = compute_tag(key, &frame[..body_len]);
+    let diff = expected.iter()
+        .zip(&frame[body_len..])
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return Err(IntegrityError::BadTag);
+    }
+
+    Ok((AuthStatus::Authenticated, body_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{Message, MessageType};
+    use heapless::Vec;
+
+    const SENDER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn frame(key: Option<&MeshKey>) -> Vec<u8, 244> {
+        let message = Message::new(MessageType::Text, SENDER, 7, b"hello").unwrap();
+        message.fragments_with_key(247, key).unwrap().next().unwrap()
+    }
+
+    #[test]
+    fn crc_catches_any_flipped_byte() {
+        let frame = frame(None);
+        assert_eq!(verify(&frame, None), Ok((AuthStatus::Unauthenticated, frame.len())));
+        for i in 0..frame.len() {
+            let mut bad = frame.clone();
+            bad[i] ^= 0x01;
+            assert!(verify(&bad, None).is_err(), "byte {}", i);
+        }
+        assert_eq!(verify(&frame[..HEADER_SIZE - 1], None), Err(IntegrityError::TooShort));
+    }
+
+    #[test]
+    fn tags_need_the_right_key() {
+        let key = MeshKey::new([1; 32]);
+        let frame = frame(Some(&key));
+        let body_len = frame.len() - TAG_SIZE;
+
+        assert_eq!(verify(&frame, Some(&key)), Ok((AuthStatus::Authenticated, body_len)));
+        assert_eq!(verify(&frame, None), Ok((AuthStatus::Unauthenticated, body_len)));
+        assert_eq!(verify(&frame, Some(&MeshKey::new([2; 32]))), Err(IntegrityError::BadTag));
+    }
+
+    #[test]
+    fn tags_survive_relaying() {
+        let key = MeshKey::new([1; 32]);
+        let mut frame = frame(Some(&key));
+        frame[TTL_OFFSET] -= 1;
+        frame[FLAGS_OFFSET] |= FLAG_RELAYED;
+        assert_eq!(verify(&frame, Some(&key)), Err(IntegrityError::Checksum));
+        seal_crc(&mut frame);
+        assert_eq!(verify(&frame, Some(&key)).map(|(auth, _)| auth), Ok(AuthStatus::Authenticated));
+    }
+
+    #[test]
+    fn tags_cover_everything_else() {
+        let key = MeshKey::new([1; 32]);
+        let frame = frame(Some(&key));
+        for i in (0..frame.len() - TAG_SIZE).filter(|i| ![TTL_OFFSET, CHECKSUM_OFFSET, CHECKSUM_OFFSET + 1].contains(i)) {
+            let mut forged = frame.clone();
+            // Flipping FLAG_AUTHENTICATED itself would just drop the tag
+            forged[i] ^= if i == FLAGS_OFFSET { 0x02 } else { 0x01 };
+            seal_crc(&mut forged);
+            assert_eq!(verify(&forged, Some(&key)), Err(IntegrityError::BadTag), "byte {}", i);
+        }
+    }
+}
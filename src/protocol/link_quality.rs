@@ -0,0 +1,252 @@
+use heapless::Vec;
+use crate::protocol::relay::LinkId;
+
+const MAX_LINKS: usize = 8;
+
+// A perfect link costs one transmission per delivery
+pub const ETX_PERFECT: u16 = 100;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkQualityConfig {
+    // Weight of a new sample in the moving averages, per-mille
+    pub smoothing_per_mille: u16,
+    // RSSI at or above this adds no cost
+    pub rssi_good_dbm: i8,
+    // RSSI at or below this doubles the cost
+    pub rssi_bad_dbm: i8,
+}
+
+impl Default for LinkQualityConfig {
+    fn default() -> Self {
+        Self {
+            smoothing_per_mille: 125,
+            rssi_good_dbm: -65,
+            rssi_bad_dbm: -90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkMetrics {
+    pub link: LinkId,
+    // Expected transmissions per delivery, x100
+    pub etx_x100: u16,
+    pub rssi_dbm: Option<i8>,
+    pub ack_per_mille: u16,
+    pub write_per_mille: u16,
+}
+
+// One neighbour link. Ratios start optimistic so a fresh link isn't shunned
+// before it has carried any traffic.
+#[derive(Clone)]
+struct LinkEstimator {
+    link: LinkId,
+    ack_per_mille: u16,
+    write_per_mille: u16,
+    // dBm x16 so the average keeps some resolution
+    rssi_x16: Option<i16>,
+}
+
+impl LinkEstimator {
+    fn new(link: LinkId) -> Self {
+        Self {
+            link,
+            ack_per_mille: 1000,
+            write_per_mille: 1000,
+            rssi_x16: None,
+        }
+    }
+
+    fn rssi_dbm(&self) -> Option<i8> {
+        self.rssi_x16.map(|r| (r / 16) as i8)
+    }
+
+    // ETX = 1 / (p_ack * p_write), scaled by up to 2x for a weak signal
+    fn etx_x100(&self, config: &LinkQualityConfig) -> u16 {
+        let ack = self.ack_per_mille.max(1) as u64;
+        let write = self.write_per_mille.max(1) as u64;
+        let etx = ETX_PERFECT as u64 * 1_000_000 / (ack * write);
+
+        let penalty_per_mille = match self.rssi_dbm() {
+            Some(rssi) if rssi < config.rssi_good_dbm => {
+                let span = (config.rssi_good_dbm as i32 - config.rssi_bad_dbm as i32).max(1);
+                let below = (config.rssi_good_dbm as i32 - rssi as i32).min(span);
+                1000 + (1000 * below / span) as u64
+            }
+            _ => 1000,
+        };
+
+        (etx * penalty_per_mille / 1000).min(u16::MAX as u64) as u16
+    }
+
+    fn metrics(&self, config: &LinkQualityConfig) -> LinkMetrics {
+        LinkMetrics {
+            link: self.link,
+            etx_x100: self.etx_x100(config),
+            rssi_dbm: self.rssi_dbm(),
+            ack_per_mille: self.ack_per_mille,
+            write_per_mille: self.write_per_mille,
+        }
+    }
+}
+
+fn ewma(current: i32, sample: i32, weight_per_mille: u16) -> i32 {
+    let weight = weight_per_mille.min(1000) as i32;
+    current + (sample - current) * weight / 1000
+}
+
+// Pure link-quality bookkeeping; the transport reports RSSI readings, ACK
+// outcomes and notify results per link and reads back ETX-like metrics.
+pub struct LinkQualityTable {
+    config: LinkQualityConfig,
+    links: Vec<LinkEstimator, MAX_LINKS>,
+}
+
+impl LinkQualityTable {
+    pub fn new(config: LinkQualityConfig) -> Self {
+        Self {
+            config,
+            links: Vec::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: LinkQualityConfig) {
+        self.config = config;
+    }
+
+    pub fn on_rssi(&mut self, link: LinkId, rssi_dbm: i8) {
+        let weight = self.config.smoothing_per_mille;
+        let estimator = self.estimator(link);
+        let sample = rssi_dbm as i32 * 16;
+        let averaged = estimator.rssi_x16.map_or(sample, |r| ewma(r as i32, sample, weight));
+        estimator.rssi_x16 = Some(averaged as i16);
+    }
+
+    // An acknowledged send through this link was delivered, or gave up
+    pub fn on_ack(&mut self, link: LinkId, delivered: bool) {
+        let weight = self.config.smoothing_per_mille;
+        let estimator = self.estimator(link);
+        let sample = if delivered { 1000 } else { 0 };
+        estimator.ack_per_mille = ewma(estimator.ack_per_mille as i32, sample, weight) as u16;
+    }
+
+    // Result of handing a frame to the link layer (data_notify on BLE)
+    pub fn on_write(&mut self, link: LinkId, ok: bool) {
+        let weight = self.config.smoothing_per_mille;
+        let estimator = self.estimator(link);
+        let sample = if ok { 1000 } else { 0 };
+        estimator.write_per_mille = ewma(estimator.write_per_mille as i32, sample, weight) as u16;
+    }
+
+    // Starts over when the link slot is reused for another neighbour
+    pub fn forget(&mut self, link: LinkId) {
+        self.links.retain(|e| e.link != link);
+    }
+
+    pub fn metrics(&self, link: LinkId) -> Option<LinkMetrics> {
+        self.links.iter().find(|e| e.link == link).map(|e| e.metrics(&self.config))
+    }
+
+    // ETX for a link, ETX_PERFECT if nothing is known about it yet
+    pub fn etx_x100(&self, link: LinkId) -> u16 {
+        self.metrics(link).map_or(ETX_PERFECT, |m| m.etx_x100)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = LinkMetrics> + '_ {
+        self.links.iter().map(|e| e.metrics(&self.config))
+    }
+
+    fn estimator(&mut self, link: LinkId) -> &mut LinkEstimator {
+        let index = match self.links.iter().position(|e| e.link == link) {
+            Some(index) => index,
+            None => {
+                if self.links.is_full() {
+                    self.links.remove(0);
+                }
+                let _ = self.links.push(LinkEstimator::new(link));
+                self.links.len() - 1
+            }
+        };
+        &mut self.links[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: LinkId = LinkId(0);
+
+    // One event fed to the estimator
+    #[derive(Clone, Copy)]
+    enum Sample {
+        Rssi(i8),
+        Ack(bool),
+        Notify(bool),
+    }
+
+    use Sample::*;
+
+    // ETX of LINK after feeding `samples` to a fresh table
+    fn etx_after(samples: &[Sample]) -> u16 {
+        let mut table = LinkQualityTable::new(LinkQualityConfig::default());
+        for sample in samples {
+            match *sample {
+                Rssi(dbm) => table.on_rssi(LINK, dbm),
+                Ack(delivered) => table.on_ack(LINK, delivered),
+                Notify(ok) => table.on_write(LINK, ok),
+            }
+        }
+        table.etx_x100(LINK)
+    }
+
+    #[test]
+    fn unknown_and_clean_links_are_perfect() {
+        assert_eq!(etx_after(&[]), ETX_PERFECT);
+        assert_eq!(etx_after(&[Ack(true), Notify(true), Rssi(-40)]), ETX_PERFECT);
+    }
+
+    #[test]
+    fn failures_raise_the_cost() {
+        // Each failure moves its ratio 12.5% towards zero: 1000 -> 875 -> 766
+        assert_eq!(etx_after(&[Notify(false)]), 114);
+        assert_eq!(etx_after(&[Ack(false), Ack(false)]), 130);
+        assert_eq!(etx_after(&[Ack(false), Notify(false)]), 130);
+    }
+
+    #[test]
+    fn weak_signal_scales_the_cost_up_to_double() {
+        assert_eq!(etx_after(&[Rssi(-65)]), 100);
+        assert_eq!(etx_after(&[Rssi(-78)]), 152);
+        assert_eq!(etx_after(&[Rssi(-90)]), 200);
+        assert_eq!(etx_after(&[Rssi(-120)]), 200);
+        assert_eq!(etx_after(&[Ack(false), Notify(false), Rssi(-90)]), 260);
+    }
+
+    #[test]
+    fn rssi_is_smoothed() {
+        // -60 then -100 averages to -65, still good enough to cost nothing
+        assert_eq!(etx_after(&[Rssi(-60), Rssi(-100)]), 100);
+    }
+
+    #[test]
+    fn link_recovers_after_successes() {
+        let mut samples = vec![Ack(false); 10];
+        assert!(etx_after(&samples) > 300);
+        samples.extend([Ack(true); 100]);
+        assert!(etx_after(&samples) <= 101);
+    }
+
+    #[test]
+    fn forgotten_links_start_over() {
+        let mut table = LinkQualityTable::new(LinkQualityConfig::default());
+        table.on_ack(LINK, false);
+        table.on_ack(LinkId(1), false);
+        table.forget(LINK);
+        assert_eq!(table.metrics(LINK), None);
+        assert_eq!(table.etx_x100(LINK), ETX_PERFECT);
+        assert_eq!(table.etx_x100(LinkId(1)), 114);
+    }
+}
@@ -1,12 +1,41 @@
-use defmt::Format;
 use heapless::Vec;
+use crate::config::{DEFAULT_TTL, MAX_MTU_SIZE};
+use crate::protocol::fragmentation::FragmentLayout;
+use crate::protocol::integrity::{compute_tag, seal_crc, AuthStatus, MeshKey, TAG_SIZE};
+use crate::protocol::message_id::MessageId;
 
-pub const PROTOCOL_VERSION: u8 = 0x01;
-pub const HEADER_SIZE: usize = 16;
-pub const MAX_PAYLOAD_SIZE: usize = 228; // 244 - 16 header
+pub const PROTOCOL_VERSION: u8 = 0x02; // v2 appended the sender's boot epoch
+pub const HEADER_SIZE: usize = 18;
+pub const MAX_PAYLOAD_SIZE: usize = 226; // 244 - 18 header
 pub const MAX_MESSAGE_SIZE: usize = 1024; // Maximum size for a complete message
+pub const MAX_FRAGMENT_SIZE: usize = 244; // Largest notification we ever send
+pub const ATT_HEADER_SIZE: usize = 3; // Opcode + handle in every notification
+pub const MIN_ATT_MTU: u16 = 23;
 
-#[derive(Debug, Clone, Copy, Format, PartialEq)]
+// Header flag bits
+pub const FLAG_RELAYED: u8 = 0x01;
+pub const FLAG_STREAM: u8 = 0x02; // Payload is one fragment of a streamed transfer
+pub const FLAG_AUTHENTICATED: u8 = 0x04; // Frame ends with a mesh-key tag, see integrity.rs
+pub const FLAG_ACK_REQUESTED: u8 = 0x08; // Unicast: receiver answers with an Ack carrying the message ID
+pub const FLAG_DIRECTED: u8 = 0x10; // Payload starts with the recipient's device ID
+
+pub const RECIPIENT_SIZE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageError {
+    // Shorter than a header
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    // Payload over MAX_MESSAGE_SIZE, or more fragments than the header can count
+    TooLarge,
+    // The MTU leaves no room for payload once the header and tag are in
+    MtuTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MessageType {
     Text = 0x01,
     Ack = 0x02,
@@ -15,7 +44,7 @@ pub enum MessageType {
 }
 
 impl TryFrom<u8> for MessageType {
-    type Error = ();
+    type Error = MessageError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
@@ -23,12 +52,13 @@ impl TryFrom<u8> for MessageType {
             0x02 => Ok(MessageType::Ack),
             0x03 => Ok(MessageType::Announce),
             0x04 => Ok(MessageType::Relay),
-            _ => Err(()),
+            _ => Err(MessageError::UnknownType(value)),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Format)]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct MessageHeader {
     pub version: u8,
     pub msg_type: MessageType,
@@ -39,6 +69,7 @@ pub struct MessageHeader {
     pub ttl: u8,
     pub flags: u8,
     pub checksum: u16,
+    pub epoch: u16, // Sender's boot counter; sequences restart from zero in every epoch
 }
 
 impl MessageHeader {
@@ -50,9 +81,10 @@ impl MessageHeader {
             sequence,
             fragment_index: 0,
             total_fragments: 1,
-            ttl: 3, // Default TTL for mesh relay
+            ttl: DEFAULT_TTL,
             flags: 0,
             checksum: 0,
+            epoch: 0,
         }
     }
 
@@ -69,17 +101,22 @@ impl MessageHeader {
         bytes[13] = self.flags;
         bytes[14] = (self.checksum >> 8) as u8;
         bytes[15] = (self.checksum & 0xFF) as u8;
+        bytes[16..18].copy_from_slice(&self.epoch.to_be_bytes());
         bytes
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, ()> {
+    pub fn is_stream(&self) -> bool {
+        self.flags & FLAG_STREAM != 0
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, MessageError> {
         if bytes.len() < HEADER_SIZE {
-            return Err(());
+            return Err(MessageError::Truncated);
         }
 
         let version = bytes[0];
         if version != PROTOCOL_VERSION {
-            return Err(());
+            return Err(MessageError::UnsupportedVersion(version));
         }
 
         let msg_type = MessageType::try_from(bytes[1])?;
@@ -88,6 +125,7 @@ impl MessageHeader {
 
         let sequence = ((bytes[8] as u16) << 8) | (bytes[9] as u16);
         let checksum = ((bytes[14] as u16) << 8) | (bytes[15] as u16);
+        let epoch = ((bytes[16] as u16) << 8) | (bytes[17] as u16);
 
         Ok(Self {
             version,
@@ -99,6 +137,7 @@ impl MessageHeader {
             ttl: bytes[12],
             flags: bytes[13],
             checksum,
+            epoch,
         })
     }
 }
@@ -107,72 +146,240 @@ impl MessageHeader {
 pub struct Message {
     pub header: MessageHeader,
     pub payload: Vec<u8, MAX_MESSAGE_SIZE>,
+    pub auth: AuthStatus,
+    // How a received message arrived, for relaying it; empty for our own
+    pub layout: FragmentLayout,
 }
 
 impl Message {
-    pub fn new(msg_type: MessageType, sender_id: [u8; 6], sequence: u16, payload: &[u8]) -> Result<Self, ()> {
-        if payload.len() > MAX_MESSAGE_SIZE {
-            return Err(());
-        }
-
+    pub fn new(msg_type: MessageType, sender_id: [u8; 6], sequence: u16, payload: &[u8]) -> Result<Self, MessageError> {
         let header = MessageHeader::new(msg_type, sender_id, sequence);
-        let mut msg_payload = Vec::new();
-        msg_payload.extend_from_slice(payload).map_err(|_| ())?;
+        let msg_payload = Vec::from_slice(payload).map_err(|_| MessageError::TooLarge)?;
 
         Ok(Self {
             header,
             payload: msg_payload,
+            auth: AuthStatus::Unauthenticated,
+            layout: FragmentLayout::default(),
         })
     }
 
-    pub fn calculate_fragments(&self) -> u8 {
+    // For one peer only: the others relay it but don't deliver or ACK it
+    pub fn new_directed(
+        msg_type: MessageType,
+        sender_id: [u8; 6],
+        recipient: [u8; RECIPIENT_SIZE],
+        sequence: u16,
+        body: &[u8],
+    ) -> Result<Self, MessageError> {
+        let mut message = Self::new(msg_type, sender_id, sequence, &recipient)?;
+        message.payload.extend_from_slice(body).map_err(|_| MessageError::TooLarge)?;
+        message.header.flags |= FLAG_DIRECTED;
+        Ok(message)
+    }
+
+    // None for broadcasts
+    pub fn recipient(&self) -> Option<[u8; RECIPIENT_SIZE]> {
+        if self.header.flags & FLAG_DIRECTED == 0 {
+            return None;
+        }
+        self.payload.get(..RECIPIENT_SIZE)?.try_into().ok()
+    }
+
+    // True unless the message is directed at someone else
+    pub fn is_for(&self, device_id: &[u8; 6]) -> bool {
+        self.recipient().is_none_or(|to| to == *device_id)
+    }
+
+    // The payload after any recipient prefix
+    pub fn body(&self) -> &[u8] {
+        match self.recipient() {
+            Some(_) => &self.payload[RECIPIENT_SIZE..],
+            None => &self.payload,
+        }
+    }
+
+    // This format has no timestamp, so epoch and sequence stand in for it
+    pub fn message_id(&self) -> MessageId {
+        MessageId::compute(
+            &self.header.sender_id,
+            ((self.header.epoch as u64) << 16) | self.header.sequence as u64,
+            self.header.msg_type as u8,
+            &self.payload,
+        )
+    }
+
+    pub fn calculate_fragments(&self, mtu: u16) -> u8 {
+        self.fragment_count(fragment_payload_size(mtu).max(1)).min(u8::MAX as usize) as u8
+    }
+
+    pub fn get_fragment(&self, index: u8) -> Option<Vec<u8, MAX_FRAGMENT_SIZE>> {
+        self.fragments(MAX_MTU_SIZE).ok()?.nth(index as usize)
+    }
+
+    // Fails if the payload needs more fragments than the header can count
+    pub fn fragments(&self, mtu: u16) -> Result<Fragments<'_>, MessageError> {
+        self.fragments_with_key(mtu, None)
+    }
+
+    // With a key every fragment is tagged, leaving TAG_SIZE less room for payload
+    pub fn fragments_with_key<'a>(&'a self, mtu: u16, key: Option<&'a MeshKey>) -> Result<Fragments<'a>, MessageError> {
+        let tag_size = if key.is_some() { TAG_SIZE } else { 0 };
+        let chunk_size = fragment_payload_size(mtu)
+            .checked_sub(tag_size)
+            .filter(|&size| size > 0)
+            .ok_or(MessageError::MtuTooSmall)?;
+        let count = self.fragment_count(chunk_size);
+        if count > u8::MAX as usize {
+            return Err(MessageError::TooLarge);
+        }
+
+        let mut header = self.header;
+        header.fragment_index = 0;
+        header.total_fragments = count as u8;
+        header.checksum = 0;
+        if key.is_some() {
+            header.flags |= FLAG_AUTHENTICATED;
+        } else {
+            header.flags &= !FLAG_AUTHENTICATED;
+        }
+
+        Ok(Fragments {
+            header: header.serialize(),
+            payload: &self.payload,
+            key,
+            chunk_size,
+            index: 0,
+            total: count as u8,
+        })
+    }
+
+    fn fragment_count(&self, chunk_size: usize) -> usize {
         let payload_len = self.payload.len();
         if payload_len == 0 {
             return 1;
         }
-        ((payload_len + MAX_PAYLOAD_SIZE - 1) / MAX_PAYLOAD_SIZE) as u8
+        payload_len.div_ceil(chunk_size)
     }
+}
+
+// Largest frame one notification carries at this ATT MTU
+pub fn max_frame_size(mtu: u16) -> usize {
+    (mtu.max(MIN_ATT_MTU) as usize - ATT_HEADER_SIZE).min(MAX_FRAGMENT_SIZE)
+}
+
+// Payload bytes that fit in one notification after the ATT and message headers
+pub fn fragment_payload_size(mtu: u16) -> usize {
+    max_frame_size(mtu) - HEADER_SIZE
+}
 
-    pub fn get_fragment(&self, index: u8) -> Option<Vec<u8, 244>> {
-        let total_fragments = self.calculate_fragments();
-        if index >= total_fragments {
+pub struct Fragments<'a> {
+    header: [u8; HEADER_SIZE],
+    payload: &'a [u8],
+    key: Option<&'a MeshKey>,
+    chunk_size: usize,
+    index: u8,
+    total: u8,
+}
+
+impl Iterator for Fragments<'_> {
+    type Item = Vec<u8, MAX_FRAGMENT_SIZE>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.total {
             return None;
         }
 
-        let start = (index as usize) * MAX_PAYLOAD_SIZE;
-        let end = ((index as usize + 1) * MAX_PAYLOAD_SIZE).min(self.payload.len());
-
-        let mut header = self.header;
-        header.fragment_index = index;
-        header.total_fragments = total_fragments;
+        let start = self.index as usize * self.chunk_size;
+        let end = (start + self.chunk_size).min(self.payload.len());
 
         let mut fragment = Vec::new();
-        let header_bytes = header.serialize();
-        fragment.extend_from_slice(&header_bytes).ok()?;
+        fragment.extend_from_slice(&self.header).ok()?;
+        fragment[10] = self.index;
         fragment.extend_from_slice(&self.payload[start..end]).ok()?;
 
-        // Calculate and update checksum
-        let checksum = calculate_crc16(&fragment[0..14], &fragment[16..]);
-        fragment[14] = (checksum >> 8) as u8;
-        fragment[15] = (checksum & 0xFF) as u8;
+        if let Some(key) = self.key {
+            let tag = compute_tag(key, &fragment);
+            fragment.extend_from_slice(&tag).ok()?;
+        }
+        seal_crc(&mut fragment);
 
+        self.index += 1;
         Some(fragment)
     }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.index = n.saturating_add(self.index as usize).min(self.total as usize) as u8;
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.total - self.index) as usize;
+        (remaining, Some(remaining))
+    }
 }
 
-fn calculate_crc16(header: &[u8], payload: &[u8]) -> u16 {
-    let mut crc: u16 = 0xFFFF;
-
-    for &byte in header.iter().chain(payload.iter()) {
-        crc ^= (byte as u16) << 8;
-        for _ in 0..8 {
-            if (crc & 0x8000) != 0 {
-                crc = (crc << 1) ^ 0x1021;
-            } else {
-                crc <<= 1;
-            }
+impl ExactSizeIterator for Fragments<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    #[test]
+    fn header_round_trips() {
+        let mut header = MessageHeader::new(MessageType::Relay, SENDER, 0xBEEF);
+        header.epoch = 0x1234;
+        header.flags = FLAG_RELAYED | FLAG_ACK_REQUESTED;
+        let parsed = MessageHeader::deserialize(&header.serialize()).unwrap();
+        assert_eq!(parsed.serialize(), header.serialize());
+    }
+
+    #[test]
+    fn bad_headers_say_why() {
+        let header = MessageHeader::new(MessageType::Text, SENDER, 1).serialize();
+        assert_eq!(MessageHeader::deserialize(&header[..HEADER_SIZE - 1]).err(), Some(MessageError::Truncated));
+
+        let mut bad = header;
+        bad[0] = 0x01;
+        assert_eq!(MessageHeader::deserialize(&bad).err(), Some(MessageError::UnsupportedVersion(0x01)));
+        bad = header;
+        bad[1] = 0x7F;
+        assert_eq!(MessageHeader::deserialize(&bad).err(), Some(MessageError::UnknownType(0x7F)));
+    }
+
+    #[test]
+    fn oversized_payloads_are_refused() {
+        let payload = [0u8; MAX_MESSAGE_SIZE + 1];
+        let result = Message::new(MessageType::Text, SENDER, 0, &payload);
+        assert_eq!(result.err(), Some(MessageError::TooLarge));
+    }
+
+    #[test]
+    fn keyed_fragments_need_room_for_the_tag() {
+        let key = MeshKey::new([7; 32]);
+        let message = Message::new(MessageType::Text, SENDER, 0, b"hello").unwrap();
+        // At 29 the tag takes every payload byte, below that it doesn't fit at all
+        for mtu in [MIN_ATT_MTU, 28, 29] {
+            assert_eq!(message.fragments_with_key(mtu, Some(&key)).err(), Some(MessageError::MtuTooSmall));
         }
+        assert_eq!(message.fragments_with_key(30, Some(&key)).unwrap().len(), 5);
+        // Without a key the smallest MTU still carries a couple of bytes
+        assert_eq!(message.fragments(MIN_ATT_MTU).unwrap().len(), 3);
     }
 
-    crc
-}
\ No newline at end of file
+    #[test]
+    fn fragments_are_sized_for_the_mtu() {
+        let payload = [0xAB; 600];
+        let message = Message::new(MessageType::Text, SENDER, 0, &payload).unwrap();
+        let frames: std::vec::Vec<_> = message.fragments(MAX_MTU_SIZE).unwrap().collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.len() <= MAX_FRAGMENT_SIZE));
+        for (i, frame) in frames.iter().enumerate() {
+            let header = MessageHeader::deserialize(frame).unwrap();
+            assert_eq!((header.fragment_index, header.total_fragments), (i as u8, 3));
+        }
+        assert_eq!(message.calculate_fragments(MAX_MTU_SIZE), 3);
+    }
+}
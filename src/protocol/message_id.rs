@@ -0,0 +1,35 @@
+use sha2::{Digest, Sha256};
+use crate::protocol::peer::peer_id;
+
+pub const MESSAGE_ID_SIZE: usize = 8;
+
+// Stable identifier for a message: the first 8 bytes of
+// SHA-256(sender padded to 8 bytes | timestamp u64 BE | type | payload).
+// Both wire formats derive it the same way, so it survives relays, reboots
+// and re-fragmentation, unlike the per-boot sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MessageId(pub [u8; MESSAGE_ID_SIZE]);
+
+impl MessageId {
+    pub fn compute(sender_id: &[u8], timestamp: u64, msg_type: u8, payload: &[u8]) -> Self {
+        let sender = peer_id(sender_id);
+
+        let mut hasher = Sha256::new();
+        hasher.update(sender);
+        hasher.update(timestamp.to_be_bytes());
+        hasher.update([msg_type]);
+        hasher.update(payload);
+        let digest = hasher.finalize();
+
+        let mut id = [0u8; MESSAGE_ID_SIZE];
+        id.copy_from_slice(&digest[..MESSAGE_ID_SIZE]);
+        Self(id)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut id = [0u8; MESSAGE_ID_SIZE];
+        id.copy_from_slice(bytes.get(..MESSAGE_ID_SIZE)?);
+        Some(Self(id))
+    }
+}
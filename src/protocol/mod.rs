@@ -1,11 +1,42 @@
 pub mod message;
+pub mod message_id;
+pub mod peer;
 pub mod handler;
 pub mod router;
 pub mod fragmentation;
 pub mod text;
+pub mod stream;
+pub mod dedupe;
+pub mod epoch;
+pub mod integrity;
+pub mod rng;
+pub mod reliability;
+pub mod announce;
+pub mod relay;
+pub mod gossip;
+pub mod routing;
+pub mod link_quality;
+pub mod topology;
+pub mod action;
 
-pub use message::{Message, MessageType, MessageHeader};
+pub use message::{Message, MessageError, MessageType, MessageHeader};
+pub use message_id::MessageId;
+pub use action::{Action, Actions, DropReason, PeerUpdate};
 pub use handler::MessageHandler;
-pub use router::MessageRouter;
-pub use fragmentation::FragmentAssembler;
-pub use text::TextMessage;
\ No newline at end of file
+pub use router::{MessageRouter, Relayable};
+pub use fragmentation::{FragmentAssembler, FragmentError, FragmentLayout};
+pub use text::TextMessage;
+pub use dedupe::{DedupeConfig, DuplicateFilter};
+pub use epoch::{Epoch, EpochCheck, EpochTracker};
+pub use integrity::{AuthStatus, MeshKey};
+pub use announce::{AnnounceConfig, AnnounceReason, AnnounceScheduler};
+pub use peer::PeerId;
+pub use relay::{LinkId, RelayFrame, RelayQueue, RelayTarget};
+pub use routing::{Route, RouteConfig, RouteTable};
+pub use link_quality::{LinkMetrics, LinkQualityConfig, LinkQualityTable};
+pub use topology::{Edge, TopologyConfig, TopologyGraph};
+pub use gossip::{GossipConfig, GossipPolicy};
+pub use reliability::{DeliveryEvent, ReliableSender, RetryPolicy};
+pub use stream::{FragmentSink, FragmentSource, RamSink, StreamAssembler, StreamFragments, StreamInfo};
+#[cfg(feature = "std")]
+pub use stream::FileSink;
\ No newline at end of file
@@ -0,0 +1,11 @@
+// Peers are keyed by 8 bytes, the BitchatPacket sender size. Six-byte
+// protocol::Message senders are zero-padded, matching how the BLE service
+// pads our own address.
+pub type PeerId = [u8; 8];
+
+pub fn peer_id(sender_id: &[u8]) -> PeerId {
+    let mut id = [0u8; 8];
+    let len = sender_id.len().min(8);
+    id[..len].copy_from_slice(&sender_id[..len]);
+    id
+}
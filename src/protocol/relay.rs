@@ -0,0 +1,192 @@
+use heapless::Vec;
+use crate::protocol::message::MAX_FRAGMENT_SIZE;
+use crate::protocol::message_id::MessageId;
+
+// Room for one message of MAX_FRAGMENTS_PER_MESSAGE fragments and then some
+const MAX_PENDING_RELAYS: usize = 16;
+
+// One BLE connection (or other transport). Floods go out on every link but
+// the one a frame arrived on; routed frames only on the next hop's link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkId(pub u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RelayTarget {
+    AllExcept(LinkId),
+    Link(LinkId),
+}
+
+impl RelayTarget {
+    pub fn includes(&self, link: LinkId) -> bool {
+        match *self {
+            RelayTarget::AllExcept(except) => link != except,
+            RelayTarget::Link(only) => link == only,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RelayError {
+    QueueFull,
+    FrameTooLarge,
+}
+
+pub struct RelayFrame {
+    pub id: MessageId,
+    pub from: LinkId,
+    pub target: RelayTarget,
+    pub frame: Vec<u8, MAX_FRAGMENT_SIZE>,
+    // Copies of the same frame overheard while this one waited
+    pub duplicates_heard: u8,
+}
+
+struct PendingRelay {
+    due_ms: u64,
+    relay: RelayFrame,
+}
+
+// Encoded frames waiting out their relay delay
+pub struct RelayQueue {
+    pending: Vec<PendingRelay, MAX_PENDING_RELAYS>,
+}
+
+impl Default for RelayQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RelayQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn schedule(&mut self, id: MessageId, frame: &[u8], from: LinkId, target: RelayTarget, due_ms: u64) -> Result<(), RelayError> {
+        let frame = Vec::from_slice(frame).map_err(|_| RelayError::FrameTooLarge)?;
+        let relay = RelayFrame { id, from, target, frame, duplicates_heard: 0 };
+
+        self.pending.push(PendingRelay { due_ms, relay })
+            .map_err(|_| {
+                warn!("Relay queue full, dropping frame from link {}", from.0);
+                RelayError::QueueFull
+            })?;
+
+        info!("Relay {:?} from link {} queued until {}", id, from.0, due_ms);
+        Ok(())
+    }
+
+    // Counts an overheard copy against a pending relay, every fragment of it;
+    // false if none is pending
+    pub fn note_duplicate(&mut self, id: &MessageId) -> bool {
+        let mut pending = false;
+        for p in self.pending.iter_mut().filter(|p| p.relay.id == *id) {
+            p.relay.duplicates_heard = p.relay.duplicates_heard.saturating_add(1);
+            pending = true;
+        }
+        pending
+    }
+
+    // Earliest relay whose delay has elapsed. Frames due together leave in
+    // the order they were queued, so fragments stay in sequence.
+    pub fn pop_due(&mut self, now_ms: u64) -> Option<RelayFrame> {
+        let index = self.pending.iter()
+            .enumerate()
+            .filter(|(_, p)| p.due_ms <= now_ms)
+            .min_by_key(|(_, p)| p.due_ms)
+            .map(|(i, _)| i)?;
+        Some(self.pending.remove(index).relay)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.pending.iter().map(|p| p.due_ms).min()
+    }
+
+    // Frames that can still be scheduled
+    pub fn free(&self) -> usize {
+        self.pending.capacity() - self.pending.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> MessageId {
+        MessageId([n; 8])
+    }
+
+    #[test]
+    fn relays_leave_in_deadline_order() {
+        let mut queue = RelayQueue::new();
+        queue.schedule(id(1), &[1], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 30).unwrap();
+        queue.schedule(id(2), &[2], LinkId(0), RelayTarget::Link(LinkId(1)), 10).unwrap();
+        queue.schedule(id(2), &[3], LinkId(0), RelayTarget::Link(LinkId(1)), 10).unwrap();
+        assert_eq!(queue.next_deadline(), Some(10));
+
+        assert!(queue.pop_due(9).is_none());
+        let frames: std::vec::Vec<_> = core::iter::from_fn(|| queue.pop_due(30)).map(|r| r.frame[0]).collect();
+        assert_eq!(frames, [2, 3, 1]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn duplicates_count_against_every_fragment() {
+        let mut queue = RelayQueue::new();
+        assert!(!queue.note_duplicate(&id(1)));
+        for fragment in 0..3 {
+            queue.schedule(id(1), &[fragment], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 10).unwrap();
+        }
+        queue.schedule(id(2), &[9], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 10).unwrap();
+
+        assert!(queue.note_duplicate(&id(1)));
+        assert!(queue.note_duplicate(&id(1)));
+        while let Some(relay) = queue.pop_due(10) {
+            let expected = if relay.id == id(1) { 2 } else { 0 };
+            assert_eq!(relay.duplicates_heard, expected);
+        }
+    }
+
+    #[test]
+    fn a_full_queue_refuses_more() {
+        let mut queue = RelayQueue::new();
+        for n in 0..MAX_PENDING_RELAYS as u8 {
+            assert_eq!(queue.free(), MAX_PENDING_RELAYS - n as usize);
+            queue.schedule(id(n), &[n], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 0).unwrap();
+        }
+        assert_eq!(queue.free(), 0);
+        assert_eq!(queue.schedule(id(0xFF), &[0], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 0), Err(RelayError::QueueFull));
+        assert_eq!(
+            queue.schedule(id(0xFF), &[0; MAX_FRAGMENT_SIZE + 1], LinkId(0), RelayTarget::AllExcept(LinkId(0)), 0),
+            Err(RelayError::FrameTooLarge),
+        );
+    }
+
+    #[test]
+    fn a_default_queue_is_empty() {
+        let queue = RelayQueue::default();
+        assert!(queue.is_empty());
+        assert_eq!(queue.free(), MAX_PENDING_RELAYS);
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test]
+    fn targets_pick_their_links() {
+        assert!(RelayTarget::AllExcept(LinkId(0)).includes(LinkId(1)));
+        assert!(!RelayTarget::AllExcept(LinkId(0)).includes(LinkId(0)));
+        assert!(RelayTarget::Link(LinkId(2)).includes(LinkId(2)));
+        assert!(!RelayTarget::Link(LinkId(2)).includes(LinkId(1)));
+    }
+}
@@ -0,0 +1,230 @@
+use heapless::{Deque, Vec};
+use crate::protocol::message::Message;
+use crate::protocol::message_id::MessageId;
+use crate::protocol::rng::Rng;
+
+const MAX_PENDING: usize = 4;
+const MAX_EVENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    pub initial_timeout_ms: u32,
+    pub max_timeout_ms: u32,
+    pub max_attempts: u8,
+    // Each timeout is scaled by a random factor in 100 +/- jitter_percent
+    pub jitter_percent: u8,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_timeout_ms: 1_000,
+            max_timeout_ms: 16_000,
+            max_attempts: 5,
+            jitter_percent: 25,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeliveryEvent {
+    Delivered(MessageId),
+    Failed(MessageId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReliabilityError {
+    TableFull,
+    AlreadyPending,
+}
+
+struct Pending {
+    id: MessageId,
+    message: Message,
+    attempts: u8,
+    retry_at_ms: u64,
+}
+
+// Pending table for unicast messages awaiting an ACK. Time only advances
+// through the `now_ms` passed in, so the whole thing runs on the host.
+pub struct ReliableSender {
+    policy: RetryPolicy,
+    pending: Vec<Pending, MAX_PENDING>,
+    events: Deque<DeliveryEvent, MAX_EVENTS>,
+}
+
+impl ReliableSender {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            pending: Vec::new(),
+            events: Deque::new(),
+        }
+    }
+
+    // Call before the first transmission of `message`; retransmits are copies
+    // of it as passed in
+    pub fn track(&mut self, message: &Message, now_ms: u64, rng: &mut Rng) -> Result<MessageId, ReliabilityError> {
+        let id = message.message_id();
+        if self.pending.iter().any(|p| p.id == id) {
+            return Err(ReliabilityError::AlreadyPending);
+        }
+
+        let entry = Pending {
+            id,
+            message: message.clone(),
+            attempts: 1,
+            retry_at_ms: now_ms + self.timeout_ms(1, rng) as u64,
+        };
+        self.pending.push(entry).map_err(|_| {
+            warn!("Reliable send table full, not tracking {:?}", id);
+            ReliabilityError::TableFull
+        })?;
+        Ok(id)
+    }
+
+    pub fn on_ack(&mut self, id: &MessageId) -> bool {
+        match self.pending.iter().position(|p| p.id == *id) {
+            Some(index) => {
+                let entry = self.pending.swap_remove(index);
+                info!("Message {:?} delivered after {} attempts", id, entry.attempts);
+                self.push_event(DeliveryEvent::Delivered(*id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the next message due for retransmission, if any. Call repeatedly
+    // until it returns None. Messages out of attempts are dropped with a
+    // Failed event instead.
+    pub fn next_retransmit(&mut self, now_ms: u64, rng: &mut Rng) -> Option<&Message> {
+        loop {
+            let index = self.pending.iter().position(|p| p.retry_at_ms <= now_ms)?;
+
+            if self.pending[index].attempts >= self.policy.max_attempts {
+                let entry = self.pending.swap_remove(index);
+                warn!("Message {:?} undelivered after {} attempts", entry.id, entry.attempts);
+                self.push_event(DeliveryEvent::Failed(entry.id));
+                continue;
+            }
+
+            let attempts = self.pending[index].attempts + 1;
+            let timeout = self.timeout_ms(attempts, rng);
+            let entry = &mut self.pending[index];
+            entry.attempts = attempts;
+            entry.retry_at_ms = now_ms + timeout as u64;
+            info!("Retransmitting {:?}, attempt {}", entry.id, attempts);
+            return Some(&entry.message);
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<DeliveryEvent> {
+        self.events.pop_front()
+    }
+
+    // Earliest time next_retransmit has work to do
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.pending.iter().map(|p| p.retry_at_ms).min()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Exponential backoff: initial * 2^(attempt - 1), capped, then jittered
+    fn timeout_ms(&self, attempt: u8, rng: &mut Rng) -> u32 {
+        let shift = attempt.saturating_sub(1).min(16) as u32;
+        let base = self.policy.initial_timeout_ms
+            .saturating_mul(1 << shift)
+            .min(self.policy.max_timeout_ms);
+        let jitter = self.policy.jitter_percent.min(100) as u32;
+        let scale = rng.range(100 - jitter, 100 + jitter);
+        (base as u64 * scale as u64 / 100) as u32
+    }
+
+    fn push_event(&mut self, event: DeliveryEvent) {
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::MessageType;
+
+    const NO_JITTER: RetryPolicy = RetryPolicy {
+        initial_timeout_ms: 1_000,
+        max_timeout_ms: 4_000,
+        max_attempts: 4,
+        jitter_percent: 0,
+    };
+
+    fn message(sequence: u16) -> Message {
+        Message::new(MessageType::Text, [1; 6], sequence, b"hi").unwrap()
+    }
+
+    // Times at which `sender` retransmits, polling every millisecond up to `until`
+    fn retransmit_times(sender: &mut ReliableSender, rng: &mut Rng, until: u64) -> std::vec::Vec<u64> {
+        (0..=until).filter(|&now| sender.next_retransmit(now, rng).is_some()).collect()
+    }
+
+    #[test]
+    fn backs_off_exponentially_then_fails() {
+        let mut rng = Rng::new(1);
+        let mut sender = ReliableSender::new(NO_JITTER);
+        let id = sender.track(&message(1), 0, &mut rng).unwrap();
+
+        // 1s, then 2s, then 4s (capped) after each attempt
+        assert_eq!(retransmit_times(&mut sender, &mut rng, 20_000), [1_000, 3_000, 7_000]);
+        assert_eq!(sender.next_event(), Some(DeliveryEvent::Failed(id)));
+        assert_eq!(sender.pending_count(), 0);
+        assert_eq!(sender.next_deadline(), None);
+    }
+
+    #[test]
+    fn ack_stops_the_retries() {
+        let mut rng = Rng::new(1);
+        let mut sender = ReliableSender::new(NO_JITTER);
+        let id = sender.track(&message(1), 0, &mut rng).unwrap();
+
+        assert!(sender.next_retransmit(1_000, &mut rng).is_some());
+        assert!(sender.on_ack(&id));
+        assert!(!sender.on_ack(&id));
+        assert_eq!(sender.next_event(), Some(DeliveryEvent::Delivered(id)));
+        assert!(retransmit_times(&mut sender, &mut rng, 20_000).is_empty());
+        assert_eq!(sender.next_event(), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_the_policy() {
+        let policy = RetryPolicy { jitter_percent: 25, ..NO_JITTER };
+        let mut rng = Rng::new(42);
+        for seed in 0..50u16 {
+            let mut sender = ReliableSender::new(policy);
+            sender.track(&message(seed), 0, &mut rng).unwrap();
+            let deadline = sender.next_deadline().unwrap();
+            assert!((750..=1_250).contains(&deadline), "{}", deadline);
+        }
+    }
+
+    #[test]
+    fn table_is_bounded() {
+        let mut rng = Rng::new(1);
+        let mut sender = ReliableSender::new(RetryPolicy::default());
+        let id = sender.track(&message(0), 0, &mut rng).unwrap();
+        assert_eq!(sender.track(&message(0), 0, &mut rng), Err(ReliabilityError::AlreadyPending));
+        for sequence in 1..MAX_PENDING as u16 {
+            sender.track(&message(sequence), 0, &mut rng).unwrap();
+        }
+        assert_eq!(sender.track(&message(99), 0, &mut rng), Err(ReliabilityError::TableFull));
+        assert!(sender.on_ack(&id));
+        assert!(sender.track(&message(99), 0, &mut rng).is_ok());
+    }
+}
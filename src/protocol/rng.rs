@@ -0,0 +1,63 @@
+// Small xorshift generator for jitter and relay decisions. Seeded by the
+// caller (hardware RNG on target, a fixed seed in host runs) so behaviour is
+// reproducible wherever the seed is.
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves the all-zero state
+        Self { state: if seed == 0 { 0x2545_F491_4F6C_DD1D } else { seed } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        (x >> 32) as u32
+    }
+
+    // Uniform in [low, high]
+    pub fn range(&mut self, low: u32, high: u32) -> u32 {
+        if high <= low {
+            return low;
+        }
+        // Widened, since the full u32 range has 2^32 values
+        let span = (high - low) as u64 + 1;
+        low + (self.next_u32() as u64 % span) as u32
+    }
+
+    // True with probability per_mille / 1000
+    pub fn chance(&mut self, per_mille: u16) -> bool {
+        self.next_u32() % 1000 < per_mille as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_stays_within_its_bounds() {
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let n = rng.range(10, 20);
+            assert!((10..=20).contains(&n));
+        }
+        assert_eq!(rng.range(5, 5), 5);
+        assert_eq!(rng.range(7, 3), 7);
+    }
+
+    #[test]
+    fn full_range_does_not_overflow() {
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            rng.range(0, u32::MAX);
+        }
+        assert!(rng.range(1, u32::MAX) >= 1);
+    }
+}
@@ -1,43 +1,208 @@
-use defmt::info;
 use heapless::Vec;
-use crate::protocol::message::{Message, MessageType};
+use crate::bitchat::announce::{AnnouncePayload, MAX_ANNOUNCE_NEIGHBORS};
+use crate::bitchat::ping::append_trace_hop;
+use crate::bitchat::{BitchatPacket, PacketType};
+use crate::protocol::dedupe::{DedupeConfig, DuplicateFilter};
+use crate::protocol::fragmentation::FragmentLayout;
+use crate::protocol::message::{Message, MessageType, FLAG_RELAYED};
+use crate::protocol::message_id::MessageId;
+use crate::protocol::peer::{peer_id, PeerId};
+use crate::protocol::gossip::{GossipConfig, GossipPolicy};
+use crate::protocol::link_quality::{LinkQualityConfig, LinkQualityTable};
+use crate::protocol::relay::{LinkId, RelayFrame, RelayQueue, RelayTarget};
+use crate::protocol::routing::{RouteConfig, RouteTable};
+use crate::protocol::topology::{TopologyConfig, TopologyGraph};
+use crate::protocol::rng::Rng;
+
+const MAX_LINK_PEERS: usize = 8;
+
+// What the router needs from a frame, whichever wire format it came in
+pub trait Relayable {
+    fn origin(&self) -> PeerId;
+    fn ttl(&self) -> u8;
+    fn id(&self) -> MessageId;
+    // Point-to-point frames are never flooded
+    fn is_ack(&self) -> bool;
+    // Spends one hop and marks the frame as relayed where the format allows
+    fn mark_relayed(&mut self);
+}
+
+impl Relayable for Message {
+    fn origin(&self) -> PeerId {
+        peer_id(&self.header.sender_id)
+    }
+
+    fn ttl(&self) -> u8 {
+        self.header.ttl
+    }
+
+    fn id(&self) -> MessageId {
+        self.message_id()
+    }
+
+    // Directed ACKs find their way back over relays; undirected ones are
+    // only meant for the neighbour that sent the message
+    fn is_ack(&self) -> bool {
+        self.header.msg_type == MessageType::Ack && self.recipient().is_none()
+    }
+
+    fn mark_relayed(&mut self) {
+        self.header.ttl = self.header.ttl.saturating_sub(1);
+        self.header.flags |= FLAG_RELAYED;
+    }
+}
 
 pub struct MessageRouter {
-    device_id: [u8; 6],
+    device_id: PeerId,
     relay_enabled: bool,
-    seen_messages: Vec<(u16, [u8; 6]), 16>, // Track recent messages to prevent relay loops
+    seen_messages: DuplicateFilter, // Track recent messages to prevent relay loops
+    relay_queue: RelayQueue,
+    gossip: GossipPolicy,
+    neighbors: u8,
+    routes: RouteTable,
+    link_quality: LinkQualityTable,
+    topology: TopologyGraph,
+    // Peer at the other end of each link, once it has announced itself
+    link_peers: Vec<(LinkId, PeerId), MAX_LINK_PEERS>,
+    rng: Rng,
+    relays_suppressed: u32,
 }
 
 impl MessageRouter {
     pub fn new(device_id: [u8; 6]) -> Self {
+        Self::with_dedupe(device_id, DedupeConfig::default())
+    }
+
+    pub fn with_dedupe(device_id: [u8; 6], dedupe: DedupeConfig) -> Self {
         Self {
-            device_id,
+            device_id: peer_id(&device_id),
             relay_enabled: true,
-            seen_messages: Vec::new(),
+            seen_messages: DuplicateFilter::new(dedupe),
+            relay_queue: RelayQueue::new(),
+            gossip: GossipPolicy::new(GossipConfig::default()),
+            neighbors: 0,
+            routes: RouteTable::new(RouteConfig::default()),
+            link_quality: LinkQualityTable::new(LinkQualityConfig::default()),
+            topology: TopologyGraph::new(peer_id(&device_id), TopologyConfig::default()),
+            link_peers: Vec::new(),
+            rng: Rng::new(u64::from_be_bytes(peer_id(&device_id))),
+            relays_suppressed: 0,
+        }
+    }
+
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn set_gossip_config(&mut self, config: GossipConfig) {
+        self.gossip = GossipPolicy::new(config);
+    }
+
+    // Current number of direct neighbours; drives forward probability and delay
+    pub fn set_neighbor_count(&mut self, neighbors: u8) {
+        self.neighbors = neighbors;
+    }
+
+    pub fn set_route_config(&mut self, config: RouteConfig) {
+        self.routes.set_config(config);
+    }
+
+    pub fn routes(&self) -> &RouteTable {
+        &self.routes
+    }
+
+    pub fn set_topology_config(&mut self, config: TopologyConfig) {
+        self.topology.set_config(config);
+    }
+
+    pub fn topology(&self) -> &TopologyGraph {
+        &self.topology
+    }
+
+    // Records the reverse path to a packet's originator and, for announces,
+    // the neighbour list it carries
+    pub fn observe(&mut self, packet: &BitchatPacket, from: LinkId, now_ms: u64) {
+        if packet.sender_id == self.device_id {
+            return;
+        }
+        self.routes.learn(packet.sender_id, from, packet.ttl, now_ms);
+
+        if packet.packet_type == PacketType::Announce {
+            // Centrals announce as soon as they connect, so the first announce
+            // on a link is taken to be from the peer at its other end
+            if self.peer_on(from).is_none() {
+                self.set_link_peer(from, packet.sender_id, now_ms);
+            }
+            let announce = AnnouncePayload::decode(&packet.payload);
+            self.topology.observe_neighbors(packet.sender_id, &announce.neighbors, now_ms);
+        }
+    }
+
+    pub fn set_link_peer(&mut self, link: LinkId, peer: PeerId, now_ms: u64) {
+        self.link_peers.retain(|(l, _)| *l != link);
+        let _ = self.link_peers.push((link, peer));
+        self.refresh_local_edges(now_ms);
+    }
+
+    pub fn peer_on(&self, link: LinkId) -> Option<PeerId> {
+        self.link_peers.iter().find(|(l, _)| *l == link).map(|(_, p)| *p)
+    }
+
+    fn link_to(&self, peer: &PeerId) -> Option<LinkId> {
+        self.link_peers.iter().find(|(_, p)| p == peer).map(|(l, _)| *l)
+    }
+
+    // Direct neighbours to list in our announce, empty if sharing is off
+    pub fn neighbor_ids(&self) -> Vec<PeerId, MAX_ANNOUNCE_NEIGHBORS> {
+        if !self.topology.config().share_neighbors {
+            return Vec::new();
         }
+        self.link_peers.iter().map(|(_, p)| *p).take(MAX_ANNOUNCE_NEIGHBORS).collect()
     }
 
-    pub fn should_relay(&mut self, message: &Message) -> bool {
-        // Don't relay our own messages
-        if message.header.sender_id == self.device_id {
+    // Our own edges are refreshed whenever we announce or a link changes
+    pub fn refresh_local_edges(&mut self, now_ms: u64) {
+        let neighbors: Vec<PeerId, MAX_LINK_PEERS> = self.link_peers.iter().map(|(_, p)| *p).collect();
+        self.topology.observe_neighbors(self.device_id, &neighbors, now_ms);
+    }
+
+    pub fn link_quality(&self) -> &LinkQualityTable {
+        &self.link_quality
+    }
+
+    // The transport feeds RSSI, ACK and write outcomes in here
+    pub fn link_quality_mut(&mut self) -> &mut LinkQualityTable {
+        &mut self.link_quality
+    }
+
+    // Sending on `link` failed or it went away; routes through it are void
+    pub fn on_link_failed(&mut self, link: LinkId) {
+        self.routes.invalidate_link(link);
+        self.link_quality.forget(link);
+        self.link_peers.retain(|(l, _)| *l != link);
+    }
+
+    pub fn should_relay<F: Relayable>(&mut self, frame: &F, now_ms: u64) -> bool {
+        // Don't relay our own messages, even when a neighbour echoes them back
+        if frame.origin() == self.device_id {
             return false;
         }
 
         // Don't relay if TTL is exhausted
-        if message.header.ttl == 0 {
+        if frame.ttl() == 0 {
             info!("Message TTL exhausted, not relaying");
             return false;
         }
 
         // Don't relay if we've already relayed this message (prevent loops)
-        let key = (message.header.sequence, message.header.sender_id);
-        if self.seen_messages.iter().any(|seen| *seen == key) {
-            info!("Already relayed message seq {}, not relaying again", message.header.sequence);
+        let id = frame.id();
+        if self.seen_messages.contains(&id) {
+            info!("Already relayed message {:?}, not relaying again", id);
             return false;
         }
 
-        // Don't relay ACK messages (they're point-to-point)
-        if message.header.msg_type == MessageType::Ack {
+        // Don't relay link-local ACKs (they're point-to-point)
+        if frame.is_ack() {
             return false;
         }
 
@@ -48,23 +213,169 @@ impl MessageRouter {
         }
 
         // Record that we're relaying this message
-        if self.seen_messages.len() >= 16 {
-            self.seen_messages.remove(0);
-        }
-        let _ = self.seen_messages.push(key);
+        self.seen_messages.insert(&id, now_ms);
 
-        info!("Will relay message seq {} with TTL {}",
-            message.header.sequence, message.header.ttl - 1);
+        info!("Will relay message {:?} with TTL {}", id, frame.ttl() - 1);
 
         true
     }
 
-    pub fn prepare_for_relay(&self, message: &mut Message) {
-        // Decrement TTL
-        message.header.ttl = message.header.ttl.saturating_sub(1);
+    pub fn prepare_for_relay<F: Relayable>(&self, frame: &mut F) {
+        frame.mark_relayed();
+    }
+
+    // Link for a packet we originate to `peer`, None if it has to be flooded
+    pub fn link_towards(&self, peer: &PeerId, now_ms: u64) -> Option<LinkId> {
+        let max_etx = self.routes.config().max_link_etx_x100;
+        self.routes.next_hop(peer, now_ms)
+            .or_else(|| self.topology.first_hop(peer).and_then(|hop| self.link_to(&hop)))
+            .filter(|link| self.link_quality.etx_x100(*link) <= max_etx)
+    }
 
-        // Could add relay node info to flags if needed
-        message.header.flags |= 0x01; // Mark as relayed
+    // Where an addressed packet should go next. Source routes win over
+    // learned reverse paths, which win over the announced topology;
+    // None means no usable route, so flood.
+    fn directed_target(&self, packet: &BitchatPacket, from: LinkId, now_ms: u64) -> Option<LinkId> {
+        let destination = match packet.route.is_empty() {
+            false => packet.route_next_hop(&self.device_id)?,
+            true => packet.directed_to()?,
+        };
+        self.link_towards(&destination, now_ms).filter(|link| *link != from)
+    }
+
+    // Checks a received packet and, if it should travel further, queues a
+    // re-encoded copy after a random delay: to the next hop's link when a
+    // fresh route is known, else to every link except `from`.
+    // Copies of a packet already waiting count towards its suppression.
+    // Returns true if a relay was queued.
+    pub fn relay_packet(&mut self, packet: &BitchatPacket, from: LinkId, now_ms: u64) -> bool {
+        self.observe(packet, from, now_ms);
+
+        let id = packet.message_id();
+        if self.relay_queue.note_duplicate(&id) {
+            info!("Overheard pending relay {:?} again", id);
+            return false;
+        }
+
+        // Packets addressed to us end here; source-routed ones only travel along their route
+        if packet.directed_to() == Some(self.device_id) {
+            return false;
+        }
+        if !packet.route.is_empty() && !packet.route.contains(&self.device_id) {
+            info!("Not on source route of {:?}, not relaying", id);
+            return false;
+        }
+
+        if !self.should_relay(packet, now_ms) {
+            return false;
+        }
+
+        let (target, delay) = match self.directed_target(packet, from, now_ms) {
+            Some(link) => {
+                info!("Routing {:?} to link {}", id, link.0);
+                (RelayTarget::Link(link), self.gossip.config().base_delay_ms)
+            }
+            // Gossip: in dense neighbourhoods only some nodes forward. The ID stays
+            // recorded either way so later copies aren't reconsidered.
+            None => match self.gossip.decide(self.neighbors, &mut self.rng) {
+                Some(delay) => (RelayTarget::AllExcept(from), delay),
+                None => {
+                    info!("Gossip: not forwarding {:?} ({} neighbors)", id, self.neighbors);
+                    return false;
+                }
+            },
+        };
+
+        let mut copy = packet.clone();
+        self.prepare_for_relay(&mut copy);
+        append_trace_hop(&mut copy, self.device_id, now_ms);
+        let frame = match copy.encode() {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Failed to re-encode packet for relay: {}", e);
+                return false;
+            }
+        };
+
+        self.relay_queue.schedule(id, &frame, from, target, now_ms + delay as u64).is_ok()
+    }
+
+    // Our own format: whether `message` should travel on and if so, where to
+    // and after what delay, decided as relay_packet does. Directed messages
+    // go towards their recipient when a route is known. The caller queues
+    // the frames with queue_relay().
+    pub fn plan_relay(&mut self, message: &Message, from: LinkId, now_ms: u64) -> Option<(RelayTarget, u32)> {
+        let id = message.message_id();
+        if self.relay_queue.free() < message.layout.len() {
+            warn!("No room to queue {} fragments of {:?}", message.layout.len(), id);
+            return None;
+        }
+        if !self.should_relay(message, now_ms) {
+            return None;
+        }
+
+        let route = message.recipient()
+            .and_then(|to| self.link_towards(&peer_id(&to), now_ms))
+            .filter(|link| *link != from);
+        match route {
+            Some(link) => {
+                info!("Routing {:?} to link {}", id, link.0);
+                Some((RelayTarget::Link(link), self.gossip.config().base_delay_ms))
+            }
+            None => match self.gossip.decide(self.neighbors, &mut self.rng) {
+                Some(delay) => Some((RelayTarget::AllExcept(from), delay)),
+                None => {
+                    info!("Gossip: not forwarding {:?} ({} neighbors)", id, self.neighbors);
+                    None
+                }
+            },
+        }
+    }
+
+    // Queues the frames `message` arrived in, as `layout` recorded them, to
+    // leave from next_relay() at `due_ms`. The frames keep the sender's tag,
+    // so nodes without the mesh key relay authenticated messages without
+    // stripping or forging it. Returns true if all of them were queued.
+    pub fn queue_relay(
+        &mut self,
+        message: &Message,
+        layout: &FragmentLayout,
+        from: LinkId,
+        target: RelayTarget,
+        due_ms: u64,
+    ) -> bool {
+        let id = message.message_id();
+        layout.relay_frames(message)
+            .all(|frame| self.relay_queue.schedule(id, &frame, from, target, due_ms).is_ok())
+    }
+
+    // A copy of a message we may have queued was heard again
+    pub fn note_duplicate(&mut self, id: &MessageId) -> bool {
+        self.relay_queue.note_duplicate(id)
+    }
+
+    // Next relay whose delay has elapsed; send it to the links in its target.
+    // Floods drowned out by copies overheard while waiting are dropped here.
+    pub fn next_relay(&mut self, now_ms: u64) -> Option<RelayFrame> {
+        while let Some(relay) = self.relay_queue.pop_due(now_ms) {
+            let flooded = matches!(relay.target, RelayTarget::AllExcept(_));
+            if flooded && self.gossip.suppressed(relay.duplicates_heard) {
+                info!("Suppressing relay {:?}, heard {} copies", relay.id, relay.duplicates_heard);
+                self.relays_suppressed = self.relays_suppressed.wrapping_add(1);
+                continue;
+            }
+            return Some(relay);
+        }
+        None
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.relay_queue.next_deadline()
+    }
+
+    // Queued relays dropped by next_relay() because enough copies were overheard
+    pub fn relays_suppressed(&self) -> u32 {
+        self.relays_suppressed
     }
 
     pub fn is_for_us(&self, message: &Message) -> bool {
@@ -80,8 +391,94 @@ impl MessageRouter {
         }
     }
 
+    pub fn relay_enabled(&self) -> bool {
+        self.relay_enabled
+    }
+
     pub fn set_relay_enabled(&mut self, enabled: bool) {
         self.relay_enabled = enabled;
         info!("Relay mode: {}", if enabled { "enabled" } else { "disabled" });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: [u8; 6] = [0x5e, 0x11, 0, 0, 0, 1];
+    const PEER: PeerId = [0x5e, 0x11, 0, 0, 0, 2, 0, 0];
+    const FAR: PeerId = [0x5e, 0x11, 0, 0, 0, 3, 0, 0];
+
+    fn text(sender: PeerId, timestamp: u64) -> BitchatPacket {
+        BitchatPacket::create_text(sender, timestamp, b"hi").unwrap()
+    }
+
+    #[test]
+    fn each_packet_is_relayed_once() {
+        let mut router = MessageRouter::new(US);
+        let packet = text(PEER, 1);
+        assert!(router.should_relay(&packet, 0));
+        assert!(!router.should_relay(&packet, 0));
+        assert!(router.should_relay(&text(PEER, 2), 0));
+    }
+
+    #[test]
+    fn own_expired_and_disabled_packets_stay_put() {
+        let mut router = MessageRouter::new(US);
+        assert!(!router.should_relay(&text(peer_id(&US), 1), 0));
+
+        let mut expired = text(PEER, 2);
+        expired.ttl = 0;
+        assert!(!router.should_relay(&expired, 0));
+
+        router.set_relay_enabled(false);
+        assert!(!router.should_relay(&text(PEER, 3), 0));
+    }
+
+    #[test]
+    fn floods_wait_and_skip_the_incoming_link() {
+        let mut router = MessageRouter::new(US);
+        assert!(router.relay_packet(&text(PEER, 1), LinkId(0), 0));
+        assert!(router.next_relay(0).is_none());
+
+        let relay = router.next_relay(1_000).unwrap();
+        assert_eq!(relay.target, RelayTarget::AllExcept(LinkId(0)));
+        let copy = BitchatPacket::decode(&relay.frame).unwrap();
+        assert_eq!(copy.ttl, text(PEER, 1).ttl - 1);
+    }
+
+    #[test]
+    fn directed_packets_follow_the_learned_route() {
+        let mut router = MessageRouter::new(US);
+        // FAR was heard on link 2
+        router.relay_packet(&text(FAR, 1), LinkId(2), 0);
+        while router.next_relay(1_000).is_some() {}
+
+        let mut packet = text(PEER, 2);
+        packet.recipient_id = Some(FAR);
+        assert!(router.relay_packet(&packet, LinkId(0), 1_000));
+        let relay = router.next_relay(2_000).unwrap();
+        assert_eq!(relay.target, RelayTarget::Link(LinkId(2)));
+    }
+
+    #[test]
+    fn overheard_copies_suppress_a_pending_flood() {
+        let mut router = MessageRouter::new(US);
+        let packet = text(PEER, 1);
+        assert!(router.relay_packet(&packet, LinkId(0), 0));
+        assert!(!router.relay_packet(&packet, LinkId(1), 1));
+        assert!(!router.relay_packet(&packet, LinkId(2), 2));
+
+        assert!(router.next_relay(1_000).is_none());
+        assert_eq!(router.relays_suppressed(), 1);
+    }
+
+    #[test]
+    fn failed_links_lose_their_routes() {
+        let mut router = MessageRouter::new(US);
+        router.relay_packet(&text(FAR, 1), LinkId(2), 0);
+        assert_eq!(router.link_towards(&FAR, 0), Some(LinkId(2)));
+        router.on_link_failed(LinkId(2));
+        assert_eq!(router.link_towards(&FAR, 0), None);
+    }
+}
@@ -0,0 +1,183 @@
+use heapless::FnvIndexMap;
+use crate::protocol::peer::PeerId;
+use crate::protocol::relay::LinkId;
+
+const MAX_ROUTES: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RouteConfig {
+    // A reverse path not confirmed by traffic for this long is no longer trusted
+    pub route_timeout_ms: u32,
+    // Routes over links worse than this (ETX x100) are skipped in favour of flooding
+    pub max_link_etx_x100: u16,
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        Self {
+            route_timeout_ms: 120_000,
+            max_link_etx_x100: 400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Route {
+    pub link: LinkId,
+    // Remaining TTL the packet arrived with; higher means fewer hops away
+    pub ttl: u8,
+    pub updated_ms: u64,
+}
+
+// Reverse paths learned from observed traffic: a packet from X arriving on
+// link L means X can be reached through L.
+pub struct RouteTable {
+    config: RouteConfig,
+    routes: FnvIndexMap<PeerId, Route, MAX_ROUTES>,
+}
+
+impl RouteTable {
+    pub fn new(config: RouteConfig) -> Self {
+        Self {
+            config,
+            routes: FnvIndexMap::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: RouteConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &RouteConfig {
+        &self.config
+    }
+
+    pub fn learn(&mut self, origin: PeerId, link: LinkId, ttl: u8, now_ms: u64) {
+        if let Some(route) = self.routes.get_mut(&origin) {
+            // Keep the shorter path unless the old one went stale or is the same link
+            if route.link == link || ttl >= route.ttl || !Self::fresh(&self.config, route, now_ms) {
+                *route = Route { link, ttl, updated_ms: now_ms };
+            }
+            return;
+        }
+
+        if self.routes.len() >= MAX_ROUTES {
+            self.expire(now_ms);
+        }
+        if self.routes.len() >= MAX_ROUTES {
+            let oldest = self.routes.iter().min_by_key(|(_, r)| r.updated_ms).map(|(k, _)| *k);
+            if let Some(oldest) = oldest {
+                self.routes.remove(&oldest);
+            }
+        }
+        let _ = self.routes.insert(origin, Route { link, ttl, updated_ms: now_ms });
+    }
+
+    // Link towards `peer`, if a fresh route is known
+    pub fn next_hop(&self, peer: &PeerId, now_ms: u64) -> Option<LinkId> {
+        self.routes.get(peer)
+            .filter(|route| Self::fresh(&self.config, route, now_ms))
+            .map(|route| route.link)
+    }
+
+    pub fn route(&self, peer: &PeerId) -> Option<&Route> {
+        self.routes.get(peer)
+    }
+
+    // The link stopped working; nothing should be routed through it any more
+    pub fn invalidate_link(&mut self, link: LinkId) {
+        let before = self.routes.len();
+        self.routes.retain(|_, route| route.link != link);
+        info!("Link {} failed, dropped {} routes", link.0, before - self.routes.len());
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.routes.remove(peer);
+    }
+
+    pub fn expire(&mut self, now_ms: u64) {
+        let config = self.config;
+        self.routes.retain(|_, route| Self::fresh(&config, route, now_ms));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Route)> {
+        self.routes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    fn fresh(config: &RouteConfig, route: &Route, now_ms: u64) -> bool {
+        now_ms.saturating_sub(route.updated_ms) < config.route_timeout_ms as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: PeerId = [0xA0; 8];
+
+    #[test]
+    fn learns_the_link_a_peer_was_heard_on() {
+        let mut routes = RouteTable::new(RouteConfig::default());
+        assert_eq!(routes.next_hop(&PEER, 0), None);
+        routes.learn(PEER, LinkId(1), 5, 0);
+        assert_eq!(routes.next_hop(&PEER, 0), Some(LinkId(1)));
+        assert_eq!(routes.len(), 1);
+    }
+
+    #[test]
+    fn keeps_the_shorter_path_while_it_is_fresh() {
+        let mut routes = RouteTable::new(RouteConfig::default());
+        routes.learn(PEER, LinkId(1), 5, 0);
+        // Arrived with less TTL left, so more hops away
+        routes.learn(PEER, LinkId(2), 3, 10);
+        assert_eq!(routes.next_hop(&PEER, 10), Some(LinkId(1)));
+        routes.learn(PEER, LinkId(2), 6, 20);
+        assert_eq!(routes.next_hop(&PEER, 20), Some(LinkId(2)));
+    }
+
+    #[test]
+    fn stale_routes_are_not_used() {
+        let config = RouteConfig { route_timeout_ms: 1_000, ..RouteConfig::default() };
+        let mut routes = RouteTable::new(config);
+        routes.learn(PEER, LinkId(1), 5, 0);
+        assert_eq!(routes.next_hop(&PEER, 999), Some(LinkId(1)));
+        assert_eq!(routes.next_hop(&PEER, 1_000), None);
+
+        // A longer path replaces a stale one
+        routes.learn(PEER, LinkId(2), 1, 1_000);
+        assert_eq!(routes.next_hop(&PEER, 1_000), Some(LinkId(2)));
+        routes.expire(2_000);
+        assert!(routes.is_empty());
+    }
+
+    #[test]
+    fn failed_links_take_their_routes_with_them() {
+        let mut routes = RouteTable::new(RouteConfig::default());
+        routes.learn(PEER, LinkId(1), 5, 0);
+        routes.learn([0xB0; 8], LinkId(2), 5, 0);
+        routes.invalidate_link(LinkId(1));
+        assert_eq!(routes.next_hop(&PEER, 0), None);
+        assert_eq!(routes.next_hop(&[0xB0; 8], 0), Some(LinkId(2)));
+    }
+
+    #[test]
+    fn a_full_table_drops_the_oldest_route() {
+        let mut routes = RouteTable::new(RouteConfig::default());
+        for n in 0..=MAX_ROUTES as u8 {
+            routes.learn([n; 8], LinkId(0), 5, n as u64);
+        }
+        assert_eq!(routes.len(), MAX_ROUTES);
+        assert!(routes.route(&[0; 8]).is_none());
+        assert!(routes.route(&[MAX_ROUTES as u8; 8]).is_some());
+    }
+}
@@ -0,0 +1,642 @@
+use heapless::Vec;
+use sha2::{Digest, Sha256};
+use crate::protocol::integrity::seal_crc;
+use crate::protocol::message::{
+    fragment_payload_size, Message, MessageHeader, FLAG_AUTHENTICATED, FLAG_STREAM, HEADER_SIZE,
+    MAX_FRAGMENT_SIZE,
+};
+
+// Streamed payloads bypass MAX_MESSAGE_SIZE: fragments are written straight to a
+// sink and only the 16-bit fragment count and the sink capacity bound their size.
+//
+// Every stream fragment carries, after the normal message header:
+//   index u16 | total u16 | offset u32
+// Fragment 0 is the manifest and holds total length u32 | SHA-256 digest [32].
+// Fragments 1..total carry data at `offset`.
+pub const STREAM_HEADER_SIZE: usize = 8;
+pub const MANIFEST_SIZE: usize = 4 + DIGEST_SIZE;
+pub const DIGEST_SIZE: usize = 32;
+pub const MAX_STREAM_FRAGMENTS: usize = 4096; // Bounds the received-bitmap, ~900KB at full MTU
+
+const BITMAP_SIZE: usize = MAX_STREAM_FRAGMENTS / 8;
+const VERIFY_CHUNK_SIZE: usize = 64;
+// A stream that hasn't had a fragment for this long gives way to a new one
+pub const STREAM_IDLE_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SinkError {
+    Unsupported,
+    OutOfSpace,
+    Io,
+    // Earlier writes haven't reached the medium yet; the data was not taken
+    Busy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamError {
+    Malformed,
+    NotStarted,
+    // Another sender's stream is still in progress
+    Busy,
+    // The MTU leaves no room for data, or for the manifest
+    MtuTooSmall,
+    // A fragment came out larger than MAX_FRAGMENT_SIZE
+    Overflow,
+    TooManyFragments,
+    IndexOutOfBounds,
+    OutOfRange,
+    HashMismatch,
+    Sink(SinkError),
+}
+
+impl From<SinkError> for StreamError {
+    fn from(e: SinkError) -> Self {
+        StreamError::Sink(e)
+    }
+}
+
+// Destination for a reassembled stream. Fragments may arrive in any order, so
+// writes are positional and the final hash check reads the data back.
+pub trait FragmentSink {
+    fn begin(&mut self, total_len: u32) -> Result<(), SinkError>;
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), SinkError>;
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError>;
+    fn finish(&mut self) -> Result<(), SinkError>;
+    fn abort(&mut self);
+}
+
+// Used when the caller has nowhere to put a stream
+pub struct NullSink;
+
+impl FragmentSink for NullSink {
+    fn begin(&mut self, _total_len: u32) -> Result<(), SinkError> {
+        Err(SinkError::Unsupported)
+    }
+
+    fn write_at(&mut self, _offset: u32, _data: &[u8]) -> Result<(), SinkError> {
+        Err(SinkError::Unsupported)
+    }
+
+    fn read_at(&mut self, _offset: u32, _buf: &mut [u8]) -> Result<(), SinkError> {
+        Err(SinkError::Unsupported)
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        Err(SinkError::Unsupported)
+    }
+
+    fn abort(&mut self) {}
+}
+
+// Keeps a stream in RAM, for the simulator and tests; the firmware writes
+// streams to flash with storage::FlashSink. Streams longer than N are refused
+// at the manifest. The data stays readable until the next stream begins.
+pub struct RamSink<const N: usize> {
+    buf: Vec<u8, N>,
+}
+
+impl<const N: usize> RamSink<N> {
+    pub const fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.buf.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl<const N: usize> Default for RamSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FragmentSink for RamSink<N> {
+    fn begin(&mut self, total_len: u32) -> Result<(), SinkError> {
+        self.buf.clear();
+        self.buf.resize(total_len as usize, 0).map_err(|_| SinkError::OutOfSpace)
+    }
+
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), SinkError> {
+        let start = offset as usize;
+        let dst = self.buf.get_mut(start..start + data.len()).ok_or(SinkError::OutOfSpace)?;
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+        let start = offset as usize;
+        let src = self.buf.get(start..start + buf.len()).ok_or(SinkError::OutOfSpace)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.buf.clear();
+    }
+}
+
+// Writes a stream to a file, for host tools and tests. A failed or aborted
+// stream leaves a truncated file behind.
+#[cfg(feature = "std")]
+pub struct FileSink {
+    file: std::fs::File,
+    len: u32,
+}
+
+#[cfg(feature = "std")]
+impl FileSink {
+    pub fn create(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { file, len: 0 })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(feature = "std")]
+impl FragmentSink for FileSink {
+    fn begin(&mut self, total_len: u32) -> Result<(), SinkError> {
+        self.file.set_len(total_len as u64).map_err(|_| SinkError::Io)?;
+        self.len = total_len;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), SinkError> {
+        use std::io::{Seek, SeekFrom, Write};
+        if offset as u64 + data.len() as u64 > self.len as u64 {
+            return Err(SinkError::OutOfSpace);
+        }
+        self.file.seek(SeekFrom::Start(offset as u64)).map_err(|_| SinkError::Io)?;
+        self.file.write_all(data).map_err(|_| SinkError::Io)
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+        use std::io::{Read, Seek, SeekFrom};
+        self.file.seek(SeekFrom::Start(offset as u64)).map_err(|_| SinkError::Io)?;
+        self.file.read_exact(buf).map_err(|_| SinkError::Io)
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        self.file.sync_all().map_err(|_| SinkError::Io)
+    }
+
+    fn abort(&mut self) {
+        let _ = self.file.set_len(0);
+        self.len = 0;
+    }
+}
+
+// Something a stream can be sent from, e.g. a flash region or a RAM slice
+pub trait FragmentSource {
+    fn len(&self) -> u32;
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl FragmentSource for &[u8] {
+    fn len(&self) -> u32 {
+        <[u8]>::len(self) as u32
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+        let start = offset as usize;
+        let src = self.get(start..start + buf.len()).ok_or(SinkError::OutOfSpace)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamInfo {
+    pub sender_id: [u8; 6],
+    pub sequence: u16,
+    pub total_len: u32,
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+struct ActiveStream {
+    header: MessageHeader,
+    info: StreamInfo,
+    total_fragments: u16,
+    received: [u8; BITMAP_SIZE],
+    received_count: u16,
+    last_fragment_ms: u64,
+}
+
+// Reassembles one stream at a time. Streams are not relayed, so only peers
+// one hop away can be sending to us.
+pub struct StreamAssembler {
+    active: Option<ActiveStream>,
+}
+
+impl StreamAssembler {
+    pub fn new() -> Self {
+        Self { active: None }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub fn abort<S: FragmentSink>(&mut self, sink: &mut S) {
+        if self.active.take().is_some() {
+            sink.abort();
+        }
+    }
+
+    pub fn add_fragment<S: FragmentSink>(
+        &mut self,
+        header: MessageHeader,
+        payload: &[u8],
+        now_ms: u64,
+        sink: &mut S,
+    ) -> Result<Option<StreamInfo>, StreamError> {
+        if payload.len() < STREAM_HEADER_SIZE {
+            return Err(StreamError::Malformed);
+        }
+
+        let index = u16::from_be_bytes([payload[0], payload[1]]);
+        let total = u16::from_be_bytes([payload[2], payload[3]]);
+        let offset = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+        let data = &payload[STREAM_HEADER_SIZE..];
+
+        if index == 0 {
+            return self.start(header, total, data, now_ms, sink).map(|_| None);
+        }
+
+        let stream = match self.active.as_mut() {
+            Some(s) if s.info.sender_id == header.sender_id && s.info.sequence == header.sequence => s,
+            _ => {
+                warn!("Stream fragment {} arrived before its manifest", index);
+                return Err(StreamError::NotStarted);
+            }
+        };
+
+        if total != stream.total_fragments {
+            return Err(StreamError::Malformed);
+        }
+        if index >= stream.total_fragments {
+            return Err(StreamError::IndexOutOfBounds);
+        }
+        if offset as u64 + data.len() as u64 > stream.info.total_len as u64 {
+            return Err(StreamError::OutOfRange);
+        }
+
+        let (byte, bit) = (index as usize / 8, 1u8 << (index % 8));
+        if stream.received[byte] & bit != 0 {
+            info!("Duplicate stream fragment {} ignored", index);
+            return Ok(None);
+        }
+
+        sink.write_at(offset, data)?;
+        stream.received[byte] |= bit;
+        stream.received_count += 1;
+        stream.last_fragment_ms = now_ms;
+
+        if stream.received_count < stream.total_fragments {
+            return Ok(None);
+        }
+
+        let stream = self.active.take().ok_or(StreamError::NotStarted)?;
+        let digest = match digest_of(stream.info.total_len, |o, buf| sink.read_at(o, buf)) {
+            Ok(digest) => digest,
+            Err(e) => {
+                sink.abort();
+                return Err(e.into());
+            }
+        };
+        if digest != stream.info.digest {
+            warn!("Stream seq {} failed end-to-end hash check", stream.header.sequence);
+            sink.abort();
+            return Err(StreamError::HashMismatch);
+        }
+
+        sink.finish()?;
+        info!("Stream complete: {} bytes in {} fragments",
+            stream.info.total_len, stream.total_fragments);
+        Ok(Some(stream.info))
+    }
+
+    fn start<S: FragmentSink>(
+        &mut self,
+        header: MessageHeader,
+        total: u16,
+        manifest: &[u8],
+        now_ms: u64,
+        sink: &mut S,
+    ) -> Result<(), StreamError> {
+        if manifest.len() < MANIFEST_SIZE || total == 0 {
+            return Err(StreamError::Malformed);
+        }
+        if total as usize > MAX_STREAM_FRAGMENTS {
+            warn!("Stream of {} fragments exceeds limit {}", total, MAX_STREAM_FRAGMENTS);
+            return Err(StreamError::TooManyFragments);
+        }
+
+        if let Some(active) = &self.active {
+            if active.info.sender_id == header.sender_id && active.info.sequence == header.sequence {
+                info!("Duplicate stream manifest ignored");
+                return Ok(());
+            }
+            // First come, first served, so one peer can't keep restarting
+            // and starve the others; a stalled stream times out
+            if now_ms.saturating_sub(active.last_fragment_ms) < STREAM_IDLE_TIMEOUT_MS {
+                info!("Stream seq {} in progress, refusing stream seq {}",
+                    active.info.sequence, header.sequence);
+                return Err(StreamError::Busy);
+            }
+            warn!("Abandoning stalled stream seq {} for stream seq {}",
+                active.info.sequence, header.sequence);
+            self.abort(sink);
+        }
+
+        let total_len = u32::from_be_bytes([manifest[0], manifest[1], manifest[2], manifest[3]]);
+        let mut digest = [0u8; DIGEST_SIZE];
+        digest.copy_from_slice(&manifest[4..MANIFEST_SIZE]);
+
+        sink.begin(total_len)?;
+
+        let mut stream = ActiveStream {
+            header,
+            info: StreamInfo {
+                sender_id: header.sender_id,
+                sequence: header.sequence,
+                total_len,
+                digest,
+            },
+            total_fragments: total,
+            received: [0; BITMAP_SIZE],
+            received_count: 1,
+            last_fragment_ms: now_ms,
+        };
+        stream.received[0] = 1;
+
+        info!("Stream seq {} started: {} bytes in {} fragments", header.sequence, total_len, total);
+        self.active = Some(stream);
+        Ok(())
+    }
+}
+
+impl Default for StreamAssembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn digest_of(
+    total_len: u32,
+    mut read_at: impl FnMut(u32, &mut [u8]) -> Result<(), SinkError>,
+) -> Result<[u8; DIGEST_SIZE], SinkError> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; VERIFY_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < total_len {
+        let n = (total_len - offset).min(VERIFY_CHUNK_SIZE as u32) as usize;
+        read_at(offset, &mut buf[..n])?;
+        hasher.update(&buf[..n]);
+        offset += n as u32;
+    }
+    Ok(hasher.finalize().into())
+}
+
+// Produces serialized stream fragments for `source`, sized for `mtu`
+pub struct StreamFragments<S: FragmentSource> {
+    header: [u8; HEADER_SIZE],
+    source: S,
+    manifest: [u8; MANIFEST_SIZE],
+    chunk_size: usize,
+    index: u16,
+    total: u16,
+}
+
+impl<S: FragmentSource> StreamFragments<S> {
+    pub fn new(message: &Message, mut source: S, mtu: u16) -> Result<Self, StreamError> {
+        // Fragment 0 must hold the whole manifest
+        let chunk_size = fragment_payload_size(mtu)
+            .checked_sub(STREAM_HEADER_SIZE)
+            .filter(|&size| size >= MANIFEST_SIZE)
+            .ok_or(StreamError::MtuTooSmall)?;
+        let total_len = source.len();
+        let data_fragments = (total_len as usize).div_ceil(chunk_size);
+        if data_fragments + 1 > u16::MAX as usize {
+            return Err(StreamError::TooManyFragments);
+        }
+
+        let mut manifest = [0u8; MANIFEST_SIZE];
+        manifest[0..4].copy_from_slice(&total_len.to_be_bytes());
+        let digest = digest_of(total_len, |o, buf| source.read_at(o, buf))?;
+        manifest[4..].copy_from_slice(&digest);
+
+        let mut header = message.header;
+        header.flags |= FLAG_STREAM;
+        header.flags &= !FLAG_AUTHENTICATED;
+        header.fragment_index = 0;
+        header.total_fragments = 1;
+        header.checksum = 0;
+
+        Ok(Self {
+            header: header.serialize(),
+            source,
+            manifest,
+            chunk_size,
+            index: 0,
+            total: data_fragments as u16 + 1,
+        })
+    }
+
+    pub fn total_fragments(&self) -> u16 {
+        self.total
+    }
+}
+
+impl<S: FragmentSource> Iterator for StreamFragments<S> {
+    type Item = Result<Vec<u8, MAX_FRAGMENT_SIZE>, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.total {
+            return None;
+        }
+
+        let index = self.index;
+        self.index += 1;
+
+        let offset = (index.saturating_sub(1) as usize * self.chunk_size) as u32;
+        Some(self.build(index, offset))
+    }
+}
+
+impl<S: FragmentSource> StreamFragments<S> {
+    fn build(&mut self, index: u16, offset: u32) -> Result<Vec<u8, MAX_FRAGMENT_SIZE>, StreamError> {
+        let mut fragment: Vec<u8, MAX_FRAGMENT_SIZE> = Vec::new();
+        fragment.extend_from_slice(&self.header)
+            .and_then(|_| fragment.extend_from_slice(&index.to_be_bytes()))
+            .and_then(|_| fragment.extend_from_slice(&self.total.to_be_bytes()))
+            .and_then(|_| fragment.extend_from_slice(&offset.to_be_bytes()))
+            .map_err(|_| StreamError::Overflow)?;
+
+        if index == 0 {
+            fragment.extend_from_slice(&self.manifest).map_err(|_| StreamError::Overflow)?;
+        } else {
+            let len = (self.source.len() - offset).min(self.chunk_size as u32) as usize;
+            let start = fragment.len();
+            fragment.resize(start + len, 0).map_err(|_| StreamError::Overflow)?;
+            self.source.read_at(offset, &mut fragment[start..])?;
+        }
+
+        seal_crc(&mut fragment);
+        Ok(fragment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{MessageType, MIN_ATT_MTU};
+
+    const MTU: u16 = 247;
+
+    fn message(sender: u8, sequence: u16) -> Message {
+        Message::new(MessageType::Text, [sender; 6], sequence, &[]).unwrap()
+    }
+
+    fn frames(message: &Message, data: &[u8], mtu: u16) -> std::vec::Vec<Vec<u8, MAX_FRAGMENT_SIZE>> {
+        StreamFragments::new(message, data, mtu).unwrap().map(Result::unwrap).collect()
+    }
+
+    fn feed<S: FragmentSink>(
+        assembler: &mut StreamAssembler,
+        frame: &[u8],
+        now_ms: u64,
+        sink: &mut S,
+    ) -> Result<Option<StreamInfo>, StreamError> {
+        let header = MessageHeader::deserialize(frame).unwrap();
+        assembler.add_fragment(header, &frame[HEADER_SIZE..], now_ms, sink)
+    }
+
+    fn data(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_out_of_order() {
+        let data = data(1500);
+        let mut frames = frames(&message(1, 9), &data, MTU);
+        assert!(frames.len() > 2);
+        // Manifest first, the data fragments in reverse
+        frames[1..].reverse();
+
+        let mut assembler = StreamAssembler::new();
+        let mut sink = RamSink::<2048>::new();
+        let (last, rest) = frames.split_last().unwrap();
+        for frame in rest {
+            assert_eq!(feed(&mut assembler, frame, 0, &mut sink), Ok(None));
+        }
+        let info = feed(&mut assembler, last, 0, &mut sink).unwrap().unwrap();
+        assert_eq!(info.total_len, 1500);
+        assert_eq!(info.sequence, 9);
+        assert_eq!(sink.as_slice(), &data[..]);
+        assert!(!assembler.is_active());
+    }
+
+    #[test]
+    fn corrupt_data_fails_the_hash_check() {
+        let data = data(600);
+        let mut frames = frames(&message(1, 1), &data, MTU);
+        let last = frames.last_mut().unwrap();
+        let end = last.len() - 1;
+        last[end] ^= 0xFF;
+
+        let mut assembler = StreamAssembler::new();
+        let mut sink = RamSink::<1024>::new();
+        let results: std::vec::Vec<_> = frames.iter().map(|f| feed(&mut assembler, f, 0, &mut sink)).collect();
+        assert_eq!(results.last(), Some(&Err(StreamError::HashMismatch)));
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn small_mtus_are_refused() {
+        let data = data(100);
+        let err = |mtu| StreamFragments::new(&message(1, 1), &data[..], mtu).err();
+        // Too small for the stream header, then for the manifest
+        assert_eq!(err(MIN_ATT_MTU), Some(StreamError::MtuTooSmall));
+        let min = (HEADER_SIZE + STREAM_HEADER_SIZE + MANIFEST_SIZE + 3) as u16;
+        assert_eq!(err(min - 1), Some(StreamError::MtuTooSmall));
+        assert_eq!(err(min), None);
+    }
+
+    #[test]
+    fn streams_larger_than_the_sink_are_refused() {
+        let data = data(600);
+        let frames = frames(&message(1, 1), &data, MTU);
+        let mut assembler = StreamAssembler::new();
+        let mut sink = RamSink::<512>::new();
+        assert_eq!(
+            feed(&mut assembler, &frames[0], 0, &mut sink),
+            Err(StreamError::Sink(SinkError::OutOfSpace))
+        );
+        assert!(!assembler.is_active());
+    }
+
+    #[test]
+    fn second_stream_waits_for_the_first() {
+        let first = frames(&message(1, 1), &data(600), MTU);
+        let second = frames(&message(2, 1), &data(300), MTU);
+        let mut assembler = StreamAssembler::default();
+        let mut sink = RamSink::<1024>::new();
+
+        assert_eq!(feed(&mut assembler, &first[0], 0, &mut sink), Ok(None));
+        assert_eq!(feed(&mut assembler, &second[0], 1_000, &mut sink), Err(StreamError::Busy));
+
+        // The first stream is unaffected and completes
+        let mut done = None;
+        for frame in &first[1..] {
+            done = feed(&mut assembler, frame, 2_000, &mut sink).unwrap();
+        }
+        assert!(done.is_some());
+        assert_eq!(feed(&mut assembler, &second[0], 3_000, &mut sink), Ok(None));
+    }
+
+    #[test]
+    fn stalled_stream_gives_way() {
+        let first = frames(&message(1, 1), &data(600), MTU);
+        let second = frames(&message(2, 1), &data(300), MTU);
+        let mut assembler = StreamAssembler::new();
+        let mut sink = RamSink::<1024>::new();
+
+        assert_eq!(feed(&mut assembler, &first[0], 0, &mut sink), Ok(None));
+        assert_eq!(feed(&mut assembler, &first[1], 5_000, &mut sink), Ok(None));
+        assert_eq!(feed(&mut assembler, &second[0], 5_000 + STREAM_IDLE_TIMEOUT_MS - 1, &mut sink), Err(StreamError::Busy));
+        assert_eq!(feed(&mut assembler, &second[0], 5_000 + STREAM_IDLE_TIMEOUT_MS, &mut sink), Ok(None));
+        assert_eq!(feed(&mut assembler, &first[2], 40_000, &mut sink), Err(StreamError::NotStarted));
+    }
+}
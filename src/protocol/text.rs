@@ -1,6 +1,6 @@
-use defmt::info;
 use heapless::String;
-use crate::protocol::message::{Message, MessageType, MAX_MESSAGE_SIZE};
+use crate::protocol::message::{Message, MessageError, MessageType, MAX_MESSAGE_SIZE, RECIPIENT_SIZE};
+use crate::protocol::message_id::MessageId;
 
 pub struct TextMessage;
 
@@ -9,19 +9,30 @@ impl TextMessage {
         sender_id: [u8; 6],
         sequence: u16,
         text: &str,
-    ) -> Result<Message, ()> {
+    ) -> Result<Message, MessageError> {
         // Convert text to bytes
         let text_bytes = text.as_bytes();
 
         if text_bytes.len() > MAX_MESSAGE_SIZE {
-            return Err(());
+            return Err(MessageError::TooLarge);
         }
 
         info!("Creating text message: {} bytes", text_bytes.len());
         Message::new(MessageType::Text, sender_id, sequence, text_bytes)
     }
 
-    pub fn parse(payload: &[u8]) -> Result<String<MAX_MESSAGE_SIZE>, ()> {
+    // Text for one peer, which ACKs it if asked to
+    pub fn create_direct(
+        sender_id: [u8; 6],
+        recipient: [u8; RECIPIENT_SIZE],
+        sequence: u16,
+        text: &str,
+    ) -> Result<Message, MessageError> {
+        info!("Creating direct text message: {} bytes", text.len());
+        Message::new_directed(MessageType::Text, sender_id, recipient, sequence, text.as_bytes())
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<String<MAX_MESSAGE_SIZE>, MessageError> {
         // Try to convert payload to UTF-8 string
         let mut result = String::new();
 
@@ -32,7 +43,7 @@ impl TextMessage {
                 core::str::from_utf8(&payload[..e.valid_up_to()]).unwrap_or("")
             });
 
-        result.push_str(text).map_err(|_| ())?;
+        result.push_str(text).map_err(|_| MessageError::TooLarge)?;
         Ok(result)
     }
 
@@ -40,7 +51,7 @@ impl TextMessage {
         device_id: [u8; 6],
         sequence: u16,
         device_name: &str,
-    ) -> Result<Message, ()> {
+    ) -> Result<Message, MessageError> {
         let announce_text = if device_name.is_empty() {
             "device online"
         } else {
@@ -49,4 +60,14 @@ impl TextMessage {
 
         Message::new(MessageType::Announce, device_id, sequence, announce_text.as_bytes())
     }
-}
\ No newline at end of file
+
+    // Directed back at the sender so relays can carry it to them
+    pub fn create_ack(
+        device_id: [u8; 6],
+        sequence: u16,
+        to: [u8; RECIPIENT_SIZE],
+        acked: &MessageId,
+    ) -> Result<Message, MessageError> {
+        Message::new_directed(MessageType::Ack, device_id, to, sequence, &acked.0)
+    }
+}
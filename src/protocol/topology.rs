@@ -0,0 +1,261 @@
+use heapless::{Deque, Vec};
+use crate::protocol::peer::PeerId;
+use crate::fmt::Bytes;
+
+pub const MAX_TOPOLOGY_NODES: usize = 32;
+pub const MAX_TOPOLOGY_EDGES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TopologyConfig {
+    // An edge not re-reported in an announce for this long is dropped
+    pub edge_timeout_ms: u32,
+    // Whether our announces carry our neighbour list
+    pub share_neighbors: bool,
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        Self {
+            // A couple of missed announce intervals
+            edge_timeout_ms: 90_000,
+            share_neighbors: true,
+        }
+    }
+}
+
+// `reporter` said it has `neighbor` as a direct neighbour
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Edge {
+    pub reporter: PeerId,
+    pub neighbor: PeerId,
+    pub updated_ms: u64,
+}
+
+// What the mesh looks like, pieced together from neighbour lists in announces.
+// Edges are treated as bidirectional for path lengths, since a BLE link is.
+pub struct TopologyGraph {
+    config: TopologyConfig,
+    local: PeerId,
+    edges: Vec<Edge, MAX_TOPOLOGY_EDGES>,
+}
+
+impl TopologyGraph {
+    pub fn new(local: PeerId, config: TopologyConfig) -> Self {
+        Self {
+            config,
+            local,
+            edges: Vec::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: TopologyConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &TopologyConfig {
+        &self.config
+    }
+
+    // Replaces everything `reporter` previously told us with its current list
+    pub fn observe_neighbors(&mut self, reporter: PeerId, neighbors: &[PeerId], now_ms: u64) {
+        self.expire(now_ms);
+        self.edges.retain(|e| e.reporter != reporter || neighbors.contains(&e.neighbor));
+
+        for neighbor in neighbors {
+            if *neighbor == reporter {
+                continue;
+            }
+            if let Some(edge) = self.edges.iter_mut().find(|e| e.reporter == reporter && e.neighbor == *neighbor) {
+                edge.updated_ms = now_ms;
+                continue;
+            }
+            if self.edges.is_full() {
+                // Oldest information goes first
+                let oldest = self.edges.iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.updated_ms)
+                    .map(|(i, _)| i);
+                if let Some(oldest) = oldest {
+                    self.edges.swap_remove(oldest);
+                }
+            }
+            let _ = self.edges.push(Edge { reporter, neighbor: *neighbor, updated_ms: now_ms });
+        }
+    }
+
+    pub fn expire(&mut self, now_ms: u64) {
+        let timeout = self.config.edge_timeout_ms as u64;
+        self.edges.retain(|e| now_ms.saturating_sub(e.updated_ms) < timeout);
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    // Every peer appearing in the graph, ourselves first
+    pub fn nodes(&self) -> Vec<PeerId, MAX_TOPOLOGY_NODES> {
+        let mut nodes: Vec<PeerId, MAX_TOPOLOGY_NODES> = Vec::new();
+        let _ = nodes.push(self.local);
+        for edge in &self.edges {
+            for peer in [edge.reporter, edge.neighbor] {
+                if !nodes.contains(&peer) {
+                    let _ = nodes.push(peer);
+                }
+            }
+        }
+        nodes
+    }
+
+    pub fn hop_count(&self, peer: &PeerId) -> Option<u8> {
+        self.shortest_path(peer).map(|(hops, _)| hops)
+    }
+
+    // Our direct neighbour on a shortest path to `peer`
+    pub fn first_hop(&self, peer: &PeerId) -> Option<PeerId> {
+        self.shortest_path(peer).and_then(|(_, first)| first)
+    }
+
+    // Hop counts to every reachable peer, ourselves at 0
+    pub fn hop_counts(&self) -> Vec<(PeerId, u8), MAX_TOPOLOGY_NODES> {
+        let nodes = self.nodes();
+        let (hops, _) = self.bfs(&nodes);
+        nodes.iter()
+            .zip(hops.iter())
+            .filter_map(|(peer, hops)| hops.map(|h| (*peer, h)))
+            .collect()
+    }
+
+    pub fn dump(&self) {
+        info!("Topology: {} edges", self.edges.len());
+        for edge in &self.edges {
+            info!("  {:02x} -> {:02x} ({} ms)", Bytes(&edge.reporter), Bytes(&edge.neighbor), edge.updated_ms);
+        }
+        for (peer, hops) in self.hop_counts() {
+            info!("  {:02x}: {} hops", Bytes(&peer), hops);
+        }
+    }
+
+    fn shortest_path(&self, peer: &PeerId) -> Option<(u8, Option<PeerId>)> {
+        let nodes = self.nodes();
+        let index = nodes.iter().position(|n| n == peer)?;
+        let (hops, first) = self.bfs(&nodes);
+        hops[index].map(|h| (h, first[index].map(|i| nodes[i])))
+    }
+
+    // Breadth-first search from ourselves. For each node returns its distance
+    // and the index of the direct neighbour the path starts with.
+    #[allow(clippy::type_complexity)]
+    fn bfs(&self, nodes: &[PeerId]) -> (Vec<Option<u8>, MAX_TOPOLOGY_NODES>, Vec<Option<usize>, MAX_TOPOLOGY_NODES>) {
+        let mut hops: Vec<Option<u8>, MAX_TOPOLOGY_NODES> = Vec::new();
+        let mut first: Vec<Option<usize>, MAX_TOPOLOGY_NODES> = Vec::new();
+        for _ in nodes {
+            let _ = hops.push(None);
+            let _ = first.push(None);
+        }
+
+        let mut queue: Deque<usize, MAX_TOPOLOGY_NODES> = Deque::new();
+        hops[0] = Some(0);
+        let _ = queue.push_back(0);
+
+        while let Some(current) = queue.pop_front() {
+            let distance = hops[current].unwrap_or(0);
+            for edge in &self.edges {
+                let other = if edge.reporter == nodes[current] {
+                    edge.neighbor
+                } else if edge.neighbor == nodes[current] {
+                    edge.reporter
+                } else {
+                    continue;
+                };
+                let Some(next) = nodes.iter().position(|n| *n == other) else { continue };
+                if hops[next].is_some() {
+                    continue;
+                }
+                hops[next] = Some(distance.saturating_add(1));
+                first[next] = if current == 0 { Some(next) } else { first[current] };
+                let _ = queue.push_back(next);
+            }
+        }
+
+        (hops, first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: PeerId = [0; 8];
+
+    fn peer(n: u8) -> PeerId {
+        [n, 0, 0, 0, 0, 0, 0, 1]
+    }
+
+    fn graph() -> TopologyGraph {
+        TopologyGraph::new(LOCAL, TopologyConfig::default())
+    }
+
+    #[test]
+    fn hop_counts_follow_the_shortest_path() {
+        let mut graph = graph();
+        // LOCAL - 1 - 2 - 3, plus a longer way round to 3 through 4 and 5
+        graph.observe_neighbors(peer(1), &[LOCAL, peer(2)], 0);
+        graph.observe_neighbors(peer(2), &[peer(3)], 0);
+        graph.observe_neighbors(LOCAL, &[peer(4)], 0);
+        graph.observe_neighbors(peer(5), &[peer(4), peer(6)], 0);
+        graph.observe_neighbors(peer(6), &[peer(3)], 0);
+
+        assert_eq!(graph.hop_count(&LOCAL), Some(0));
+        assert_eq!(graph.hop_count(&peer(2)), Some(2));
+        assert_eq!(graph.hop_count(&peer(3)), Some(3));
+        assert_eq!(graph.first_hop(&peer(3)), Some(peer(1)));
+        assert_eq!(graph.first_hop(&peer(6)), Some(peer(4)));
+        assert_eq!(graph.hop_count(&peer(9)), None);
+        assert_eq!(graph.hop_counts().len(), 7);
+    }
+
+    #[test]
+    fn a_new_list_replaces_the_old_one() {
+        let mut graph = graph();
+        graph.observe_neighbors(peer(1), &[LOCAL, peer(2)], 0);
+        graph.observe_neighbors(peer(1), &[LOCAL, peer(1)], 10);
+        assert_eq!(graph.edges().len(), 1);
+        assert_eq!(graph.hop_count(&peer(2)), None);
+    }
+
+    #[test]
+    fn edges_expire_unless_reported_again() {
+        let mut graph = graph();
+        let timeout = TopologyConfig::default().edge_timeout_ms as u64;
+        graph.observe_neighbors(peer(1), &[LOCAL], 0);
+        graph.observe_neighbors(peer(2), &[peer(1)], 0);
+        graph.observe_neighbors(peer(1), &[LOCAL], timeout - 1);
+
+        graph.expire(timeout);
+        assert_eq!(graph.edges().len(), 1);
+        assert_eq!(graph.hop_count(&peer(1)), Some(1));
+        assert_eq!(graph.hop_count(&peer(2)), None);
+
+        graph.expire(2 * timeout);
+        assert!(graph.edges().is_empty());
+    }
+
+    #[test]
+    fn long_chains_stop_at_the_node_limit() {
+        let mut graph = graph();
+        let length = MAX_TOPOLOGY_NODES as u8 + 8;
+        graph.observe_neighbors(peer(1), &[LOCAL], 0);
+        for n in 1..length {
+            graph.observe_neighbors(peer(n + 1), &[peer(n)], 0);
+        }
+
+        assert_eq!(graph.nodes().len(), MAX_TOPOLOGY_NODES);
+        let counts = graph.hop_counts();
+        assert_eq!(counts.len(), MAX_TOPOLOGY_NODES);
+        assert!(counts.iter().all(|(peer, hops)| *hops == peer[0]));
+        assert_eq!(graph.hop_count(&peer(MAX_TOPOLOGY_NODES as u8 - 1)), Some(MAX_TOPOLOGY_NODES as u8 - 1));
+        assert_eq!(graph.hop_count(&peer(length)), None);
+    }
+}
@@ -0,0 +1,287 @@
+// Recordings of what a node was fed, and a driver that feeds them back.
+//
+// A recording is text, one input per line, so a field capture can be trimmed
+// by hand and checked in as a regression test:
+//
+//   node 5e1100000001 epoch=1 seed=42 nickname=alice
+//   0 connect 0
+//   135 rx 0 0101030000000000...
+//   140 mtu 0 247
+//   150 rssi 0 -61
+//   400 send hello there
+//   900 disconnect 0
+//
+// The `node` line rebuilds the node with the same address, boot epoch, RNG
+// seed and nickname. Times are milliseconds of node uptime and must not go
+// backwards. `rx` carries the frame as written to the link, in hex; `send` is
+// text the application broadcast, up to the end of the line. Timers are not
+// recorded: the replayer fires them itself when their deadline comes up, so
+// a replay is deterministic without them.
+
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+
+use crate::bitchat::announce::MAX_NICKNAME_LEN;
+use crate::node::{AppEvent, LinkEvent, NodeCore, MAX_LINKS};
+use crate::protocol::action::Frame;
+use crate::protocol::message::MAX_FRAGMENT_SIZE;
+use crate::protocol::relay::LinkId;
+
+pub const MAX_SEND_TEXT: usize = MAX_FRAGMENT_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeIdentity {
+    pub address: [u8; 6],
+    pub boot_epoch: u32,
+    pub seed: u64,
+    pub nickname: Option<String<MAX_NICKNAME_LEN>>,
+}
+
+impl NodeIdentity {
+    pub fn build(&self) -> NodeCore {
+        let mut node = NodeCore::new(self.address, self.boot_epoch, self.seed);
+        if let Some(nickname) = &self.nickname {
+            node.set_nickname(nickname);
+        }
+        node
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Connected(LinkId),
+    Frame(LinkId, Frame),
+    Mtu(LinkId, u16),
+    Rssi(LinkId, i8),
+    Disconnected(LinkId),
+    Send(String<MAX_SEND_TEXT>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recorded {
+    pub at_ms: u64,
+    pub input: Input,
+}
+
+impl Recorded {
+    pub fn frame(at_ms: u64, link: LinkId, data: &[u8]) -> Option<Self> {
+        let frame = Vec::from_slice(data).ok()?;
+        Some(Self { at_ms, input: Input::Frame(link, frame) })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Node(NodeIdentity),
+    Input(Recorded),
+    // Blank or comment
+    Empty,
+}
+
+// One line of a recording. `#` starts a comment except inside `send` text.
+pub fn parse_line(line: &str) -> Result<Line, &'static str> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Line::Empty);
+    }
+    let (first, rest) = split_word(line);
+
+    if first == "node" {
+        return parse_node(rest).map(Line::Node);
+    }
+
+    let at_ms = first.parse().map_err(|_| "expected a time in ms or `node`")?;
+    let (kind, rest) = split_word(rest);
+    let input = if kind == "send" {
+        let mut text = String::new();
+        text.push_str(rest).map_err(|_| "send text too long")?;
+        Input::Send(text)
+    } else {
+        let rest = rest.split('#').next().unwrap_or("");
+        let (link, rest) = split_word(rest);
+        let link = LinkId(link.parse().map_err(|_| "expected a link ID")?);
+        let (value, _) = split_word(rest);
+        match kind {
+            "connect" => Input::Connected(link),
+            "disconnect" => Input::Disconnected(link),
+            "rx" => Input::Frame(link, parse_hex(value)?),
+            "mtu" => Input::Mtu(link, value.parse().map_err(|_| "bad MTU")?),
+            "rssi" => Input::Rssi(link, value.parse().map_err(|_| "bad RSSI")?),
+            _ => return Err("unknown input"),
+        }
+    };
+    Ok(Line::Input(Recorded { at_ms, input }))
+}
+
+fn parse_node(args: &str) -> Result<NodeIdentity, &'static str> {
+    let mut words = args.split('#').next().unwrap_or("").split_whitespace();
+    let address: Vec<u8, 6> = parse_hex(words.next().ok_or("node needs an address")?)?;
+    let mut identity = NodeIdentity {
+        address: address.into_array().map_err(|_| "address must be 6 bytes")?,
+        boot_epoch: 0,
+        seed: 0,
+        nickname: None,
+    };
+    for word in words {
+        let (key, value) = word.split_once('=').ok_or("expected key=value")?;
+        match key {
+            "epoch" => identity.boot_epoch = value.parse().map_err(|_| "bad epoch")?,
+            "seed" => identity.seed = value.parse().map_err(|_| "bad seed")?,
+            "nickname" => {
+                let mut nickname = String::new();
+                nickname.push_str(value).map_err(|_| "nickname too long")?;
+                identity.nickname = Some(nickname);
+            }
+            _ => return Err("unknown node field"),
+        }
+    }
+    Ok(identity)
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Result<Vec<u8, N>, &'static str> {
+    if !text.len().is_multiple_of(2) {
+        return Err("odd number of hex digits");
+    }
+    let mut bytes = Vec::new();
+    for i in (0..text.len()).step_by(2) {
+        let byte = text.get(i..i + 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            .ok_or("bad hex")?;
+        bytes.push(byte).map_err(|_| "frame too long")?;
+    }
+    Ok(bytes)
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Display for NodeIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node {} epoch={} seed={}", Hex(&self.address), self.boot_epoch, self.seed)?;
+        match &self.nickname {
+            // Nicknames with spaces can't be written back; the default is used
+            Some(nickname) if !nickname.contains(char::is_whitespace) => write!(f, " nickname={}", nickname),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Recorded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.at_ms)?;
+        match &self.input {
+            Input::Connected(link) => write!(f, "connect {}", link.0),
+            Input::Frame(link, frame) => write!(f, "rx {} {}", link.0, Hex(frame)),
+            Input::Mtu(link, mtu) => write!(f, "mtu {} {}", link.0, mtu),
+            Input::Rssi(link, rssi) => write!(f, "rssi {} {}", link.0, rssi),
+            Input::Disconnected(link) => write!(f, "disconnect {}", link.0),
+            Input::Send(text) => {
+                f.write_str("send ")?;
+                // Keep the text on one line
+                text.chars().try_for_each(|c| f.write_char(if c == '\n' { ' ' } else { c }))
+            }
+        }
+    }
+}
+
+// What the node did in response. Handed straight to a callback, so the size
+// of the event variant doesn't matter.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Output {
+    Transmit(LinkId, Frame),
+    Event(AppEvent),
+}
+
+// Feeds recorded inputs into a NodeCore on a virtual clock. Between inputs it
+// fires the node's timers at their deadlines, and every frame the node queues
+// counts as sent, so the same recording always produces the same outputs.
+pub struct Replayer {
+    node: NodeCore,
+    now_ms: u64,
+}
+
+impl Replayer {
+    pub fn new(identity: &NodeIdentity) -> Self {
+        Self {
+            node: identity.build(),
+            now_ms: 0,
+        }
+    }
+
+    pub fn node(&self) -> &NodeCore {
+        &self.node
+    }
+
+    pub fn node_mut(&mut self) -> &mut NodeCore {
+        &mut self.node
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    // Runs timers up to the input's time, then applies the input. Inputs
+    // timed before the clock are applied at the current time.
+    pub fn feed(&mut self, recorded: &Recorded, out: &mut impl FnMut(u64, Output)) {
+        self.advance_to(recorded.at_ms, out);
+        let now = self.now_ms;
+        match &recorded.input {
+            Input::Connected(link) => self.node.handle(LinkEvent::Connected(*link), now),
+            Input::Frame(link, frame) => self.node.handle(LinkEvent::BytesReceived(*link, frame), now),
+            Input::Mtu(link, mtu) => self.node.handle(LinkEvent::MtuChanged(*link, *mtu), now),
+            Input::Rssi(link, rssi) => self.node.handle(LinkEvent::Rssi(*link, *rssi), now),
+            Input::Disconnected(link) => self.node.handle(LinkEvent::Disconnected(*link), now),
+            Input::Send(text) => {
+                if let Err(e) = self.node.send_text(text, now) {
+                    warn!("Replayed send failed: {:?}", e);
+                }
+            }
+        }
+        self.drain(out);
+    }
+
+    // Fires every timer due by `at_ms` and moves the clock there
+    pub fn advance_to(&mut self, at_ms: u64, out: &mut impl FnMut(u64, Output)) {
+        loop {
+            let deadline = self.node.poll_timeout().max(self.now_ms);
+            if deadline > at_ms {
+                break;
+            }
+            self.now_ms = deadline;
+            self.node.handle(LinkEvent::TimerFired, self.now_ms);
+            self.drain(out);
+            // A deadline still due right after firing would spin
+            if self.node.poll_timeout() <= self.now_ms {
+                self.now_ms += 1;
+            }
+        }
+        self.now_ms = self.now_ms.max(at_ms);
+    }
+
+    fn drain(&mut self, out: &mut impl FnMut(u64, Output)) {
+        for link in 0..MAX_LINKS as u8 {
+            let link = LinkId(link);
+            while let Some(frame) = self.node.next_transmit(link) {
+                out(self.now_ms, Output::Transmit(link, frame.clone()));
+                self.node.transmitted(link, true);
+            }
+        }
+        while let Some(event) = self.node.poll_event() {
+            out(self.now_ms, Output::Event(event));
+        }
+    }
+}
@@ -0,0 +1,486 @@
+// Node settings that used to be compile-time constants in config.rs. They are
+// kept in flash (storage.rs) and changed at runtime from the console or the
+// settings characteristic; config.rs only supplies the defaults.
+//
+// Stored record, integers big-endian:
+//
+//   magic "BS" | version | body length | body | crc16 over version..body
+//
+// Version 1 body:
+//
+//   ttl | flags (bit 0 relay, bit 1 stats replies) | announce interval s u16 |
+//   tx power dBm i8 | role | nickname length | nickname
+//
+// Fields are only ever appended. A record from an older build ends early and
+// the fields it lacks keep their defaults; a newer build's record is read as
+// far as this one understands it. A field that changes meaning needs a new
+// version and a conversion in Settings::decode(). Values that fail validation
+// on load fall back to their defaults one by one; the rest of the record
+// still counts.
+
+use core::fmt;
+use heapless::{String, Vec};
+
+use crate::bitchat::announce::MAX_NICKNAME_LEN;
+use crate::config::{DEFAULT_TTL, DEVICE_NAME, RELAY_ENABLED, STATS_REPLIES};
+use crate::protocol::integrity::crc16;
+
+pub const SETTINGS_VERSION: u8 = 1;
+const MAGIC: [u8; 2] = *b"BS";
+const BODY_SIZE: usize = 7 + MAX_NICKNAME_LEN;
+pub const SETTINGS_RECORD_SIZE: usize = 4 + BODY_SIZE + 2;
+
+pub const MAX_TTL: u8 = 7;
+// The announce scheduler jitters by 5 s either way
+pub const MIN_ANNOUNCE_INTERVAL_S: u16 = 10;
+pub const MAX_ANNOUNCE_INTERVAL_S: u16 = 3600;
+pub const DEFAULT_ANNOUNCE_INTERVAL_S: u16 = 30;
+// What the nRF52840 radio accepts
+pub const TX_POWER_LEVELS: [i8; 14] = [-40, -20, -16, -12, -8, -4, 0, 2, 3, 4, 5, 6, 7, 8];
+
+const FLAG_RELAY: u8 = 1 << 0;
+const FLAG_STATS_REPLIES: u8 = 1 << 1;
+
+// Settings keys, in the order they are listed
+pub const KEYS: [&str; 7] = ["nickname", "ttl", "relay", "announce-interval", "tx-power", "role", "stats-replies"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    // Relays if the relay setting says so
+    Peer = 0,
+    // Always relays, e.g. a mains-powered board in a window
+    Repeater = 1,
+    // Never relays, e.g. a battery-powered badge
+    Leaf = 2,
+}
+
+impl Role {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Role::Peer),
+            1 => Some(Role::Repeater),
+            2 => Some(Role::Leaf),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Peer => "peer",
+            Role::Repeater => "repeater",
+            Role::Leaf => "leaf",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Role::Peer, Role::Repeater, Role::Leaf].into_iter().find(|role| role.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub nickname: String<MAX_NICKNAME_LEN>,
+    // TTL of packets this node originates
+    pub default_ttl: u8,
+    pub relay: bool,
+    pub announce_interval_s: u16,
+    pub tx_power_dbm: i8,
+    pub role: Role,
+    pub stats_replies: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let mut nickname = String::new();
+        let _ = nickname.push_str(DEVICE_NAME);
+        Self {
+            nickname,
+            default_ttl: DEFAULT_TTL,
+            relay: RELAY_ENABLED,
+            announce_interval_s: DEFAULT_ANNOUNCE_INTERVAL_S,
+            tx_power_dbm: 0,
+            role: Role::Peer,
+            stats_replies: STATS_REPLIES,
+        }
+    }
+}
+
+impl Settings {
+    // Whether the router should relay, once the role has had its say
+    pub fn relays(&self) -> bool {
+        match self.role {
+            Role::Peer => self.relay,
+            Role::Repeater => true,
+            Role::Leaf => false,
+        }
+    }
+
+    pub fn announce_interval_ms(&self) -> u32 {
+        self.announce_interval_s as u32 * 1000
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        check_nickname(&self.nickname)?;
+        check_ttl(self.default_ttl)?;
+        check_announce_interval(self.announce_interval_s)?;
+        check_tx_power(self.tx_power_dbm)
+    }
+
+    // Changes one setting from its text form; nothing changes on error
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        let value = value.trim();
+        let mut next = self.clone();
+        match key {
+            "nickname" => {
+                next.nickname.clear();
+                next.nickname.push_str(value).map_err(|_| "nickname too long")?;
+            }
+            "ttl" => next.default_ttl = value.parse().map_err(|_| "expected a number")?,
+            "relay" => next.relay = parse_on_off(value)?,
+            "announce-interval" => {
+                let secs = value.strip_suffix('s').unwrap_or(value).trim_end();
+                next.announce_interval_s = secs.parse().map_err(|_| "expected seconds")?;
+            }
+            "tx-power" => {
+                let dbm = value.strip_suffix("dBm").unwrap_or(value).trim_end();
+                next.tx_power_dbm = dbm.parse().map_err(|_| "expected dBm")?;
+            }
+            "role" => next.role = Role::parse(value).ok_or("expected peer, repeater or leaf")?,
+            "stats-replies" => next.stats_replies = parse_on_off(value)?,
+            _ => return Err("unknown key"),
+        }
+        next.validate()?;
+        *self = next;
+        Ok(())
+    }
+
+    // `<key> <value>`, as the settings characteristic receives it
+    pub fn set_line(&mut self, line: &str) -> Result<(), &'static str> {
+        let (key, value) = line.trim().split_once(char::is_whitespace).ok_or("expected <key> <value>")?;
+        self.set(key, value)
+    }
+
+    // One setting in the text form set() accepts
+    pub fn get(&self, key: &str) -> Option<Value<'_>> {
+        let value = match key {
+            "nickname" => Value::Text(self.nickname.as_str()),
+            "ttl" => Value::Number(self.default_ttl),
+            "relay" => Value::OnOff(self.relay),
+            "announce-interval" => Value::Seconds(self.announce_interval_s),
+            "tx-power" => Value::Dbm(self.tx_power_dbm),
+            "role" => Value::Text(self.role.name()),
+            "stats-replies" => Value::OnOff(self.stats_replies),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn encode(&self) -> Vec<u8, SETTINGS_RECORD_SIZE> {
+        let mut flags = 0;
+        if self.relay {
+            flags |= FLAG_RELAY;
+        }
+        if self.stats_replies {
+            flags |= FLAG_STATS_REPLIES;
+        }
+        let mut body: Vec<u8, BODY_SIZE> = Vec::new();
+        let _ = body.push(self.default_ttl);
+        let _ = body.push(flags);
+        let _ = body.extend_from_slice(&self.announce_interval_s.to_be_bytes());
+        let _ = body.push(self.tx_power_dbm as u8);
+        let _ = body.push(self.role as u8);
+        let _ = body.push(self.nickname.len() as u8);
+        let _ = body.extend_from_slice(self.nickname.as_bytes());
+
+        let head = [SETTINGS_VERSION, body.len() as u8];
+        let mut record = Vec::new();
+        let _ = record.extend_from_slice(&MAGIC);
+        let _ = record.extend_from_slice(&head);
+        let _ = record.extend_from_slice(&body);
+        let _ = record.extend_from_slice(&crc16(&head, &body).to_be_bytes());
+        record
+    }
+
+    // Reads a stored record; trailing bytes (slot padding) are ignored
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < 4 || data[..2] != MAGIC {
+            return Err("No settings record");
+        }
+        let version = data[2];
+        if version == 0 {
+            return Err("Unsupported settings version");
+        }
+        let body_len = data[3] as usize;
+        let Some(body) = data.get(4..4 + body_len) else { return Err("Settings record truncated") };
+        let Some(crc) = data.get(4 + body_len..6 + body_len) else { return Err("Settings record truncated") };
+        if crc16(&data[2..4], body) != u16::from_be_bytes([crc[0], crc[1]]) {
+            return Err("Settings checksum mismatch");
+        }
+        if version > SETTINGS_VERSION {
+            warn!("Settings version {} is newer than {}, reading what we know", version, SETTINGS_VERSION);
+        }
+
+        let mut settings = Settings::default();
+        let mut fields = body.iter().copied();
+        if let Some(ttl) = fields.next() {
+            settings.default_ttl = ttl;
+        }
+        if let Some(flags) = fields.next() {
+            settings.relay = flags & FLAG_RELAY != 0;
+            settings.stats_replies = flags & FLAG_STATS_REPLIES != 0;
+        }
+        if let (Some(hi), Some(lo)) = (fields.next(), fields.next()) {
+            settings.announce_interval_s = u16::from_be_bytes([hi, lo]);
+        }
+        if let Some(dbm) = fields.next() {
+            settings.tx_power_dbm = dbm as i8;
+        }
+        if let Some(role) = fields.next() {
+            // Unknown roles from a newer build read as the default
+            settings.role = Role::from_u8(role).unwrap_or(Role::Peer);
+        }
+        if let Some(len) = fields.next() {
+            let name = &body[body_len - fields.len()..];
+            let name = name.get(..len as usize).ok_or("Settings record truncated")?;
+            if let Ok(name) = core::str::from_utf8(name) {
+                settings.nickname.clear();
+                let _ = settings.nickname.push_str(name);
+            }
+        }
+
+        settings.sanitize();
+        Ok(settings)
+    }
+
+    // Swaps every invalid field for its default
+    fn sanitize(&mut self) {
+        let defaults = Settings::default();
+        if let Err(e) = check_nickname(&self.nickname) {
+            warn!("Stored settings: {}, using default", e);
+            self.nickname = defaults.nickname;
+        }
+        if let Err(e) = check_ttl(self.default_ttl) {
+            warn!("Stored settings: {}, using default", e);
+            self.default_ttl = defaults.default_ttl;
+        }
+        if let Err(e) = check_announce_interval(self.announce_interval_s) {
+            warn!("Stored settings: {}, using default", e);
+            self.announce_interval_s = defaults.announce_interval_s;
+        }
+        if let Err(e) = check_tx_power(self.tx_power_dbm) {
+            warn!("Stored settings: {}, using default", e);
+            self.tx_power_dbm = defaults.tx_power_dbm;
+        }
+    }
+}
+
+// `<key> <value>` per line, in KEYS order
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in KEYS.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if let Some(value) = self.get(key) {
+                write!(f, "{} {}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A setting formatted the way set() reads it back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Text(&'a str),
+    Number(u8),
+    OnOff(bool),
+    Seconds(u16),
+    Dbm(i8),
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::OnOff(on) => write!(f, "{}", if *on { "on" } else { "off" }),
+            Value::Seconds(secs) => write!(f, "{}s", secs),
+            Value::Dbm(dbm) => write!(f, "{}dBm", dbm),
+        }
+    }
+}
+
+fn parse_on_off(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "on" | "1" | "true" => Ok(true),
+        "off" | "0" | "false" => Ok(false),
+        _ => Err("expected on or off"),
+    }
+}
+
+fn check_nickname(nickname: &str) -> Result<(), &'static str> {
+    if nickname.trim().is_empty() {
+        return Err("nickname is empty");
+    }
+    Ok(())
+}
+
+fn check_ttl(ttl: u8) -> Result<(), &'static str> {
+    if ttl == 0 || ttl > MAX_TTL {
+        return Err("ttl must be 1 to 7");
+    }
+    Ok(())
+}
+
+fn check_announce_interval(secs: u16) -> Result<(), &'static str> {
+    if !(MIN_ANNOUNCE_INTERVAL_S..=MAX_ANNOUNCE_INTERVAL_S).contains(&secs) {
+        return Err("announce interval must be 10 to 3600 s");
+    }
+    Ok(())
+}
+
+fn check_tx_power(dbm: i8) -> Result<(), &'static str> {
+    if !TX_POWER_LEVELS.contains(&dbm) {
+        return Err("tx power must be -40, -20, -16, -12, -8, -4, 0 or 2 to 8 dBm");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    // A record around `body`, as some build would have written it
+    fn record(version: u8, body: &[u8]) -> Vec<u8> {
+        let head = [version, body.len() as u8];
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&head);
+        data.extend_from_slice(body);
+        data.extend_from_slice(&crc16(&head, body).to_be_bytes());
+        data
+    }
+
+    fn custom() -> Settings {
+        let mut settings = Settings::default();
+        settings.set("nickname", "attic").unwrap();
+        settings.set("ttl", "3").unwrap();
+        settings.set("relay", "off").unwrap();
+        settings.set("announce-interval", "120s").unwrap();
+        settings.set("tx-power", "4dBm").unwrap();
+        settings.set("role", "repeater").unwrap();
+        settings.set("stats-replies", "on").unwrap();
+        settings
+    }
+
+    #[test]
+    fn records_decode_to_what_was_encoded() {
+        let settings = custom();
+        let mut data = settings.encode().to_vec();
+        // Slot padding after the record is ignored
+        data.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(Settings::decode(&data), Ok(settings));
+        assert_eq!(Settings::decode(&Settings::default().encode()), Ok(Settings::default()));
+    }
+
+    #[test]
+    fn records_with_a_bad_checksum_are_rejected() {
+        let mut data = custom().encode().to_vec();
+        data[5] ^= 0x01;
+        assert_eq!(Settings::decode(&data), Err("Settings checksum mismatch"));
+
+        let data = custom().encode();
+        assert_eq!(Settings::decode(&data[..data.len() - 1]), Err("Settings record truncated"));
+        assert_eq!(Settings::decode(&[0xFF; 16]), Err("No settings record"));
+        assert_eq!(Settings::decode(&record(0, &[3])), Err("Unsupported settings version"));
+    }
+
+    #[test]
+    fn shorter_records_keep_defaults_for_missing_fields() {
+        // ttl, flags and announce interval only
+        let settings = Settings::decode(&record(1, &[4, FLAG_STATS_REPLIES, 0, 60])).unwrap();
+        assert_eq!(settings.default_ttl, 4);
+        assert!(!settings.relay);
+        assert!(settings.stats_replies);
+        assert_eq!(settings.announce_interval_s, 60);
+        let defaults = Settings::default();
+        assert_eq!(settings.tx_power_dbm, defaults.tx_power_dbm);
+        assert_eq!(settings.role, defaults.role);
+        assert_eq!(settings.nickname, defaults.nickname);
+
+        assert_eq!(Settings::decode(&record(1, &[])), Ok(defaults));
+    }
+
+    #[test]
+    fn newer_records_are_read_as_far_as_understood() {
+        let mut body = custom().encode()[4..].to_vec();
+        body.truncate(body.len() - 2);
+        body.extend_from_slice(&[0xAA, 0xBB]);
+        assert_eq!(Settings::decode(&record(SETTINGS_VERSION + 1, &body)), Ok(custom()));
+    }
+
+    #[test]
+    fn invalid_fields_fall_back_to_their_defaults() {
+        let defaults = Settings::default();
+        // ttl 0, interval 5 s, 1 dBm, unknown role, blank nickname
+        let body = [0, FLAG_RELAY, 0, 5, 1, 9, 1, b' '];
+        let settings = Settings::decode(&record(1, &body)).unwrap();
+        assert_eq!(settings.default_ttl, defaults.default_ttl);
+        assert_eq!(settings.announce_interval_s, defaults.announce_interval_s);
+        assert_eq!(settings.tx_power_dbm, defaults.tx_power_dbm);
+        assert_eq!(settings.role, Role::Peer);
+        assert_eq!(settings.nickname, defaults.nickname);
+        // The valid field still counts
+        assert!(settings.relay);
+
+        let body = [MAX_TTL + 1, 0, 0xFF, 0xFF, 0x80, 0, 0];
+        let settings = Settings::decode(&record(1, &body)).unwrap();
+        assert_eq!(settings.default_ttl, defaults.default_ttl);
+        assert_eq!(settings.announce_interval_s, defaults.announce_interval_s);
+        assert_eq!(settings.tx_power_dbm, defaults.tx_power_dbm);
+    }
+
+    #[test]
+    fn set_parses_every_key() {
+        let settings = custom();
+        assert_eq!(settings.nickname.as_str(), "attic");
+        assert_eq!(settings.default_ttl, 3);
+        assert!(!settings.relay);
+        assert_eq!(settings.announce_interval_s, 120);
+        assert_eq!(settings.tx_power_dbm, 4);
+        assert_eq!(settings.role, Role::Repeater);
+        assert!(settings.stats_replies);
+        assert!(settings.relays());
+
+        // Every key reads back in a form set() accepts
+        for key in KEYS {
+            let value = settings.get(key).unwrap().to_string();
+            let mut copy = Settings::default();
+            copy.set(key, &value).unwrap();
+            assert_eq!(copy.get(key), settings.get(key), "{}", key);
+        }
+        assert_eq!(settings.to_string().lines().count(), KEYS.len());
+    }
+
+    #[test]
+    fn bad_values_change_nothing() {
+        let mut settings = custom();
+        assert_eq!(settings.set("ttl", "8"), Err("ttl must be 1 to 7"));
+        assert_eq!(settings.set("ttl", "three"), Err("expected a number"));
+        assert_eq!(settings.set("relay", "maybe"), Err("expected on or off"));
+        assert_eq!(settings.set("announce-interval", "9s"), Err("announce interval must be 10 to 3600 s"));
+        assert!(settings.set("tx-power", "1dBm").is_err());
+        assert_eq!(settings.set("role", "king"), Err("expected peer, repeater or leaf"));
+        assert_eq!(settings.set("nickname", " "), Err("nickname is empty"));
+        assert_eq!(settings.set("colour", "red"), Err("unknown key"));
+        assert_eq!(settings.set_line("ttl"), Err("expected <key> <value>"));
+        assert_eq!(settings, custom());
+
+        settings.set_line("  role leaf ").unwrap();
+        assert_eq!(settings.role, Role::Leaf);
+        assert!(!settings.relays());
+    }
+}
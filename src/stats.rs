@@ -0,0 +1,274 @@
+// Counters for what the node received, sent and lost along the way, so drops
+// show up as numbers instead of scattered warnings. NodeCore keeps the
+// registry; a snapshot adds the uptime it was taken at and is what the
+// console, the stats characteristic and mesh stats replies hand out.
+//
+// Encoded snapshot, all integers big-endian:
+//
+//   version | uptime ms u64 | rx counts | tx counts | decode errors | totals
+//
+// Counts are u32 per packet type, bitchat types 0..=11 then message types
+// 0..=4, slot 0 of each holding types this build doesn't know. Decode errors
+// follow DecodeError order, totals the field order of Stats.
+
+use core::fmt;
+use heapless::Vec;
+
+use crate::bitchat::{BitchatPacket, PacketType};
+use crate::protocol::handler::HandlerError;
+use crate::protocol::message::PROTOCOL_VERSION;
+use crate::protocol::peer::PeerId;
+
+pub const STATS_VERSION: u8 = 1;
+pub const STATS_TTL: u8 = 7;
+
+const BITCHAT_TYPES: usize = 12;
+const MESSAGE_TYPES: usize = 5;
+pub const DECODE_ERRORS: usize = 6;
+const TOTALS: usize = 6;
+const COUNTERS: usize = 2 * (BITCHAT_TYPES + MESSAGE_TYPES) + DECODE_ERRORS + TOTALS;
+pub const STATS_SIZE: usize = 1 + 8 + 4 * COUNTERS;
+
+const BITCHAT_NAMES: [&str; BITCHAT_TYPES] = [
+    "other", "text", "announce", "file", "ack", "discovery",
+    "keepalive", "error", "ping", "pingreply", "stats", "statsreply",
+];
+const MESSAGE_NAMES: [&str; MESSAGE_TYPES] = ["other", "text", "ack", "announce", "relay"];
+
+// Why a frame that reached us was thrown away before it could be handled.
+// Bad checksums are counted on their own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    // Neither format could make sense of it
+    Malformed,
+    Fragment,
+    Stream,
+    Authentication,
+    StaleEpoch,
+    Reliability,
+}
+
+impl DecodeError {
+    pub const ALL: [DecodeError; DECODE_ERRORS] = [
+        DecodeError::Malformed,
+        DecodeError::Fragment,
+        DecodeError::Stream,
+        DecodeError::Authentication,
+        DecodeError::StaleEpoch,
+        DecodeError::Reliability,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DecodeError::Malformed => "malformed",
+            DecodeError::Fragment => "fragment",
+            DecodeError::Stream => "stream",
+            DecodeError::Authentication => "auth",
+            DecodeError::StaleEpoch => "stale-epoch",
+            DecodeError::Reliability => "reliability",
+        }
+    }
+}
+
+// Frames per wire format and type
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TypeCounts {
+    pub bitchat: [u32; BITCHAT_TYPES],
+    pub message: [u32; MESSAGE_TYPES],
+}
+
+impl TypeCounts {
+    // Both formats carry their version and type in the first two bytes
+    pub fn count(&mut self, frame: &[u8]) {
+        let (Some(&version), Some(&kind)) = (frame.first(), frame.get(1)) else { return };
+        let table: &mut [u32] = if version == PROTOCOL_VERSION { &mut self.message } else { &mut self.bitchat };
+        let slot = if (kind as usize) < table.len() { kind as usize } else { 0 };
+        table[slot] = table[slot].wrapping_add(1);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.bitchat.iter().chain(&self.message).fold(0, |sum, n| sum.wrapping_add(*n))
+    }
+
+    fn counters(&self) -> impl Iterator<Item = &u32> {
+        self.bitchat.iter().chain(&self.message)
+    }
+
+    fn counters_mut(&mut self) -> impl Iterator<Item = &mut u32> {
+        self.bitchat.iter_mut().chain(&mut self.message)
+    }
+}
+
+// The registry. Counters wrap rather than saturate; readers diff snapshots.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub rx: TypeCounts,
+    pub tx: TypeCounts,
+    pub decode_errors: [u32; DECODE_ERRORS],
+    pub checksum_failures: u32,
+    pub duplicates: u32,
+    pub fragment_timeouts: u32,
+    pub relays: u32,
+    pub relay_suppressions: u32,
+    pub notify_failures: u32,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            rx: TypeCounts { bitchat: [0; BITCHAT_TYPES], message: [0; MESSAGE_TYPES] },
+            tx: TypeCounts { bitchat: [0; BITCHAT_TYPES], message: [0; MESSAGE_TYPES] },
+            decode_errors: [0; DECODE_ERRORS],
+            checksum_failures: 0,
+            duplicates: 0,
+            fragment_timeouts: 0,
+            relays: 0,
+            relay_suppressions: 0,
+            notify_failures: 0,
+        }
+    }
+
+    pub fn decode_error(&mut self, error: DecodeError) {
+        let slot = &mut self.decode_errors[error as usize];
+        *slot = slot.wrapping_add(1);
+    }
+
+    // A frame our own format rejected and the bitchat format couldn't parse either
+    pub fn rejected(&mut self, error: &HandlerError) {
+        let error = match error {
+            HandlerError::ChecksumError => {
+                self.checksum_failures = self.checksum_failures.wrapping_add(1);
+                return;
+            }
+            HandlerError::InvalidMessage => DecodeError::Malformed,
+            HandlerError::FragmentationError(_) => DecodeError::Fragment,
+            HandlerError::StreamError(_) => DecodeError::Stream,
+            HandlerError::AuthenticationError => DecodeError::Authentication,
+            HandlerError::StaleEpoch => DecodeError::StaleEpoch,
+            HandlerError::ReliabilityError(_) => DecodeError::Reliability,
+        };
+        self.decode_error(error);
+    }
+
+    pub fn snapshot(&self, now_ms: u64) -> StatsSnapshot {
+        StatsSnapshot { uptime_ms: now_ms, stats: self.clone() }
+    }
+
+    fn totals(&self) -> [u32; TOTALS] {
+        [
+            self.checksum_failures,
+            self.duplicates,
+            self.fragment_timeouts,
+            self.relays,
+            self.relay_suppressions,
+            self.notify_failures,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+    pub uptime_ms: u64,
+    pub stats: Stats,
+}
+
+impl StatsSnapshot {
+    pub fn encode(&self) -> Vec<u8, STATS_SIZE> {
+        let stats = &self.stats;
+        let totals = stats.totals();
+        let mut data = Vec::new();
+        let _ = data.push(STATS_VERSION);
+        let _ = data.extend_from_slice(&self.uptime_ms.to_be_bytes());
+        let counters = stats.rx.counters()
+            .chain(stats.tx.counters())
+            .chain(&stats.decode_errors)
+            .chain(&totals);
+        for counter in counters {
+            let _ = data.extend_from_slice(&counter.to_be_bytes());
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        if data.first() != Some(&STATS_VERSION) {
+            return Err("Unsupported stats version");
+        }
+        if data.len() < STATS_SIZE {
+            return Err("Stats too small");
+        }
+        let mut uptime = [0u8; 8];
+        uptime.copy_from_slice(&data[1..9]);
+
+        let mut values = data[9..STATS_SIZE].chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]));
+        let mut stats = Stats::new();
+        let mut totals = [0u32; TOTALS];
+        let slots = stats.rx.counters_mut()
+            .chain(stats.tx.counters_mut())
+            .chain(&mut stats.decode_errors)
+            .chain(&mut totals);
+        for (slot, value) in slots.zip(&mut values) {
+            *slot = value;
+        }
+        let [checksum_failures, duplicates, fragment_timeouts, relays, relay_suppressions, notify_failures] = totals;
+        stats.checksum_failures = checksum_failures;
+        stats.duplicates = duplicates;
+        stats.fragment_timeouts = fragment_timeouts;
+        stats.relays = relays;
+        stats.relay_suppressions = relay_suppressions;
+        stats.notify_failures = notify_failures;
+
+        Ok(Self { uptime_ms: u64::from_be_bytes(uptime), stats })
+    }
+}
+
+// Several lines, zero counts left out
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        writeln!(f, "uptime {}.{:03} s", self.uptime_ms / 1000, self.uptime_ms % 1000)?;
+        write_counts(f, "rx", &stats.rx)?;
+        write_counts(f, "tx", &stats.tx)?;
+
+        write!(f, "errors checksum={}", stats.checksum_failures)?;
+        for error in DecodeError::ALL {
+            let count = stats.decode_errors[error as usize];
+            if count > 0 {
+                write!(f, " {}={}", error.name(), count)?;
+            }
+        }
+        writeln!(f)?;
+        write!(f, "totals duplicates={} fragment-timeouts={} relays={} relay-suppressions={} notify-failures={}",
+            stats.duplicates, stats.fragment_timeouts, stats.relays, stats.relay_suppressions, stats.notify_failures)
+    }
+}
+
+fn write_counts(f: &mut fmt::Formatter<'_>, label: &str, counts: &TypeCounts) -> fmt::Result {
+    write!(f, "{} {}", label, counts.total())?;
+    for (format, names, table) in [("bitchat", &BITCHAT_NAMES[..], &counts.bitchat[..]), ("message", &MESSAGE_NAMES[..], &counts.message[..])] {
+        for (name, count) in names.iter().zip(table) {
+            if *count > 0 {
+                write!(f, " {}:{}={}", format, name, count)?;
+            }
+        }
+    }
+    writeln!(f)
+}
+
+// Asks `target` for its counters; only nodes with stats replies enabled answer
+pub fn create_request(local_id: PeerId, target: PeerId, timestamp: u64) -> Result<BitchatPacket, &'static str> {
+    let mut packet = BitchatPacket::new(PacketType::Stats, local_id, &[])?;
+    packet.timestamp = timestamp;
+    packet.ttl = STATS_TTL;
+    packet.recipient_id = Some(target);
+    Ok(packet)
+}
+
+pub fn create_reply(request: &BitchatPacket, local_id: PeerId, timestamp: u64, snapshot: &StatsSnapshot) -> Result<BitchatPacket, &'static str> {
+    let mut reply = BitchatPacket::new(PacketType::StatsReply, local_id, &snapshot.encode())?;
+    reply.timestamp = timestamp;
+    reply.ttl = STATS_TTL;
+    reply.recipient_id = Some(request.sender_id);
+    Ok(reply)
+}
@@ -0,0 +1,618 @@
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::{Deque, Vec};
+
+use crate::protocol::message::MAX_FRAGMENT_SIZE;
+use crate::protocol::stream::{FragmentSink, FragmentSource, SinkError};
+use crate::settings::{Settings, SETTINGS_RECORD_SIZE};
+
+// Top of flash is reserved in memory.x for persistent state
+pub const SETTINGS_PAGE: u32 = 0x000F_E000;
+// The first is where a single-page epoch log used to live, so it carries on
+pub const EPOCH_PAGES: [u32; 2] = [0x000F_F000, 0x000F_D000];
+// Incoming streams, just below the persistent pages
+pub const STREAM_REGION: u32 = 0x000B_D000;
+pub const STREAM_REGION_SIZE: u32 = 256 * 1024;
+const PAGE_SIZE: u32 = 4096;
+const WORD_SIZE: u32 = 4;
+const EMPTY_WORD: u32 = 0xFFFF_FFFF;
+const SETTINGS_SLOT: u32 = 64;
+// Flash work a FlashSink can have outstanding; a page erase takes ~85ms, so
+// this covers a page worth of fragments arriving meanwhile
+const MAX_FLASH_OPS: usize = 16;
+// One fragment's data, widened to whole words
+const STAGED_WRITE_SIZE: usize = MAX_FRAGMENT_SIZE + 2 * WORD_SIZE as usize;
+
+const _: () = assert!(SETTINGS_RECORD_SIZE <= SETTINGS_SLOT as usize);
+const _: () = assert!(STREAM_REGION + STREAM_REGION_SIZE <= EPOCH_PAGES[1]);
+
+// The boot epoch is an append-only log of u32 words, one per boot, across
+// two pages. Once one page is full the other is erased and the log carries
+// on there, so the last epoch stays in flash even if power fails mid-erase.
+pub async fn next_boot_epoch<F: NorFlash>(flash: &mut F) -> Result<u32, F::Error> {
+    // Newest epoch so far, with the page it is on and the slot after it
+    let mut newest: Option<(u32, usize, u32)> = None;
+
+    for (page, &base) in EPOCH_PAGES.iter().enumerate() {
+        let mut slot = 0u32;
+        while slot < PAGE_SIZE {
+            let mut word = [0u8; 4];
+            flash.read(base + slot, &mut word).await?;
+            let value = u32::from_le_bytes(word);
+            if value == EMPTY_WORD {
+                break;
+            }
+            slot += 4;
+            if newest.is_none_or(|(epoch, _, _)| value > epoch) {
+                newest = Some((value, page, slot));
+            }
+        }
+    }
+
+    let (last, page, slot) = newest.unwrap_or((0, 0, 0));
+
+    // Zero is never issued so it can mean "unknown" elsewhere
+    let mut epoch = last.wrapping_add(1);
+    if epoch == 0 || epoch == EMPTY_WORD {
+        epoch = 1;
+    }
+
+    let (page, slot) = if slot >= PAGE_SIZE {
+        let other = 1 - page;
+        flash.erase(EPOCH_PAGES[other], EPOCH_PAGES[other] + PAGE_SIZE).await?;
+        (other, 0)
+    } else {
+        (page, slot)
+    };
+    flash.write(EPOCH_PAGES[page] + slot, &epoch.to_le_bytes()).await?;
+
+    info!("Boot epoch {}", epoch);
+    Ok(epoch)
+}
+
+// The settings page is an append-only log of fixed slots, the newest last. A
+// slot that fails to decode (power lost mid-write, say) is skipped, so the
+// settings saved before it stay in force.
+pub async fn load_settings<F: NorFlash>(flash: &mut F) -> Result<Settings, F::Error> {
+    let mut settings = None;
+    let mut slot = 0u32;
+
+    while slot < PAGE_SIZE {
+        let mut record = [0u8; SETTINGS_SLOT as usize];
+        flash.read(SETTINGS_PAGE + slot, &mut record).await?;
+        if record[..4] == EMPTY_WORD.to_le_bytes() {
+            break;
+        }
+        match Settings::decode(&record) {
+            Ok(saved) => settings = Some(saved),
+            Err(e) => warn!("Settings slot {}: {}", slot / SETTINGS_SLOT, e),
+        }
+        slot += SETTINGS_SLOT;
+    }
+
+    match settings {
+        Some(settings) => {
+            info!("Loaded settings from slot {}", slot / SETTINGS_SLOT - 1);
+            Ok(settings)
+        }
+        None => {
+            info!("No saved settings, using defaults");
+            Ok(Settings::default())
+        }
+    }
+}
+
+// Appends `settings` after the last used slot. Once every 64 saves the page
+// is full and gets erased first; losing power right then means the next boot
+// starts from defaults.
+pub async fn save_settings<F: NorFlash>(flash: &mut F, settings: &Settings) -> Result<(), F::Error> {
+    let mut slot = 0u32;
+
+    while slot < PAGE_SIZE {
+        let mut word = [0u8; 4];
+        flash.read(SETTINGS_PAGE + slot, &mut word).await?;
+        if u32::from_le_bytes(word) == EMPTY_WORD {
+            break;
+        }
+        slot += SETTINGS_SLOT;
+    }
+
+    if slot >= PAGE_SIZE {
+        flash.erase(SETTINGS_PAGE, SETTINGS_PAGE + PAGE_SIZE).await?;
+        slot = 0;
+    }
+    let encoded = settings.encode();
+    let mut record = [0xFFu8; SETTINGS_SLOT as usize];
+    record[..encoded.len()].copy_from_slice(&encoded);
+    flash.write(SETTINGS_PAGE + slot, &record).await?;
+
+    info!("Saved settings to slot {}", slot / SETTINGS_SLOT);
+    Ok(())
+}
+
+// One step of writing a stream, left for whoever owns the flash since the
+// softdevice only writes asynchronously
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum FlashOp {
+    Erase { address: u32 },
+    Write { address: u32, data: Vec<u8, STAGED_WRITE_SIZE> },
+}
+
+pub async fn apply<F: NorFlash>(flash: &mut F, op: &FlashOp) -> Result<(), F::Error> {
+    match op {
+        FlashOp::Erase { address } => flash.erase(*address, address + PAGE_SIZE).await,
+        FlashOp::Write { address, data } => flash.write(*address, data).await,
+    }
+}
+
+// Writes a stream into a reserved region of NOR flash. The assembler is
+// synchronous, so erases and writes are queued as FlashOps instead, for the
+// flash owner to take with next_op() and report with op_done(). Reads go to
+// `region`, a synchronous view of the same flash, with the queued operations
+// laid over it, so the final hash check needn't wait for them.
+pub struct FlashSink<R: FragmentSource> {
+    region: R,
+    base: u32,
+    len: u32,
+    // Pages erased (or queued to be) since the stream began
+    erased: u64,
+    ops: Deque<FlashOp, MAX_FLASH_OPS>,
+    // An operation for this stream failed, so its data can't be trusted
+    failed: bool,
+}
+
+impl<R: FragmentSource> FlashSink<R> {
+    pub fn new(region: R, base: u32) -> Self {
+        assert!(region.len() <= u64::BITS * PAGE_SIZE && base.is_multiple_of(PAGE_SIZE));
+        Self { region, base, len: 0, erased: 0, ops: Deque::new(), failed: false }
+    }
+
+    pub fn region(&self) -> &R {
+        &self.region
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Oldest operation not yet carried out; call op_done() once it was attempted
+    pub fn next_op(&self) -> Option<&FlashOp> {
+        self.ops.front()
+    }
+
+    // Outcome of the operation from next_op(). A failure fails the stream.
+    pub fn op_done(&mut self, ok: bool) {
+        if self.ops.pop_front().is_some() && !ok {
+            self.failed = true;
+        }
+    }
+
+    // Nothing left queued, so the region reads the same as the stream
+    pub fn is_flushed(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn page_count(&self) -> u32 {
+        self.len.div_ceil(PAGE_SIZE)
+    }
+
+    fn needs_erase(&self, page: u32) -> bool {
+        page < self.page_count() && self.erased & (1 << page) == 0
+    }
+
+    fn erase(&mut self, page: u32) {
+        if self.ops.push_back(FlashOp::Erase { address: self.base + page * PAGE_SIZE }).is_ok() {
+            self.erased |= 1 << page;
+        }
+    }
+}
+
+impl<R: FragmentSource> FragmentSink for FlashSink<R> {
+    fn begin(&mut self, total_len: u32) -> Result<(), SinkError> {
+        if total_len > self.region.len() {
+            return Err(SinkError::OutOfSpace);
+        }
+        self.len = total_len;
+        self.erased = 0;
+        self.failed = false;
+        if self.needs_erase(0) && !self.ops.is_full() {
+            self.erase(0);
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u32, data: &[u8]) -> Result<(), SinkError> {
+        if self.failed {
+            return Err(SinkError::Io);
+        }
+        let end = offset as u64 + data.len() as u64;
+        if end > self.len as u64 {
+            return Err(SinkError::OutOfSpace);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = end as u32;
+        let (first, last) = (offset / PAGE_SIZE, (end - 1) / PAGE_SIZE);
+        let erases = (first..=last).filter(|&page| self.needs_erase(page)).count();
+        if self.ops.capacity() - self.ops.len() < erases + 1 {
+            return Err(SinkError::Busy);
+        }
+
+        // NOR writes must be word aligned. Pad partial words with 0xFF, which
+        // leaves the bits written by the neighbouring fragment untouched.
+        let start = offset - offset % WORD_SIZE;
+        let mut staged = Vec::new();
+        staged.resize(end.next_multiple_of(WORD_SIZE) as usize - start as usize, 0xFF)
+            .map_err(|_| SinkError::OutOfSpace)?;
+        staged[(offset - start) as usize..][..data.len()].copy_from_slice(data);
+
+        for page in first..=last {
+            if self.needs_erase(page) {
+                self.erase(page);
+            }
+        }
+        let _ = self.ops.push_back(FlashOp::Write { address: self.base + start, data: staged });
+
+        // In-order fragments then rarely have to wait for an erase
+        if self.needs_erase(last + 1) && !self.ops.is_full() {
+            self.erase(last + 1);
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+        if self.failed {
+            return Err(SinkError::Io);
+        }
+        if offset as u64 + buf.len() as u64 > self.len as u64 {
+            return Err(SinkError::OutOfSpace);
+        }
+        self.region.read_at(offset, buf)?;
+
+        // What flash will hold once the queue is through
+        let (from, to) = (offset as usize, offset as usize + buf.len());
+        for op in &self.ops {
+            let (at, len) = match op {
+                FlashOp::Erase { address } => (address - self.base, PAGE_SIZE as usize),
+                FlashOp::Write { address, data } => (address - self.base, data.len()),
+            };
+            let (start, stop) = (from.max(at as usize), to.min(at as usize + len));
+            if start >= stop {
+                continue;
+            }
+            let dst = &mut buf[start - from..stop - from];
+            match op {
+                FlashOp::Erase { .. } => dst.fill(0xFF),
+                FlashOp::Write { data, .. } => {
+                    let src = &data[start - at as usize..stop - at as usize];
+                    for (cell, byte) in dst.iter_mut().zip(src) {
+                        *cell &= byte;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        if self.failed {
+            return Err(SinkError::Io);
+        }
+        Ok(())
+    }
+
+    // Queued operations are still carried out; they only touch our region
+    fn abort(&mut self) {
+        self.len = 0;
+    }
+}
+
+// The stream region read straight from the memory map; only erasing and
+// writing need the softdevice
+#[cfg(feature = "firmware")]
+pub struct MappedFlash {
+    base: u32,
+    len: u32,
+}
+
+#[cfg(feature = "firmware")]
+impl MappedFlash {
+    // The caller reserves the range in memory.x, see STREAM_REGION
+    pub const fn new(base: u32, len: u32) -> Self {
+        Self { base, len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: flash is always mapped and nothing else is placed here
+        unsafe { core::slice::from_raw_parts(self.base as *const u8, self.len as usize) }
+    }
+}
+
+#[cfg(feature = "firmware")]
+impl FragmentSource for MappedFlash {
+    fn len(&self) -> u32 {
+        self.len
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+        let start = offset as usize;
+        let src = self.as_slice().get(start..start + buf.len()).ok_or(SinkError::OutOfSpace)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use embedded_storage_async::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use crate::protocol::message::{Message, MessageHeader, MessageType, HEADER_SIZE};
+    use crate::protocol::stream::{StreamAssembler, StreamFragments};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const BASE: u32 = 0x000F_D000;
+
+    // A range of flash, erased and written like NOR
+    struct RamFlash {
+        base: u32,
+        mem: std::vec::Vec<u8>,
+    }
+
+    impl RamFlash {
+        // The reserved top 12K
+        fn new() -> Self {
+            Self::region(BASE, 3)
+        }
+
+        fn region(base: u32, pages: u32) -> Self {
+            Self { base, mem: vec![0xFF; (pages * PAGE_SIZE) as usize] }
+        }
+
+        fn range(&self, offset: u32, len: usize) -> core::ops::Range<usize> {
+            let start = (offset - self.base) as usize;
+            start..start + len
+        }
+    }
+
+    // The flash as MappedFlash sees it on the device, shared with whoever
+    // applies the sink's operations
+    #[derive(Clone)]
+    struct Mapped(Rc<RefCell<RamFlash>>);
+
+    impl FragmentSource for Mapped {
+        fn len(&self) -> u32 {
+            self.0.borrow().mem.len() as u32
+        }
+
+        fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), SinkError> {
+            let flash = self.0.borrow();
+            let src = flash.mem.get(offset as usize..offset as usize + buf.len()).ok_or(SinkError::OutOfSpace)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 4;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let range = self.range(offset, bytes.len());
+            bytes.copy_from_slice(&self.mem[range]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let range = self.range(from, (to - from) as usize);
+            self.mem[range].fill(0xFF);
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let range = self.range(offset, bytes.len());
+            // NOR can only clear bits
+            for (cell, byte) in self.mem[range].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    // Nothing here ever waits
+    fn run<T>(future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("flash future pending"),
+        }
+    }
+
+    fn boot(flash: &mut RamFlash) -> u32 {
+        run(next_boot_epoch(flash)).unwrap()
+    }
+
+    #[test]
+    fn epochs_count_up_across_page_switches() {
+        let mut flash = RamFlash::new();
+        let per_page = PAGE_SIZE / 4;
+        for expected in 1..=3 * per_page {
+            assert_eq!(boot(&mut flash), expected);
+        }
+    }
+
+    #[test]
+    fn interrupted_erase_keeps_the_epoch() {
+        let mut flash = RamFlash::new();
+        let both_pages = 2 * PAGE_SIZE / 4;
+        for _ in 0..both_pages {
+            boot(&mut flash);
+        }
+        // Power lost after the next boot erased the older page but before it
+        // wrote its epoch
+        let older = flash.range(EPOCH_PAGES[0], PAGE_SIZE as usize);
+        flash.mem[older].fill(0xFF);
+        assert_eq!(boot(&mut flash), both_pages + 1);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut flash = RamFlash::new();
+        assert_eq!(run(load_settings(&mut flash)).unwrap(), Settings::default());
+
+        let settings = Settings { default_ttl: 5, ..Settings::default() };
+        run(save_settings(&mut flash, &settings)).unwrap();
+        assert_eq!(run(load_settings(&mut flash)).unwrap(), settings);
+    }
+
+    const PAGES: u32 = 4;
+
+    // A stream sink over four pages of flash left full of an older stream
+    fn stream_sink() -> (FlashSink<Mapped>, Rc<RefCell<RamFlash>>) {
+        let mut flash = RamFlash::region(STREAM_REGION, PAGES);
+        flash.mem.fill(0);
+        let flash = Rc::new(RefCell::new(flash));
+        (FlashSink::new(Mapped(flash.clone()), STREAM_REGION), flash)
+    }
+
+    fn apply_all(sink: &mut FlashSink<Mapped>, flash: &Rc<RefCell<RamFlash>>) {
+        while let Some(op) = sink.next_op().cloned() {
+            let ok = run(apply(&mut *flash.borrow_mut(), &op)).is_ok();
+            sink.op_done(ok);
+        }
+    }
+
+    fn data(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn feed(assembler: &mut StreamAssembler, frame: &[u8], sink: &mut FlashSink<Mapped>) -> Option<u32> {
+        let header = MessageHeader::deserialize(frame).unwrap();
+        let info = assembler.add_fragment(header, &frame[HEADER_SIZE..], 0, sink).unwrap();
+        info.map(|info| info.total_len)
+    }
+
+    #[test]
+    fn streams_are_written_to_flash() {
+        let (mut sink, flash) = stream_sink();
+        let data = data(3 * PAGE_SIZE as usize + 123);
+        let message = Message::new(MessageType::Text, [7; 6], 1, &[]).unwrap();
+        let mut assembler = StreamAssembler::new();
+
+        let mut completed = None;
+        for frame in StreamFragments::new(&message, &data[..], 247).unwrap() {
+            completed = feed(&mut assembler, &frame.unwrap(), &mut sink);
+            apply_all(&mut sink, &flash);
+        }
+        assert_eq!(completed, Some(data.len() as u32));
+        assert_eq!(&flash.borrow().mem[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn hash_check_sees_queued_writes() {
+        let (mut sink, flash) = stream_sink();
+        let data = data(2000);
+        let message = Message::new(MessageType::Text, [7; 6], 1, &[]).unwrap();
+        let mut assembler = StreamAssembler::new();
+
+        // Out of order, with only the manifest's erase carried out; the queue
+        // still has room since each write stays on the first page
+        let mut frames: std::vec::Vec<_> = StreamFragments::new(&message, &data[..], 247).unwrap()
+            .map(|frame| frame.unwrap())
+            .collect();
+        frames[1..].reverse();
+        feed(&mut assembler, &frames[0], &mut sink);
+        apply_all(&mut sink, &flash);
+        let mut completed = None;
+        for frame in &frames[1..] {
+            completed = feed(&mut assembler, frame, &mut sink);
+        }
+        assert_eq!(completed, Some(2000));
+        assert!(!sink.is_flushed());
+        assert!(flash.borrow().mem[..2000].iter().all(|&b| b == 0xFF));
+
+        apply_all(&mut sink, &flash);
+        assert_eq!(&flash.borrow().mem[..2000], &data[..]);
+    }
+
+    #[test]
+    fn unaligned_writes_keep_their_neighbours() {
+        let (mut sink, flash) = stream_sink();
+        sink.begin(16).unwrap();
+        sink.write_at(5, &[1, 2, 3]).unwrap();
+        sink.write_at(0, &[9; 5]).unwrap();
+        sink.write_at(8, &[4; 8]).unwrap();
+        for op in &sink.ops {
+            if let FlashOp::Write { address, data } = op {
+                assert!(address.is_multiple_of(WORD_SIZE) && (data.len() as u32).is_multiple_of(WORD_SIZE));
+            }
+        }
+
+        let mut buf = [0u8; 16];
+        sink.read_at(0, &mut buf).unwrap();
+        let expected = [9, 9, 9, 9, 9, 1, 2, 3, 4, 4, 4, 4, 4, 4, 4, 4];
+        assert_eq!(buf, expected);
+        apply_all(&mut sink, &flash);
+        assert_eq!(flash.borrow().mem[..16], expected);
+    }
+
+    #[test]
+    fn a_full_queue_refuses_writes_until_drained() {
+        let (mut sink, flash) = stream_sink();
+        sink.begin(PAGE_SIZE).unwrap();
+        let mut offset = 0;
+        while sink.write_at(offset, &[0x55; 64]).is_ok() {
+            offset += 64;
+        }
+        assert_eq!(sink.write_at(offset, &[0x55; 64]), Err(SinkError::Busy));
+        assert_eq!(sink.ops.len(), MAX_FLASH_OPS);
+
+        apply_all(&mut sink, &flash);
+        sink.write_at(offset, &[0x55; 64]).unwrap();
+    }
+
+    #[test]
+    fn streams_larger_than_the_region_are_refused() {
+        let (mut sink, _flash) = stream_sink();
+        assert_eq!(sink.begin(PAGES * PAGE_SIZE + 1), Err(SinkError::OutOfSpace));
+        sink.begin(PAGES * PAGE_SIZE).unwrap();
+        assert_eq!(sink.write_at(PAGES * PAGE_SIZE - 2, &[0; 4]), Err(SinkError::OutOfSpace));
+    }
+
+    #[test]
+    fn a_failed_operation_fails_the_stream() {
+        let (mut sink, _flash) = stream_sink();
+        sink.begin(100).unwrap();
+        sink.write_at(0, &[1; 50]).unwrap();
+        sink.op_done(false);
+        let mut buf = [0u8; 50];
+        assert_eq!(sink.read_at(0, &mut buf), Err(SinkError::Io));
+        assert_eq!(sink.finish(), Err(SinkError::Io));
+
+        // The next stream starts afresh
+        sink.begin(100).unwrap();
+        sink.write_at(0, &[1; 50]).unwrap();
+    }
+}
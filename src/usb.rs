@@ -0,0 +1,233 @@
+// Command console over USB CDC ACM. The softdevice owns the POWER peripheral,
+// so VBUS comes from its SoC events (see ble::softdevice_task) through a
+// SoftwareVbusDetect. Lines typed into the serial port go to the shell in
+// bitchat_metal::console.
+
+use core::fmt::Write;
+
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_nrf::interrupt::{self, InterruptExt, Priority};
+use embassy_nrf::peripherals::USBD;
+use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
+use embassy_nrf::usb::{self as nrf_usb, Driver};
+use embassy_nrf::bind_interrupts;
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::Timer;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, UsbDevice};
+use heapless::String;
+use nrf_softdevice::{raw, SocEvent};
+use static_cell::StaticCell;
+
+use bitchat_metal::capture::pcapng::MAX_BLOCK_SIZE;
+use bitchat_metal::config::DEVICE_NAME;
+use bitchat_metal::console::{LineBuffer, Outcome};
+
+use crate::ble::service::BitchatServer;
+
+bind_interrupts!(struct Irqs {
+    USBD => nrf_usb::InterruptHandler<USBD>;
+});
+
+pub type UsbDriver = Driver<'static, USBD, &'static SoftwareVbusDetect>;
+
+const MAX_PACKET: usize = 64;
+// Every typed byte echoes as at most three
+const MAX_ECHO: usize = 3 * MAX_PACKET;
+// Longer replies are cut short; `topology` on a big mesh is the one that hits it
+const MAX_REPLY: usize = 1536;
+const PROMPT: &str = "> ";
+
+// USBREGSTATUS bits as reported by sd_power_usbregstatus_get
+const USBREG_VBUS_DETECTED: u32 = 1 << 0;
+const USBREG_OUTPUT_READY: u32 = 1 << 1;
+
+// Shared by the softdevice task, which feeds it, and the USB driver
+pub fn vbus_detect() -> &'static SoftwareVbusDetect {
+    static VBUS: OnceLock<SoftwareVbusDetect> = OnceLock::new();
+    VBUS.get_or_init(|| SoftwareVbusDetect::new(false, false))
+}
+
+// Asks the softdevice for USB power events and catches up on the current
+// state, since a cable plugged in before boot raises none
+pub fn enable_power_events(vbus: &SoftwareVbusDetect) {
+    let mut status = 0;
+    unsafe {
+        raw::sd_power_usbdetected_enable(1);
+        raw::sd_power_usbpwrrdy_enable(1);
+        raw::sd_power_usbremoved_enable(1);
+        raw::sd_power_usbregstatus_get(&mut status);
+    }
+    if status & USBREG_VBUS_DETECTED != 0 {
+        vbus.detected(true);
+    }
+    if status & USBREG_OUTPUT_READY != 0 {
+        vbus.ready();
+    }
+}
+
+pub fn on_soc_event(vbus: &SoftwareVbusDetect, event: SocEvent) {
+    match event {
+        SocEvent::PowerUsbDetected => vbus.detected(true),
+        SocEvent::PowerUsbPowerReady => vbus.ready(),
+        SocEvent::PowerUsbRemoved => vbus.detected(false),
+        _ => {}
+    }
+}
+
+pub fn driver(usbd: USBD, vbus: &'static SoftwareVbusDetect) -> UsbDriver {
+    // Anything above P2 would preempt the softdevice
+    interrupt::USBD.set_priority(Priority::P2);
+    Driver::new(usbd, Irqs, vbus)
+}
+
+// Builds the CDC ACM device and spawns the tasks serving it
+pub fn start(spawner: &Spawner, driver: UsbDriver, server: &'static BitchatServer) {
+    let mut config = embassy_usb::Config::new(0x1209, 0x0001);
+    config.manufacturer = Some("bitchat-metal");
+    config.product = Some(DEVICE_NAME);
+    config.max_power = 100;
+    config.max_packet_size_0 = MAX_PACKET as u8;
+    // Interface association descriptors, so Windows binds its CDC driver
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut CONFIG_DESCRIPTOR.init([0; 256])[..],
+        &mut BOS_DESCRIPTOR.init([0; 256])[..],
+        &mut [],
+        &mut CONTROL_BUF.init([0; 64])[..],
+    );
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), MAX_PACKET as u16);
+    let device = builder.build();
+
+    spawner.must_spawn(usb_task(device));
+    spawner.must_spawn(console_task(class, server));
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut device: UsbDevice<'static, UsbDriver>) -> ! {
+    device.run().await
+}
+
+#[embassy_executor::task]
+async fn console_task(mut class: CdcAcmClass<'static, UsbDriver>, server: &'static BitchatServer) -> ! {
+    loop {
+        class.wait_connection().await;
+        info!("USB console attached");
+        if let Err(e) = serve(&mut class, server).await {
+            info!("USB console detached: {:?}", e);
+        }
+    }
+}
+
+async fn serve(class: &mut CdcAcmClass<'static, UsbDriver>, server: &BitchatServer) -> Result<(), EndpointError> {
+    let mut lines: LineBuffer = LineBuffer::new();
+    let mut packet = [0u8; MAX_PACKET];
+    write_text(class, "bitchat-metal console, `help` lists commands\n").await?;
+    write_text(class, PROMPT).await?;
+
+    loop {
+        let len = class.read_packet(&mut packet).await?;
+        let mut echo: String<MAX_ECHO> = String::new();
+        for &byte in &packet[..len] {
+            push_echo(&mut echo, byte);
+            let Some(line) = lines.push(byte) else { continue };
+
+            let mut reply: String<MAX_REPLY> = String::new();
+            let outcome = match line {
+                Ok(line) => server.run_command(line, &mut reply),
+                Err(e) => {
+                    let _ = writeln!(reply, "error: {}", e);
+                    Outcome::Done
+                }
+            };
+            write_text(class, &echo).await?;
+            echo.clear();
+            write_text(class, &reply).await?;
+
+            if outcome == Outcome::DumpCapture {
+                write_capture(class, server).await?;
+            }
+            if outcome == Outcome::Reboot {
+                // A change made just before would otherwise be lost
+                server.flush_settings().await;
+                // Let the host read the reply first
+                Timer::after_millis(100).await;
+                reboot();
+            }
+            write_text(class, PROMPT).await?;
+        }
+        write_text(class, &echo).await?;
+    }
+}
+
+// Terminals don't echo locally, so typed characters are sent back
+fn push_echo(echo: &mut String<MAX_ECHO>, byte: u8) {
+    let _ = match byte {
+        b'\r' | b'\n' => echo.push_str("\r\n"),
+        0x08 | 0x7f => echo.push_str("\x08 \x08"),
+        0x20..=0x7e => echo.push(byte as char),
+        _ => Ok(()),
+    };
+}
+
+// Writes the capture ring as a pcapng file in hex, 48 bytes per line. On the
+// host: grep -o 'pcapng [0-9a-f]*' | cut -d' ' -f2 | xxd -r -p
+async fn write_capture(
+    class: &mut CdcAcmClass<'static, UsbDriver>,
+    server: &BitchatServer,
+) -> Result<(), EndpointError> {
+    let mut export = server.capture_export();
+    let mut block = [0u8; MAX_BLOCK_SIZE];
+    while let Some(len) = server.next_capture_block(&mut export, &mut block) {
+        for chunk in block[..len].chunks(48) {
+            let mut line: String<104> = String::new();
+            let _ = line.push_str("pcapng ");
+            for byte in chunk {
+                let _ = write!(line, "{:02x}", byte);
+            }
+            let _ = line.push('\n');
+            write_text(class, &line).await?;
+        }
+    }
+    Ok(())
+}
+
+// Sends text with LF turned into CRLF. A packet is sent once it can't take
+// another CRLF without filling up, so none is ever MAX_PACKET long and none
+// needs a zero-length packet after it.
+async fn write_text(class: &mut CdcAcmClass<'static, UsbDriver>, text: &str) -> Result<(), EndpointError> {
+    let mut packet: heapless::Vec<u8, MAX_PACKET> = heapless::Vec::new();
+    for byte in text.bytes() {
+        if packet.len() + 2 >= MAX_PACKET {
+            class.write_packet(&packet).await?;
+            packet.clear();
+        }
+        if byte == b'\n' {
+            let _ = packet.push(b'\r');
+        }
+        let _ = packet.push(byte);
+    }
+    if !packet.is_empty() {
+        class.write_packet(&packet).await?;
+    }
+    Ok(())
+}
+
+fn reboot() -> ! {
+    warn!("Rebooting on console request");
+    // A system reset through the SCB is allowed with the softdevice enabled
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,40 @@
+// Every recording under recordings/ is a regression test: the replay tool
+// exits non-zero when one of its `expect` or `reject` lines fails.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn recordings() -> Vec<PathBuf> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("recordings");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rec"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn recordings_replay_as_expected() {
+    let paths = recordings();
+    assert!(!paths.is_empty(), "no recordings found");
+
+    let mut failed = Vec::new();
+    for path in &paths {
+        let text = std::fs::read_to_string(path).unwrap();
+        assert!(
+            text.lines().any(|line| line.trim_start().starts_with("expect ")),
+            "{} checks nothing",
+            path.display(),
+        );
+
+        let output = Command::new(env!("CARGO_BIN_EXE_replay")).arg(path).output().unwrap();
+        if !output.status.success() {
+            println!("{}", String::from_utf8_lossy(&output.stdout));
+            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+            failed.push(path.display().to_string());
+        }
+    }
+    assert!(failed.is_empty(), "failed: {}", failed.join(", "));
+}